@@ -7,7 +7,7 @@
 #[cfg(test)]
 mod tests {
     use anyhow::{anyhow, Result};
-    use libfalcon::{unit::gb, Runner};
+    use libfalcon::prelude::*;
 
     #[tokio::test]
     #[ignore]
@@ -4,7 +4,7 @@
 
 // Copyright 2022 Oxide Computer Company
 
-use libfalcon::{cli::run, error::Error, unit::gb, Runner};
+use libfalcon::prelude::*;
 
 #[tokio::main]
 async fn main() -> Result<(), Error> {
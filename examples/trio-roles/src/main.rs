@@ -0,0 +1,70 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+// Copyright 2022 Oxide Computer Company
+
+//! A pair of traffic generators talking through a router.
+//!
+//!   violin 10.0.1.2 -- 10.0.1.1 cello 10.0.2.1 -- 10.0.2.2 piano
+
+use libfalcon::prelude::*;
+
+#[tokio::main]
+async fn main() -> Result<(), Error> {
+    let mut d = topology();
+    run(&mut d).await?;
+    Ok(())
+}
+
+fn topology() -> Runner {
+    let mut d = Runner::new("trio");
+
+    let violin = d.node("violin", "helios-2.3", 2, gb(2));
+    let cello = d.node("cello", "helios-2.3", 2, gb(2));
+    let piano = d.node("piano", "helios-2.3", 2, gb(2));
+
+    d.link(violin, cello);
+    d.link(cello, piano);
+
+    d.role(violin, Role::TrafficGen);
+    d.role(cello, Role::Ipv4Router);
+    d.role(piano, Role::TrafficGen);
+
+    d
+}
+
+#[cfg(test)]
+mod tests {
+    use anyhow::{anyhow, Result};
+
+    #[tokio::test]
+    #[ignore]
+    async fn trio_traffic_flows() -> Result<()> {
+        let d = super::topology();
+        let violin = d.find_node("violin").unwrap();
+        let cello = d.find_node("cello").unwrap();
+        let piano = d.find_node("piano").unwrap();
+
+        d.launch().await?;
+
+        let addr = |ifx: &str, addr: &str| {
+            format!("ipadm create-addr -t -T static -a {} {}/v4", addr, ifx)
+        };
+        d.exec(violin, &addr("vioif0", "10.0.1.2/24")).await?;
+        d.exec(cello, &addr("vioif0", "10.0.1.1/24")).await?;
+        d.exec(cello, &addr("vioif1", "10.0.2.1/24")).await?;
+        d.exec(piano, &addr("vioif0", "10.0.2.2/24")).await?;
+        d.exec(violin, "route add default 10.0.1.1").await?;
+        d.exec(piano, "route add default 10.0.2.1").await?;
+
+        // piano's iperf3 server was started by its role, this only succeeds
+        // if the router forwards
+        let out = d.exec(violin, "iperf3 -c 10.0.2.2 -t 2").await?;
+        if !out.contains("receiver") {
+            return Err(anyhow!("no traffic reached piano:\n{}", out));
+        }
+
+        Ok(())
+    }
+}
@@ -0,0 +1,190 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+// Copyright 2022 Oxide Computer Company
+
+//! Nodes named on the command line. A plain name is a node of the topology
+//! run from the current directory. A name qualified as `topology:node` is a
+//! node of any running topology, found through the registry.
+
+use crate::error::Error;
+use crate::Deployment;
+use camino::Utf8PathBuf;
+use std::fmt;
+use std::str::FromStr;
+
+/// A node name, qualified with its topology or not.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct NodeAddr {
+    /// The topology of the node, the current one if `None`.
+    pub topology: Option<String>,
+    pub node: String,
+}
+
+impl FromStr for NodeAddr {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Error> {
+        let mut parts = s.split(':');
+        let (first, second) = (parts.next().unwrap_or(""), parts.next());
+        if parts.next().is_some() {
+            return Err(Error::Cli(format!(
+                "{} is ambiguous, qualify a node once as topology:node",
+                s
+            )));
+        }
+        match second {
+            None if !first.is_empty() => Ok(NodeAddr {
+                topology: None,
+                node: first.into(),
+            }),
+            Some(node) if !first.is_empty() && !node.is_empty() => {
+                Ok(NodeAddr {
+                    topology: Some(first.into()),
+                    node: node.into(),
+                })
+            }
+            _ => Err(Error::Cli(format!(
+                "{:?} is not a node or topology:node name",
+                s
+            ))),
+        }
+    }
+}
+
+impl fmt::Display for NodeAddr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.topology {
+            Some(ref t) => write!(f, "{}:{}", t, self.node),
+            None => write!(f, "{}", self.node),
+        }
+    }
+}
+
+/// Resolve `addrs`, which must all name nodes of one topology, against the
+/// `current` topology. Returns the plain node names, and when any name is
+/// qualified, the falcon directory and topology `lookup` finds for it.
+pub fn resolve<F>(
+    current: &Deployment,
+    addrs: &[NodeAddr],
+    lookup: F,
+) -> Result<(Vec<String>, Option<(Utf8PathBuf, Deployment)>), Error>
+where
+    F: FnOnce(&str) -> Result<(Utf8PathBuf, Deployment), Error>,
+{
+    let topology = |a: &NodeAddr| -> String {
+        a.topology.clone().unwrap_or_else(|| current.name.clone())
+    };
+    if let Some(first) = addrs.first() {
+        if let Some(other) =
+            addrs.iter().find(|a| topology(a) != topology(first))
+        {
+            return Err(Error::Cli(format!(
+                "{} and {} are in different topologies, only external links \
+                join topologies",
+                first, other
+            )));
+        }
+    }
+    let nodes = addrs.iter().map(|a| a.node.clone()).collect();
+
+    let name = match addrs.iter().find_map(|a| a.topology.as_ref()) {
+        Some(name) => name,
+        None => return Ok((nodes, None)),
+    };
+    let (dir, d) = lookup(name)?;
+    for a in addrs {
+        if d.node_named(&a.node).is_none() {
+            return Err(Error::NotFound(format!(
+                "node {} in topology {}",
+                a.node, name
+            )));
+        }
+    }
+    Ok((nodes, Some((dir, d))))
+}
+
+#[cfg(test)]
+mod test {
+    use anyhow::{anyhow, Result};
+
+    /// Test that node names qualified with their topology resolve through the
+    /// registry, and that ambiguous, unknown and mixed names are refused.
+    #[test]
+    fn node_addresses() -> Result<()> {
+        use crate::address::{resolve, NodeAddr};
+        use crate::error::Error;
+
+        let addr = |s: &str| s.parse::<NodeAddr>();
+        assert_eq!(
+            addr("core:rs1")?,
+            NodeAddr {
+                topology: Some("core".into()),
+                node: "rs1".into()
+            }
+        );
+        assert_eq!(addr("violin")?.topology, None);
+        assert_eq!(addr("core:rs1")?.to_string(), "core:rs1");
+        for bad in ["core:rs1:up0", "core:", ":rs1", ""] {
+            assert!(matches!(addr(bad), Err(Error::Cli(_))), "{}", bad);
+        }
+
+        let mut lab = crate::Runner::new("lab");
+        lab.persistent = true;
+        lab.node("violin", "helios-2.3", 1, 1024);
+        let mut core = crate::Runner::new("core");
+        core.persistent = true;
+        core.node("rs1", "helios-2.3", 1, 1024);
+        core.node("rs2", "helios-2.3", 1, 1024);
+        let core_dir = camino::Utf8PathBuf::from("/opt/core/.falcon");
+        let core_ron = ron::ser::to_string(&core.deployment)?;
+        let lookup = |name: &str| -> Result<_, Error> {
+            match name {
+                "core" => {
+                    let d: crate::Deployment = ron::de::from_str(&core_ron)?;
+                    Ok((core_dir.clone(), d))
+                }
+                _ => Err(Error::ExternalPeer(format!(
+                    "no topology named {} is running",
+                    name
+                ))),
+            }
+        };
+        let addrs = |names: &[&str]| -> Result<Vec<NodeAddr>> {
+            Ok(names
+                .iter()
+                .map(|n| n.parse())
+                .collect::<Result<_, Error>>()?)
+        };
+
+        // plain names keep meaning the current topology
+        let (nodes, other) =
+            resolve(&lab.deployment, &addrs(&["violin"])?, lookup)?;
+        assert_eq!(nodes, vec!["violin"]);
+        assert!(other.is_none());
+
+        let pair = addrs(&["core:rs1", "core:rs2"])?;
+        let (nodes, other) = resolve(&lab.deployment, &pair, lookup)?;
+        assert_eq!(nodes, vec!["rs1", "rs2"]);
+        let (dir, d) = other.ok_or_else(|| anyhow!("core not resolved"))?;
+        assert_eq!((dir, d.name.as_str()), (core_dir.clone(), "core"));
+
+        let mixed = addrs(&["core:rs1", "violin"])?;
+        assert!(matches!(
+            resolve(&lab.deployment, &mixed, lookup),
+            Err(Error::Cli(_))
+        ));
+        let missing = addrs(&["core:rs9"])?;
+        assert!(matches!(
+            resolve(&lab.deployment, &missing, lookup),
+            Err(Error::NotFound(_))
+        ));
+        let gone = addrs(&["gone:rs1"])?;
+        assert!(matches!(
+            resolve(&lab.deployment, &gone, lookup),
+            Err(Error::ExternalPeer(_))
+        ));
+        Ok(())
+    }
+}
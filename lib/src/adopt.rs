@@ -0,0 +1,483 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+// Copyright 2022 Oxide Computer Company
+
+//! Adopting vnics that already exist under a name falcon planned to create.
+//! A vnic whose properties match the plan is used as is. One that differs is
+//! refused with a property by property diff, unless the runner allows
+//! correcting mismatches and every difference can be corrected in place.
+//! Adopted vnics are recorded in the falcon directory, and are left in place
+//! when the topology's network is destroyed.
+//!
+//! Only the vnics of external links are adopted. Falcon records the ones it
+//! creates, so one it finds under a name it recorded is its own leftover
+//! from an earlier run and is replaced rather than adopted. The simnets and
+//! vnics of point to point links, and the management network, are always
+//! falcon's own and are replaced when found.
+
+use crate::error::Error;
+use crate::mgmt::{link_exists, net_cmd};
+use crate::{Backend, Runner, Step, DLADM_BIN};
+use camino::Utf8Path;
+use slog::info;
+use std::fmt;
+use std::fs;
+
+/// The file in the falcon directory listing adopted vnics, one per line.
+pub const ADOPTED_FILE: &str = "adopted";
+
+/// The file in the falcon directory listing the external link vnics falcon
+/// created, one per line.
+pub const CREATED_FILE: &str = "created";
+
+/// The properties compared between a planned vnic and one found on the host.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct VnicProps {
+    /// The link the vnic is created over.
+    pub over: String,
+    /// The vnic's MAC address, `None` in a plan when any address will do.
+    pub mac: Option<Vec<u8>>,
+    /// The VLAN id, 0 for none.
+    pub vid: u16,
+    pub mtu: u32,
+}
+
+/// A property of an existing vnic that differs from the plan.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PropDiff {
+    pub prop: &'static str,
+    pub planned: String,
+    pub actual: String,
+    /// Whether falcon can safely change the property in place.
+    pub correctable: bool,
+}
+
+impl fmt::Display for PropDiff {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{}: planned {}, found {}",
+            self.prop, self.planned, self.actual
+        )?;
+        if self.correctable {
+            write!(f, " (correctable with --adopt-mismatched)")?;
+        }
+        Ok(())
+    }
+}
+
+/// Format a MAC address the way `dladm` does.
+pub(crate) fn fmt_mac(mac: &[u8]) -> String {
+    mac.iter()
+        .map(|b| format!("{:x}", b))
+        .collect::<Vec<_>>()
+        .join(":")
+}
+
+/// The differences between a `planned` vnic and the `actual` one. The MAC
+/// address and MTU can be changed on an existing vnic, the link it is over
+/// and its VLAN id cannot.
+pub fn diff(planned: &VnicProps, actual: &VnicProps) -> Vec<PropDiff> {
+    let mut result = Vec::new();
+    if planned.over != actual.over {
+        result.push(PropDiff {
+            prop: "over",
+            planned: planned.over.clone(),
+            actual: actual.over.clone(),
+            correctable: false,
+        });
+    }
+    if let (Some(p), Some(a)) = (&planned.mac, &actual.mac) {
+        if p != a {
+            result.push(PropDiff {
+                prop: "mac",
+                planned: fmt_mac(p),
+                actual: fmt_mac(a),
+                correctable: true,
+            });
+        }
+    }
+    if planned.vid != actual.vid {
+        result.push(PropDiff {
+            prop: "vid",
+            planned: planned.vid.to_string(),
+            actual: actual.vid.to_string(),
+            correctable: false,
+        });
+    }
+    if planned.mtu != actual.mtu {
+        result.push(PropDiff {
+            prop: "mtu",
+            planned: planned.mtu.to_string(),
+            actual: actual.mtu.to_string(),
+            correctable: true,
+        });
+    }
+    result
+}
+
+/// Split a line of `dladm -p` output into fields. Colons within a field are
+/// escaped with a backslash.
+pub(crate) fn split_parseable(line: &str) -> Vec<String> {
+    let mut fields = vec![String::new()];
+    let mut chars = line.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '\\' => {
+                if let Some(n) = chars.next() {
+                    fields.last_mut().unwrap().push(n);
+                }
+            }
+            ':' => fields.push(String::new()),
+            c => fields.last_mut().unwrap().push(c),
+        }
+    }
+    fields
+}
+
+/// Parse `dladm show-vnic -p -o over,macaddress,vid` output along with the
+/// vnic's MTU.
+pub(crate) fn parse_show_vnic(out: &str, mtu: u32) -> Option<VnicProps> {
+    let f = split_parseable(out.lines().next()?.trim());
+    if f.len() != 3 {
+        return None;
+    }
+    let mac = f[1]
+        .split(':')
+        .map(|b| u8::from_str_radix(b, 16).ok())
+        .collect::<Option<Vec<u8>>>()?;
+    Some(VnicProps {
+        over: f[0].clone(),
+        mac: Some(mac),
+        vid: f[2].parse().ok()?,
+        mtu,
+    })
+}
+
+/// The MTU of a data link.
+pub(crate) fn link_mtu(host: &dyn Backend, link: &str) -> Result<u32, Error> {
+    let out = host.run(
+        DLADM_BIN,
+        &["show-linkprop", "-c", "-o", "value", "-p", "mtu", link],
+    )?;
+    let value = String::from_utf8(out.stdout)?;
+    value.trim().parse().map_err(|_| {
+        Error::Link(format!("cannot read the mtu of {}: {}", link, value))
+    })
+}
+
+/// The properties of the vnic `name`, `None` if no link has that name.
+pub(crate) fn show_vnic(
+    host: &dyn Backend,
+    name: &str,
+) -> Result<Option<VnicProps>, Error> {
+    if !link_exists(host, name) {
+        return Ok(None);
+    }
+    let out = host.run(
+        DLADM_BIN,
+        &["show-vnic", "-p", "-o", "over,macaddress,vid", name],
+    )?;
+    let not_vnic =
+        || Error::Link(format!("{} already exists and is not a vnic", name));
+    if !out.status.success() {
+        return Err(not_vnic());
+    }
+    let out = String::from_utf8(out.stdout)?;
+    parse_show_vnic(&out, link_mtu(host, name)?)
+        .map(Some)
+        .ok_or_else(not_vnic)
+}
+
+/// The vnics adopted by the deployment in `falcon_dir`.
+pub fn adopted(falcon_dir: &Utf8Path) -> Vec<String> {
+    read_names(&falcon_dir.join(ADOPTED_FILE))
+}
+
+pub(crate) fn mark_adopted(
+    falcon_dir: &Utf8Path,
+    name: &str,
+) -> Result<(), Error> {
+    add_name(&falcon_dir.join(ADOPTED_FILE), name)
+}
+
+/// The external link vnics the deployment in `falcon_dir` created and has
+/// not destroyed yet.
+pub fn created(falcon_dir: &Utf8Path) -> Vec<String> {
+    read_names(&falcon_dir.join(CREATED_FILE))
+}
+
+pub(crate) fn mark_created(
+    falcon_dir: &Utf8Path,
+    name: &str,
+) -> Result<(), Error> {
+    add_name(&falcon_dir.join(CREATED_FILE), name)
+}
+
+pub(crate) fn unmark_created(
+    falcon_dir: &Utf8Path,
+    name: &str,
+) -> Result<(), Error> {
+    let path = falcon_dir.join(CREATED_FILE);
+    let names: Vec<String> = read_names(&path)
+        .into_iter()
+        .filter(|n| n != name)
+        .collect();
+    if names.is_empty() {
+        return match fs::remove_file(&path) {
+            Err(e) if e.kind() != std::io::ErrorKind::NotFound => Err(e.into()),
+            _ => Ok(()),
+        };
+    }
+    fs::write(&path, names.join("\n") + "\n")?;
+    Ok(())
+}
+
+fn read_names(path: &Utf8Path) -> Vec<String> {
+    fs::read_to_string(path)
+        .map(|s| s.lines().map(Into::into).collect())
+        .unwrap_or_default()
+}
+
+fn add_name(path: &Utf8Path, name: &str) -> Result<(), Error> {
+    let mut names = read_names(path);
+    if !names.iter().any(|n| n == name) {
+        names.push(name.into());
+    }
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir)?;
+    }
+    fs::write(path, names.join("\n") + "\n")?;
+    Ok(())
+}
+
+/// Forget earlier adoptions, ahead of creating the network again.
+pub(crate) fn clear_adopted(falcon_dir: &Utf8Path) -> Result<(), Error> {
+    match fs::remove_file(falcon_dir.join(ADOPTED_FILE)) {
+        Err(e) if e.kind() != std::io::ErrorKind::NotFound => Err(e.into()),
+        _ => Ok(()),
+    }
+}
+
+impl Runner {
+    /// Take over the existing vnic `name` in place of creating it as
+    /// `planned`, or explain why it cannot be.
+    pub(crate) fn adopt_vnic(
+        &self,
+        name: &str,
+        planned: &VnicProps,
+        actual: &VnicProps,
+    ) -> Result<(), Error> {
+        let diffs = diff(planned, actual);
+        let correct =
+            self.adopt_mismatched && diffs.iter().all(|d| d.correctable);
+        if !diffs.is_empty() && !correct {
+            let lines: Vec<String> =
+                diffs.iter().map(|d| format!("  {}", d)).collect();
+            return Err(Error::Link(format!(
+                "vnic {} already exists and differs from the plan\n{}",
+                name,
+                lines.join("\n")
+            )));
+        }
+        for d in diffs.iter() {
+            match d.prop {
+                "mac" => net_cmd(
+                    &self.plan,
+                    DLADM_BIN,
+                    &["modify-vnic", "-t", "-m", &d.planned, name],
+                )?,
+                "mtu" => {
+                    let prop = format!("mtu={}", d.planned);
+                    net_cmd(
+                        &self.plan,
+                        DLADM_BIN,
+                        &["set-linkprop", "-t", "-p", &prop, name],
+                    )?
+                }
+                _ => {}
+            }
+            info!(
+                self.log,
+                "{}: corrected {} from {} to {}",
+                name,
+                d.prop,
+                d.actual,
+                d.planned
+            );
+        }
+        info!(
+            self.log,
+            "adopting existing vnic {}, falcon will leave it in place", name
+        );
+        let path = self.falcon_dir.join(ADOPTED_FILE);
+        self.plan
+            .step(Step::write(path), || mark_adopted(&self.falcon_dir, name))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::test::Scratch;
+    use anyhow::{anyhow, Result};
+
+    /// Test that existing vnics are adopted when they match the plan, refused
+    /// with a diff when they do not, and corrected in place only when allowed
+    /// and only for the properties falcon can change.
+    #[test]
+    fn vnic_adoption() -> Result<()> {
+        use crate::adopt::{
+            adopted, clear_adopted, diff, parse_show_vnic, VnicProps,
+        };
+        use crate::ops::fake;
+        use std::sync::{Arc, Mutex};
+
+        let found = parse_show_vnic("igb0:2\\:8\\:20\\:ab\\:cd\\:ef:0\n", 1500)
+            .ok_or_else(|| anyhow!("unparsed"))?;
+        assert_eq!(
+            found,
+            VnicProps {
+                over: "igb0".into(),
+                mac: Some(vec![0x02, 0x08, 0x20, 0xab, 0xcd, 0xef]),
+                vid: 0,
+                mtu: 1500,
+            }
+        );
+        assert_eq!(parse_show_vnic("igb0:0", 1500), None);
+
+        // any address will do when the plan has none
+        let planned = VnicProps {
+            mac: None,
+            ..found.clone()
+        };
+        assert!(diff(&planned, &found).is_empty());
+
+        let mismatched = VnicProps {
+            mac: Some(vec![0x02, 0x08, 0x20, 0, 0, 1]),
+            mtu: 9000,
+            ..found.clone()
+        };
+        let d = diff(&mismatched, &found);
+        assert_eq!(d.len(), 2);
+        assert!(d.iter().all(|d| d.correctable));
+        assert_eq!(
+            d[0].to_string(),
+            "mac: planned 2:8:20:0:0:1, found 2:8:20:ab:cd:ef \
+            (correctable with --adopt-mismatched)"
+        );
+        let elsewhere = VnicProps {
+            over: "e1000g0".into(),
+            vid: 12,
+            ..mismatched.clone()
+        };
+        let d = diff(&elsewhere, &found);
+        let props: Vec<&str> = d.iter().map(|d| d.prop).collect();
+        assert_eq!(props, ["over", "mac", "vid", "mtu"]);
+        assert!(!d[0].correctable && !d[2].correctable);
+
+        let commands = Arc::new(Mutex::new(Vec::<String>::new()));
+        let log = commands.clone();
+        let host = fake::backend(move |_, args| {
+            log.lock().unwrap().push(args.join(" "));
+            fake::ok("")
+        });
+
+        let scratch = Scratch::new("adopt")?;
+        let mut r = scratch.runner("adopt");
+        r.set_backend(host);
+
+        r.adopt_vnic("adopt_violin_vnic0", &planned, &found)?;
+        assert!(commands.lock().unwrap().is_empty());
+        assert_eq!(adopted(&r.falcon_dir), ["adopt_violin_vnic0"]);
+
+        let e = r
+            .adopt_vnic("adopt_piano_vnic0", &mismatched, &found)
+            .unwrap_err()
+            .to_string();
+        assert!(e.contains("adopt_piano_vnic0 already exists"));
+        assert!(e.contains("mtu: planned 9000, found 1500"));
+
+        r.adopt_mismatched = true;
+        let e = r.adopt_vnic("adopt_cello_vnic0", &elsewhere, &found);
+        assert!(e.is_err());
+        assert!(commands.lock().unwrap().is_empty());
+
+        r.adopt_vnic("adopt_piano_vnic0", &mismatched, &found)?;
+        assert_eq!(
+            *commands.lock().unwrap(),
+            [
+                "modify-vnic -t -m 2:8:20:0:0:1 adopt_piano_vnic0",
+                "set-linkprop -t -p mtu=9000 adopt_piano_vnic0",
+            ]
+        );
+        assert_eq!(
+            adopted(&r.falcon_dir),
+            ["adopt_violin_vnic0", "adopt_piano_vnic0"]
+        );
+
+        clear_adopted(&r.falcon_dir)?;
+        assert!(adopted(&r.falcon_dir).is_empty());
+        clear_adopted(&r.falcon_dir)?;
+
+        Ok(())
+    }
+
+    /// Test that an external link's vnic left by an earlier run is replaced,
+    /// while one falcon did not create is adopted and left in place.
+    #[test]
+    fn ext_link_leftovers() -> Result<()> {
+        use crate::adopt::{adopted, created, mark_created};
+        use crate::ops::fake;
+        use std::sync::{Arc, Mutex};
+
+        let calls = Arc::new(Mutex::new(Vec::<String>::new()));
+        let log = calls.clone();
+        let host = fake::backend(move |bin, args| {
+            if bin.starts_with("libnet.") {
+                log.lock()
+                    .unwrap()
+                    .push(format!("{} {}", bin, args.join(" ")));
+            }
+            fake::ok(match args.first().copied() {
+                Some("show-vnic") => "igb0:2\\:8\\:20\\:ab\\:cd\\:ef:0\n",
+                Some("show-linkprop") => "1500\n",
+                _ => "",
+            })
+        });
+
+        let scratch = Scratch::new("extlink")?;
+        let mut r = scratch.runner("extlink");
+        r.set_backend(host);
+        let violin = r.node("violin", "helios-2.3", 1, 1024);
+        r.ext_link("igb0", violin);
+        let ext = &r.deployment.ext_links[0];
+        let vnic = r.deployment.vnic_link_name(&ext.endpoint);
+
+        // falcon's own leftover is deleted and created again
+        mark_created(&r.falcon_dir, &vnic)?;
+        ext.create(&r)?;
+        assert_eq!(
+            *calls.lock().unwrap(),
+            [
+                format!("libnet.delete_link {}", vnic),
+                format!("libnet.create_vnic_link {} igb0", vnic),
+            ]
+        );
+        assert!(adopted(&r.falcon_dir).is_empty());
+        assert_eq!(created(&r.falcon_dir), [vnic.clone()]);
+        ext.destroy(&r)?;
+        assert!(created(&r.falcon_dir).is_empty());
+
+        // once falcon destroyed its own, a vnic by that name is someone
+        // else's
+        calls.lock().unwrap().clear();
+        ext.create(&r)?;
+        ext.destroy(&r)?;
+        assert!(calls.lock().unwrap().is_empty());
+        assert_eq!(adopted(&r.falcon_dir), [vnic]);
+
+        Ok(())
+    }
+}
@@ -0,0 +1,282 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+// Copyright 2022 Oxide Computer Company
+
+//! The guest side configuration launch applies to a node, and checks of
+//! whether a running guest still matches it. Launch and audit both work from
+//! `Node::expectations` so they cannot disagree about what a node should look
+//! like.
+
+use crate::error::Error;
+use crate::{Deployment, GuestMountMechanism, Node, NodeRef, Runner};
+use clap::ValueEnum;
+use serde::Serialize;
+use std::fmt;
+
+/// The kinds of guest configuration falcon manages.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, ValueEnum)]
+pub enum AuditCategory {
+    Mounts,
+    Hostname,
+    Hosts,
+    Network,
+}
+
+impl fmt::Display for AuditCategory {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let s = match self {
+            Self::Mounts => "mounts",
+            Self::Hostname => "hostname",
+            Self::Hosts => "hosts",
+            Self::Network => "network",
+        };
+        write!(f, "{}", s)
+    }
+}
+
+/// A single piece of guest configuration: how to apply it and how to check
+/// it is still in place.
+#[derive(Debug, Clone)]
+pub struct Expectation {
+    pub category: AuditCategory,
+    /// What is being checked, e.g. `/etc/nodename`.
+    pub what: String,
+    /// The output of `probe` when the configuration is in place.
+    pub expected: String,
+    /// Command whose output reflects the current state in the guest.
+    probe: String,
+    /// Commands that put the configuration in place.
+    apply: Vec<String>,
+}
+
+/// A difference between a node's expected and actual configuration.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct Drift {
+    pub category: AuditCategory,
+    pub what: String,
+    pub expected: String,
+    pub actual: String,
+}
+
+/// The result of auditing a single node.
+#[derive(Debug, Default, Serialize)]
+pub struct NodeAudit {
+    pub node: String,
+    pub drift: Vec<Drift>,
+}
+
+impl fmt::Display for NodeAudit {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.drift.is_empty() {
+            return writeln!(f, "{}: ok", self.node);
+        }
+        writeln!(f, "{}:", self.node)?;
+        for d in &self.drift {
+            writeln!(f, "  [{}] {}", d.category, d.what)?;
+            writeln!(f, "    - {}", d.expected)?;
+            writeln!(f, "    + {}", d.actual)?;
+        }
+        Ok(())
+    }
+}
+
+impl Node {
+    /// The guest configuration launch applies to this node as part of `d`, in
+    /// the order it is applied.
+    pub(crate) fn expectations(
+        &self,
+        d: &Deployment,
+    ) -> Result<Vec<Expectation>, Error> {
+        let mut result = Vec::new();
+
+        // TODO this will only work as expected for one mount.
+        for mount in &self.mounts {
+            let dst = &mount.destination;
+            let (setup, probe, expected) = match mount.mechanism {
+                GuestMountMechanism::Mount => (
+                    format!(
+                        "mkdir -p {dst}; mount -t 9p -o ro,msize=65536 {dst} \
+                        {dst}",
+                        dst = dst
+                    ),
+                    format!(
+                        "grep -q ' {} 9p ' /proc/mounts && echo mounted \
+                        || echo unmounted",
+                        dst
+                    ),
+                    "mounted",
+                ),
+                GuestMountMechanism::P9kp => (
+                    format!("mkdir -p {dst}; cd {dst}; p9kp pull", dst = dst),
+                    format!("test -d {} && echo present || echo missing", dst),
+                    "present",
+                ),
+            };
+            result.push(Expectation {
+                category: AuditCategory::Mounts,
+                what: format!("mount {}", dst),
+                expected: expected.into(),
+                probe,
+                apply: vec![setup, "cd".into()],
+            });
+        }
+
+        result.push(Expectation {
+            category: AuditCategory::Hostname,
+            what: "hostname".into(),
+            expected: self.name.clone(),
+            probe: "hostname".into(),
+            apply: vec![format!("hostname {}", self.name)],
+        });
+        result.push(Expectation {
+            category: AuditCategory::Hostname,
+            what: "/etc/nodename".into(),
+            expected: self.name.clone(),
+            probe: "cat /etc/nodename".into(),
+            apply: vec![format!("echo '{}' > /etc/nodename", self.name)],
+        });
+
+        for (addr, apply, probe) in
+            d.mgmt_guest_config(&self.name, self.guest)?
+        {
+            result.push(Expectation {
+                category: AuditCategory::Network,
+                what: format!("management address {}", addr),
+                expected: "present".into(),
+                probe,
+                apply: vec![apply],
+            });
+        }
+
+        let mut lines: Vec<String> = ["::1", "127.0.0.1"]
+            .iter()
+            .map(|addr| format!("{addr} {name}.local {name}", name = self.name))
+            .collect();
+        lines.extend(d.mgmt_hosts()?);
+        for line in lines {
+            result.push(Expectation {
+                category: AuditCategory::Hosts,
+                what: format!("/etc/hosts entry '{}'", line),
+                expected: "present".into(),
+                probe: format!(
+                    "grep -qxF '{}' /etc/hosts && echo present || echo missing",
+                    line
+                ),
+                apply: vec![format!("echo '{}' >> /etc/hosts", line)],
+            });
+        }
+
+        Ok(result)
+    }
+
+    /// Commands that apply this node's guest configuration from scratch.
+    pub(crate) fn setup_commands(
+        &self,
+        d: &Deployment,
+    ) -> Result<Vec<String>, Error> {
+        Ok(self
+            .expectations(d)?
+            .into_iter()
+            .flat_map(|e| e.apply)
+            .collect())
+    }
+}
+
+/// Compare probe outputs against expectations, in the same order.
+pub(crate) fn drift(
+    expectations: &[Expectation],
+    actual: &[String],
+) -> Vec<Drift> {
+    expectations
+        .iter()
+        .zip(actual.iter())
+        .filter(|(e, a)| e.expected != a.trim())
+        .map(|(e, a)| Drift {
+            category: e.category,
+            what: e.what.clone(),
+            expected: e.expected.clone(),
+            actual: a.trim().to_string(),
+        })
+        .collect()
+}
+
+impl Runner {
+    /// Check a running node's guest configuration against what launch
+    /// applied.
+    pub async fn audit(&self, node: NodeRef) -> Result<NodeAudit, Error> {
+        let n = self.get_node(node);
+        let expectations = n.expectations(&self.deployment)?;
+        let probes = expectations.iter().map(|e| e.probe.clone()).collect();
+        let actual = self.do_exec_all(&n.name, probes).await?;
+        Ok(NodeAudit {
+            node: n.name.clone(),
+            drift: drift(&expectations, &actual),
+        })
+    }
+
+    /// Re-apply the configuration of a node that has drifted, limited to
+    /// `categories`. Returns the audit taken before fixing.
+    pub async fn audit_fix(
+        &self,
+        node: NodeRef,
+        categories: &[AuditCategory],
+    ) -> Result<NodeAudit, Error> {
+        let audit = self.audit(node).await?;
+        let n = self.get_node(node);
+        let cmds: Vec<String> = n
+            .expectations(&self.deployment)?
+            .into_iter()
+            .filter(|e| categories.contains(&e.category))
+            .filter(|e| audit.drift.iter().any(|d| d.what == e.what))
+            .flat_map(|e| e.apply)
+            .collect();
+        if !cmds.is_empty() {
+            self.do_exec_all(&n.name, cmds).await?;
+        }
+        Ok(audit)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use anyhow::Result;
+
+    #[test]
+    fn audit_expectations() -> Result<()> {
+        use crate::audit::{drift, AuditCategory};
+
+        let mut r = crate::Runner::new("audit");
+        r.persistent = true;
+        let violin = r.node("violin", "helios-2.3", 1, 1024);
+        let n = r.get_node(violin);
+
+        // launch applies exactly what audit checks for
+        assert_eq!(
+            n.setup_commands(&r.deployment)?,
+            [
+                "hostname violin",
+                "echo 'violin' > /etc/nodename",
+                "echo '::1 violin.local violin' >> /etc/hosts",
+                "echo '127.0.0.1 violin.local violin' >> /etc/hosts",
+            ]
+        );
+
+        let expectations = n.expectations(&r.deployment)?;
+        let actual: Vec<String> = vec![
+            "violin".into(),
+            "unknown\n".into(),
+            "present".into(),
+            "missing".into(),
+        ];
+        let d = drift(&expectations, &actual);
+        assert_eq!(d.len(), 2);
+        assert_eq!(d[0].category, AuditCategory::Hostname);
+        assert_eq!(d[0].what, "/etc/nodename");
+        assert_eq!(d[0].actual, "unknown");
+        assert_eq!(d[1].category, AuditCategory::Hosts);
+
+        Ok(())
+    }
+}
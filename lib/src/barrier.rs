@@ -0,0 +1,185 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+// Copyright 2022 Oxide Computer Company
+
+//! Running a command on several nodes at nearly the same instant. The command
+//! is first typed on every node's console without being entered. Once all
+//! consoles have it staged, each is sent the enter key at once. The start
+//! skew is measured on the host as the spread between the consoles echoing
+//! the enter key back.
+
+use crate::error::Error;
+use crate::events;
+use crate::{NodeRef, Runner};
+use futures::future::join_all;
+use std::future::Future;
+use tokio::time::{Duration, Instant};
+
+/// The outcome of a synchronized exec.
+#[derive(Debug, Clone)]
+pub struct SyncResult {
+    /// The spread between the earliest and latest start.
+    pub skew: Duration,
+    /// The output on each node, in the order the nodes were given.
+    pub outputs: Vec<(String, String)>,
+}
+
+/// Await all triggers together, each resolving to when its command started
+/// along with whatever else the trigger produced.
+pub(crate) async fn trigger_all<F, T>(
+    triggers: impl IntoIterator<Item = F>,
+) -> Result<Vec<(Instant, T)>, Error>
+where
+    F: Future<Output = Result<(Instant, T), Error>>,
+{
+    join_all(triggers).await.into_iter().collect()
+}
+
+/// The spread between the earliest and latest of `starts`.
+pub(crate) fn skew(starts: &[Instant]) -> Duration {
+    match (starts.iter().min(), starts.iter().max()) {
+        (Some(min), Some(max)) => *max - *min,
+        _ => Duration::ZERO,
+    }
+}
+
+/// Fail if `skew` exceeds `max_skew`.
+pub(crate) fn check_skew(
+    skew: Duration,
+    max_skew: Duration,
+) -> Result<(), Error> {
+    if skew > max_skew {
+        return Err(Error::Exec(format!(
+            "start skew of {:?} exceeds the maximum of {:?}",
+            skew, max_skew
+        )));
+    }
+    Ok(())
+}
+
+impl Runner {
+    /// Run `cmd` on `nodes` at nearly the same instant. Fails if the
+    /// measured start skew exceeds `max_skew`, in which case the command
+    /// has still run everywhere.
+    pub async fn exec_synchronized(
+        &self,
+        nodes: &[NodeRef],
+        cmd: &str,
+        max_skew: Duration,
+    ) -> Result<SyncResult, Error> {
+        let names: Vec<String> = nodes
+            .iter()
+            .map(|n| self.deployment.nodes[n.index].name.clone())
+            .collect();
+        self.do_exec_synchronized(&names, cmd, max_skew).await
+    }
+
+    pub(crate) async fn do_exec_synchronized(
+        &self,
+        names: &[String],
+        cmd: &str,
+        max_skew: Duration,
+    ) -> Result<SyncResult, Error> {
+        let start = Instant::now();
+        let result = self.sync_session(names, cmd).await;
+        events::record(
+            self.events_log.as_deref(),
+            &self.deployment.name,
+            "exec-sync",
+            None,
+            start.elapsed(),
+            result.is_ok(),
+        );
+        let result = result?;
+        check_skew(result.skew, max_skew)?;
+        Ok(result)
+    }
+
+    async fn sync_session(
+        &self,
+        names: &[String],
+        cmd: &str,
+    ) -> Result<SyncResult, Error> {
+        let mut sessions = names
+            .iter()
+            .map(|name| self.serial_commander(name))
+            .collect::<Result<Vec<_>, Error>>()?;
+
+        // log in and stage everywhere before triggering anywhere
+        let mut wss = join_all(sessions.iter_mut().map(|sc| async move {
+            let mut ws = sc.start(true).await?;
+            sc.stage(&mut ws, cmd).await?;
+            Ok::<_, Error>(ws)
+        }))
+        .await
+        .into_iter()
+        .collect::<Result<Vec<_>, Error>>()?;
+
+        let triggered = trigger_all(
+            sessions
+                .iter_mut()
+                .zip(wss.iter_mut())
+                .map(|(sc, ws)| sc.trigger(ws)),
+        )
+        .await?;
+        let starts: Vec<Instant> =
+            triggered.iter().map(|(at, _)| *at).collect();
+
+        let outputs = join_all(
+            sessions.iter_mut().zip(wss.iter_mut()).zip(triggered).map(
+                |((sc, ws), (_, echoed))| async move {
+                    let out = sc.finish(ws, echoed, None).await?;
+                    sc.logout(ws).await?;
+                    Ok::<_, Error>(out)
+                },
+            ),
+        )
+        .await
+        .into_iter()
+        .collect::<Result<Vec<_>, Error>>()?;
+
+        Ok(SyncResult {
+            skew: skew(&starts),
+            outputs: names.iter().cloned().zip(outputs).collect(),
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use anyhow::Result;
+
+    /// Test that the start skew of a synchronized exec is the spread between
+    /// the earliest and latest trigger, measured on a paused clock.
+    #[tokio::test(start_paused = true)]
+    async fn sync_exec_skew() -> Result<()> {
+        use crate::barrier::{check_skew, skew, trigger_all};
+        use tokio::time::{sleep, Duration, Instant};
+
+        let base = Instant::now();
+        let delays = [3u64, 0, 7];
+        let triggered = trigger_all(delays.iter().map(|ms| async move {
+            sleep(Duration::from_millis(*ms)).await;
+            Ok((Instant::now(), *ms))
+        }))
+        .await?;
+
+        // results come back in trigger order, not start order
+        let tags: Vec<u64> = triggered.iter().map(|(_, t)| *t).collect();
+        assert_eq!(tags, delays);
+        assert_eq!(triggered[1].0, base);
+
+        let starts: Vec<Instant> =
+            triggered.iter().map(|(at, _)| *at).collect();
+        assert_eq!(skew(&starts), Duration::from_millis(7));
+        assert_eq!(skew(&starts[..1]), Duration::ZERO);
+        assert_eq!(skew(&[]), Duration::ZERO);
+
+        assert!(check_skew(skew(&starts), Duration::from_millis(7)).is_ok());
+        assert!(check_skew(skew(&starts), Duration::from_millis(5)).is_err());
+
+        Ok(())
+    }
+}
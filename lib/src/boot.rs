@@ -0,0 +1,321 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+// Copyright 2022 Oxide Computer Company
+
+//! Admission of nodes into their first boot. A node's first boot and setup
+//! can be heavy on I/O, e.g. package operations, and many of them at once
+//! can saturate the host's pool until every boot runs past its timeouts. A
+//! launch can limit how many nodes are booting at once, and can watch how
+//! busy a pool is to admit only one at a time while the pool is saturated.
+//! Nodes that have finished booting do not count, and neither do nodes
+//! launched without setup, whose boot the launch does not wait for. Without
+//! limits every node boots at once.
+
+use crate::error::Error;
+use crate::{Backend, Runner};
+use slog::{info, warn};
+use std::sync::{Mutex, MutexGuard};
+use tokio::time::{sleep, Duration, Instant};
+
+const KSTAT_BIN: &str = "/usr/bin/kstat";
+
+/// How often waiting nodes check for room, and how often a watched pool is
+/// sampled.
+const POLL: Duration = Duration::from_secs(1);
+
+/// How long a sample of a watched pool may take before it is given up.
+const SAMPLE_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Limits on nodes in their first boot and setup.
+#[derive(Debug, Clone, Default)]
+pub struct BootBudget {
+    /// How many nodes may boot at once, any number if `None`.
+    pub nodes: Option<usize>,
+    pub watch: Option<PoolWatch>,
+}
+
+/// A pool whose I/O load lowers admission to one node at a time.
+#[derive(Debug, Clone)]
+pub struct PoolWatch {
+    pub pool: String,
+    /// The busy percentage at which the pool counts as saturated.
+    pub saturated_at: u8,
+}
+
+/// Admits nodes into their first boot within a launch.
+pub(crate) struct BootGate {
+    budget: BootBudget,
+    state: Mutex<GateState>,
+}
+
+#[derive(Default)]
+struct GateState {
+    booting: usize,
+    /// The last sample of the watched pool's cumulative run time, in
+    /// nanoseconds, and when it was taken.
+    sample: Option<(u64, Instant)>,
+    saturated: bool,
+}
+
+/// A node's place in its first boot, given up when dropped.
+pub(crate) struct BootPermit<'a> {
+    gate: &'a BootGate,
+    /// How long the node waited to be admitted.
+    pub(crate) queued: Duration,
+}
+
+impl BootGate {
+    pub(crate) fn new(budget: &BootBudget) -> Self {
+        BootGate {
+            budget: budget.clone(),
+            state: Mutex::new(GateState::default()),
+        }
+    }
+
+    /// True if admission is limited at all.
+    pub(crate) fn is_limited(&self) -> bool {
+        self.budget.nodes.is_some() || self.budget.watch.is_some()
+    }
+
+    /// Wait until there is room for another node to boot.
+    pub(crate) async fn admit(&self, r: &Runner) -> BootPermit<'_> {
+        let start = Instant::now();
+        loop {
+            self.sample(r).await;
+            if self.try_admit() {
+                return BootPermit {
+                    gate: self,
+                    queued: start.elapsed(),
+                };
+            }
+            sleep(POLL).await;
+        }
+    }
+
+    fn try_admit(&self) -> bool {
+        let mut state = self.lock();
+        let limit = if state.saturated {
+            1
+        } else {
+            self.budget.nodes.unwrap_or(usize::MAX).max(1)
+        };
+        if state.booting >= limit {
+            return false;
+        }
+        state.booting += 1;
+        true
+    }
+
+    /// Sample the watched pool if it is due, noting whether it is saturated.
+    /// The gate is not locked while the sample is taken, so other nodes are
+    /// not held up by it.
+    async fn sample(&self, r: &Runner) {
+        let watch = match self.budget.watch {
+            Some(ref w) => w,
+            None => return,
+        };
+        let due = |state: &GateState| {
+            state.sample.map_or(true, |(_, at)| at.elapsed() >= POLL)
+        };
+        if !due(&self.lock()) {
+            return;
+        }
+        let rtime = pool_rtime(r.backend(), &watch.pool).await;
+        let mut state = self.lock();
+        // another node sampled the pool in the meantime
+        if !due(&state) {
+            return;
+        }
+        match rtime {
+            Ok(rtime) => {
+                let now = Instant::now();
+                if let Some((last, at)) = state.sample {
+                    let busy = busy_percent(last, rtime, now - at);
+                    let saturated = busy >= f64::from(watch.saturated_at);
+                    if saturated != state.saturated {
+                        self.report(r, busy, saturated);
+                    }
+                    state.saturated = saturated;
+                }
+                state.sample = Some((rtime, now));
+            }
+            // an unreadable pool does not hold up the launch
+            Err(e) => {
+                warn!(r.log, "sampling pool {}: {}", watch.pool, e);
+                state.sample = None;
+                state.saturated = false;
+            }
+        }
+    }
+
+    fn report(&self, r: &Runner, busy: f64, saturated: bool) {
+        let pool = self.budget.watch.as_ref().map_or("", |w| w.pool.as_str());
+        info!(r.log, "pool {} is {:.0}% busy", pool, busy);
+        if saturated {
+            r.output.progress(format!(
+                "pool {} is {:.0}% busy, booting one node at a time",
+                pool, busy
+            ));
+        } else {
+            r.output.progress(format!(
+                "pool {} is {:.0}% busy, resuming boots",
+                pool, busy
+            ));
+        }
+    }
+
+    fn lock(&self) -> MutexGuard<'_, GateState> {
+        // the state stays consistent even if a holder panicked
+        self.state.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+impl Drop for BootPermit<'_> {
+    fn drop(&mut self) {
+        self.gate.lock().booting -= 1;
+    }
+}
+
+/// How busy a pool was over `elapsed`, given its cumulative run time in
+/// nanoseconds at the start and end.
+pub(crate) fn busy_percent(before: u64, after: u64, elapsed: Duration) -> f64 {
+    if elapsed.is_zero() {
+        return 0.0;
+    }
+    let busy = after.saturating_sub(before) as f64 / elapsed.as_nanos() as f64;
+    (busy * 100.0).min(100.0)
+}
+
+/// The cumulative time the pool has had I/O in progress, in nanoseconds.
+async fn pool_rtime(host: &dyn Backend, pool: &str) -> Result<u64, Error> {
+    let stat = format!("zfs:0:{}:rtime", pool);
+    let out = host
+        .run_within(KSTAT_BIN, &["-p", &stat], SAMPLE_TIMEOUT)
+        .await?;
+    if !out.status.success() {
+        return Err(Error::Exec(format!(
+            "kstat {}: {}",
+            stat,
+            String::from_utf8_lossy(&out.stderr).trim()
+        )));
+    }
+    let out = String::from_utf8(out.stdout)?;
+    match out.split_whitespace().nth(1) {
+        Some(v) => Ok(v.parse()?),
+        None => Err(Error::NotFound(stat)),
+    }
+}
+
+impl Runner {
+    /// Let at most `n` nodes be in their first boot and setup at once. Other
+    /// nodes wait their turn, and the launch report shows for how long.
+    /// Nodes launched without setup are not waited for and do not count.
+    pub fn boot_io_budget(&mut self, n: usize) {
+        self.boot_budget.nodes = Some(n);
+    }
+
+    /// Watch how busy `pool` is while nodes boot, and admit only one node
+    /// at a time while it is at least `saturated_at` percent busy.
+    pub fn boot_io_watch(&mut self, pool: &str, saturated_at: u8) {
+        self.boot_budget.watch = Some(PoolWatch {
+            pool: pool.into(),
+            saturated_at,
+        });
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use anyhow::Result;
+
+    /// Test that a boot budget holds nodes back until earlier ones finish
+    /// booting, recording how long each waited, and that a saturated pool
+    /// lowers admission to one node at a time until it calms down.
+    #[tokio::test(start_paused = true)]
+    async fn boot_budget() -> Result<()> {
+        use crate::boot::{busy_percent, BootGate};
+        use crate::ops::fake;
+        use crate::output::OutputCtx;
+        use futures::future::join_all;
+        use std::sync::atomic::{AtomicU64, Ordering};
+        use std::sync::Arc;
+        use tokio::time::{sleep, Duration};
+
+        // boot a node for `ms` once admitted, giving how long it waited
+        async fn boot(gate: &BootGate, r: &crate::Runner, ms: u64) -> u64 {
+            let permit = gate.admit(r).await;
+            sleep(Duration::from_millis(ms)).await;
+            permit.queued.as_secs()
+        }
+
+        let second = Duration::from_secs(1);
+        assert_eq!(busy_percent(0, 500_000_000, second), 50.0);
+        assert_eq!(busy_percent(0, 3_000_000_000, second), 100.0);
+        assert_eq!(busy_percent(5, 1, second), 0.0);
+        assert_eq!(busy_percent(0, 1, Duration::ZERO), 0.0);
+
+        let mut r = crate::Runner::new("budget");
+        r.persistent = true;
+        r.set_output(OutputCtx::silent());
+
+        // without a budget every node boots at once
+        let gate = BootGate::new(&r.boot_budget);
+        assert!(!gate.is_limited());
+        let permits = join_all((0..4).map(|_| gate.admit(&r))).await;
+        assert!(permits.iter().all(|p| p.queued.is_zero()));
+        drop(permits);
+
+        r.boot_io_budget(2);
+        let gate = BootGate::new(&r.boot_budget);
+        assert!(gate.is_limited());
+        let mut queued = join_all([
+            boot(&gate, &r, 9500),
+            boot(&gate, &r, 19500),
+            boot(&gate, &r, 4500),
+            boot(&gate, &r, 4500),
+        ])
+        .await;
+        queued[2..].sort_unstable();
+        assert_eq!(queued, vec![0, 0, 10, 15]);
+
+        // the pool is busy for twice the time between samples until calmed
+        let step = Arc::new(AtomicU64::new(2_000_000_000));
+        let rtime = AtomicU64::new(0);
+        let s = step.clone();
+        let host = fake::backend(move |_, args| match args {
+            ["-p", "zfs:0:rpool:rtime"] => {
+                let busy = s.load(Ordering::SeqCst);
+                let t = rtime.fetch_add(busy, Ordering::SeqCst) + busy;
+                fake::ok(format!("zfs:0:rpool:rtime\t{}\n", t))
+            }
+            _ => fake::fail("unexpected command"),
+        });
+        let mut r = crate::Runner::new("watched");
+        r.persistent = true;
+        r.set_backend(host);
+        r.set_output(OutputCtx::silent());
+        r.boot_io_watch("rpool", 90);
+        let gate = BootGate::new(&r.boot_budget);
+        let first = gate.admit(&r).await;
+        sleep(second).await;
+        let held = async move {
+            sleep(Duration::from_millis(2500)).await;
+            drop(first);
+        };
+        let (_, mut queued) = tokio::join!(
+            held,
+            join_all([boot(&gate, &r, 500), boot(&gate, &r, 500)])
+        );
+        queued.sort_unstable();
+        assert_eq!(queued, vec![3, 4]);
+
+        step.store(0, Ordering::SeqCst);
+        sleep(second).await;
+        let permits = join_all((0..2).map(|_| gate.admit(&r))).await;
+        assert!(permits.iter().all(|p| p.queued.is_zero()));
+
+        Ok(())
+    }
+}
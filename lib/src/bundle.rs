@@ -0,0 +1,488 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+// Copyright 2022 Oxide Computer Company
+
+//! Bundles capture what is needed to reproduce a node on another host: the
+//! image identity, the propolis binary, the instance spec, the topology the
+//! node was launched from, its propolis environment and the commands it is
+//! set up with at first boot. Secret environment values are redacted
+//! throughout.
+
+use crate::env::{Environment, REDACTED};
+use crate::error::Error;
+use crate::image::{self, ImageName};
+use crate::{ops, Backend, Deployment, Node, NodeRef, Runner, ZFS_BIN};
+use camino::{Utf8Path, Utf8PathBuf};
+use flate2::read::GzDecoder;
+use flate2::write::GzEncoder;
+use flate2::Compression;
+use ron::ser::{to_string_pretty, PrettyConfig};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::fs;
+use std::io::Read;
+use std::process::{Command, Stdio};
+
+/// The bundle manifest version written by this version of falcon. Version 2
+/// added the boot script, environment and user data.
+pub const BUNDLE_VERSION: u32 = 2;
+
+const MANIFEST: &str = "manifest.ron";
+const TOPOLOGY: &str = "topology.ron";
+const IMAGE_STREAM: &str = "image.zfs";
+const BOOTROM: &str = "/var/ovmf/OVMF_CODE.fd";
+
+/// Describes the contents of a bundle. Fields added in later versions must
+/// carry a serde default so older bundles remain readable, and unknown fields
+/// are ignored so older falcon versions can read newer bundles.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct BundleManifest {
+    /// Manifest format version.
+    pub version: u32,
+    /// Version of falcon that created the bundle.
+    pub falcon_version: String,
+    /// Name of the deployment the node belongs to.
+    pub deployment: String,
+    /// Name of the bundled node.
+    pub node: String,
+    pub image: ImageIdentity,
+    pub propolis: PropolisIdentity,
+    /// Archive path of the propolis instance spec.
+    pub instance_spec: String,
+    /// Archive path of the topology the node was launched from.
+    pub topology: String,
+    /// Archive path of a zfs send stream of the image, if included.
+    #[serde(default)]
+    pub image_stream: Option<String>,
+    /// Archive path of the commands run on the node's console at first boot,
+    /// `None` for nodes that are not set up.
+    #[serde(default)]
+    pub boot_script: Option<String>,
+    /// Archive path of the node's propolis-server environment.
+    #[serde(default)]
+    pub environment: Option<String>,
+    /// Archive path of the cloud-init user data the node was launched with.
+    /// Falcon does not give propolis any user data, so this is `None` until
+    /// it does.
+    #[serde(default)]
+    pub user_data: Option<String>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ImageIdentity {
+    pub name: String,
+    /// The zfs guid of the image's `@base` snapshot.
+    pub guid: String,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PropolisIdentity {
+    /// Output of `propolis-server -V`.
+    pub version: String,
+    /// Hex encoded sha256 of the propolis-server binary.
+    pub sha256: String,
+}
+
+impl Runner {
+    /// Bundle the launch recipe of `node` into a gzipped tarball at `output`.
+    /// The disk image is only included when `include_image` is set, as a zfs
+    /// send stream of the image's `@base` snapshot.
+    pub fn bundle(
+        &self,
+        node: NodeRef,
+        output: impl AsRef<Utf8Path>,
+        include_image: bool,
+    ) -> Result<BundleManifest, Error> {
+        let n = self.get_node(node);
+
+        let mut spec = self.falcon_dir.clone();
+        spec.push(format!("{}.toml", n.name));
+        let mut topology = self.falcon_dir.clone();
+        topology.push(TOPOLOGY);
+
+        let image = image::resolve(self.backend(), &n.image_dataset, &n.image)?;
+        let manifest = BundleManifest {
+            version: BUNDLE_VERSION,
+            falcon_version: env!("CARGO_PKG_VERSION").into(),
+            deployment: self.deployment.name.clone(),
+            node: n.name.clone(),
+            image: ImageIdentity {
+                name: image.name.into(),
+                guid: image.guid,
+            },
+            propolis: propolis_identity(&self.propolis_binary)?,
+            instance_spec: format!("{}.toml", n.name),
+            topology: TOPOLOGY.into(),
+            image_stream: if include_image {
+                Some(IMAGE_STREAM.into())
+            } else {
+                None
+            },
+            boot_script: if n.do_setup {
+                Some(format!("{}.boot.sh", n.name))
+            } else {
+                None
+            },
+            environment: Some(format!("{}.env", n.name)),
+            user_data: None,
+        };
+
+        let mut generated = vec![(
+            format!("{}.env", n.name),
+            Environment::for_node(&self.deployment.name, n)?.to_string(),
+        )];
+        if let Some(ref script) = manifest.boot_script {
+            generated.push((script.clone(), boot_script(&self.deployment, n)?));
+        }
+
+        let mut files = vec![
+            (manifest.instance_spec.clone(), spec),
+            (manifest.topology.clone(), topology),
+        ];
+
+        // The tar header needs the entry size up front, so stage the send
+        // stream in the falcon dir first.
+        let mut stream = self.falcon_dir.clone();
+        stream.push(format!("{}.zfs", n.name));
+        if include_image {
+            let out = fs::File::create(&stream)?;
+            let send = Command::new(ZFS_BIN)
+                .args(["send", image.snapshot.as_str()])
+                .stdout(out)
+                .stderr(Stdio::piped())
+                .output()?;
+            if !send.status.success() {
+                return Err(Error::Zfs(String::from_utf8(send.stderr)?));
+            }
+            files.push((IMAGE_STREAM.into(), stream.clone()));
+        }
+
+        let result =
+            write_bundle(output.as_ref(), &manifest, &files, &generated);
+        if include_image {
+            let _ = fs::remove_file(&stream);
+        }
+        result?;
+
+        Ok(manifest)
+    }
+}
+
+/// Write a bundle archive containing the manifest, the provided
+/// `(archive path, host path)` files and the `(archive path, contents)`
+/// generated ones.
+pub(crate) fn write_bundle(
+    output: &Utf8Path,
+    manifest: &BundleManifest,
+    files: &[(String, Utf8PathBuf)],
+    generated: &[(String, String)],
+) -> Result<(), Error> {
+    let pretty = PrettyConfig::new().separate_tuple_members(true);
+    let manifest = format!("{}\n", to_string_pretty(manifest, pretty)?);
+
+    let gz = GzEncoder::new(fs::File::create(output)?, Compression::default());
+    let mut tar = tar::Builder::new(gz);
+
+    let entries = std::iter::once((MANIFEST, manifest.as_str()))
+        .chain(generated.iter().map(|(n, c)| (n.as_str(), c.as_str())));
+    for (name, contents) in entries {
+        let mut header = tar::Header::new_gnu();
+        header.set_size(contents.len() as u64);
+        header.set_mode(0o644);
+        header.set_cksum();
+        tar.append_data(&mut header, name, contents.as_bytes())?;
+    }
+
+    for (name, path) in files {
+        tar.append_path_with_name(path, name)?;
+    }
+    tar.into_inner()?.finish()?;
+
+    Ok(())
+}
+
+/// Read the manifest out of a bundle archive.
+pub fn read_manifest(
+    bundle: impl AsRef<Utf8Path>,
+) -> Result<BundleManifest, Error> {
+    let gz = GzDecoder::new(fs::File::open(bundle.as_ref())?);
+    let mut tar = tar::Archive::new(gz);
+    for entry in tar.entries()? {
+        let mut entry = entry?;
+        if entry.path()?.to_str() == Some(MANIFEST) {
+            let mut s = String::new();
+            entry.read_to_string(&mut s)?;
+            return Ok(ron::de::from_str(&s)?);
+        }
+    }
+    Err(Error::NotFound(format!(
+        "{} in {}",
+        MANIFEST,
+        bundle.as_ref()
+    )))
+}
+
+/// Read a file other than the manifest out of a bundle archive.
+pub(crate) fn read_file(
+    bundle: &Utf8Path,
+    name: &str,
+) -> Result<Vec<u8>, Error> {
+    let gz = GzDecoder::new(fs::File::open(bundle)?);
+    let mut tar = tar::Archive::new(gz);
+    for entry in tar.entries()? {
+        let mut entry = entry?;
+        if entry.path()?.to_str() == Some(name) {
+            let mut buf = Vec::new();
+            entry.read_to_end(&mut buf)?;
+            return Ok(buf);
+        }
+    }
+    Err(Error::NotFound(format!("{} in {}", name, bundle)))
+}
+
+/// Check whether this host can satisfy the recipe in `bundle`. Returns a
+/// description of each requirement that is not met, an empty list means the
+/// node can be reproduced here.
+pub fn verify(
+    host: &dyn Backend,
+    bundle: impl AsRef<Utf8Path>,
+    propolis_binary: &str,
+    image_dataset: &str,
+) -> Result<Vec<String>, Error> {
+    let bundle = bundle.as_ref();
+    let m = read_manifest(bundle)?;
+    let mut missing = Vec::new();
+
+    if m.version > BUNDLE_VERSION {
+        missing.push(format!(
+            "bundle version {} is newer than supported version {}, \
+            upgrade falcon for a complete check",
+            m.version, BUNDLE_VERSION,
+        ));
+    }
+
+    match propolis_identity(propolis_binary) {
+        Ok(p) => {
+            if p.sha256 != m.propolis.sha256 {
+                missing.push(format!(
+                    "propolis binary {} has sha256 {}, bundle needs {} ({})",
+                    propolis_binary,
+                    p.sha256,
+                    m.propolis.sha256,
+                    m.propolis.version,
+                ));
+            }
+        }
+        Err(e) => missing.push(format!(
+            "propolis binary {}: {}, bundle needs {} ({})",
+            propolis_binary, e, m.propolis.sha256, m.propolis.version,
+        )),
+    }
+
+    let snapshot = format!("{}/img/{}@base", image_dataset, m.image.name);
+    let image = m
+        .image
+        .name
+        .parse::<ImageName>()
+        .and_then(|name| image::resolve(host, image_dataset, &name));
+    match image.map(|i| i.guid) {
+        Ok(guid) if guid == m.image.guid => {}
+        Ok(guid) => missing.push(format!(
+            "image {} has guid {}, bundle needs {}",
+            snapshot, guid, m.image.guid,
+        )),
+        Err(_) => {
+            let hint = match m.image_stream {
+                Some(ref stream) => format!(
+                    ", the bundle carries it as {}: \
+                    tar -xzf {} {} && zfs receive {}/img/{} < {}",
+                    stream, bundle, stream, image_dataset, m.image.name, stream,
+                ),
+                None => String::new(),
+            };
+            missing.push(format!(
+                "image {} with guid {}{}",
+                snapshot, m.image.guid, hint
+            ));
+        }
+    }
+
+    if !Utf8Path::new(BOOTROM).exists() {
+        missing.push(format!("bootrom {}", BOOTROM));
+    }
+
+    let topo = Deployment::from_ron(&String::from_utf8(read_file(
+        bundle,
+        &m.topology,
+    )?)?)?;
+    if let Some(n) = topo.nodes.iter().find(|n| n.name == m.node) {
+        for mount in &n.mounts {
+            if !mount.source.exists() {
+                missing.push(format!("mount source {}", mount.source));
+            }
+        }
+    }
+
+    if !ops::dataset_exists(host, image_dataset)? {
+        missing.push(format!("dataset {}", image_dataset));
+    }
+
+    Ok(missing)
+}
+
+/// The commands run on the console of `n` at first boot, as a script. Any of
+/// the node's secret environment values that turn up in them are redacted.
+pub(crate) fn boot_script(d: &Deployment, n: &Node) -> Result<String, Error> {
+    let mut cmds = n.setup_commands(d)?;
+    cmds.extend(n.role_commands());
+    let mut script = format!(
+        "#!/bin/sh\n# run on the console of {} at first boot\n",
+        n.name
+    );
+    for cmd in cmds {
+        script.push_str(&cmd);
+        script.push('\n');
+    }
+    for secret in n.secrets.values().filter(|s| !s.is_empty()) {
+        script = script.replace(secret.as_str(), REDACTED);
+    }
+    Ok(script)
+}
+
+fn propolis_identity(binary: &str) -> Result<PropolisIdentity, Error> {
+    let path = which(binary)
+        .ok_or_else(|| Error::NotFound(format!("{} on PATH", binary)))?;
+    let out = Command::new(&path).args(["-V"]).output()?;
+    let version = String::from_utf8(out.stdout)?.trim().to_string();
+    let sha256 = sha256_hex(&fs::read(&path)?);
+    Ok(PropolisIdentity { version, sha256 })
+}
+
+pub(crate) fn sha256_hex(data: &[u8]) -> String {
+    Sha256::digest(data)
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect()
+}
+
+/// Resolve a binary name against PATH the way `Command` would.
+pub(crate) fn which(binary: &str) -> Option<Utf8PathBuf> {
+    if binary.contains('/') {
+        let p = Utf8PathBuf::from(binary);
+        return if p.exists() { Some(p) } else { None };
+    }
+    let path = std::env::var("PATH").ok()?;
+    path.split(':')
+        .map(|dir| Utf8Path::new(dir).join(binary))
+        .find(|p| p.is_file())
+}
+
+#[cfg(test)]
+mod test {
+    use crate::test::Scratch;
+    use anyhow::Result;
+
+    fn test_manifest() -> crate::bundle::BundleManifest {
+        use crate::bundle::*;
+        BundleManifest {
+            version: BUNDLE_VERSION,
+            falcon_version: "0.1.0".into(),
+            deployment: "duo".into(),
+            node: "violin".into(),
+            image: ImageIdentity {
+                name: "helios-2.3".into(),
+                guid: "1234567890".into(),
+            },
+            propolis: PropolisIdentity {
+                version: "propolis-server 0.1.0".into(),
+                sha256: crate::bundle::sha256_hex(b"propolis"),
+            },
+            instance_spec: "violin.toml".into(),
+            topology: "topology.ron".into(),
+            image_stream: None,
+            boot_script: Some("violin.boot.sh".into()),
+            environment: Some("violin.env".into()),
+            user_data: None,
+        }
+    }
+
+    /// Bundles written by newer falcon versions may carry fields this version
+    /// does not know about, and older bundles may lack fields added since.
+    #[test]
+    fn bundle_manifest_compat() -> Result<()> {
+        use crate::bundle::BundleManifest;
+
+        let m = test_manifest();
+        let s = ron::ser::to_string(&m)?;
+        let back: BundleManifest = ron::de::from_str(&s)?;
+        assert_eq!(m, back);
+
+        let newer = s.replacen(
+            "version:2,",
+            "version:3,cloud_init:Some(\"data\"),checksums:[1,2],",
+            1,
+        );
+        assert_ne!(newer, s);
+        let back: BundleManifest = ron::de::from_str(&newer)?;
+        assert_eq!(back.version, 3);
+        assert_eq!(back.node, m.node);
+
+        // a version 1 manifest
+        let older = s
+            .replacen("version:2,", "version:1,", 1)
+            .replacen(",image_stream:None", "", 1)
+            .replacen(",boot_script:Some(\"violin.boot.sh\")", "", 1)
+            .replacen(",environment:Some(\"violin.env\")", "", 1)
+            .replacen(",user_data:None", "", 1);
+        assert!(!older.contains("boot_script"), "{}", older);
+        let back: BundleManifest = ron::de::from_str(&older)?;
+        assert_eq!(back.image_stream, None);
+        assert_eq!(back.boot_script, None);
+        assert_eq!(back.environment, None);
+
+        Ok(())
+    }
+
+    #[test]
+    fn bundle_archive_roundtrip() -> Result<()> {
+        let scratch = Scratch::new("bundle")?;
+        let dir = &scratch.dir;
+        let spec = dir.join("violin.toml");
+        std::fs::write(&spec, "bootrom = \"/var/ovmf/OVMF_CODE.fd\"\n")?;
+
+        let m = test_manifest();
+        let out = dir.join("violin.tar.gz");
+        crate::bundle::write_bundle(
+            &out,
+            &m,
+            &[("violin.toml".into(), spec.clone())],
+            &[("violin.env".into(), "RUST_LOG=debug\n".into())],
+        )?;
+        assert_eq!(crate::bundle::read_manifest(&out)?, m);
+        assert_eq!(
+            crate::bundle::read_file(&out, "violin.toml")?,
+            std::fs::read(&spec)?,
+        );
+        assert_eq!(
+            crate::bundle::read_file(&out, "violin.env")?,
+            b"RUST_LOG=debug\n"
+        );
+        assert!(crate::bundle::read_file(&out, "image.zfs").is_err());
+
+        // the boot script is the node's setup, secrets redacted
+        let mut r = crate::Runner::new("duo");
+        r.persistent = true;
+        let violin = r.node("violin", "helios-2.3", 1, 1024);
+        r.propolis_env(violin, "HOST", "secret:violin")?;
+        let n = &r.deployment.nodes[violin.index];
+        let script = crate::bundle::boot_script(&r.deployment, n)?;
+        assert!(script.starts_with("#!/bin/sh\n"));
+        let setup = n.setup_commands(&r.deployment)?;
+        assert!(setup.iter().any(|c| c.contains("violin")));
+        assert!(!script.contains("violin"), "{}", script);
+
+        Ok(())
+    }
+}
@@ -0,0 +1,291 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+// Copyright 2022 Oxide Computer Company
+
+//! Console output around operations that change a node, such as a reboot or
+//! hyperstart. The node's console is followed from the start of the operation
+//! until a tail window after it ends, and written to
+//! `.falcon/log/ops/<node>-<op>-<timestamp>.log`. Capture runs in its own
+//! task: the operation never waits on it, and a console that cannot be
+//! reached only leaves the log empty.
+//!
+//! A process that exits once the operation is done, such as the command
+//! line, hands the tail window to a process of its own with
+//! `OpCapture::detach` rather than waiting it out.
+
+use crate::error::Error;
+use crate::snapshot::utc_stamp;
+use crate::Runner;
+use camino::{Utf8Path, Utf8PathBuf};
+use futures::StreamExt;
+use slog::warn;
+use std::fs;
+use std::io::Write;
+use std::net::{IpAddr, Ipv6Addr, SocketAddr};
+use std::process::{Command, Stdio};
+use std::time::{SystemTime, UNIX_EPOCH};
+use tokio::sync::oneshot;
+use tokio::task::JoinHandle;
+use tokio::time::{sleep, Duration};
+use tokio_tungstenite::connect_async;
+use tokio_tungstenite::tungstenite::Message;
+
+/// How long capture continues after an operation ends unless the runner says
+/// otherwise.
+pub const DEFAULT_TAIL: Duration = Duration::from_secs(60);
+
+/// How long to wait between attempts to reach a console that is not up yet,
+/// e.g. while a hyperstart brings up a new propolis server.
+const RECONNECT: Duration = Duration::from_secs(1);
+
+/// Where captures of the deployment in `falcon_dir` are written.
+pub fn ops_log_dir(falcon_dir: &Utf8Path) -> Utf8PathBuf {
+    falcon_dir.join("log").join("ops")
+}
+
+/// The capture file of `op` on `node` started at `secs` since the epoch.
+pub(crate) fn capture_path(
+    falcon_dir: &Utf8Path,
+    node: &str,
+    op: &str,
+    secs: u64,
+) -> Utf8PathBuf {
+    ops_log_dir(falcon_dir).join(format!(
+        "{}-{}-{}.log",
+        node,
+        op,
+        utc_stamp(secs)
+    ))
+}
+
+/// The console capture of a single operation.
+pub struct OpCapture {
+    /// Where the console output is written.
+    pub path: Utf8PathBuf,
+    port: u16,
+    tail: Duration,
+    done: Option<oneshot::Sender<()>>,
+    task: JoinHandle<()>,
+}
+
+impl OpCapture {
+    /// Start following the console of the propolis server listening on
+    /// `port`, until `tail` after `finish` is called or the capture is
+    /// dropped.
+    pub(crate) fn start(
+        path: Utf8PathBuf,
+        port: u16,
+        tail: Duration,
+    ) -> Result<OpCapture, Error> {
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+        let file = fs::File::create(&path)?;
+        let (done, ended) = oneshot::channel();
+        let task = tokio::spawn(follow(serial_url(port), file, ended, tail));
+        Ok(OpCapture {
+            path,
+            port,
+            tail,
+            done: Some(done),
+            task,
+        })
+    }
+
+    /// Mark the operation as finished, starting the tail window. A failed
+    /// operation's error is returned pointing at the capture.
+    pub(crate) fn finish<T>(
+        &mut self,
+        result: Result<T, Error>,
+    ) -> Result<T, Error> {
+        if let Some(done) = self.done.take() {
+            let _ = done.send(());
+        }
+        result.map_err(|e| Error::Captured {
+            error: Box::new(e),
+            log: self.path.clone(),
+        })
+    }
+
+    /// Wait for the tail window to pass.
+    pub async fn wait(self) {
+        let _ = self.task.await;
+    }
+
+    /// Finish the capture in a process of its own, running `falcon capture`
+    /// with the executable of this one, so this process can exit without
+    /// waiting out the tail window. Capture in this process stops, output
+    /// printed while the new process connects may be missed.
+    pub fn detach(mut self) -> Result<(), Error> {
+        if let Some(done) = self.done.take() {
+            let _ = done.send(());
+        }
+        self.task.abort();
+        let exe = std::env::current_exe()?;
+        Command::new(exe)
+            .args([
+                "capture",
+                "--port",
+                &self.port.to_string(),
+                "--tail",
+                &self.tail.as_secs().to_string(),
+                self.path.as_str(),
+            ])
+            .stdin(Stdio::null())
+            .stdout(Stdio::null())
+            .stderr(Stdio::null())
+            .spawn()?;
+        Ok(())
+    }
+}
+
+/// Append the console of the propolis server listening on `port` to `path`
+/// for `tail`, as the process `OpCapture::detach` starts.
+pub async fn follow_detached(
+    path: &Utf8Path,
+    port: u16,
+    tail: Duration,
+) -> Result<(), Error> {
+    let file = fs::OpenOptions::new().append(true).open(path)?;
+    // the operation has already ended
+    let (_, ended) = oneshot::channel();
+    follow(serial_url(port), file, ended, tail).await;
+    Ok(())
+}
+
+/// The serial console endpoint of the propolis server listening on `port`.
+fn serial_url(port: u16) -> String {
+    let addr = SocketAddr::new(
+        IpAddr::V6(Ipv6Addr::new(0, 0, 0, 0, 0, 0, 0, 1)),
+        port,
+    );
+    format!("ws://{}/instance/serial", addr)
+}
+
+/// Copy console output to `file` until `tail` after `ended` fires. The
+/// console is reconnected whenever it goes away, as it does when the
+/// propolis server is restarted.
+async fn follow(
+    url: String,
+    mut file: fs::File,
+    ended: oneshot::Receiver<()>,
+    tail: Duration,
+) {
+    // a dropped sender ends the operation just like a sent one
+    let stop = async {
+        let _ = ended.await;
+        sleep(tail).await;
+    };
+    tokio::pin!(stop);
+
+    let mut conn = None;
+    loop {
+        let ws = match conn {
+            Some(ref mut ws) => ws,
+            None => {
+                tokio::select! {
+                    _ = &mut stop => return,
+                    c = connect_async(url.as_str()) => match c {
+                        Ok((ws, _)) => conn = Some(ws),
+                        Err(_) => tokio::select! {
+                            _ = &mut stop => return,
+                            _ = sleep(RECONNECT) => {}
+                        },
+                    },
+                }
+                continue;
+            }
+        };
+        let msg = tokio::select! {
+            _ = &mut stop => return,
+            msg = ws.next() => msg,
+        };
+        match msg {
+            Some(Ok(Message::Binary(data))) => {
+                if file.write_all(&data).is_err() {
+                    return;
+                }
+            }
+            Some(Ok(Message::Close(..))) | Some(Err(_)) | None => conn = None,
+            Some(Ok(_)) => {}
+        }
+    }
+}
+
+impl Runner {
+    /// Start capturing the named node's console around `op`, unless capture
+    /// is turned off or this is a dry run. Capture problems are warned
+    /// about, they never keep the operation from going ahead.
+    pub(crate) fn capture_op(&self, name: &str, op: &str) -> Option<OpCapture> {
+        if self.plan().is_dry_run() {
+            return None;
+        }
+        let tail = self.op_capture?;
+        let secs = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or(0);
+        let path = capture_path(&self.falcon_dir, name, op, secs);
+        let port =
+            fs::read_to_string(self.falcon_dir.join(format!("{}.port", name)))
+                .map_err(Error::from)
+                .and_then(|p| Ok(p.trim_end().parse::<u16>()?));
+        let started = port.and_then(|port| OpCapture::start(path, port, tail));
+        match started {
+            Ok(c) => Some(c),
+            Err(e) => {
+                warn!(self.log, "{}: not capturing the console: {}", name, e);
+                None
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::test::Scratch;
+    use anyhow::Result;
+
+    /// Test that an operation's console capture is named after the node and
+    /// operation, outlives the operation by its tail window without holding it
+    /// up, and that a failed operation's error points at the capture. A
+    /// detached tail appends to the capture.
+    #[tokio::test]
+    async fn op_console_capture() -> Result<()> {
+        use crate::capture::{capture_path, follow_detached, OpCapture};
+        use crate::error::Error;
+        use std::time::Duration;
+
+        let scratch = Scratch::new("capture")?;
+        let dir = &scratch.dir;
+        let path = capture_path(dir, "violin", "reboot", 1717251135);
+        assert_eq!(path, dir.join("log/ops/violin-reboot-20240601T141215.log"));
+
+        // nothing listens on the port, the capture keeps trying in the
+        // background
+        let mut capture = OpCapture::start(path.clone(), 9, Duration::ZERO)?;
+        let result: Result<(), Error> =
+            capture.finish(Err(Error::Exec("reboot failed".into())));
+        let e = result.unwrap_err();
+        assert_eq!(
+            e.to_string(),
+            format!("exec: reboot failed\nconsole output: {}", path)
+        );
+        tokio::time::timeout(Duration::from_secs(10), capture.wait()).await?;
+        assert!(path.exists());
+        std::fs::write(&path, "booting\n")?;
+        follow_detached(&path, 9, Duration::ZERO).await?;
+        assert_eq!(std::fs::read_to_string(&path)?, "booting\n");
+
+        // capture is off when the runner says so
+        let mut r = crate::Runner::new("capture");
+        r.persistent = true;
+        r.falcon_dir = dir.clone();
+        r.op_capture = None;
+        assert!(r.capture_op("violin", "reboot").is_none());
+
+        Ok(())
+    }
+}
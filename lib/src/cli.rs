@@ -5,28 +5,42 @@
 // Copyright 2022 Oxide Computer Company
 
 use std::fs;
-use std::process::Command;
 use std::{
-    io::{stdout, Write},
+    io::{stdout, BufReader, Read, Write},
     net::{IpAddr, Ipv4Addr, SocketAddr},
     os::unix::prelude::AsRawFd,
+    time::{SystemTime, UNIX_EPOCH},
 };
 
 use anyhow::{anyhow, Context};
 use camino::{Utf8Path, Utf8PathBuf};
-use clap::ArgAction;
+use clap::{ArgAction, ValueEnum};
 use colored::*;
 use futures::{SinkExt, StreamExt};
 use propolis_client::{types::InstanceStateRequested, Client};
-use ron::de::from_str;
-use slog::{o, warn, Drain, Level, Logger};
+use slog::{info, o, warn, Drain, Level, Logger};
 use tabwriter::TabWriter;
 use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::time::{Duration, Instant};
 use tokio_tungstenite::tungstenite::Message;
 
 use clap::Parser;
 
-use crate::{dataset, error::Error, Deployment, Runner, DEFAULT_FALCON_DIR};
+use crate::address::NodeAddr;
+use crate::audit::AuditCategory;
+use crate::capture::{self, OpCapture};
+use crate::config::{config_path, ColorPreference, UserConfig};
+use crate::cores::HypervisorState;
+use crate::env::Environment;
+use crate::health::{Health, HealthOptions, Verdict};
+use crate::output::{OutputCtx, Verbosity};
+use crate::react::{ConsoleTrigger, RateLimit, ReactAction};
+use crate::retry::{RetryOp, RetryPolicy};
+use crate::{
+    address, cores, crash, dhcp, error::Error, events, image, ops, registry,
+    snapshot, workspace, Deployment, Link, Plan, Runner, Step,
+    DEFAULT_FALCON_DIR,
+};
 
 pub enum RunMode {
     Unspec,
@@ -41,6 +55,14 @@ struct Opts {
     #[clap(short, long, action = ArgAction::Count)]
     verbose: u8,
 
+    /// Only show warnings and errors
+    #[clap(short, long, conflicts_with = "verbose")]
+    quiet: bool,
+
+    /// Whether to color output, over the color config setting
+    #[clap(long, value_name = "WHEN")]
+    color: Option<ColorPreference>,
+
     #[clap(subcommand)]
     subcmd: SubCommand,
 }
@@ -71,6 +93,64 @@ enum SubCommand {
     Snapshot(CmdSnapshot),
     #[clap(about = "execute a command on a node")]
     Exec(CmdExec),
+    #[clap(about = "manage an smf service on a helios node")]
+    Svc(CmdSvc),
+    #[clap(about = "bundle a node's launch recipe to reproduce it elsewhere")]
+    Bundle(CmdBundle),
+    #[clap(about = "check guests still match their launch configuration")]
+    Audit(CmdAudit),
+    #[clap(about = "manage per-user falcon defaults")]
+    Config(CmdConfig),
+    #[clap(about = "summarize how long operations have taken")]
+    Timings(CmdTimings),
+    #[clap(about = "show whether each vm's hypervisor is running")]
+    Status(CmdStatus),
+    #[clap(about = "list cores left by crashed hypervisors")]
+    Cores(CmdCores),
+    #[clap(about = "move images between hosts as zfs send streams")]
+    Image(CmdImage),
+    #[clap(about = "show the link between two nodes")]
+    Link(CmdLink),
+    #[clap(about = "collect panic output and crash dumps from guests")]
+    Crash(CmdCrash),
+    #[clap(about = "show a vm's instance spec and hypervisor environment")]
+    Spec(CmdSpec),
+    #[clap(about = "check the whole topology, exiting non-zero unless ok")]
+    Health(CmdHealth),
+    #[clap(about = "inspect the management network's dhcp responder")]
+    Dhcp(CmdDhcp),
+    #[clap(about = "run a command when a node's console prints a pattern")]
+    React(CmdReact),
+    #[clap(about = "add or remove a running vm's NICs")]
+    Nic(CmdNic),
+    /// Finish the console capture of an operation, as handed off by reboot
+    /// and hyperstart
+    #[clap(hide = true)]
+    Capture(CmdCapture),
+}
+
+impl SubCommand {
+    /// Whether the command prints its result as JSON.
+    fn json(&self) -> bool {
+        match self {
+            SubCommand::Launch(c) => c.dry_run.json,
+            SubCommand::Destroy(c) => c.dry_run.json,
+            SubCommand::Reboot(c) => c.dry_run.json,
+            SubCommand::Hyperstop(c) => c.dry_run.json,
+            SubCommand::Hyperstart(c) => c.dry_run.json,
+            SubCommand::Netcreate(c) => c.dry_run.json,
+            SubCommand::Netdestroy(c) => c.dry_run.json,
+            SubCommand::Snapshot(c) => c.dry_run.json,
+            SubCommand::Image(c) => c.subcmd.dry_run().json,
+            SubCommand::Audit(c) => c.json,
+            SubCommand::Timings(c) => c.json,
+            SubCommand::Health(c) => c.json,
+            SubCommand::Dhcp(c) => {
+                matches!(c.subcmd, DhcpCommand::Leases { json: true })
+            }
+            _ => false,
+        }
+    }
 }
 
 #[derive(Parser)]
@@ -83,6 +163,34 @@ struct CmdLaunch {
     /// The path of the falcon output directory
     #[clap(short, long, default_value_t = Utf8PathBuf::from(DEFAULT_FALCON_DIR))]
     falcon_dir: Utf8PathBuf,
+
+    #[clap(flatten)]
+    datasets: DatasetOpts,
+
+    /// Adopt existing vnics that differ from the plan, correcting their MAC
+    /// address and MTU in place. Other differences still fail the launch.
+    #[clap(long)]
+    adopt_mismatched: bool,
+
+    /// Launch even though the last destroy in the falcon directory failed
+    #[clap(long)]
+    force: bool,
+
+    /// Boot at most this many nodes at once, the rest wait their turn. Nodes
+    /// without setup are not waited for and do not count
+    #[clap(long, value_name = "NODES")]
+    boot_io_budget: Option<usize>,
+
+    /// Boot one node at a time while this pool is saturated
+    #[clap(long, value_name = "POOL")]
+    boot_io_watch: Option<String>,
+
+    /// How busy, in percent, the watched pool must be to count as saturated
+    #[clap(long, default_value_t = 90, requires = "boot_io_watch")]
+    saturated_at: u8,
+
+    #[clap(flatten)]
+    dry_run: DryRunOpts,
 }
 
 #[derive(Parser)]
@@ -91,6 +199,9 @@ struct CmdPreflight {
     /// The path of the falcon output directory
     #[clap(short, long, default_value_t = Utf8PathBuf::from(DEFAULT_FALCON_DIR))]
     falcon_dir: Utf8PathBuf,
+
+    #[clap(flatten)]
+    datasets: DatasetOpts,
 }
 
 #[derive(Parser)]
@@ -99,6 +210,113 @@ struct CmdDestroy {
     /// The path of the falcon output directory
     #[clap(short, long, default_value_t = Utf8PathBuf::from(DEFAULT_FALCON_DIR))]
     falcon_dir: Utf8PathBuf,
+
+    #[clap(flatten)]
+    datasets: DatasetOpts,
+
+    #[clap(flatten)]
+    dry_run: DryRunOpts,
+}
+
+/// Show the steps a command would take instead of taking them.
+#[derive(Parser)]
+struct DryRunOpts {
+    /// Print the commands, API calls and file writes the command would make
+    /// in order, without making them
+    #[clap(long)]
+    dry_run: bool,
+
+    /// Print the planned steps as JSON
+    #[clap(long, requires = "dry_run")]
+    json: bool,
+}
+
+impl DryRunOpts {
+    fn apply(&self, r: &mut Runner) {
+        if self.dry_run {
+            r.set_dry_run(true);
+        }
+    }
+
+    /// Print the plan of a dry run.
+    fn report(&self, r: &Runner) -> Result<(), Error> {
+        if !self.dry_run {
+            return Ok(());
+        }
+        let plan = r.plan();
+        if self.json {
+            println!("{}", serde_json::to_string(&plan.steps())?);
+        } else if plan.steps().is_empty() {
+            r.output().info("nothing to do");
+        } else {
+            print!("{}", plan);
+        }
+        Ok(())
+    }
+}
+
+/// Dataset overrides, taking precedence over the FALCON_IMAGE_DATASET,
+/// FALCON_TOPO_DATASET and FALCON_DATASET environment variables.
+#[derive(Parser)]
+struct DatasetOpts {
+    /// The parent dataset images are read from
+    #[clap(long)]
+    image_dataset: Option<String>,
+
+    /// The parent dataset topology clones are created under
+    #[clap(long)]
+    topo_dataset: Option<String>,
+}
+
+impl DatasetOpts {
+    fn apply(&self, r: &mut Runner) {
+        if let Some(ref ds) = self.image_dataset {
+            r.set_image_dataset(ds);
+        }
+        if let Some(ref ds) = self.topo_dataset {
+            r.set_topo_dataset(ds);
+        }
+    }
+}
+
+/// Names a point to point link by the nodes at its ends. Every command that
+/// operates on a link takes these arguments.
+#[derive(Parser)]
+struct LinkArgs {
+    /// Node at one end of the link
+    node_a: String,
+
+    /// Node at the other end of the link
+    node_b: String,
+
+    /// Which of several parallel links between the nodes, in the order they
+    /// were declared
+    #[clap(long)]
+    index: Option<usize>,
+}
+
+impl LinkArgs {
+    fn resolve<'a>(&self, d: &'a Deployment) -> Result<&'a Link, Error> {
+        d.resolve_link(&self.node_a, &self.node_b, self.index)
+    }
+}
+
+#[derive(Parser)]
+#[clap(infer_subcommands = true)]
+struct CmdLink {
+    #[clap(flatten)]
+    link: LinkArgs,
+}
+
+#[derive(Parser)]
+#[clap(infer_subcommands = true)]
+struct CmdSpec {
+    /// Name of the VM to show
+    vm_name: String,
+
+    /// The path of the falcon output directory
+    #[clap(short, long, default_value_t = Utf8PathBuf::from(DEFAULT_FALCON_DIR))]
+    falcon_dir: Utf8PathBuf,
 }
 
 #[derive(Parser)]
@@ -112,12 +330,41 @@ struct CmdSerial {
     falcon_dir: Utf8PathBuf,
 }
 
+/// Console capture around operations that change a node. Output is written
+/// to `.falcon/log/ops/`.
+#[derive(Parser)]
+struct CaptureOpts {
+    /// Do not capture the node's console around the operation
+    #[clap(long)]
+    no_capture: bool,
+
+    /// Seconds to keep capturing the console after the operation ends
+    #[clap(long, conflicts_with = "no_capture")]
+    capture_tail: Option<u64>,
+}
+
+impl CaptureOpts {
+    fn apply(&self, r: &mut Runner) {
+        if self.no_capture {
+            r.op_capture = None;
+        } else if let Some(secs) = self.capture_tail {
+            r.op_capture = Some(Duration::from_secs(secs));
+        }
+    }
+}
+
 #[derive(Parser)]
 #[clap(infer_subcommands = true)]
 struct CmdReboot {
     /// Name of the VM to reboot
     vm_name: String,
 
+    #[clap(flatten)]
+    capture: CaptureOpts,
+
+    #[clap(flatten)]
+    dry_run: DryRunOpts,
+
     /// The path of the falcon output directory
     #[clap(short, long, default_value_t = Utf8PathBuf::from(DEFAULT_FALCON_DIR))]
     falcon_dir: Utf8PathBuf,
@@ -133,6 +380,9 @@ struct CmdHyperstop {
     #[clap(short, long)]
     all: bool,
 
+    #[clap(flatten)]
+    dry_run: DryRunOpts,
+
     /// The path of the falcon output directory
     #[clap(short, long, default_value_t = Utf8PathBuf::from(DEFAULT_FALCON_DIR))]
     falcon_dir: Utf8PathBuf,
@@ -152,6 +402,12 @@ struct CmdHyperstart {
     #[clap(short, long)]
     all: bool,
 
+    #[clap(flatten)]
+    capture: CaptureOpts,
+
+    #[clap(flatten)]
+    dry_run: DryRunOpts,
+
     /// The path of the falcon output directory
     #[clap(short, long, default_value_t = Utf8PathBuf::from(DEFAULT_FALCON_DIR))]
     falcon_dir: Utf8PathBuf,
@@ -159,47 +415,535 @@ struct CmdHyperstart {
 
 #[derive(Parser)]
 #[clap(infer_subcommands = true)]
-struct CmdNetCreate {}
+struct CmdNetCreate {
+    /// Adopt existing vnics that differ from the plan, correcting their MAC
+    /// address and MTU in place. Other differences still fail the create.
+    #[clap(long)]
+    adopt_mismatched: bool,
+
+    #[clap(flatten)]
+    dry_run: DryRunOpts,
+}
 
 #[derive(Parser)]
 #[clap(infer_subcommands = true)]
-struct CmdNetDestroy {}
+struct CmdNetDestroy {
+    #[clap(flatten)]
+    dry_run: DryRunOpts,
+}
 
 #[derive(Parser)]
 #[clap(infer_subcommands = true)]
 struct CmdSnapshot {
     /// Name of the VM to snaphost
-    vm_name: String,
+    #[clap(required_unless_present = "prune")]
+    vm_name: Option<String>,
 
     /// What to name the new snapshot
-    snapshot_name: String,
+    #[clap(required_unless_present_any = ["auto", "prune"])]
+    snapshot_name: Option<String>,
+
+    /// Name the snapshot after the VM and the current time, and make it
+    /// subject to pruning
+    #[clap(long, conflicts_with = "snapshot_name")]
+    auto: bool,
+
+    /// Why the snapshot was taken
+    #[clap(long)]
+    description: Option<String>,
+
+    /// Remove old automatic snapshots instead of taking one, explicitly
+    /// named ones are never removed
+    #[clap(
+        long,
+        conflicts_with_all = ["vm_name", "snapshot_name", "auto", "description"]
+    )]
+    prune: bool,
+
+    /// How many automatic snapshots to keep per node when pruning
+    #[clap(long, requires = "prune")]
+    keep: Option<usize>,
+
+    /// Also keep automatic snapshots younger than this many days when
+    /// pruning
+    #[clap(long, requires = "prune")]
+    keep_days: Option<u32>,
 
     /// The path of the falcon output directory
     #[clap(short, long, default_value_t = Utf8PathBuf::from(DEFAULT_FALCON_DIR))]
     falcon_dir: Utf8PathBuf,
+
+    /// The parent dataset to create the new image under, or to prune images
+    /// from, defaults to the image dataset of the node
+    #[clap(long)]
+    image_dataset: Option<String>,
+
+    #[clap(flatten)]
+    dry_run: DryRunOpts,
 }
 
 #[derive(Parser)]
 #[clap(infer_subcommands = true)]
-struct CmdInfo {}
+struct CmdInfo {
+    /// Only show this VM
+    vm_name: Option<String>,
+}
 
 #[derive(Parser)]
-#[clap(infer_subcommands = true)]
+#[clap(infer_subcommands = true, allow_missing_positional = true)]
 struct CmdExec {
-    node: String,
+    /// The node to run on, omitted with --all
+    #[clap(required_unless_present = "all", conflicts_with = "all")]
+    node: Option<String>,
+
+    /// The command to run. With --all it is the only positional argument,
+    /// as in `exec --all --sync -- <COMMAND>`
     command: String,
 
+    /// Feed this process's stdin to the command. This is the default when
+    /// stdin is not a terminal. Input is limited to 256 KiB.
+    #[clap(long, conflicts_with = "all")]
+    stdin: bool,
+
+    /// Leave this process's stdin alone even when it is not a terminal
+    #[clap(long, conflicts_with = "stdin")]
+    no_stdin: bool,
+
+    /// Run on every node
+    #[clap(long)]
+    all: bool,
+
+    /// Start the command on all nodes at nearly the same instant and print
+    /// the measured start skew
+    #[clap(long, requires = "all")]
+    sync: bool,
+
+    /// With --sync, fail if the start skew exceeds this many milliseconds
+    #[clap(long, default_value_t = 100, requires = "sync")]
+    max_skew_ms: u64,
+
     /// The path of the falcon output directory
     #[clap(short, long, default_value_t = Utf8PathBuf::from(DEFAULT_FALCON_DIR))]
     falcon_dir: Utf8PathBuf,
 }
 
+#[derive(Parser)]
+#[clap(infer_subcommands = true)]
+struct CmdSvc {
+    /// Name of the VM to manage services on
+    vm_name: String,
+
+    /// The service FMRI
+    fmri: String,
+
+    /// What to do with the service
+    #[clap(value_enum)]
+    action: SvcAction,
+
+    /// The path of the falcon output directory
+    #[clap(short, long, default_value_t = Utf8PathBuf::from(DEFAULT_FALCON_DIR))]
+    falcon_dir: Utf8PathBuf,
+}
+
+#[derive(Parser)]
+#[clap(infer_subcommands = true)]
+struct CmdBundle {
+    /// Name of the VM to bundle
+    vm_name: Option<String>,
+
+    /// Where to write the bundle, defaults to <vm_name>.tar.gz
+    #[clap(short, long)]
+    output: Option<Utf8PathBuf>,
+
+    /// Attach a zfs send stream of the node's image
+    #[clap(long)]
+    include_image: bool,
+
+    /// Check that this host can satisfy the recipe in the given bundle
+    #[clap(long, conflicts_with_all = ["vm_name", "output", "include_image"])]
+    verify: Option<Utf8PathBuf>,
+
+    /// The propolis-server binary to use
+    #[clap(short, long)]
+    propolis: Option<String>,
+
+    /// The path of the falcon output directory
+    #[clap(short, long, default_value_t = Utf8PathBuf::from(DEFAULT_FALCON_DIR))]
+    falcon_dir: Utf8PathBuf,
+
+    #[clap(flatten)]
+    datasets: DatasetOpts,
+}
+
+#[derive(Parser)]
+#[clap(infer_subcommands = true)]
+struct CmdAudit {
+    /// Only audit this VM, defaults to all VMs in the topology
+    vm_name: Option<String>,
+
+    /// Re-apply drifted configuration in these categories
+    #[clap(long, value_enum, value_delimiter = ',')]
+    fix: Vec<AuditCategory>,
+
+    /// Print JSON rather than a human readable report
+    #[clap(long)]
+    json: bool,
+
+    /// The path of the falcon output directory
+    #[clap(short, long, default_value_t = Utf8PathBuf::from(DEFAULT_FALCON_DIR))]
+    falcon_dir: Utf8PathBuf,
+}
+
+#[derive(Parser)]
+#[clap(infer_subcommands = true)]
+struct CmdStatus {
+    /// The path of the falcon output directory
+    #[clap(short, long, default_value_t = Utf8PathBuf::from(DEFAULT_FALCON_DIR))]
+    falcon_dir: Utf8PathBuf,
+}
+
+#[derive(Parser)]
+#[clap(infer_subcommands = true)]
+struct CmdHealth {
+    /// Seconds each propolis server and host tool has to answer
+    #[clap(long, default_value_t = 5)]
+    timeout: u64,
+
+    /// Degrade nodes on a pool at least this full, in percent
+    #[clap(long, default_value_t = 90)]
+    disk_threshold: u8,
+
+    /// Degrade nodes that panicked within this many hours
+    #[clap(long, default_value_t = 24)]
+    panic_window: u64,
+
+    /// Print JSON rather than tables
+    #[clap(long)]
+    json: bool,
+
+    /// The path of the falcon output directory
+    #[clap(short, long, default_value_t = Utf8PathBuf::from(DEFAULT_FALCON_DIR))]
+    falcon_dir: Utf8PathBuf,
+}
+
+#[derive(Parser)]
+#[clap(infer_subcommands = true)]
+struct CmdNic {
+    #[clap(subcommand)]
+    subcmd: NicCommand,
+
+    /// The path of the falcon output directory
+    #[clap(short, long, default_value_t = Utf8PathBuf::from(DEFAULT_FALCON_DIR))]
+    falcon_dir: Utf8PathBuf,
+}
+
+#[derive(Parser)]
+enum NicCommand {
+    #[clap(about = "link a running vm to a peer through new NICs")]
+    Add {
+        /// Name of the VM to add a NIC to
+        vm_name: String,
+        /// Name of the VM at the other end of the new link
+        peer: String,
+    },
+    #[clap(about = "unplug the NICs of a link between running vms")]
+    Remove {
+        #[clap(flatten)]
+        link: LinkArgs,
+    },
+}
+
+#[derive(Parser)]
+#[clap(infer_subcommands = true)]
+struct CmdReact {
+    /// Regular expression matched against each console line
+    #[clap(long)]
+    pattern: String,
+
+    /// Only watch nodes whose name matches this glob
+    #[clap(long, default_value = "*")]
+    node: String,
+
+    /// Shell command to run, `{node}` is replaced with the node's name
+    #[clap(long)]
+    run: String,
+
+    /// Stop after the first firing on any node
+    #[clap(long)]
+    once: bool,
+
+    /// Firings allowed per node within --per
+    #[clap(long, default_value_t = 3)]
+    burst: u32,
+
+    /// Seconds over which --burst applies
+    #[clap(long, default_value_t = 60)]
+    per: u64,
+
+    /// The path of the falcon output directory
+    #[clap(short, long, default_value_t = Utf8PathBuf::from(DEFAULT_FALCON_DIR))]
+    falcon_dir: Utf8PathBuf,
+}
+
+#[derive(Parser)]
+#[clap(infer_subcommands = true)]
+struct CmdDhcp {
+    #[clap(subcommand)]
+    subcmd: DhcpCommand,
+
+    /// The path of the falcon output directory
+    #[clap(short, long, default_value_t = Utf8PathBuf::from(DEFAULT_FALCON_DIR))]
+    falcon_dir: Utf8PathBuf,
+}
+
+#[derive(Parser)]
+enum DhcpCommand {
+    #[clap(about = "list addresses leased to nodes")]
+    Leases {
+        /// Print JSON rather than a table
+        #[clap(long)]
+        json: bool,
+    },
+    /// Run the responder, as started by launch and netcreate
+    #[clap(hide = true)]
+    Serve,
+}
+
+#[derive(Parser)]
+struct CmdCapture {
+    /// The port of the propolis server whose console is followed
+    #[clap(long)]
+    port: u16,
+
+    /// Seconds to keep following the console
+    #[clap(long)]
+    tail: u64,
+
+    /// The capture file to append to
+    log: Utf8PathBuf,
+}
+
+#[derive(Parser)]
+#[clap(infer_subcommands = true)]
+struct CmdCores {
+    /// Remove the listed cores
+    #[clap(long)]
+    clean: bool,
+
+    /// The path of the falcon output directory
+    #[clap(short, long, default_value_t = Utf8PathBuf::from(DEFAULT_FALCON_DIR))]
+    falcon_dir: Utf8PathBuf,
+}
+
+#[derive(Parser)]
+#[clap(infer_subcommands = true)]
+struct CmdImage {
+    #[clap(subcommand)]
+    subcmd: ImageCommand,
+}
+
+#[derive(Parser)]
+enum ImageCommand {
+    #[clap(about = "write an image's send stream to a file")]
+    Export(CmdImageExport),
+    #[clap(about = "receive an image from a send stream file")]
+    Import(CmdImageImport),
+    #[clap(about = "derive a new image from an existing one")]
+    Clone(CmdImageClone),
+}
+
+impl ImageCommand {
+    fn dry_run(&self) -> &DryRunOpts {
+        match self {
+            ImageCommand::Export(c) => &c.dry_run,
+            ImageCommand::Import(c) => &c.dry_run,
+            ImageCommand::Clone(c) => &c.dry_run,
+        }
+    }
+}
+
+#[derive(Parser)]
+#[clap(infer_subcommands = true)]
+struct CmdImageExport {
+    /// Name of the image to export
+    image: String,
+
+    /// Where to write the stream, defaults to <image>.zfs, or <image>.zfs.zst
+    /// when compressed
+    #[clap(short, long)]
+    output: Option<Utf8PathBuf>,
+
+    /// Compress the stream
+    #[clap(long, value_name = "zstd[:LEVEL]")]
+    compress: Option<String>,
+
+    /// The parent dataset images are read from
+    #[clap(long)]
+    image_dataset: Option<String>,
+
+    #[clap(flatten)]
+    dry_run: DryRunOpts,
+}
+
+#[derive(Parser)]
+#[clap(infer_subcommands = true)]
+struct CmdImageImport {
+    /// The send stream to receive, compressed streams are detected
+    input: Utf8PathBuf,
+
+    /// Name to give the image
+    image: String,
+
+    /// Skip the receive if an image with the same @base snapshot exists
+    #[clap(long)]
+    dedup_check: bool,
+
+    /// The parent dataset images are received into
+    #[clap(long)]
+    image_dataset: Option<String>,
+
+    #[clap(flatten)]
+    dry_run: DryRunOpts,
+}
+
+#[derive(Parser)]
+#[clap(infer_subcommands = true)]
+struct CmdImageClone {
+    /// Name of the image to clone
+    src: String,
+
+    /// Name to give the new image
+    dst: String,
+
+    /// Replace the new image if it already exists
+    #[clap(long)]
+    force: bool,
+
+    /// The parent dataset both images live under
+    #[clap(long)]
+    image_dataset: Option<String>,
+
+    #[clap(flatten)]
+    dry_run: DryRunOpts,
+}
+
+#[derive(Parser)]
+#[clap(infer_subcommands = true)]
+struct CmdCrash {
+    #[clap(subcommand)]
+    subcmd: CrashCommand,
+
+    /// The path of the falcon output directory
+    #[clap(short, long, default_value_t = Utf8PathBuf::from(DEFAULT_FALCON_DIR))]
+    falcon_dir: Utf8PathBuf,
+}
+
+#[derive(Parser)]
+enum CrashCommand {
+    #[clap(about = "list collected panic output and crash dumps")]
+    List(CmdCrashList),
+    #[clap(about = "extract and copy crash dumps off a node")]
+    Pull(CmdCrashPull),
+    #[clap(about = "follow a node's console and collect panics")]
+    Watch(CmdCrashWatch),
+}
+
+#[derive(Parser)]
+#[clap(infer_subcommands = true)]
+struct CmdCrashList {
+    /// Only list files from this VM
+    vm_name: Option<String>,
+}
+
+#[derive(Parser)]
+#[clap(infer_subcommands = true)]
+struct CmdCrashPull {
+    /// Name of the VM to pull dumps from
+    vm_name: String,
+
+    /// Leave dumps larger than this many MiB on the node
+    #[clap(long, default_value_t = 256)]
+    max_size: u64,
+}
+
+#[derive(Parser)]
+#[clap(infer_subcommands = true)]
+struct CmdCrashWatch {
+    /// Name of the VM to watch
+    vm_name: String,
+
+    /// Reboot the node after a panic and pull its crash dump once it is back
+    #[clap(long)]
+    reboot: bool,
+
+    /// Leave dumps larger than this many MiB on the node
+    #[clap(long, default_value_t = 256)]
+    max_size: u64,
+}
+
+#[derive(Parser)]
+#[clap(infer_subcommands = true)]
+struct CmdTimings {
+    /// Only summarize this operation, e.g. launch, destroy or exec
+    #[clap(long)]
+    op: Option<String>,
+
+    /// Include every deployment in the log, not just this topology
+    #[clap(long)]
+    all: bool,
+
+    /// Print JSON rather than a table
+    #[clap(long)]
+    json: bool,
+}
+
+#[derive(Parser)]
+#[clap(infer_subcommands = true)]
+struct CmdConfig {
+    #[clap(subcommand)]
+    subcmd: ConfigCommand,
+}
+
+#[derive(Parser)]
+enum ConfigCommand {
+    #[clap(about = "show a setting")]
+    Get {
+        /// The setting to show
+        key: String,
+    },
+    #[clap(about = "change a setting")]
+    Set {
+        /// The setting to change
+        key: String,
+        /// The new value
+        value: String,
+    },
+    #[clap(about = "remove a setting")]
+    Unset {
+        /// The setting to remove
+        key: String,
+    },
+    #[clap(about = "show all settings")]
+    List,
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+enum SvcAction {
+    Enable,
+    Disable,
+    Restart,
+    Status,
+}
+
 /// Entry point for a command line application. Will parse command line
 /// arguments and take actions accordingly.
 ///
+/// Topology programs should import everything they need through
+/// [`crate::prelude`], which is the supported import path.
+///
 /// # Examples
 /// ```no_run
-/// use libfalcon::{cli::run, Runner};
+/// use libfalcon::prelude::*;
 ///
 /// let mut r = Runner::new("duo");
 ///
@@ -214,11 +958,67 @@ struct CmdExec {
 /// ```
 pub async fn run(r: &mut Runner) -> Result<RunMode, Error> {
     r.persistent = true;
+    // this process exits once a launch is done, the responder must not
+    r.detach_dhcp = true;
 
     let opts: Opts = Opts::parse();
-    match opts.subcmd {
+
+    // debug output shows, among other things, the retry policy in effect
+    // whenever an operation starts retrying
+    if opts.verbose > 0 {
+        r.log = create_logger();
+    }
+    let mut out = OutputCtx::terminal();
+    out.verbosity = match (opts.quiet, opts.verbose) {
+        (true, _) => Verbosity::Quiet,
+        (false, 0) => Verbosity::Normal,
+        (false, _) => Verbosity::Verbose,
+    };
+    // a command printing JSON prints its messages as JSON too, before
+    // anything can be said
+    out.json = opts.subcmd.json();
+
+    // per-user defaults sit below the flags applied by each subcommand
+    let user_config = match UserConfig::load(&out) {
+        Ok(c) => c,
+        Err(e) => {
+            out.warn(format!("ignoring user config: {}", e));
+            UserConfig::default()
+        }
+    };
+    user_config.apply(r);
+    match opts.color {
+        Some(ColorPreference::Always) => colored::control::set_override(true),
+        Some(ColorPreference::Never) => colored::control::set_override(false),
+        Some(ColorPreference::Auto) => colored::control::unset_override(),
+        None => {}
+    }
+    out.color = colored::control::SHOULD_COLORIZE.should_colorize();
+    r.set_output(out);
+
+    let result = run_subcommand(r, opts.subcmd, &user_config).await;
+
+    // launch and destroy report on the marker themselves
+    let reported = matches!(result, Ok(RunMode::Launch) | Ok(RunMode::Destroy));
+    if !reported && workspace::destroy_failed(&r.falcon_dir).is_some() {
+        r.output().warn(format!(
+            "the last destroy of this topology failed, its state is kept in {}",
+            r.falcon_dir
+        ));
+    }
+    result
+}
+
+async fn run_subcommand(
+    r: &mut Runner,
+    mut subcmd: SubCommand,
+    user_config: &UserConfig,
+) -> Result<RunMode, Error> {
+    qualify(r, &mut subcmd)?;
+    match subcmd {
         SubCommand::Preflight(p) => {
             r.falcon_dir = p.falcon_dir;
+            p.datasets.apply(r);
             preflight(r).await;
             Ok(RunMode::Unspec)
         }
@@ -227,30 +1027,64 @@ pub async fn run(r: &mut Runner) -> Result<RunMode, Error> {
                 r.propolis_binary = path
             }
             r.falcon_dir = l.falcon_dir;
+            l.datasets.apply(r);
+            r.adopt_mismatched = l.adopt_mismatched;
+            r.force_launch = l.force;
+            if let Some(n) = l.boot_io_budget {
+                r.boot_io_budget(n);
+            }
+            if let Some(ref pool) = l.boot_io_watch {
+                r.boot_io_watch(pool, l.saturated_at);
+            }
+            l.dry_run.apply(r);
             launch(r).await;
+            l.dry_run.report(r)?;
             Ok(RunMode::Launch)
         }
         SubCommand::Destroy(d) => {
             r.falcon_dir = d.falcon_dir;
+            persisted_datasets(r);
+            d.datasets.apply(r);
+            d.dry_run.apply(r);
             destroy(r);
+            d.dry_run.report(r)?;
             Ok(RunMode::Destroy)
         }
         SubCommand::Serial(ref c) => {
             console(&c.vm_name, &c.falcon_dir).await?;
             Ok(RunMode::Unspec)
         }
-        SubCommand::Info(_) => {
-            info(r)?;
+        SubCommand::Info(ref c) => {
+            info(r, c)?;
             Ok(RunMode::Unspec)
         }
         SubCommand::Reboot(ref c) => {
-            reboot(&c.vm_name, &c.falcon_dir).await?;
+            r.falcon_dir = c.falcon_dir.clone();
+            c.capture.apply(r);
+            c.dry_run.apply(r);
+            let start = Instant::now();
+            let mut capture = r.capture_op(&c.vm_name, "reboot");
+            let result = reboot(r.plan(), &c.vm_name, &c.falcon_dir).await;
+            r.record_event(
+                "reboot",
+                Some(&c.vm_name),
+                start.elapsed(),
+                result.is_ok(),
+            );
+            let result = match capture {
+                Some(ref mut cap) => cap.finish(result),
+                None => result,
+            };
+            detach_captures(r, capture.into_iter().collect());
+            result?;
+            c.dry_run.report(r)?;
             Ok(RunMode::Unspec)
         }
         SubCommand::Hyperstop(ref c) => {
+            c.dry_run.apply(r);
             if c.all {
                 for x in &r.deployment.nodes {
-                    hyperstop(&x.name, &c.falcon_dir).await?;
+                    hyperstop(r, &x.name, &c.falcon_dir).await?;
                 }
             } else {
                 match c.vm_name {
@@ -259,56 +1093,275 @@ pub async fn run(r: &mut Runner) -> Result<RunMode, Error> {
                             "vm name required unless --all flag is used".into(),
                         ))
                     }
-                    Some(ref n) => hyperstop(n, &c.falcon_dir).await?,
+                    Some(ref n) => hyperstop(r, n, &c.falcon_dir).await?,
                 }
             }
+            c.dry_run.report(r)?;
             Ok(RunMode::Unspec)
         }
         SubCommand::Hyperstart(ref c) => {
             let propolis_binary = match c.propolis {
                 Some(ref path) => path.clone(),
-                None => "propolis-server".into(),
+                None => r.propolis_binary.clone(),
             };
-            if c.all {
-                for x in &r.deployment.nodes {
-                    hyperstart(&x.name, propolis_binary.clone(), &c.falcon_dir)
-                        .await?;
+            r.falcon_dir = c.falcon_dir.clone();
+            c.capture.apply(r);
+            c.dry_run.apply(r);
+            let retry = r.retry_policy(RetryOp::PropolisEnsure);
+            let names: Vec<String> = match (c.all, &c.vm_name) {
+                (true, _) => {
+                    r.deployment.nodes.iter().map(|n| n.name.clone()).collect()
                 }
-            } else {
-                match c.vm_name {
-                    None => {
-                        return Err(Error::Cli(
-                            "vm name required unless --all flag is used".into(),
-                        ))
-                    }
-                    Some(ref n) => {
-                        hyperstart(n, propolis_binary, &c.falcon_dir).await?
-                    }
+                (false, Some(n)) => vec![n.clone()],
+                (false, None) => {
+                    return Err(Error::Cli(
+                        "vm name required unless --all flag is used".into(),
+                    ))
+                }
+            };
+            let mut captures = Vec::new();
+            let mut result = Ok(());
+            for name in names.iter() {
+                let mut capture = r.capture_op(name, "hyperstart");
+                let started = hyperstart(
+                    r.plan(),
+                    name,
+                    propolis_binary.clone(),
+                    &c.falcon_dir,
+                    &retry,
+                )
+                .await;
+                let started = match capture {
+                    Some(ref mut cap) => cap.finish(started),
+                    None => started,
+                };
+                captures.extend(capture);
+                if started.is_err() {
+                    result = started;
+                    break;
                 }
             }
+            detach_captures(r, captures);
+            result?;
+            c.dry_run.report(r)?;
             Ok(RunMode::Unspec)
         }
-        SubCommand::Netcreate(_) => {
+        SubCommand::Netcreate(ref c) => {
+            r.adopt_mismatched = c.adopt_mismatched;
+            c.dry_run.apply(r);
             netcreate(r).await;
+            c.dry_run.report(r)?;
             Ok(RunMode::Unspec)
         }
-        SubCommand::Netdestroy(_) => {
+        SubCommand::Netdestroy(ref c) => {
+            c.dry_run.apply(r);
             netdestroy(r);
+            c.dry_run.report(r)?;
             Ok(RunMode::Unspec)
         }
         SubCommand::Snapshot(s) => {
-            snapshot(s)?;
+            s.dry_run.apply(r);
+            if s.prune {
+                snapshot_prune(r, user_config, &s)?;
+            } else {
+                snapshot(r, &s)?;
+            }
+            s.dry_run.report(r)?;
             Ok(RunMode::Unspec)
         }
         SubCommand::Exec(ref c) => {
             r.falcon_dir = c.falcon_dir.clone();
-            exec(r, &c.node, &c.command).await?;
+            exec(r, c).await?;
+            Ok(RunMode::Unspec)
+        }
+        SubCommand::Svc(ref c) => {
+            r.falcon_dir = c.falcon_dir.clone();
+            svc(r, c).await?;
+            Ok(RunMode::Unspec)
+        }
+        SubCommand::Bundle(ref c) => {
+            r.falcon_dir = c.falcon_dir.clone();
+            if let Some(ref path) = c.propolis {
+                r.propolis_binary = path.clone();
+            }
+            persisted_datasets(r);
+            c.datasets.apply(r);
+            bundle(r, c)?;
+            Ok(RunMode::Unspec)
+        }
+        SubCommand::Config(ref c) => {
+            config(r, &c.subcmd)?;
+            Ok(RunMode::Unspec)
+        }
+        SubCommand::Timings(ref c) => {
+            timings(r, c)?;
+            Ok(RunMode::Unspec)
+        }
+        SubCommand::Status(ref c) => {
+            status(r, &c.falcon_dir)?;
+            Ok(RunMode::Unspec)
+        }
+        SubCommand::Health(ref c) => {
+            r.falcon_dir = c.falcon_dir.clone();
+            health(r, c).await?;
+            Ok(RunMode::Unspec)
+        }
+        SubCommand::Nic(ref c) => {
+            r.falcon_dir = c.falcon_dir.clone();
+            nic(r, &c.subcmd).await?;
+            Ok(RunMode::Unspec)
+        }
+        SubCommand::React(ref c) => {
+            r.falcon_dir = c.falcon_dir.clone();
+            react(r, c).await?;
+            Ok(RunMode::Unspec)
+        }
+        SubCommand::Dhcp(ref c) => {
+            r.falcon_dir = c.falcon_dir.clone();
+            match c.subcmd {
+                DhcpCommand::Leases { json } => dhcp_leases(r, json)?,
+                DhcpCommand::Serve => r.serve_dhcp_detached().await?,
+            }
+            Ok(RunMode::Unspec)
+        }
+        SubCommand::Capture(ref c) => {
+            let tail = Duration::from_secs(c.tail);
+            capture::follow_detached(&c.log, c.port, tail).await?;
+            Ok(RunMode::Unspec)
+        }
+        SubCommand::Cores(ref c) => {
+            list_cores(r, c)?;
+            Ok(RunMode::Unspec)
+        }
+        SubCommand::Crash(ref c) => {
+            r.falcon_dir = c.falcon_dir.clone();
+            match c.subcmd {
+                CrashCommand::List(ref l) => list_crashes(r, l)?,
+                CrashCommand::Pull(ref p) => crash_pull(r, p).await?,
+                CrashCommand::Watch(ref w) => crash_watch(r, w).await?,
+            }
+            Ok(RunMode::Unspec)
+        }
+        SubCommand::Link(ref c) => {
+            show_link(r, c).await?;
+            Ok(RunMode::Unspec)
+        }
+        SubCommand::Spec(ref c) => {
+            r.falcon_dir = c.falcon_dir.clone();
+            spec(r, c)?;
+            Ok(RunMode::Unspec)
+        }
+        SubCommand::Image(ref c) => {
+            let dry_run = c.subcmd.dry_run();
+            dry_run.apply(r);
+            match c.subcmd {
+                ImageCommand::Export(ref c) => image_export(r, c)?,
+                ImageCommand::Import(ref c) => image_import(r, c)?,
+                ImageCommand::Clone(ref c) => image_clone(r, c)?,
+            }
+            dry_run.report(r)?;
+            Ok(RunMode::Unspec)
+        }
+        SubCommand::Audit(ref c) => {
+            r.falcon_dir = c.falcon_dir.clone();
+            audit(r, c).await?;
             Ok(RunMode::Unspec)
         }
     }
 }
 
-fn info(r: &Runner) -> anyhow::Result<()> {
+/// Resolve the node names a subcommand was given. Names qualified as
+/// `topology:node` switch the runner, and the subcommand's falcon directory,
+/// to that topology. Either way the names are left as plain node names.
+fn qualify(r: &mut Runner, subcmd: &mut SubCommand) -> Result<(), Error> {
+    let (names, falcon_dir): (Vec<&mut String>, Option<&mut Utf8PathBuf>) =
+        match subcmd {
+            SubCommand::Serial(c) => {
+                (vec![&mut c.vm_name], Some(&mut c.falcon_dir))
+            }
+            SubCommand::Reboot(c) => {
+                (vec![&mut c.vm_name], Some(&mut c.falcon_dir))
+            }
+            SubCommand::Hyperstop(c) => {
+                (c.vm_name.iter_mut().collect(), Some(&mut c.falcon_dir))
+            }
+            SubCommand::Hyperstart(c) => {
+                (c.vm_name.iter_mut().collect(), Some(&mut c.falcon_dir))
+            }
+            SubCommand::Snapshot(c) => {
+                (c.vm_name.iter_mut().collect(), Some(&mut c.falcon_dir))
+            }
+            SubCommand::Info(c) => (c.vm_name.iter_mut().collect(), None),
+            SubCommand::Exec(c) => {
+                (c.node.iter_mut().collect(), Some(&mut c.falcon_dir))
+            }
+            SubCommand::Svc(c) => {
+                (vec![&mut c.vm_name], Some(&mut c.falcon_dir))
+            }
+            SubCommand::Bundle(c) => {
+                (c.vm_name.iter_mut().collect(), Some(&mut c.falcon_dir))
+            }
+            SubCommand::Audit(c) => {
+                (c.vm_name.iter_mut().collect(), Some(&mut c.falcon_dir))
+            }
+            SubCommand::Link(c) => {
+                (vec![&mut c.link.node_a, &mut c.link.node_b], None)
+            }
+            SubCommand::Crash(c) => {
+                let names = match c.subcmd {
+                    CrashCommand::List(ref mut l) => {
+                        l.vm_name.iter_mut().collect()
+                    }
+                    CrashCommand::Pull(ref mut p) => vec![&mut p.vm_name],
+                    CrashCommand::Watch(ref mut w) => vec![&mut w.vm_name],
+                };
+                (names, Some(&mut c.falcon_dir))
+            }
+            SubCommand::Spec(c) => {
+                (vec![&mut c.vm_name], Some(&mut c.falcon_dir))
+            }
+            SubCommand::Nic(c) => {
+                let names = match c.subcmd {
+                    NicCommand::Add {
+                        ref mut vm_name,
+                        ref mut peer,
+                    } => vec![vm_name, peer],
+                    NicCommand::Remove { ref mut link } => {
+                        vec![&mut link.node_a, &mut link.node_b]
+                    }
+                };
+                (names, Some(&mut c.falcon_dir))
+            }
+            _ => return Ok(()),
+        };
+
+    let addrs = names
+        .iter()
+        .map(|n| n.parse())
+        .collect::<Result<Vec<NodeAddr>, Error>>()?;
+    let registry = r.registry.clone();
+    let (nodes, other) = address::resolve(&r.deployment, &addrs, |name| {
+        registry::lookup(registry.as_deref(), name)
+    })?;
+    for (name, node) in names.into_iter().zip(nodes) {
+        *name = node;
+    }
+    if let Some((dir, d)) = other {
+        if let Some(f) = falcon_dir {
+            *f = dir.clone();
+        }
+        r.falcon_dir = dir;
+        r.deployment = d;
+    }
+    Ok(())
+}
+
+fn info(r: &Runner, c: &CmdInfo) -> anyhow::Result<()> {
+    if let Some(ref name) = c.vm_name {
+        if r.deployment.node_named(name).is_none() {
+            return Err(Error::NotFound(name.clone()).into());
+        }
+    }
     let mut tw = TabWriter::new(stdout());
 
     println!("{} {}", "name:".dimmed(), r.deployment.name,);
@@ -316,23 +1369,30 @@ fn info(r: &Runner) -> anyhow::Result<()> {
     println!("{}", "Nodes".bright_black());
     writeln!(
         &mut tw,
-        "{}\t{}\t{}\t{}\t{}",
+        "{}\t{}\t{}\t{}\t{}\t{}",
         "Name".dimmed(),
         "Image".dimmed(),
         "Radix".dimmed(),
+        "Roles".dimmed(),
         "Mounts".dimmed(),
         "UUID".dimmed(),
     )?;
     writeln!(
         &mut tw,
-        "{}\t{}\t{}\t{}\t{}",
+        "{}\t{}\t{}\t{}\t{}\t{}",
         "----".bright_black(),
         "-----".bright_black(),
         "-----".bright_black(),
+        "-----".bright_black(),
         "------".bright_black(),
         "----".bright_black(),
     )?;
     for x in &r.deployment.nodes {
+        if c.vm_name.as_ref().map_or(false, |n| *n != x.name) {
+            continue;
+        }
+        let roles: Vec<String> =
+            x.roles.iter().map(|r| r.to_string()).collect();
         let mount = {
             if !x.mounts.is_empty() {
                 format!("{} -> {}", x.mounts[0].source, x.mounts[0].destination,)
@@ -342,113 +1402,464 @@ fn info(r: &Runner) -> anyhow::Result<()> {
         };
         writeln!(
             &mut tw,
-            "{}\t{}\t{}\t{}\t{}",
-            x.name, x.image, x.radix, mount, x.id,
+            "{}\t{}\t{}\t{}\t{}\t{}",
+            x.name,
+            x.image,
+            x.radix,
+            roles.join(","),
+            mount,
+            x.id,
         )?;
         if x.mounts.len() > 1 {
             for m in &x.mounts[1..] {
                 let mount = format!("{} -> {}", m.source, m.destination,);
-                writeln!(&mut tw, "\t\t\t{}\t", mount)?;
+                writeln!(&mut tw, "\t\t\t\t{}\t", mount)?;
             }
         }
     }
     tw.flush()?;
 
+    let d = &r.deployment;
+    let externs: Vec<_> = d
+        .extern_links
+        .iter()
+        .filter(|l| {
+            let name = &d.nodes[l.endpoint.node.index].name;
+            c.vm_name.as_ref().map_or(true, |n| n == name)
+        })
+        .collect();
+    if externs.is_empty() {
+        return Ok(());
+    }
+    println!("{}", "External Links".bright_black());
+    writeln!(
+        &mut tw,
+        "{}\t{}\t{}\t{}\t{}",
+        "Node".dimmed(),
+        "Port".dimmed(),
+        "Topology".dimmed(),
+        "Peer".dimmed(),
+        "Peer Port".dimmed(),
+    )?;
+    writeln!(
+        &mut tw,
+        "{}\t{}\t{}\t{}\t{}",
+        "----".bright_black(),
+        "----".bright_black(),
+        "--------".bright_black(),
+        "----".bright_black(),
+        "---------".bright_black(),
+    )?;
+    for l in externs {
+        writeln!(
+            &mut tw,
+            "{}\t{}\t{}\t{}\t{}",
+            d.nodes[l.endpoint.node.index].name,
+            l.endpoint.index,
+            l.peer.topology,
+            l.peer.node,
+            l.peer.link_name.as_deref().unwrap_or("any"),
+        )?;
+    }
+    tw.flush()?;
+
+    Ok(())
+}
+
+async fn show_link(r: &Runner, c: &CmdLink) -> anyhow::Result<()> {
+    let d = &r.deployment;
+    let l = c.link.resolve(d)?;
+    let states =
+        ops::link_states(r.backend(), HealthOptions::default().timeout)
+            .await
+            .unwrap_or_else(|e| {
+                r.output().warn(format!("link states unknown: {}", e));
+                Default::default()
+            });
+    println!("{} {}", "id:".dimmed(), l.id);
+    if let Some(ref name) = l.name {
+        println!("{} {}", "name:".dimmed(), name);
+    }
+
+    let mut tw = TabWriter::new(stdout());
+    writeln!(
+        &mut tw,
+        "{}\t{}\t{}\t{}",
+        "Node".dimmed(),
+        "Port".dimmed(),
+        "Host Link".dimmed(),
+        "State".dimmed(),
+    )?;
+    writeln!(
+        &mut tw,
+        "{}\t{}\t{}\t{}",
+        "----".bright_black(),
+        "----".bright_black(),
+        "---------".bright_black(),
+        "-----".bright_black(),
+    )?;
+    for (n, e) in d.link_nodes(l).iter().zip(l.endpoints.iter()) {
+        let vnic = d.vnic_link_name(e);
+        let state = states.get(&vnic).map_or("-", String::as_str);
+        writeln!(&mut tw, "{}\t{}\t{}\t{}", n.name, e.index, vnic, state)?;
+    }
+    tw.flush()?;
+
+    Ok(())
+}
+
+fn spec(r: &Runner, c: &CmdSpec) -> anyhow::Result<()> {
+    let node = match r.deployment.node_named(&c.vm_name) {
+        Some(n) => n,
+        None => return Err(Error::NotFound(c.vm_name.clone()).into()),
+    };
+
+    let path = r.falcon_dir.join(format!("{}.toml", node.name));
+    match fs::read_to_string(&path) {
+        Ok(spec) => {
+            println!("{} {}", "spec:".dimmed(), path);
+            print!("{}", spec);
+        }
+        Err(_) => println!("{} not launched yet", "spec:".dimmed()),
+    }
+
+    let env = Environment::for_node(&r.deployment.name, node)?;
+    println!("{}", "Environment".bright_black());
+    let mut tw = TabWriter::new(stdout());
+    writeln!(
+        &mut tw,
+        "{}\t{}\t{}",
+        "Name".dimmed(),
+        "Value".dimmed(),
+        "Source".dimmed(),
+    )?;
+    writeln!(
+        &mut tw,
+        "{}\t{}\t{}",
+        "----".bright_black(),
+        "-----".bright_black(),
+        "------".bright_black(),
+    )?;
+    for v in env.vars.iter() {
+        writeln!(&mut tw, "{}\t{}\t{}", v.key, v.display_value(), v.source)?;
+    }
+    tw.flush()?;
+    for key in env.overridden.iter() {
+        println!(
+            "{} {} is set by falcon, the value given for it is ignored",
+            "warning:".yellow(),
+            key
+        );
+    }
+
     Ok(())
 }
 
 async fn preflight(r: &Runner) {
     if let Err(e) = r.preflight() {
-        println!("{}", e)
+        r.output().error(e.to_string())
     }
 }
 
 async fn launch(r: &Runner) {
-    if let Err(e) = r.launch().await {
-        println!("{}", e)
+    match r.launch().await {
+        Ok(_) if r.plan().is_dry_run() => {}
+        Ok(report) => print!("{}", report),
+        Err(e) => failed(r, e),
     }
 }
 
 async fn netcreate(r: &Runner) {
     if let Err(e) = r.net_launch().await {
-        println!("{}", e)
+        failed(r, e)
     }
 }
 
-fn netdestroy(r: &Runner) {
-    if let Err(e) = r.net_destroy() {
-        println!("{}", e)
+fn netdestroy(r: &Runner) {
+    if let Err(e) = r.net_destroy() {
+        failed(r, e)
+    }
+}
+
+/// Report an operation's error along with the step it failed at.
+fn failed(r: &Runner, e: Error) {
+    r.output().error(e.to_string());
+    if let Some((n, step)) = r.plan().failed() {
+        r.output().error(format!("failed at step {}: {}", n, step));
+    }
+}
+
+/// Use the datasets recorded in the launched topology, so a destroy finds the
+/// clones wherever launch put them.
+fn persisted_datasets(r: &mut Runner) {
+    let d = match Deployment::load(&r.falcon_dir) {
+        Ok(d) => d,
+        // nothing launched yet
+        Err(Error::IO(e)) if e.kind() == std::io::ErrorKind::NotFound => return,
+        Err(e) => {
+            r.output().warn(format!(
+                "reading {}/topology.ron, using the datasets of the \
+                topology program: {}",
+                r.falcon_dir, e
+            ));
+            return;
+        }
+    };
+    for n in r.deployment.nodes.iter_mut() {
+        if let Some(p) = d.nodes.iter().find(|p| p.name == n.name) {
+            n.image_dataset = p.image_dataset.clone();
+            n.topo_dataset = p.topo_dataset.clone();
+        }
+    }
+}
+
+fn snapshot(r: &Runner, cmd: &CmdSnapshot) -> Result<(), Error> {
+    let vm_name = match cmd.vm_name {
+        Some(ref n) => n.as_str(),
+        None => return Err(Error::Cli("vm name required".into())),
+    };
+
+    // read topology
+    let d = Deployment::load(&cmd.falcon_dir)?;
+
+    let start = Instant::now();
+    let result = do_snapshot(r, &d, vm_name, cmd);
+    if r.plan().is_dry_run() {
+        result?;
+        return Ok(());
     }
+    events::record(
+        r.events_log.as_deref(),
+        &d.name,
+        "snapshot",
+        Some(vm_name),
+        start.elapsed(),
+        result.is_ok(),
+    );
+    println!("{}", result?);
+    Ok(())
 }
 
-fn snapshot(cmd: CmdSnapshot) -> Result<(), Error> {
-    // read topology
-    let mut path = cmd.falcon_dir.to_path_buf();
-    path.push("topology.ron");
-    let topo_ron = fs::read_to_string(&path)?;
-    let d: Deployment = from_str(&topo_ron)?;
-    path.pop();
+/// Snapshot a node into a new image, returning the image's name.
+fn do_snapshot(
+    r: &Runner,
+    d: &Deployment,
+    vm_name: &str,
+    cmd: &CmdSnapshot,
+) -> Result<String, Error> {
+    let plan = r.plan();
 
     // get node from topology
     let mut node = None;
     for n in &d.nodes {
-        if n.name == cmd.vm_name {
+        if n.name == vm_name {
             node = Some(n);
         }
     }
 
     let node = match node {
-        None => return Err(Error::NotFound(cmd.vm_name)),
+        None => return Err(Error::NotFound(vm_name.into())),
         Some(node) => node,
     };
 
-    let dataset = dataset();
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0);
+    // the snapshot becomes an image
+    let snapshot_name: image::ImageName = match cmd.snapshot_name {
+        Some(ref name) => name.parse()?,
+        None => snapshot::auto_name(vm_name, now).parse()?,
+    };
+
+    let image_dataset = match cmd.image_dataset {
+        Some(ref ds) => ds.clone(),
+        None => node.image_dataset.clone(),
+    };
 
-    let source = format!("{}/topo/{}/{}", dataset, d.name, node.name);
+    let source = format!("{}/topo/{}/{}", node.topo_dataset, d.name, node.name);
     let source_snapshot = format!("{}@base", source);
 
-    let dest = format!("{}/img/{}", dataset, cmd.snapshot_name,);
-    let dest_snapshot = format!("{}@base", source);
+    let dest = format!("{}/img/{}", image_dataset, snapshot_name);
+    let dest_snapshot = format!("{}@base", dest);
 
     // first take a snapshot of the node clone
-    let out = Command::new("zfs")
-        .args(["snapshot", source_snapshot.as_ref()])
-        .output()?;
-    if !out.status.success() {
-        return Err(Error::Zfs(String::from_utf8(out.stderr)?));
+    plan.zfs(&["snapshot", source_snapshot.as_ref()])?;
+
+    if ops::pool_of(&node.topo_dataset) != ops::pool_of(&image_dataset) {
+        // clones cannot span pools, copy the snapshot over instead. The
+        // received dataset comes with its own @base snapshot.
+        r.output().warn(format!(
+            "{} and {} are in different pools, copying with zfs \
+            send/receive",
+            node.topo_dataset, image_dataset,
+        ));
+        plan.zfs_send_receive(&source_snapshot, &dest)?;
+    } else {
+        // next clone the source snapshot to a new base image
+        plan.zfs(&["clone", source_snapshot.as_ref(), dest.as_ref()])?;
+
+        // promote the base image to uncouple from source snapshot
+        plan.zfs(&["promote", dest.as_ref()])?;
+
+        // finally create base snapshot for new image
+        plan.zfs(&["snapshot", dest_snapshot.as_ref()])?;
     }
 
-    // next clone the source snapshot to a new base image
-    let out = Command::new("zfs")
-        .args(["clone", source_snapshot.as_ref(), dest.as_ref()])
-        .output()?;
+    // record where the image came from, which also marks automatic ones
+    // for pruning
+    snapshot::tag(
+        plan,
+        &dest,
+        cmd.auto,
+        vm_name,
+        now,
+        cmd.description.as_deref(),
+    )?;
+
+    Ok(snapshot_name.into())
+}
 
-    if !out.status.success() {
-        return Err(Error::Zfs(String::from_utf8(out.stderr)?));
+fn snapshot_prune(
+    r: &Runner,
+    config: &UserConfig,
+    c: &CmdSnapshot,
+) -> Result<(), Error> {
+    let mut policy = config.retention();
+    if let Some(keep) = c.keep {
+        policy.keep = keep;
+    }
+    if c.keep_days.is_some() {
+        policy.keep_days = c.keep_days;
+    }
+    let image_dataset = match c.image_dataset {
+        Some(ref ds) => ds.as_str(),
+        None => r.image_dataset.as_str(),
+    };
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0);
+    let report = snapshot::prune(r.plan(), image_dataset, &policy, now)?;
+    if r.plan().is_dry_run() {
+        for (image, why) in report.skipped.iter() {
+            r.output().info(format!(
+                "{} {}: {}",
+                "skipping".yellow(),
+                image,
+                why
+            ));
+        }
+        return Ok(());
+    }
+    for image in report.removed.iter() {
+        r.output().info(format!("{} {}", "removed".green(), image));
+    }
+    for (image, why) in report.skipped.iter() {
+        r.output()
+            .info(format!("{} {}: {}", "skipped".yellow(), image, why));
     }
+    Ok(())
+}
 
-    // promote the base image to uncouple from source snapshot
-    let out = Command::new("zfs")
-        .args(["promote", dest.as_ref()])
-        .output()?;
-    if !out.status.success() {
-        return Err(Error::Zfs(String::from_utf8(out.stderr)?));
+fn image_export(r: &Runner, c: &CmdImageExport) -> Result<(), Error> {
+    let compress = c
+        .compress
+        .as_deref()
+        .map(str::parse::<image::Compress>)
+        .transpose()?;
+    let image: image::ImageName = c.image.parse()?;
+    let image_dataset = c.image_dataset.as_deref().unwrap_or(&r.image_dataset);
+    let output = match (&c.output, compress) {
+        (Some(path), _) => path.clone(),
+        (None, Some(_)) => format!("{}.zfs.zst", c.image).into(),
+        (None, None) => format!("{}.zfs", c.image).into(),
+    };
+    let p = image::export(
+        r.plan(),
+        image_dataset,
+        &image,
+        &output,
+        compress,
+        &mut show_progress,
+    )?;
+    if r.plan().is_dry_run() {
+        return Ok(());
     }
+    show_progress(p);
+    eprintln!();
+    r.output().info(format!(
+        "{} {} to {}",
+        "exported".green(),
+        c.image,
+        output
+    ));
+    Ok(())
+}
 
-    // finally create base snapshot for new image
-    let out = Command::new("zfs")
-        .args(["snapshot", dest_snapshot.as_ref()])
-        .output()?;
-    if !out.status.success() {
-        return Err(Error::Zfs(String::from_utf8(out.stderr)?));
+fn image_import(r: &Runner, c: &CmdImageImport) -> Result<(), Error> {
+    let image: image::ImageName = c.image.parse()?;
+    let image_dataset = c.image_dataset.as_deref().unwrap_or(&r.image_dataset);
+    match image::import(
+        r.plan(),
+        image_dataset,
+        &image,
+        &c.input,
+        c.dedup_check,
+        &mut show_progress,
+    )? {
+        image::ImportOutcome::Received { .. } if r.plan().is_dry_run() => {}
+        image::ImportOutcome::Received { format, progress } => {
+            show_progress(progress);
+            eprintln!();
+            r.output().info(format!(
+                "{} {} from {} stream {}",
+                "imported".green(),
+                c.image,
+                format,
+                c.input
+            ));
+        }
+        image::ImportOutcome::Duplicate { existing, guid } => {
+            r.output().info(format!(
+                "{} {} already has @base guid {}, not receiving {}",
+                "skipped:".yellow(),
+                existing,
+                guid,
+                c.input
+            ));
+        }
     }
+    Ok(())
+}
 
+fn image_clone(r: &Runner, c: &CmdImageClone) -> Result<(), Error> {
+    let src: image::ImageName = c.src.parse()?;
+    let dst: image::ImageName = c.dst.parse()?;
+    let image_dataset = c.image_dataset.as_deref().unwrap_or(&r.image_dataset);
+    image::clone(r.plan(), image_dataset, &src, &dst, c.force)?;
+    if r.plan().is_dry_run() {
+        return Ok(());
+    }
+    r.output()
+        .info(format!("{} {} from {}", "cloned".green(), c.dst, c.src));
     Ok(())
 }
 
+fn show_progress(p: image::Progress) {
+    eprint!(
+        "\r{} {} logical, {} compressed",
+        "progress:".dimmed(),
+        ops::human_bytes(p.logical),
+        ops::human_bytes(p.compressed),
+    );
+}
+
 fn destroy(r: &Runner) {
-    if let Err(e) = r.destroy() {
-        println!("{}", e)
+    match r.destroy() {
+        Ok(_) if r.plan().is_dry_run() => {}
+        Ok(report) => print!("{}", report),
+        Err(e) => failed(r, e),
     }
 }
 
@@ -619,7 +2030,11 @@ fn create_logger() -> Logger {
     Logger::root(drain, o!())
 }
 
-async fn reboot(name: &str, falcon_dir: &Utf8Path) -> Result<(), Error> {
+async fn reboot(
+    plan: &Plan,
+    name: &str,
+    falcon_dir: &Utf8Path,
+) -> Result<(), Error> {
     let mut path = falcon_dir.to_path_buf();
     path.push(format!("{name}.port"));
     let port: u16 = fs::read_to_string(&path)?.trim_end().parse()?;
@@ -629,30 +2044,55 @@ async fn reboot(name: &str, falcon_dir: &Utf8Path) -> Result<(), Error> {
     let client = Client::new(&format!("http://{}", addr));
 
     // reboot
-    client
-        .instance_state_put()
-        .body(InstanceStateRequested::Reboot)
-        .send()
-        .await
-        .with_context(|| anyhow!("failed to reboot machine"))?;
-
-    Ok(())
+    let reboot = async {
+        client
+            .instance_state_put()
+            .body(InstanceStateRequested::Reboot)
+            .send()
+            .await
+            .with_context(|| anyhow!("failed to reboot machine"))?;
+        Ok::<_, Error>(())
+    };
+    let step = Step::call("propolis.instance_state_put", &[name, "reboot"]);
+    plan.step_async(step, reboot).await
 }
 
-async fn hyperstop(name: &str, falcon_dir: &Utf8Path) -> Result<(), Error> {
+async fn hyperstop(
+    r: &Runner,
+    name: &str,
+    falcon_dir: &Utf8Path,
+) -> Result<(), Error> {
     let log = create_logger();
+    let plan = r.plan();
+
+    if let Some(core) = cores::crashed(falcon_dir, name)? {
+        warn!(
+            log,
+            "{} crashed (pid {}), core saved to {}", name, core.pid, core.path
+        );
+        if !plan.is_dry_run() {
+            events::record_crash(
+                r.events_log.as_deref(),
+                &r.deployment.name,
+                name,
+                &core.path,
+            );
+        }
+    }
 
     let mut path = falcon_dir.to_path_buf();
     path.push(format!("{name}.pid"));
 
     // read pid
     match fs::read_to_string(&path) {
-        Ok(pid) => match pid.trim_end().parse() {
+        Ok(pid) => match pid.trim_end().parse::<i32>() {
             Ok(pid) => {
-                unsafe {
-                    libc::kill(pid, libc::SIGKILL);
-                }
-                fs::remove_file(&path)?;
+                let step = Step::call("kill", &["-9", &pid.to_string()]);
+                plan.step(step, || {
+                    unsafe { libc::kill(pid, libc::SIGKILL) };
+                    Ok(())
+                })?;
+                plan.remove(&path)?;
             }
             Err(e) => warn!(log, "could not parse pidfile for {}: {}", name, e),
         },
@@ -675,10 +2115,7 @@ async fn hyperstop(name: &str, falcon_dir: &Utf8Path) -> Result<(), Error> {
 
     // destroy bhyve vm
     let vm_arg = format!("--vm={}", uuid);
-    match Command::new("bhyvectl")
-        .args(["--destroy", vm_arg.as_ref()])
-        .output()
-    {
+    match plan.run("bhyvectl", &["--destroy", &vm_arg]) {
         Ok(_) => {}
         Err(e) => {
             warn!(log, "delete bhyve vm for {}: {}", name, e);
@@ -689,17 +2126,31 @@ async fn hyperstop(name: &str, falcon_dir: &Utf8Path) -> Result<(), Error> {
     Ok(())
 }
 
+/// Hand console captures to processes of their own to run out their tail
+/// window, so the command exits as soon as the operations they cover are
+/// done. A capture that cannot be handed off is cut short.
+fn detach_captures(r: &Runner, captures: Vec<OpCapture>) {
+    for c in captures {
+        let path = c.path.clone();
+        match c.detach() {
+            Ok(()) => info!(r.log, "capturing console to {}", path),
+            Err(e) => {
+                warn!(r.log, "console capture cut short: {}: {}", path, e)
+            }
+        }
+    }
+}
+
 async fn hyperstart(
+    plan: &Plan,
     name: &str,
     propolis_binary: String,
     falcon_dir: &Utf8Path,
+    retry: &RetryPolicy,
 ) -> Result<(), Error> {
     // read topology
+    let d = Deployment::load(falcon_dir)?;
     let mut path = falcon_dir.to_path_buf();
-    path.push("topology.ron");
-    let topo_ron = fs::read_to_string(&path)?;
-    let d: Deployment = from_str(&topo_ron)?;
-    path.pop();
 
     let mut node = None;
     for n in &d.nodes {
@@ -725,23 +2176,572 @@ async fn hyperstart(
     let id: uuid::Uuid = fs::read_to_string(&path)?.trim_end().parse()?;
     path.pop();
     let log = create_logger();
+    let env = Environment::for_node(&d.name, node)?;
 
     crate::launch_vm(
         &log,
+        plan,
         &propolis_binary,
         port,
         vnc_port,
         &id,
         node,
         falcon_dir,
+        retry,
+        &env,
     )
     .await?;
 
     Ok(())
 }
 
-async fn exec(r: &Runner, node: &str, command: &str) -> Result<(), Error> {
-    println!("{}", r.do_exec(node, command).await?);
+async fn exec(r: &Runner, c: &CmdExec) -> Result<(), Error> {
+    if c.all {
+        return exec_all(r, c).await;
+    }
+    let node = c
+        .node
+        .as_deref()
+        .ok_or_else(|| Error::Cli("usage: exec <NODE> <COMMAND>".into()))?;
+    let command = &c.command;
+    let piped = unsafe { libc::isatty(libc::STDIN_FILENO) } == 0;
+    let out = if c.stdin || (piped && !c.no_stdin) {
+        // one byte over the cap is enough to refuse oversized input without
+        // reading all of it
+        let mut input = Vec::new();
+        std::io::stdin()
+            .take(crate::SERIAL_STDIN_CAP as u64 + 1)
+            .read_to_end(&mut input)?;
+        r.do_exec_with_stdin(node, command, &input).await?
+    } else {
+        r.do_exec(node, command).await?
+    };
+    println!("{}", out);
+    Ok(())
+}
+
+async fn exec_all(r: &Runner, c: &CmdExec) -> Result<(), Error> {
+    let command = &c.command;
+    let names: Vec<String> =
+        r.deployment.nodes.iter().map(|n| n.name.clone()).collect();
+    let outputs = if c.sync {
+        let max_skew = Duration::from_millis(c.max_skew_ms);
+        let result = r.do_exec_synchronized(&names, command, max_skew).await?;
+        println!("{} {:?}", "skew:".dimmed(), result.skew);
+        result.outputs
+    } else {
+        let outs = futures::future::join_all(
+            names.iter().map(|name| r.do_exec(name, command)),
+        )
+        .await
+        .into_iter()
+        .collect::<Result<Vec<_>, Error>>()?;
+        names.into_iter().zip(outs).collect()
+    };
+    for (name, out) in outputs {
+        println!("{}", format!("{}:", name).dimmed());
+        println!("{}", out);
+    }
+    Ok(())
+}
+
+async fn svc(r: &Runner, c: &CmdSvc) -> Result<(), Error> {
+    let node = r
+        .find_node(&c.vm_name)
+        .ok_or_else(|| Error::NotFound(c.vm_name.clone()))?;
+    let smf = r.smf(node);
+    match c.action {
+        SvcAction::Enable => smf.enable(&c.fmri).await?,
+        SvcAction::Disable => smf.disable(&c.fmri).await?,
+        SvcAction::Restart => smf.restart(&c.fmri).await?,
+        SvcAction::Status => println!("{}", smf.status(&c.fmri).await?),
+    }
+    Ok(())
+}
+
+fn bundle(r: &Runner, c: &CmdBundle) -> Result<(), Error> {
+    if let Some(ref path) = c.verify {
+        let missing = crate::bundle::verify(
+            r.backend(),
+            path,
+            &r.propolis_binary,
+            &r.image_dataset,
+        )?;
+        if missing.is_empty() {
+            println!("{}", "all bundle requirements satisfied".green());
+            return Ok(());
+        }
+        for m in &missing {
+            println!("{} {}", "missing:".red(), m);
+        }
+        return Err(Error::Cli(format!(
+            "{} bundle requirement(s) not satisfied",
+            missing.len()
+        )));
+    }
+
+    let name = c.vm_name.as_ref().ok_or_else(|| {
+        Error::Cli("vm name required unless --verify is used".into())
+    })?;
+    let node = r
+        .find_node(name)
+        .ok_or_else(|| Error::NotFound(name.clone()))?;
+    let output = match c.output {
+        Some(ref p) => p.clone(),
+        None => Utf8PathBuf::from(format!("{}.tar.gz", name)),
+    };
+    let m = r.bundle(node, &output, c.include_image)?;
+    println!(
+        "{} {} (image {} guid {}, propolis {})",
+        "wrote".dimmed(),
+        output,
+        m.image.name,
+        m.image.guid,
+        m.propolis.version,
+    );
+    Ok(())
+}
+
+fn config(r: &Runner, c: &ConfigCommand) -> Result<(), Error> {
+    let path = config_path().ok_or_else(|| {
+        Error::Config("cannot locate config, HOME is not set".into())
+    })?;
+    let mut config = if path.exists() {
+        UserConfig::load_from(&path, r.output())?
+    } else {
+        UserConfig::default()
+    };
+    match c {
+        ConfigCommand::Get { key } => match config.get(key)? {
+            Some(v) => println!("{}", v),
+            None => println!("{}", "unset".dimmed()),
+        },
+        ConfigCommand::Set { key, value } => {
+            config.set(key, value)?;
+            config.save_to(&path)?;
+        }
+        ConfigCommand::Unset { key } => {
+            config.unset(key)?;
+            config.save_to(&path)?;
+        }
+        ConfigCommand::List => {
+            let mut tw = TabWriter::new(stdout());
+            for key in crate::config::KEYS {
+                let value = match config.get(key)? {
+                    Some(v) => v,
+                    None => "unset".dimmed().to_string(),
+                };
+                writeln!(&mut tw, "{}\t{}", key.dimmed(), value)?;
+            }
+            tw.flush()?;
+        }
+    }
+    Ok(())
+}
+
+fn status(r: &Runner, falcon_dir: &Utf8Path) -> Result<(), Error> {
+    let mut tw = TabWriter::new(stdout());
+    writeln!(
+        &mut tw,
+        "{}\t{}\t{}\t{}",
+        "Name".dimmed(),
+        "Pid".dimmed(),
+        "State".dimmed(),
+        "Core".dimmed(),
+    )?;
+    writeln!(
+        &mut tw,
+        "{}\t{}\t{}\t{}",
+        "----".bright_black(),
+        "---".bright_black(),
+        "-----".bright_black(),
+        "----".bright_black(),
+    )?;
+    for n in r.deployment.iter_nodes() {
+        let state = cores::hypervisor_state(falcon_dir, &n.name)?;
+        let pid = state.pid().map_or("-".into(), |p| p.to_string());
+        let (colored, core) = match state {
+            HypervisorState::Stopped => (state.to_string().normal(), "".into()),
+            HypervisorState::Running(_) => {
+                (state.to_string().green(), "".into())
+            }
+            HypervisorState::Exited(_) => {
+                (state.to_string().yellow(), "".into())
+            }
+            HypervisorState::Crashed(ref core) => {
+                (state.to_string().red(), core.path.to_string())
+            }
+        };
+        writeln!(&mut tw, "{}\t{}\t{}\t{}", n.name, pid, colored, core)?;
+    }
+    tw.flush()?;
+    Ok(())
+}
+
+async fn nic(r: &mut Runner, c: &NicCommand) -> Result<(), Error> {
+    let node = |r: &Runner, name: &str| {
+        r.find_node(name)
+            .ok_or_else(|| Error::NotFound(name.to_string()))
+    };
+    match c {
+        NicCommand::Add { vm_name, peer } => {
+            let (a, b) = (node(r, vm_name)?, node(r, peer)?);
+            let l = r.hotplug_link(a, b).await?;
+            r.output().info(format!(
+                "{} link {} to {}",
+                "added".green(),
+                l.id(),
+                peer
+            ));
+        }
+        NicCommand::Remove { link } => {
+            let id = link.resolve(&r.deployment)?.id;
+            r.hotunplug(node(r, &link.node_a)?, crate::LinkRef { id })
+                .await?;
+            r.output()
+                .info(format!("{} link {}", "removed".green(), id));
+        }
+    }
+    Ok(())
+}
+
+async fn react(r: &mut Runner, c: &CmdReact) -> Result<(), Error> {
+    let mut t =
+        ConsoleTrigger::new(&c.pattern, ReactAction::Run(c.run.clone()))?;
+    t.nodes = c.node.clone();
+    t.once = c.once;
+    t.limit = RateLimit {
+        burst: c.burst.max(1),
+        per: Duration::from_secs(c.per),
+    };
+    r.on_console_trigger(t);
+    tokio::select! {
+        result = r.react() => result,
+        _ = tokio::signal::ctrl_c() => Ok(()),
+    }
+}
+
+fn dhcp_leases(r: &Runner, json: bool) -> Result<(), Error> {
+    let leases = dhcp::leases(&r.falcon_dir)?;
+    if json {
+        println!("{}", serde_json::to_string(&leases)?);
+        return Ok(());
+    }
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0);
+
+    let mut tw = TabWriter::new(stdout());
+    writeln!(
+        &mut tw,
+        "{}\t{}\t{}\t{}",
+        "Mac".dimmed(),
+        "Address".dimmed(),
+        "Hostname".dimmed(),
+        "Expires".dimmed(),
+    )?;
+    writeln!(
+        &mut tw,
+        "{}\t{}\t{}\t{}",
+        "---".bright_black(),
+        "-------".bright_black(),
+        "--------".bright_black(),
+        "-------".bright_black(),
+    )?;
+    for l in leases.iter() {
+        let expires = match l.remaining(now) {
+            Some(s) => format!("in {}m", (s + 59) / 60).normal(),
+            None => "expired".yellow(),
+        };
+        writeln!(
+            &mut tw,
+            "{}\t{}\t{}\t{}",
+            l.mac,
+            l.addr,
+            l.hostname.as_deref().unwrap_or("-"),
+            expires,
+        )?;
+    }
+    tw.flush()?;
+    Ok(())
+}
+
+async fn health(r: &Runner, c: &CmdHealth) -> Result<(), Error> {
+    let opts = HealthOptions {
+        timeout: Duration::from_secs(c.timeout.max(1)),
+        disk_threshold: c.disk_threshold,
+        panic_window: Duration::from_secs(c.panic_window * 60 * 60),
+    };
+    let report = r.health(&opts).await?;
+
+    if c.json {
+        println!("{}", serde_json::to_string(&report)?);
+    } else {
+        print_health("Nodes", "Node", &report.nodes)?;
+        print_health("Links", "Link", &report.links)?;
+        println!("{} {}", "health:".dimmed(), colored_verdict(report.verdict));
+    }
+
+    match report.verdict {
+        Verdict::Ok => Ok(()),
+        v => Err(Error::Unhealthy(v)),
+    }
+}
+
+fn colored_verdict(v: Verdict) -> ColoredString {
+    match v {
+        Verdict::Ok => v.to_string().green(),
+        Verdict::Unknown | Verdict::Degraded => v.to_string().yellow(),
+        Verdict::Failed => v.to_string().red(),
+    }
+}
+
+/// One row per node or link, one column per check.
+fn print_health(
+    title: &str,
+    kind: &str,
+    items: &[Health],
+) -> Result<(), Error> {
+    if items.is_empty() {
+        return Ok(());
+    }
+    let mut names: Vec<&str> = Vec::new();
+    for c in items.iter().flat_map(|h| h.checks.iter()) {
+        if !names.contains(&c.name) {
+            names.push(c.name);
+        }
+    }
+
+    println!("{}", title.bright_black());
+    let mut tw = TabWriter::new(stdout());
+    let mut header = vec![kind.dimmed().to_string()];
+    let mut rule = vec!["-".repeat(kind.len()).bright_black().to_string()];
+    for n in names.iter() {
+        let title = format!("{}{}", n[..1].to_uppercase(), &n[1..]);
+        rule.push("-".repeat(title.len()).bright_black().to_string());
+        header.push(title.dimmed().to_string());
+    }
+    header.push("Verdict".dimmed().to_string());
+    rule.push("-------".bright_black().to_string());
+    writeln!(&mut tw, "{}", header.join("\t"))?;
+    writeln!(&mut tw, "{}", rule.join("\t"))?;
+    for h in items {
+        let mut row = vec![h.name.clone()];
+        for n in names.iter() {
+            row.push(match h.check(n) {
+                None => "-".into(),
+                Some(c) if c.verdict == Verdict::Ok => c.detail.clone(),
+                Some(c) => c.detail.yellow().to_string(),
+            });
+        }
+        row.push(colored_verdict(h.verdict).to_string());
+        writeln!(&mut tw, "{}", row.join("\t"))?;
+    }
+    tw.flush()?;
+    Ok(())
+}
+
+fn list_cores(r: &Runner, c: &CmdCores) -> Result<(), Error> {
+    if let Err(e) = cores::check_capture(r.backend(), &c.falcon_dir) {
+        r.output().warn(e.to_string());
+    }
+
+    let mut found = Vec::new();
+    for n in r.deployment.iter_nodes() {
+        found.extend(cores::node_cores(&c.falcon_dir, &n.name)?);
+    }
+
+    let mut tw = TabWriter::new(stdout());
+    writeln!(
+        &mut tw,
+        "{}\t{}\t{}\t{}",
+        "Node".dimmed(),
+        "Pid".dimmed(),
+        "Size".dimmed(),
+        "Path".dimmed(),
+    )?;
+    writeln!(
+        &mut tw,
+        "{}\t{}\t{}\t{}",
+        "----".bright_black(),
+        "---".bright_black(),
+        "----".bright_black(),
+        "----".bright_black(),
+    )?;
+    for core in found.iter() {
+        writeln!(
+            &mut tw,
+            "{}\t{}\t{}\t{}",
+            core.node,
+            core.pid,
+            ops::human_bytes(core.size),
+            core.path,
+        )?;
+    }
+    tw.flush()?;
+
+    if c.clean {
+        for core in found.iter() {
+            fs::remove_file(&core.path)?;
+        }
+        r.output().info(format!("removed {} core(s)", found.len()));
+    }
+    Ok(())
+}
+
+fn list_crashes(r: &Runner, c: &CmdCrashList) -> Result<(), Error> {
+    let mut found = Vec::new();
+    for n in r.deployment.iter_nodes() {
+        if c.vm_name.as_ref().map_or(false, |v| *v != n.name) {
+            continue;
+        }
+        found.extend(crash::node_crashes(&r.falcon_dir, &n.name)?);
+    }
+
+    let mut tw = TabWriter::new(stdout());
+    writeln!(
+        &mut tw,
+        "{}\t{}\t{}",
+        "Node".dimmed(),
+        "Size".dimmed(),
+        "Path".dimmed(),
+    )?;
+    writeln!(
+        &mut tw,
+        "{}\t{}\t{}",
+        "----".bright_black(),
+        "----".bright_black(),
+        "----".bright_black(),
+    )?;
+    for f in found.iter() {
+        writeln!(
+            &mut tw,
+            "{}\t{}\t{}",
+            f.node,
+            ops::human_bytes(f.size),
+            f.path,
+        )?;
+    }
+    tw.flush()?;
+    Ok(())
+}
+
+async fn crash_pull(r: &Runner, c: &CmdCrashPull) -> Result<(), Error> {
+    if r.deployment.node_named(&c.vm_name).is_none() {
+        return Err(Error::NotFound(c.vm_name.clone()));
+    }
+    let report = r.pull_crash_dumps(&c.vm_name, c.max_size << 20).await?;
+    for path in report.pulled.iter() {
+        r.output().info(format!("{} {}", "pulled".green(), path));
+    }
+    for (what, why) in report.skipped.iter() {
+        r.output()
+            .info(format!("{} {}: {}", "skipped".yellow(), what, why));
+    }
+    Ok(())
+}
+
+async fn crash_watch(r: &Runner, c: &CmdCrashWatch) -> Result<(), Error> {
+    if r.deployment.node_named(&c.vm_name).is_none() {
+        return Err(Error::NotFound(c.vm_name.clone()));
+    }
+    let policy = crash::CrashPolicy {
+        reboot: c.reboot,
+        max_dump_bytes: c.max_size << 20,
+    };
+    r.do_watch_panics(&c.vm_name, policy).await
+}
+
+fn timings(r: &Runner, c: &CmdTimings) -> Result<(), Error> {
+    let path = r.events_log.clone().ok_or_else(|| {
+        Error::Config("cannot locate events log, HOME is not set".into())
+    })?;
+    let timings = if path.exists() {
+        let deployment = (!c.all).then_some(r.deployment.name.as_str());
+        events::aggregate(
+            BufReader::new(fs::File::open(&path)?),
+            deployment,
+            c.op.as_deref(),
+        )?
+    } else {
+        Vec::new()
+    };
+
+    if c.json {
+        println!("{}", serde_json::to_string(&timings)?);
+        return Ok(());
+    }
+
+    let mut tw = TabWriter::new(stdout());
+    writeln!(
+        &mut tw,
+        "{}\t{}\t{}\t{}\t{}\t{}",
+        "Op".dimmed(),
+        "Node".dimmed(),
+        "Count".dimmed(),
+        "Min".dimmed(),
+        "Median".dimmed(),
+        "P95".dimmed(),
+    )?;
+    writeln!(
+        &mut tw,
+        "{}\t{}\t{}\t{}\t{}\t{}",
+        "--".bright_black(),
+        "----".bright_black(),
+        "-----".bright_black(),
+        "---".bright_black(),
+        "------".bright_black(),
+        "---".bright_black(),
+    )?;
+    let secs = |ms: u64| format!("{:.1}s", ms as f64 / 1000.0);
+    for t in timings {
+        writeln!(
+            &mut tw,
+            "{}\t{}\t{}\t{}\t{}\t{}",
+            t.op,
+            t.node.as_deref().unwrap_or("-"),
+            t.count,
+            secs(t.min_ms),
+            secs(t.median_ms),
+            secs(t.p95_ms),
+        )?;
+    }
+    tw.flush()?;
+    Ok(())
+}
+
+async fn audit(r: &Runner, c: &CmdAudit) -> Result<(), Error> {
+    let nodes = match c.vm_name {
+        Some(ref name) => vec![r
+            .find_node(name)
+            .ok_or_else(|| Error::NotFound(name.clone()))?],
+        None => r.all_nodes(),
+    };
+
+    let mut audits = Vec::new();
+    for node in nodes {
+        let audit = if c.fix.is_empty() {
+            r.audit(node).await?
+        } else {
+            r.audit_fix(node, &c.fix).await?
+        };
+        if !c.json {
+            print!("{}", audit);
+            let fixed: Vec<_> = audit
+                .drift
+                .iter()
+                .filter(|d| c.fix.contains(&d.category))
+                .collect();
+            for d in fixed {
+                println!("  {} {}", "fixed".green(), d.what);
+            }
+        }
+        audits.push(audit);
+    }
+    if c.json {
+        println!("{}", serde_json::to_string(&audits)?);
+    }
     Ok(())
 }
 
@@ -766,3 +2766,42 @@ pub fn oxide_cli_style() -> clap::builder::Styles {
             anstyle::Color::Rgb(anstyle::RgbColor(232, 104, 134)),
         )))
 }
+
+#[cfg(test)]
+mod test {
+    /// Test that exec takes the command as its only positional argument with
+    /// --all, before or after `--`, and needs a node without it.
+    #[test]
+    fn exec_args() {
+        use super::{CmdExec, Opts, SubCommand};
+        use clap::Parser;
+
+        let exec = |args: &[&str]| -> Option<CmdExec> {
+            let argv = ["falcon", "exec"].iter().chain(args);
+            match Opts::try_parse_from(argv).ok()?.subcmd {
+                SubCommand::Exec(c) => Some(c),
+                _ => None,
+            }
+        };
+
+        let c = exec(&["violin", "uname -a"]).unwrap();
+        assert_eq!(c.node.as_deref(), Some("violin"));
+        assert_eq!(c.command, "uname -a");
+        assert!(!c.all);
+
+        for args in [
+            &["--all", "--sync", "--", "echo a:b"][..],
+            &["--all", "echo a:b"],
+        ] {
+            let c = exec(args).unwrap();
+            assert!(c.all);
+            assert_eq!(c.node, None);
+            assert_eq!(c.command, "echo a:b");
+            assert_eq!(c.sync, args.contains(&"--sync"));
+        }
+
+        assert!(exec(&["uname -a"]).is_none());
+        assert!(exec(&["--all", "violin", "uname -a"]).is_none());
+        assert!(exec(&["--sync", "violin", "uname -a"]).is_none());
+    }
+}
@@ -0,0 +1,433 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+// Copyright 2022 Oxide Computer Company
+
+//! Per-user defaults read from `$HOME/.config/falcon/config.toml`. Settings
+//! here sit below command line flags and environment variables and above the
+//! built-in defaults.
+
+use crate::error::Error;
+use crate::output::OutputCtx;
+use crate::snapshot::Retention;
+use crate::{Runner, DEFAULT_DATASET, DEFAULT_PROPOLIS_BINARY};
+use camino::{Utf8Path, Utf8PathBuf};
+use clap::ValueEnum;
+use serde::{Deserialize, Serialize};
+use std::convert::TryFrom;
+use std::fmt;
+use std::fs;
+use std::str::FromStr;
+
+/// Every key the config file understands, in listing order.
+pub const KEYS: [&str; 9] = [
+    "propolis",
+    "dataset",
+    "port_range",
+    "color",
+    "lease_hours",
+    "editor",
+    "snapshot_keep",
+    "snapshot_keep_days",
+    "archive_dir",
+];
+
+#[derive(Debug, Default, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct UserConfig {
+    /// The propolis-server binary to use.
+    pub propolis: Option<String>,
+    /// The parent dataset for images and topology clones.
+    pub dataset: Option<String>,
+    /// Ports propolis servers and their vnc consoles are bound to.
+    pub port_range: Option<PortRange>,
+    /// Whether to color output.
+    pub color: Option<ColorPreference>,
+    /// How long a topology is expected to live.
+    pub lease_hours: Option<u32>,
+    /// Editor for interactive commands.
+    pub editor: Option<String>,
+    /// How many automatic snapshots per node `snapshot --prune` keeps.
+    pub snapshot_keep: Option<usize>,
+    /// How many days of automatic snapshots `snapshot --prune` keeps.
+    pub snapshot_keep_days: Option<u32>,
+    /// Where destroyed topologies leave their events log and crash files.
+    pub archive_dir: Option<Utf8PathBuf>,
+}
+
+/// An inclusive range of ports, written `start-end`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(try_from = "String", into = "String")]
+pub struct PortRange {
+    pub start: u16,
+    pub end: u16,
+}
+
+impl FromStr for PortRange {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (start, end) = s.split_once('-').ok_or_else(|| {
+            Error::Config(format!("port range '{}' is not start-end", s))
+        })?;
+        let start: u16 = start.trim().parse()?;
+        let end: u16 = end.trim().parse()?;
+        if start == 0 || start > end {
+            return Err(Error::Config(format!(
+                "port range '{}' must be non-empty and start above 0",
+                s
+            )));
+        }
+        Ok(PortRange { start, end })
+    }
+}
+
+impl TryFrom<String> for PortRange {
+    type Error = Error;
+
+    fn try_from(s: String) -> Result<Self, Self::Error> {
+        s.parse()
+    }
+}
+
+impl From<PortRange> for String {
+    fn from(r: PortRange) -> Self {
+        r.to_string()
+    }
+}
+
+impl fmt::Display for PortRange {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}-{}", self.start, self.end)
+    }
+}
+
+#[derive(
+    Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, ValueEnum,
+)]
+#[serde(rename_all = "lowercase")]
+pub enum ColorPreference {
+    Auto,
+    Always,
+    Never,
+}
+
+impl FromStr for ColorPreference {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "auto" => Ok(Self::Auto),
+            "always" => Ok(Self::Always),
+            "never" => Ok(Self::Never),
+            _ => Err(Error::Config(format!(
+                "color must be auto, always or never, not '{}'",
+                s
+            ))),
+        }
+    }
+}
+
+impl fmt::Display for ColorPreference {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let s = match self {
+            Self::Auto => "auto",
+            Self::Always => "always",
+            Self::Never => "never",
+        };
+        write!(f, "{}", s)
+    }
+}
+
+/// The location of the per-user config file.
+pub fn config_path() -> Option<Utf8PathBuf> {
+    let base = match std::env::var("XDG_CONFIG_HOME") {
+        Ok(s) if !s.is_empty() => Utf8PathBuf::from(s),
+        _ => match std::env::var("HOME") {
+            Ok(s) if !s.is_empty() => Utf8PathBuf::from(s).join(".config"),
+            _ => return None,
+        },
+    };
+    Some(base.join("falcon").join("config.toml"))
+}
+
+impl UserConfig {
+    /// Load the per-user config, an absent file is an empty config.
+    pub fn load(out: &OutputCtx) -> Result<Self, Error> {
+        match config_path() {
+            Some(path) if path.exists() => Self::load_from(&path, out),
+            _ => Ok(Self::default()),
+        }
+    }
+
+    /// Load a config file. Keys this version of falcon does not know are
+    /// warned about on `out` and ignored, so newer config files keep
+    /// working.
+    pub fn load_from(path: &Utf8Path, out: &OutputCtx) -> Result<Self, Error> {
+        let (config, unknown) = Self::parse(&fs::read_to_string(path)?)?;
+        for key in unknown {
+            out.warn(format!("{}: ignoring unknown key '{}'", path, key));
+        }
+        Ok(config)
+    }
+
+    /// Parse config file contents, returning the config along with any keys
+    /// that were not understood.
+    pub fn parse(s: &str) -> Result<(Self, Vec<String>), Error> {
+        let table: toml::Table = toml::from_str(s)?;
+        let unknown = table
+            .keys()
+            .filter(|k| !KEYS.contains(&k.as_str()))
+            .cloned()
+            .collect();
+        Ok((toml::from_str(s)?, unknown))
+    }
+
+    /// Validate and write the config to `path`, replacing any existing file
+    /// atomically.
+    pub fn save_to(&self, path: &Utf8Path) -> Result<(), Error> {
+        let out = toml::to_string(self)?;
+        // make sure what we write reads back as the same config
+        if Self::parse(&out)?.0 != *self {
+            return Err(Error::Config("config does not round trip".into()));
+        }
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+        let tmp = path.with_extension(format!("toml.{}", std::process::id()));
+        fs::write(&tmp, out)?;
+        fs::rename(&tmp, path)?;
+        Ok(())
+    }
+
+    /// Get a setting by key. Returns `None` for keys that are not set.
+    pub fn get(&self, key: &str) -> Result<Option<String>, Error> {
+        Ok(match key {
+            "propolis" => self.propolis.clone(),
+            "dataset" => self.dataset.clone(),
+            "port_range" => self.port_range.map(|x| x.to_string()),
+            "color" => self.color.map(|x| x.to_string()),
+            "lease_hours" => self.lease_hours.map(|x| x.to_string()),
+            "editor" => self.editor.clone(),
+            "snapshot_keep" => self.snapshot_keep.map(|x| x.to_string()),
+            "snapshot_keep_days" => {
+                self.snapshot_keep_days.map(|x| x.to_string())
+            }
+            "archive_dir" => self.archive_dir.as_ref().map(|x| x.to_string()),
+            _ => return Err(unknown_key(key)),
+        })
+    }
+
+    /// Set a setting by key, validating the value.
+    pub fn set(&mut self, key: &str, value: &str) -> Result<(), Error> {
+        match key {
+            "propolis" => self.propolis = Some(non_empty(key, value)?),
+            "dataset" => {
+                let ds = non_empty(key, value)?;
+                if ds.starts_with('/') || ds.ends_with('/') {
+                    return Err(Error::Config(format!(
+                        "dataset '{}' must be a dataset name, not a path",
+                        ds
+                    )));
+                }
+                self.dataset = Some(ds);
+            }
+            "port_range" => self.port_range = Some(value.parse()?),
+            "color" => self.color = Some(value.parse()?),
+            "lease_hours" => self.lease_hours = Some(value.parse()?),
+            "editor" => self.editor = Some(non_empty(key, value)?),
+            "snapshot_keep" => self.snapshot_keep = Some(value.parse()?),
+            "snapshot_keep_days" => {
+                self.snapshot_keep_days = Some(value.parse()?)
+            }
+            "archive_dir" => {
+                self.archive_dir = Some(non_empty(key, value)?.into())
+            }
+            _ => return Err(unknown_key(key)),
+        }
+        Ok(())
+    }
+
+    /// Remove a setting by key.
+    pub fn unset(&mut self, key: &str) -> Result<(), Error> {
+        match key {
+            "propolis" => self.propolis = None,
+            "dataset" => self.dataset = None,
+            "port_range" => self.port_range = None,
+            "color" => self.color = None,
+            "lease_hours" => self.lease_hours = None,
+            "editor" => self.editor = None,
+            "snapshot_keep" => self.snapshot_keep = None,
+            "snapshot_keep_days" => self.snapshot_keep_days = None,
+            "archive_dir" => self.archive_dir = None,
+            _ => return Err(unknown_key(key)),
+        }
+        Ok(())
+    }
+
+    /// The editor to use, `$EDITOR` taking precedence over the config.
+    pub fn editor(&self) -> String {
+        match std::env::var("EDITOR") {
+            Ok(s) if !s.is_empty() => s,
+            _ => self.editor.clone().unwrap_or_else(|| "vi".into()),
+        }
+    }
+
+    /// Snapshot retention with these defaults applied.
+    pub fn retention(&self) -> Retention {
+        let d = Retention::default();
+        Retention {
+            keep: self.snapshot_keep.unwrap_or(d.keep),
+            keep_days: self.snapshot_keep_days.or(d.keep_days),
+        }
+    }
+
+    /// Apply these defaults to a runner. Only settings the program left at
+    /// falcon's built-in defaults are filled in, and settings that can also
+    /// come from the environment only when the environment does not set
+    /// them.
+    pub fn apply(&self, r: &mut Runner) {
+        if let Some(ref p) = self.propolis {
+            if r.propolis_binary == DEFAULT_PROPOLIS_BINARY {
+                r.propolis_binary = p.clone();
+            }
+        }
+        if let Some(ref ds) = self.dataset {
+            if !env_set("FALCON_DATASET") {
+                if !env_set("FALCON_IMAGE_DATASET")
+                    && r.image_dataset == DEFAULT_DATASET
+                {
+                    r.set_image_dataset(ds);
+                }
+                if !env_set("FALCON_TOPO_DATASET")
+                    && r.topo_dataset == DEFAULT_DATASET
+                {
+                    r.set_topo_dataset(ds);
+                }
+            }
+        }
+        if r.port_range.is_none() {
+            r.port_range = self.port_range;
+        }
+        if r.archive_dir.is_none() {
+            r.archive_dir = self.archive_dir.clone();
+        }
+        match self.color {
+            _ if env_set("NO_COLOR") || env_set("CLICOLOR_FORCE") => {}
+            Some(ColorPreference::Always) => {
+                colored::control::set_override(true)
+            }
+            Some(ColorPreference::Never) => {
+                colored::control::set_override(false)
+            }
+            Some(ColorPreference::Auto) | None => {}
+        }
+    }
+}
+
+fn env_set(var: &str) -> bool {
+    matches!(std::env::var(var), Ok(s) if !s.is_empty())
+}
+
+fn non_empty(key: &str, value: &str) -> Result<String, Error> {
+    if value.trim().is_empty() {
+        return Err(Error::Config(format!("{} cannot be empty", key)));
+    }
+    Ok(value.into())
+}
+
+fn unknown_key(key: &str) -> Error {
+    Error::Config(format!(
+        "unknown key '{}', expected one of {}",
+        key,
+        KEYS.join(", ")
+    ))
+}
+
+#[cfg(test)]
+mod test {
+    use crate::test::Scratch;
+    use anyhow::Result;
+
+    #[test]
+    fn user_config() -> Result<()> {
+        use crate::config::{ColorPreference, PortRange, UserConfig};
+        use crate::output::OutputCtx;
+
+        let (c, unknown) = UserConfig::parse(
+            "propolis = \"/opt/propolis/bin/propolis-server\"\n\
+             port_range = \"12000-12999\"\n\
+             color = \"never\"\n\
+             telemetry = true\n",
+        )?;
+        assert_eq!(unknown, ["telemetry"]);
+        assert_eq!(
+            c.propolis.as_deref(),
+            Some("/opt/propolis/bin/propolis-server")
+        );
+        assert_eq!(
+            c.port_range,
+            Some(PortRange {
+                start: 12000,
+                end: 12999
+            })
+        );
+        assert_eq!(c.color, Some(ColorPreference::Never));
+        assert_eq!(c.dataset, None);
+
+        let mut c = UserConfig::default();
+        c.set("dataset", "tank/falcon")?;
+        c.set("lease_hours", "8")?;
+        assert_eq!(c.get("dataset")?.as_deref(), Some("tank/falcon"));
+        assert!(c.set("dataset", "/tank/falcon").is_err());
+        assert!(c.set("port_range", "13000-12000").is_err());
+        assert!(c.set("color", "sometimes").is_err());
+        assert!(c.set("lease_hours", "soon").is_err());
+        assert!(c.set("nope", "x").is_err());
+        c.unset("lease_hours")?;
+        assert_eq!(c.get("lease_hours")?, None);
+
+        let scratch = Scratch::new("config")?;
+        let dir = &scratch.dir;
+        let path = dir.join("falcon").join("config.toml");
+        c.save_to(&path)?;
+        let out = OutputCtx::silent();
+        assert_eq!(UserConfig::load_from(&path, &out)?, c);
+
+        // what the program set explicitly wins over the user config, what it
+        // left at the defaults is filled in
+        let c = UserConfig {
+            propolis: Some("/opt/propolis/bin/propolis-server".into()),
+            dataset: Some("tank/falcon".into()),
+            port_range: Some(PortRange {
+                start: 12000,
+                end: 12999,
+            }),
+            archive_dir: Some("/var/tmp/falcon".into()),
+            ..Default::default()
+        };
+        let mut r = crate::Runner::new("config");
+        r.persistent = true;
+        r.propolis_binary = "/work/propolis-server".into();
+        r.set_image_dataset("ssd/img");
+        r.set_topo_dataset("ssd/topo");
+        r.port_range = Some(PortRange {
+            start: 14000,
+            end: 14099,
+        });
+        c.apply(&mut r);
+        assert_eq!(r.propolis_binary, "/work/propolis-server");
+        assert_eq!(r.image_dataset, "ssd/img");
+        assert_eq!(r.topo_dataset, "ssd/topo");
+        assert_eq!(r.port_range.map(|p| p.start), Some(14000));
+        assert_eq!(r.archive_dir.as_deref(), c.archive_dir.as_deref());
+
+        let mut r = crate::Runner::new("config");
+        r.persistent = true;
+        c.apply(&mut r);
+        assert_eq!(r.propolis_binary, "/opt/propolis/bin/propolis-server");
+        assert_eq!(r.port_range, c.port_range);
+
+        Ok(())
+    }
+}
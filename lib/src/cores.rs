@@ -0,0 +1,313 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+// Copyright 2022 Oxide Computer Company
+
+//! Core files of crashed propolis-server instances. Each instance is given a
+//! per-process core file pattern when it is launched so its core lands in
+//! `.falcon/cores/<node>/` rather than wherever the system puts cores, if
+//! anywhere.
+
+use crate::error::Error;
+use crate::Backend;
+use camino::{Utf8Path, Utf8PathBuf};
+use serde::Serialize;
+use std::ffi::CString;
+use std::fmt;
+use std::fs;
+use std::os::unix::process::CommandExt;
+use std::process::Command;
+use std::time::SystemTime;
+
+pub(crate) const COREADM_BIN: &str = "/usr/bin/coreadm";
+
+/// A core file left by a propolis-server instance.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct CoreFile {
+    pub node: String,
+    /// The pid of the process that dumped core.
+    pub pid: u32,
+    pub path: Utf8PathBuf,
+    pub size: u64,
+    /// Seconds since the unix epoch at which the core was written.
+    pub time: u64,
+}
+
+/// Where cores of the named node's propolis instances are written.
+pub fn cores_dir(falcon_dir: &Utf8Path, node: &str) -> Utf8PathBuf {
+    falcon_dir.join("cores").join(node)
+}
+
+/// The pid a core file was written by, from its name.
+pub(crate) fn core_pid(name: &str) -> Option<u32> {
+    name.strip_prefix("core.")?.rsplit('.').next()?.parse().ok()
+}
+
+/// The per-process core file pattern for the named node's instances,
+/// creating the directory it names.
+pub(crate) fn core_pattern(
+    falcon_dir: &Utf8Path,
+    node: &str,
+) -> Result<Utf8PathBuf, Error> {
+    let dir = cores_dir(falcon_dir, node);
+    fs::create_dir_all(&dir)?;
+    // coreadm patterns must be absolute
+    let dir = Utf8PathBuf::try_from(fs::canonicalize(&dir)?)
+        .map_err(|e| Error::PathError(e.to_string()))?;
+    Ok(dir.join("core.%f.%p"))
+}
+
+extern "C" {
+    // <sys/corectl.h>, what `coreadm -p` sets the pattern with
+    fn core_set_process_path(
+        buf: *const libc::c_char,
+        bufsize: libc::size_t,
+        pid: libc::pid_t,
+    ) -> libc::c_int;
+}
+
+/// Direct cores of the process `cmd` spawns to `pattern`. The pattern is set
+/// in the child between fork and exec, so it is in place before the program
+/// runs, and is inherited by any children it forks. Failing to set it does
+/// not fail the spawn, it is noted on the child's stderr.
+pub(crate) fn capture(
+    cmd: &mut Command,
+    pattern: &Utf8Path,
+) -> Result<(), Error> {
+    const FAILED: &[u8] =
+        b"falcon: cannot set the core file pattern, cores will not be kept\n";
+    let path = CString::new(pattern.as_str())
+        .map_err(|e| Error::PathError(e.to_string()))?;
+    // Only async-signal-safe calls are made between fork and exec, and
+    // nothing is allocated there.
+    unsafe {
+        cmd.pre_exec(move || {
+            let len = path.as_bytes_with_nul().len();
+            if core_set_process_path(path.as_ptr(), len, libc::getpid()) != 0 {
+                libc::write(
+                    libc::STDERR_FILENO,
+                    FAILED.as_ptr() as *const libc::c_void,
+                    FAILED.len(),
+                );
+            }
+            Ok(())
+        });
+    }
+    Ok(())
+}
+
+/// The cores of the named node's propolis instances, oldest first.
+pub fn node_cores(
+    falcon_dir: &Utf8Path,
+    node: &str,
+) -> Result<Vec<CoreFile>, Error> {
+    let dir = cores_dir(falcon_dir, node);
+    let entries = match fs::read_dir(&dir) {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+            return Ok(Vec::new())
+        }
+        Err(e) => return Err(e.into()),
+    };
+    let mut result = Vec::new();
+    for entry in entries {
+        let entry = entry?;
+        let name = entry.file_name().to_string_lossy().to_string();
+        let pid = match core_pid(&name) {
+            Some(pid) => pid,
+            None => continue,
+        };
+        let md = entry.metadata()?;
+        let time = md
+            .modified()?
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or(0);
+        result.push(CoreFile {
+            node: node.into(),
+            pid,
+            path: dir.join(&name),
+            size: md.len(),
+            time,
+        });
+    }
+    result.sort_by_key(|c| (c.time, c.pid));
+    Ok(result)
+}
+
+/// The core left by the named node's instance `pid`, if it crashed.
+pub fn core_of(
+    falcon_dir: &Utf8Path,
+    node: &str,
+    pid: u32,
+) -> Result<Option<CoreFile>, Error> {
+    Ok(node_cores(falcon_dir, node)?
+        .into_iter()
+        .find(|c| c.pid == pid))
+}
+
+/// True if process `pid` is still running.
+pub(crate) fn alive(pid: i32) -> bool {
+    unsafe { libc::kill(pid, 0) == 0 }
+}
+
+/// The pid of the named node's last propolis instance, if it is known.
+pub fn last_pid(falcon_dir: &Utf8Path, node: &str) -> Option<u32> {
+    let path = falcon_dir.join(format!("{}.pid", node));
+    fs::read_to_string(path).ok()?.trim_end().parse().ok()
+}
+
+/// The core left by the named node's last propolis instance if that instance
+/// is no longer running and dumped core.
+pub fn crashed(
+    falcon_dir: &Utf8Path,
+    node: &str,
+) -> Result<Option<CoreFile>, Error> {
+    match last_pid(falcon_dir, node) {
+        Some(pid) if !alive(pid as i32) => core_of(falcon_dir, node, pid),
+        _ => Ok(None),
+    }
+}
+
+/// Whether the named node's hypervisor is running, as far as the host can
+/// tell.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum HypervisorState {
+    /// No instance has been launched, or it has been stopped.
+    Stopped,
+    Running(u32),
+    /// The instance went away without leaving a core.
+    Exited(u32),
+    Crashed(CoreFile),
+}
+
+impl HypervisorState {
+    pub fn pid(&self) -> Option<u32> {
+        match self {
+            Self::Stopped => None,
+            Self::Running(pid) | Self::Exited(pid) => Some(*pid),
+            Self::Crashed(core) => Some(core.pid),
+        }
+    }
+}
+
+impl fmt::Display for HypervisorState {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Stopped => write!(f, "stopped"),
+            Self::Running(_) => write!(f, "running"),
+            Self::Exited(_) => write!(f, "exited"),
+            Self::Crashed(_) => write!(f, "crashed"),
+        }
+    }
+}
+
+/// The state of the named node's last propolis instance.
+pub fn hypervisor_state(
+    falcon_dir: &Utf8Path,
+    node: &str,
+) -> Result<HypervisorState, Error> {
+    let pid = match last_pid(falcon_dir, node) {
+        None => return Ok(HypervisorState::Stopped),
+        Some(pid) => pid,
+    };
+    if alive(pid as i32) {
+        return Ok(HypervisorState::Running(pid));
+    }
+    Ok(match core_of(falcon_dir, node, pid)? {
+        Some(core) => HypervisorState::Crashed(core),
+        None => HypervisorState::Exited(pid),
+    })
+}
+
+/// Check cores of falcon launched instances will actually be written.
+/// Per-process core patterns only take effect when enabled system wide.
+pub fn check_capture(
+    host: &dyn Backend,
+    falcon_dir: &Utf8Path,
+) -> Result<(), Error> {
+    let out = host.run(COREADM_BIN, &[])?;
+    let settings = String::from_utf8_lossy(&out.stdout);
+    if !per_process_enabled(&settings) {
+        return Err(Error::Environment {
+            problem: "per-process core dumps are disabled, propolis crashes \
+                will not leave a core"
+                .into(),
+            fix: "pfexec coreadm -e process".into(),
+        });
+    }
+
+    let dir = falcon_dir.join("cores");
+    let probe = dir.join(".probe");
+    let writable = fs::create_dir_all(&dir)
+        .and_then(|_| fs::write(&probe, b""))
+        .and_then(|_| fs::remove_file(&probe));
+    if let Err(e) = writable {
+        return Err(Error::Environment {
+            problem: format!("cannot write cores to {}: {}", dir, e),
+            fix: format!("make {} writable by the falcon user", dir),
+        });
+    }
+    Ok(())
+}
+
+/// Whether `coreadm` output shows per-process core dumps enabled.
+pub(crate) fn per_process_enabled(settings: &str) -> bool {
+    settings.lines().any(|l| {
+        let l = l.trim();
+        l.starts_with("per-process core dumps:") && l.ends_with("enabled")
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use crate::test::Scratch;
+    use anyhow::Result;
+
+    #[test]
+    fn propolis_cores() -> Result<()> {
+        use crate::cores::{
+            core_pid, crashed, node_cores, per_process_enabled,
+        };
+
+        assert_eq!(core_pid("core.propolis-server.4242"), Some(4242));
+        assert_eq!(core_pid("core.propolis.server.7"), Some(7));
+        assert_eq!(core_pid("propolis-server.7"), None);
+        assert_eq!(core_pid("core.propolis-server"), None);
+
+        let settings = concat!(
+            "     global core file pattern: \n",
+            "          global core dumps: disabled\n",
+            "     per-process core dumps: enabled\n",
+        );
+        assert!(per_process_enabled(settings));
+        assert!(!per_process_enabled(&settings.replace(
+            "per-process core dumps: enabled",
+            "per-process core dumps: disabled"
+        )));
+
+        let scratch = Scratch::new("cores")?;
+        let dir = &scratch.dir;
+        let cores = crate::cores::cores_dir(dir, "violin");
+        std::fs::create_dir_all(&cores)?;
+        std::fs::write(cores.join("core.propolis-server.999999"), b"core")?;
+        std::fs::write(cores.join("notes.txt"), b"")?;
+
+        let found = node_cores(dir, "violin")?;
+        assert_eq!(found.len(), 1);
+        assert_eq!((found[0].pid, found[0].size), (999999, 4));
+        assert!(node_cores(dir, "piano")?.is_empty());
+
+        // no pid file means no instance to have crashed
+        assert_eq!(crashed(dir, "violin")?, None);
+        // a dead pid with a core crashed, one without a core just exited
+        std::fs::write(dir.join("violin.pid"), "999999\n")?;
+        assert_eq!(crashed(dir, "violin")?, Some(found[0].clone()));
+        std::fs::write(dir.join("violin.pid"), "999998\n")?;
+        assert_eq!(crashed(dir, "violin")?, None);
+
+        Ok(())
+    }
+}
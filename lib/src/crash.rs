@@ -0,0 +1,596 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+// Copyright 2022 Oxide Computer Company
+
+//! The aftermath of helios guest panics. A panic watch follows a node's
+//! console and saves the panic output to `.falcon/crash/<node>/`. It can
+//! reboot the node as well, and once the node is back it extracts the crash
+//! dump with savecore and copies it next to the panic output. Every step is
+//! bounded in time and failures are logged rather than ending the watch.
+
+use crate::error::Error;
+use crate::{events, NodeRef, Runner};
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use camino::{Utf8Path, Utf8PathBuf};
+use futures::StreamExt;
+use propolis_client::types::InstanceStateRequested;
+use serde::Serialize;
+use slog::{info, warn};
+use std::fs;
+use std::io::{BufWriter, Write};
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::time::{SystemTime, UNIX_EPOCH};
+use tokio::time::{sleep, timeout, Duration};
+use tokio_tungstenite::tungstenite::Message;
+
+/// How the first line of a helios panic starts, e.g.
+/// `panic[cpu0]/thread=fffffe0002a05c20: forced crash dump initiated`.
+const PANIC_SIGNATURE: &str = "panic[cpu";
+/// Lines that end a panic block.
+const PANIC_END: [&str; 3] = [
+    "rebooting...",
+    "Press any key to reboot",
+    "press any key to reboot",
+];
+/// The most lines of a panic block kept.
+const PANIC_BLOCK_LINES: usize = 500;
+/// A panic block is considered complete after this much console silence.
+const PANIC_QUIET: Duration = Duration::from_secs(10);
+const REBOOT_TIMEOUT: Duration = Duration::from_secs(30);
+/// How long the guest has to come back and have its dump pulled.
+const COLLECT_TIMEOUT: Duration = Duration::from_secs(1800);
+
+/// What a panic watch does beyond saving the panic output.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CrashPolicy {
+    /// Reboot the node once its panic output is saved, then pull the dump.
+    pub reboot: bool,
+    /// Dump files larger than this are left on the node.
+    pub max_dump_bytes: u64,
+}
+
+impl Default for CrashPolicy {
+    fn default() -> Self {
+        CrashPolicy {
+            reboot: false,
+            max_dump_bytes: 256 << 20,
+        }
+    }
+}
+
+/// A panic log or crash dump collected from a node.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct CrashFile {
+    pub node: String,
+    pub path: Utf8PathBuf,
+    pub size: u64,
+    /// Seconds since the unix epoch at which the file was written.
+    pub time: u64,
+}
+
+/// The outcome of pulling crash dumps off a node.
+#[derive(Debug, Default)]
+pub struct PullReport {
+    pub pulled: Vec<Utf8PathBuf>,
+    /// Dumps left on the node, with the reason.
+    pub skipped: Vec<(String, String)>,
+}
+
+/// Where panic output and dumps of the named node are kept.
+pub fn crash_dir(falcon_dir: &Utf8Path, node: &str) -> Utf8PathBuf {
+    falcon_dir.join("crash").join(node)
+}
+
+/// Whether a file collected from a node is panic output rather than a dump.
+pub(crate) fn is_panic_log(path: &Utf8Path) -> bool {
+    path.file_name().map_or(false, |n| n.starts_with("panic-"))
+}
+
+/// The files collected from the named node, oldest first.
+pub fn node_crashes(
+    falcon_dir: &Utf8Path,
+    node: &str,
+) -> Result<Vec<CrashFile>, Error> {
+    let dir = crash_dir(falcon_dir, node);
+    let entries = match fs::read_dir(&dir) {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+            return Ok(Vec::new())
+        }
+        Err(e) => return Err(e.into()),
+    };
+    let mut result = Vec::new();
+    for entry in entries {
+        let entry = entry?;
+        let md = entry.metadata()?;
+        if !md.is_file() {
+            continue;
+        }
+        let time = md
+            .modified()?
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or(0);
+        result.push(CrashFile {
+            node: node.into(),
+            path: dir.join(entry.file_name().to_string_lossy().as_ref()),
+            size: md.len(),
+            time,
+        });
+    }
+    result.sort_by_key(|c| (c.time, c.path.clone()));
+    Ok(result)
+}
+
+/// Picks panic blocks out of console output as it arrives.
+#[derive(Default)]
+pub(crate) struct PanicScanner {
+    /// A trailing partial line.
+    pending: String,
+    block: Option<Vec<String>>,
+}
+
+impl PanicScanner {
+    /// Feed console output, returning a panic block once its end is seen.
+    pub(crate) fn feed(&mut self, data: &str) -> Option<String> {
+        self.pending += data;
+        let mut result = None;
+        while let Some(i) = self.pending.find('\n') {
+            let line: String = self.pending.drain(..=i).collect();
+            let line = line.trim_end_matches(&['\r', '\n'][..]);
+            match self.block {
+                None if line.contains(PANIC_SIGNATURE) => {
+                    self.block = Some(vec![line.into()]);
+                }
+                None => {}
+                Some(ref mut block) => {
+                    block.push(line.into());
+                    if PANIC_END.iter().any(|e| line.contains(e))
+                        || block.len() >= PANIC_BLOCK_LINES
+                    {
+                        result = self.flush();
+                    }
+                }
+            }
+        }
+        result
+    }
+
+    /// Give up waiting for the end of a panic block, returning what there is
+    /// of it.
+    pub(crate) fn flush(&mut self) -> Option<String> {
+        let mut block = self.block.take()?;
+        if !self.pending.is_empty() {
+            block.push(self.pending.trim_end_matches('\r').into());
+            self.pending.clear();
+        }
+        Some(block.join("\n") + "\n")
+    }
+
+    pub(crate) fn in_panic(&self) -> bool {
+        self.block.is_some()
+    }
+}
+
+/// The dumps savecore left in the guest, from lines of `<size> <path>`.
+pub(crate) fn parse_dump_list(out: &str) -> Vec<(u64, String)> {
+    out.lines()
+        .filter_map(|l| {
+            let mut parts = l.split_whitespace();
+            let size = parts.next()?.parse().ok()?;
+            let path = parts.next()?;
+            Some((size, path.into()))
+        })
+        .collect()
+}
+
+/// Whether `dumpadm` output shows a dump device to take dumps to.
+pub(crate) fn dump_device_configured(dumpadm: &str) -> bool {
+    dumpadm.lines().any(|l| {
+        let l = l.trim();
+        l.starts_with("Dump device:") && !l.contains("none")
+    })
+}
+
+/// The directory savecore writes dumps to, from `dumpadm` output.
+pub(crate) fn savecore_dir(dumpadm: &str) -> Option<String> {
+    dumpadm.lines().find_map(|l| {
+        // e.g. `Savecore directory: /var/crash/helios (minfree = 10MB)`
+        let dir = l.trim().strip_prefix("Savecore directory:")?;
+        dir.split_whitespace().next().map(Into::into)
+    })
+}
+
+/// Decodes a dump copied over the console as lines of base64, writing it
+/// out as it arrives. Writing more than `limit` bytes fails.
+pub(crate) struct DumpDecoder<W> {
+    out: W,
+    /// Characters of the last line short of a whole base64 quantum.
+    carry: String,
+    written: u64,
+    limit: u64,
+}
+
+impl<W: Write> DumpDecoder<W> {
+    pub(crate) fn new(out: W, limit: u64) -> Self {
+        DumpDecoder {
+            out,
+            carry: String::new(),
+            written: 0,
+            limit,
+        }
+    }
+
+    pub(crate) fn feed(&mut self, line: &str) -> Result<(), Error> {
+        if !line.is_ascii() {
+            return Err(Error::Exec(format!("not base64: {:?}", line)));
+        }
+        self.carry
+            .extend(line.chars().filter(|c| !c.is_whitespace()));
+        let whole = self.carry.len() / 4 * 4;
+        let data = STANDARD
+            .decode(&self.carry[..whole])
+            .map_err(|e| Error::Exec(format!("decoding dump: {}", e)))?;
+        self.carry.drain(..whole);
+        self.written += data.len() as u64;
+        if self.written > self.limit {
+            return Err(Error::Exec(format!(
+                "dump is over the {} bytes expected",
+                self.limit
+            )));
+        }
+        self.out.write_all(&data)?;
+        Ok(())
+    }
+
+    /// Finish the dump, returning its size.
+    pub(crate) fn finish(mut self) -> Result<u64, Error> {
+        if !self.carry.is_empty() {
+            return Err(Error::Exec("dump ends mid base64 quantum".into()));
+        }
+        self.out.flush()?;
+        Ok(self.written)
+    }
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+impl Runner {
+    /// Watch the referenced node's console for panics until the console goes
+    /// away. See the module documentation for what happens on a panic.
+    pub async fn watch_panics(
+        &self,
+        n: NodeRef,
+        policy: CrashPolicy,
+    ) -> Result<(), Error> {
+        let name = self.deployment.nodes[n.index].name.clone();
+        self.do_watch_panics(&name, policy).await
+    }
+
+    pub(crate) async fn do_watch_panics(
+        &self,
+        name: &str,
+        policy: CrashPolicy,
+    ) -> Result<(), Error> {
+        loop {
+            let block = match self.next_panic(name).await? {
+                Some(block) => block,
+                None => return Ok(()),
+            };
+            match self.save_panic(name, &block) {
+                Ok(path) => {
+                    warn!(self.log, "{}: panicked, saved {}", name, path);
+                    events::record_panic(
+                        self.events_log.as_deref(),
+                        &self.deployment.name,
+                        name,
+                        &path,
+                    );
+                }
+                Err(e) => warn!(self.log, "{}: saving panic: {}", name, e),
+            }
+            if !policy.reboot {
+                continue;
+            }
+
+            match timeout(REBOOT_TIMEOUT, self.reboot_node(name)).await {
+                Ok(Ok(())) => {}
+                Ok(Err(e)) => {
+                    warn!(self.log, "{}: reboot after panic: {}", name, e);
+                    continue;
+                }
+                Err(_) => {
+                    warn!(self.log, "{}: reboot after panic timed out", name);
+                    continue;
+                }
+            }
+            let pull = self.pull_crash_dumps(name, policy.max_dump_bytes);
+            match timeout(COLLECT_TIMEOUT, pull).await {
+                Ok(Ok(report)) => {
+                    for p in report.pulled.iter() {
+                        info!(self.log, "{}: pulled {}", name, p);
+                    }
+                    for (f, why) in report.skipped.iter() {
+                        warn!(self.log, "{}: left {}: {}", name, f, why);
+                    }
+                }
+                Ok(Err(e)) => warn!(self.log, "{}: pulling dump: {}", name, e),
+                Err(_) => warn!(self.log, "{}: pulling dump timed out", name),
+            }
+        }
+    }
+
+    /// Follow the named node's console until a panic block has been seen.
+    /// Returns `None` once the console closes.
+    async fn next_panic(&self, name: &str) -> Result<Option<String>, Error> {
+        let mut sc = self.serial_commander(name)?;
+        let mut ws = sc.connect().await?;
+        let mut scanner = PanicScanner::default();
+        loop {
+            let msg = if scanner.in_panic() {
+                match timeout(PANIC_QUIET, ws.next()).await {
+                    Ok(msg) => msg,
+                    Err(_) => return Ok(scanner.flush()),
+                }
+            } else {
+                ws.next().await
+            };
+            match msg {
+                Some(Ok(Message::Binary(data))) => {
+                    let s = String::from_utf8_lossy(&data);
+                    if let Some(block) = scanner.feed(&s) {
+                        return Ok(Some(block));
+                    }
+                }
+                Some(Ok(Message::Close(..))) | None => {
+                    return Ok(scanner.flush())
+                }
+                Some(Ok(_)) => continue,
+                Some(Err(e)) => return Err(e.into()),
+            }
+        }
+    }
+
+    fn save_panic(
+        &self,
+        name: &str,
+        block: &str,
+    ) -> Result<Utf8PathBuf, Error> {
+        let dir = crash_dir(&self.falcon_dir, name);
+        fs::create_dir_all(&dir)?;
+        let path = dir.join(format!("panic-{}.txt", now()));
+        fs::write(&path, block)?;
+        Ok(path)
+    }
+
+    async fn reboot_node(&self, name: &str) -> Result<(), Error> {
+        let port: u16 =
+            fs::read_to_string(self.falcon_dir.join(format!("{}.port", name)))?
+                .trim_end()
+                .parse()?;
+        let addr =
+            SocketAddr::new(IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)), port);
+        propolis_client::Client::new(&format!("http://{}", addr))
+            .instance_state_put()
+            .body(InstanceStateRequested::Reboot)
+            .send()
+            .await?;
+        // let the guest leave the panic screen before looking for a prompt
+        sleep(Duration::from_secs(5)).await;
+        Ok(())
+    }
+
+    /// Have savecore extract a pending crash dump on the named node, then
+    /// copy the dumps it holds into the node's crash directory. Dumps already
+    /// copied and dumps over `max_bytes` are skipped.
+    pub async fn pull_crash_dumps(
+        &self,
+        name: &str,
+        max_bytes: u64,
+    ) -> Result<PullReport, Error> {
+        let mut report = PullReport::default();
+        let dumpadm = self.do_exec(name, "dumpadm 2>&1").await?;
+        if !dump_device_configured(&dumpadm) {
+            report.skipped.push((
+                "crash dump".into(),
+                "no dump device is configured, see dumpadm(8)".into(),
+            ));
+            return Ok(report);
+        }
+        let savecore = match savecore_dir(&dumpadm) {
+            Some(dir) => dir,
+            None => {
+                report.skipped.push((
+                    "crash dump".into(),
+                    "dumpadm shows no savecore directory".into(),
+                ));
+                return Ok(report);
+            }
+        };
+        let out = self
+            .do_exec_all(
+                name,
+                vec![
+                    "savecore -v 2>&1; true".into(),
+                    format!(
+                        "for f in {}/vmdump.*; do [ -f \"$f\" ] && \
+                        echo \"$(wc -c < \"$f\") $f\"; done; true",
+                        savecore
+                    ),
+                ],
+            )
+            .await?;
+
+        let dir = crash_dir(&self.falcon_dir, name);
+        fs::create_dir_all(&dir)?;
+        for (size, path) in parse_dump_list(&out[1]) {
+            if size > max_bytes {
+                report.skipped.push((
+                    path,
+                    format!(
+                        "{} bytes is over the {} byte cap",
+                        size, max_bytes
+                    ),
+                ));
+                continue;
+            }
+            let host = path.trim_start_matches('/').replace('/', "_");
+            let dest = dir.join(host);
+            if fs::metadata(&dest).map_or(false, |m| m.len() == size) {
+                continue;
+            }
+            // decode as the copy streams in, never holding the whole dump
+            let partial = Utf8PathBuf::from(format!("{}.partial", dest));
+            let file = BufWriter::new(fs::File::create(&partial)?);
+            let mut decoder = DumpDecoder::new(file, size);
+            let cmd = format!("base64 < {}", path);
+            let streamed = self
+                .do_exec_lines(name, &cmd, |line| decoder.feed(line))
+                .await;
+            match streamed.and_then(|_| decoder.finish()) {
+                Ok(n) if n == size => {
+                    fs::rename(&partial, &dest)?;
+                    report.pulled.push(dest);
+                }
+                Ok(n) => {
+                    let _ = fs::remove_file(&partial);
+                    report.skipped.push((
+                        path,
+                        format!("copied {} of {} bytes", n, size),
+                    ));
+                }
+                Err(e) => {
+                    let _ = fs::remove_file(&partial);
+                    return Err(Error::Exec(format!(
+                        "copying {} from {}: {}",
+                        path, name, e
+                    )));
+                }
+            }
+        }
+        Ok(report)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::test::Scratch;
+    use anyhow::Result;
+
+    /// Test that panic output is picked out of console output however it is
+    /// split up, and that savecore and dumpadm output are understood.
+    #[test]
+    fn guest_panic_capture() -> Result<()> {
+        use crate::crash::{
+            dump_device_configured, node_crashes, parse_dump_list,
+            savecore_dir, DumpDecoder, PanicScanner,
+        };
+        use base64::engine::general_purpose::STANDARD;
+        use base64::Engine;
+
+        let console = "\r\nhelios console login: \r\n\
+            \r\npanic[cpu1]/thread=fffffe0002a05c20: forced crash dump \
+            initiated at user request\r\n\
+            \r\nfffffe0002a05b10 genunix:kadmin+517 ()\r\n\
+            fffffe0002a05b80 genunix:uadmin+11d ()\r\n\
+            \r\ndumping to /dev/zvol/dsk/rpool/dump, offset 65536, \
+            content: kernel\
+            \r\n100% done: 51234 pages dumped, dump succeeded\r\n\
+            rebooting...\r\nafter the block\r\n";
+
+        // byte at a time and all at once
+        for chunk in [1, console.len()] {
+            let mut s = PanicScanner::default();
+            let mut blocks = Vec::new();
+            for part in console.as_bytes().chunks(chunk) {
+                blocks.extend(s.feed(std::str::from_utf8(part)?));
+            }
+            assert_eq!(blocks.len(), 1);
+            let lines: Vec<&str> = blocks[0].lines().collect();
+            assert!(lines[0].starts_with("panic[cpu1]/thread="));
+            assert_eq!(lines[2], "fffffe0002a05b10 genunix:kadmin+517 ()");
+            assert_eq!(lines.last(), Some(&"rebooting..."));
+            assert!(!s.in_panic());
+        }
+
+        // a panic that never gets to rebooting is flushed as is
+        let mut s = PanicScanner::default();
+        assert_eq!(
+            s.feed("panic[cpu0]/thread=1: oops\r\nstack line\r\npart"),
+            None
+        );
+        assert!(s.in_panic());
+        assert_eq!(
+            s.flush().as_deref(),
+            Some("panic[cpu0]/thread=1: oops\nstack line\npart\n")
+        );
+        assert_eq!(s.flush(), None);
+
+        let dumpadm = "      Dump content: kernel pages\n       \
+            Dump device: /dev/zvol/dsk/rpool/dump (dedicated)\n";
+        assert!(dump_device_configured(dumpadm));
+        assert!(!dump_device_configured(
+            "Dump device: none (dumps disabled)\n"
+        ));
+        assert!(!dump_device_configured(""));
+
+        assert_eq!(
+            parse_dump_list(
+                "    1048576 /var/crash/helios/vmdump.0\n\
+                ls: no match\n\
+                42 /var/crash/helios/vmdump.1\n"
+            ),
+            [
+                (1048576, "/var/crash/helios/vmdump.0".to_string()),
+                (42, "/var/crash/helios/vmdump.1".to_string()),
+            ]
+        );
+
+        let dumpadm = "      Dump content: kernel pages\n       \
+            Dump device: /dev/zvol/dsk/rpool/dump (dedicated)\n\
+            Savecore directory: /var/crash/helios (minfree = 10MB)\n";
+        assert_eq!(savecore_dir(dumpadm).as_deref(), Some("/var/crash/helios"));
+        assert_eq!(savecore_dir("Dump device: none\n"), None);
+
+        // dumps decode line by line whatever the line length, and are cut
+        // off at the size listed
+        let dump: Vec<u8> = (0..1000u32).map(|i| (i % 251) as u8).collect();
+        let encoded = STANDARD.encode(&dump);
+        for width in [76, 5, 1] {
+            let mut out = Vec::new();
+            let mut d = DumpDecoder::new(&mut out, dump.len() as u64);
+            for line in encoded.as_bytes().chunks(width) {
+                d.feed(std::str::from_utf8(line)?)?;
+            }
+            assert_eq!(d.finish()?, dump.len() as u64);
+            assert_eq!(out, dump);
+        }
+        let mut d = DumpDecoder::new(Vec::new(), 10);
+        assert!(d.feed(&encoded[..76]).is_err());
+        let mut d = DumpDecoder::new(Vec::new(), 1000);
+        d.feed(&encoded[..6])?;
+        assert!(d.finish().is_err());
+        let mut d = DumpDecoder::new(Vec::new(), 1000);
+        assert!(d.feed("panic[cpu0] oops").is_err());
+
+        let scratch = Scratch::new("crash")?;
+        let dir = &scratch.dir;
+        assert!(node_crashes(dir, "violin")?.is_empty());
+        let crash = crate::crash::crash_dir(dir, "violin");
+        std::fs::create_dir_all(&crash)?;
+        std::fs::write(crash.join("panic-1.txt"), "panic")?;
+        let found = node_crashes(dir, "violin")?;
+        assert_eq!(found.len(), 1);
+        assert_eq!(found[0].size, 5);
+
+        Ok(())
+    }
+}
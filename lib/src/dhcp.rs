@@ -0,0 +1,989 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+// Copyright 2022 Oxide Computer Company
+
+//! A small DHCP and TFTP responder on the management network, for PXE and
+//! installer testing without a DHCP server in a guest. It speaks enough of
+//! RFC 2131 to hand out addresses from a range, with options 66 and 67
+//! pointing clients at a boot file served over TFTP from a host directory.
+//!
+//! The responder's sockets are bound to the host's management vnic with
+//! `IP_BOUND_IF`, so it never answers on the host's other networks. Leases
+//! are kept in `.falcon/dhcp-leases.ron`.
+//!
+//! The command line exits once a launch is done, so there the responder runs
+//! as a separate `falcon dhcp serve` process, like the propolis servers.
+//! That process holds a lock on its pid file while it runs, and is only
+//! signalled while it does, never another process that reused its pid.
+//! Topology programs that launch through the library run it as a task
+//! instead.
+//!
+//! The responder serves the management network, the one segment every node
+//! and the host share. Falcon's other links are point to point simnets with
+//! no host interface to answer on, so DHCP cannot be added per link.
+
+use crate::error::Error;
+use crate::lock::{self, FileLock};
+use crate::{Deployment, Runner, Step};
+use camino::{Utf8Path, Utf8PathBuf};
+use ipnet::Ipv4Net;
+use serde::{Deserialize, Serialize};
+use slog::{info, warn, Logger};
+use std::fs;
+use std::io;
+use std::io::Write;
+use std::net::{Ipv4Addr, SocketAddr, SocketAddrV4};
+use std::ops::RangeInclusive;
+use std::os::unix::io::{AsRawFd, FromRawFd};
+use std::process::Command;
+use std::time::{SystemTime, UNIX_EPOCH};
+use tokio::net::UdpSocket;
+use tokio::time::{timeout, Duration};
+
+/// Where leases are kept in the falcon directory.
+pub const LEASES_FILE: &str = "dhcp-leases.ron";
+/// The pid of a detached responder in the falcon directory.
+const PID_FILE: &str = "dhcp.pid";
+
+/// How long a lease lasts.
+pub const LEASE_TIME: Duration = Duration::from_secs(3600);
+
+const SERVER_PORT: u16 = 67;
+const CLIENT_PORT: u16 = 68;
+const TFTP_PORT: u16 = 69;
+
+/// Binds a socket to an interface, from `<netinet/in.h>` on illumos.
+const IP_BOUND_IF: libc::c_int = 0x41;
+
+const MAGIC_COOKIE: [u8; 4] = [99, 130, 83, 99];
+const BOOTREQUEST: u8 = 1;
+const BOOTREPLY: u8 = 2;
+/// The smallest message BOOTP relays and old clients accept.
+const MIN_MESSAGE: usize = 300;
+
+const OPT_PAD: u8 = 0;
+const OPT_SUBNET_MASK: u8 = 1;
+const OPT_ROUTER: u8 = 3;
+const OPT_HOSTNAME: u8 = 12;
+const OPT_REQUESTED_ADDR: u8 = 50;
+const OPT_LEASE_TIME: u8 = 51;
+const OPT_MESSAGE_TYPE: u8 = 53;
+const OPT_SERVER_ID: u8 = 54;
+const OPT_TFTP_SERVER: u8 = 66;
+const OPT_BOOTFILE: u8 = 67;
+const OPT_END: u8 = 255;
+
+const DHCPDISCOVER: u8 = 1;
+const DHCPOFFER: u8 = 2;
+const DHCPREQUEST: u8 = 3;
+const DHCPDECLINE: u8 = 4;
+const DHCPACK: u8 = 5;
+const DHCPNAK: u8 = 6;
+const DHCPRELEASE: u8 = 7;
+const DHCPINFORM: u8 = 8;
+
+const TFTP_RRQ: u16 = 1;
+const TFTP_DATA: u16 = 3;
+const TFTP_ACK: u16 = 4;
+const TFTP_ERROR: u16 = 5;
+const TFTP_BLOCK: usize = 512;
+const TFTP_TIMEOUT: Duration = Duration::from_secs(1);
+const TFTP_RETRIES: usize = 5;
+
+/// What the responder hands out.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct DhcpConfig {
+    /// The addresses leased to clients. The range must be within the IPv4
+    /// management prefix and clear of the host's and nodes' addresses.
+    pub range: RangeInclusive<Ipv4Addr>,
+    /// The default route given to clients, if any.
+    pub router: Option<Ipv4Addr>,
+    /// The file clients boot, relative to `tftp_root`, e.g. `pxeboot.bin`.
+    pub boot_file: Option<String>,
+    /// The host directory served over TFTP. Without one there is no TFTP.
+    pub tftp_root: Option<Utf8PathBuf>,
+}
+
+impl DhcpConfig {
+    pub fn new(range: RangeInclusive<Ipv4Addr>) -> Self {
+        DhcpConfig {
+            range,
+            router: None,
+            boot_file: None,
+            tftp_root: None,
+        }
+    }
+
+    /// Check the range fits the management network's IPv4 prefix, which is
+    /// returned, and no fixed address falls inside it.
+    pub(crate) fn check(&self, d: &Deployment) -> Result<Ipv4Net, Error> {
+        let prefix = d.mgmt.v4.ok_or_else(|| {
+            Error::Dhcp("an IPv4 management network is required".into())
+        })?;
+        let (first, last) = (*self.range.start(), *self.range.end());
+        if first > last
+            || !prefix.contains(&first)
+            || !prefix.contains(&last)
+            || first == prefix.network()
+            || last == prefix.broadcast()
+        {
+            return Err(Error::Dhcp(format!(
+                "range {}-{} is not within {}",
+                first, last, prefix
+            )));
+        }
+        let mut fixed = d.mgmt.host_addrs()?;
+        for i in 0..d.nodes.len() {
+            fixed.extend(d.mgmt.node_addrs(i)?);
+        }
+        for a in fixed {
+            if let std::net::IpAddr::V4(a) = a.addr() {
+                if self.range.contains(&a) {
+                    return Err(Error::Dhcp(format!(
+                        "range {}-{} includes the fixed address {}",
+                        first, last, a
+                    )));
+                }
+            }
+        }
+        Ok(prefix)
+    }
+}
+
+/// An address leased to a client.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Lease {
+    /// The client's hardware address, e.g. `2:8:20:ab:cd:ef`.
+    pub mac: String,
+    pub addr: Ipv4Addr,
+    /// The host name the client gave, if any.
+    pub hostname: Option<String>,
+    /// Seconds since the unix epoch at which the lease runs out.
+    pub expires: u64,
+}
+
+impl Lease {
+    /// Seconds left on the lease at `now`, `None` once it has run out.
+    pub fn remaining(&self, now: u64) -> Option<u64> {
+        self.expires.checked_sub(now).filter(|s| *s > 0)
+    }
+}
+
+/// The leases of the deployment in `falcon_dir`.
+pub fn leases(falcon_dir: &Utf8Path) -> Result<Vec<Lease>, Error> {
+    match fs::read_to_string(falcon_dir.join(LEASES_FILE)) {
+        Ok(s) => Ok(ron::de::from_str(&s)?),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(Vec::new()),
+        Err(e) => Err(e.into()),
+    }
+}
+
+pub(crate) fn save_leases(
+    falcon_dir: &Utf8Path,
+    leases: &[Lease],
+) -> Result<(), Error> {
+    let s = ron::ser::to_string_pretty(leases, ron::ser::PrettyConfig::new())?;
+    fs::write(falcon_dir.join(LEASES_FILE), s)?;
+    Ok(())
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+/// A DHCP message.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct Packet {
+    pub op: u8,
+    pub htype: u8,
+    pub hlen: u8,
+    pub xid: u32,
+    pub flags: u16,
+    pub ciaddr: Ipv4Addr,
+    pub yiaddr: Ipv4Addr,
+    pub siaddr: Ipv4Addr,
+    pub giaddr: Ipv4Addr,
+    pub chaddr: [u8; 16],
+    pub file: String,
+    pub options: Vec<(u8, Vec<u8>)>,
+}
+
+fn addr_at(buf: &[u8], at: usize) -> Ipv4Addr {
+    Ipv4Addr::new(buf[at], buf[at + 1], buf[at + 2], buf[at + 3])
+}
+
+impl Packet {
+    pub(crate) fn parse(buf: &[u8]) -> Option<Packet> {
+        if buf.len() < 240 || buf[236..240] != MAGIC_COOKIE {
+            return None;
+        }
+        let mut chaddr = [0u8; 16];
+        chaddr.copy_from_slice(&buf[28..44]);
+        let file = &buf[108..236];
+        let file = &file[..file.iter().position(|b| *b == 0).unwrap_or(128)];
+        let mut options = Vec::new();
+        let mut i = 240;
+        while i < buf.len() {
+            match buf[i] {
+                OPT_PAD => i += 1,
+                OPT_END => break,
+                code => {
+                    let len = *buf.get(i + 1)? as usize;
+                    let value = buf.get(i + 2..i + 2 + len)?;
+                    options.push((code, value.to_vec()));
+                    i += 2 + len;
+                }
+            }
+        }
+        Some(Packet {
+            op: buf[0],
+            htype: buf[1],
+            hlen: buf[2],
+            xid: u32::from_be_bytes([buf[4], buf[5], buf[6], buf[7]]),
+            flags: u16::from_be_bytes([buf[10], buf[11]]),
+            ciaddr: addr_at(buf, 12),
+            yiaddr: addr_at(buf, 16),
+            siaddr: addr_at(buf, 20),
+            giaddr: addr_at(buf, 24),
+            chaddr,
+            file: String::from_utf8_lossy(file).into_owned(),
+            options,
+        })
+    }
+
+    pub(crate) fn encode(&self) -> Vec<u8> {
+        let mut buf = vec![0u8; 240];
+        buf[0] = self.op;
+        buf[1] = self.htype;
+        buf[2] = self.hlen;
+        buf[4..8].copy_from_slice(&self.xid.to_be_bytes());
+        buf[10..12].copy_from_slice(&self.flags.to_be_bytes());
+        buf[12..16].copy_from_slice(&self.ciaddr.octets());
+        buf[16..20].copy_from_slice(&self.yiaddr.octets());
+        buf[20..24].copy_from_slice(&self.siaddr.octets());
+        buf[24..28].copy_from_slice(&self.giaddr.octets());
+        buf[28..44].copy_from_slice(&self.chaddr);
+        let file = self.file.as_bytes();
+        let n = file.len().min(127);
+        buf[108..108 + n].copy_from_slice(&file[..n]);
+        buf[236..240].copy_from_slice(&MAGIC_COOKIE);
+        for (code, value) in self.options.iter() {
+            buf.push(*code);
+            buf.push(value.len() as u8);
+            buf.extend_from_slice(value);
+        }
+        buf.push(OPT_END);
+        if buf.len() < MIN_MESSAGE {
+            buf.resize(MIN_MESSAGE, OPT_PAD);
+        }
+        buf
+    }
+
+    pub(crate) fn option(&self, code: u8) -> Option<&[u8]> {
+        self.options
+            .iter()
+            .find(|(c, _)| *c == code)
+            .map(|(_, v)| v.as_slice())
+    }
+
+    fn option_addr(&self, code: u8) -> Option<Ipv4Addr> {
+        match self.option(code)? {
+            [a, b, c, d] => Some(Ipv4Addr::new(*a, *b, *c, *d)),
+            _ => None,
+        }
+    }
+
+    pub(crate) fn message_type(&self) -> Option<u8> {
+        self.option(OPT_MESSAGE_TYPE)?.first().copied()
+    }
+
+    /// The client's hardware address as `dladm` shows it.
+    pub(crate) fn mac(&self) -> String {
+        let n = (self.hlen as usize).min(16);
+        crate::adopt::fmt_mac(&self.chaddr[..n])
+    }
+}
+
+/// Answers DHCP messages from the leases it holds.
+pub(crate) struct Responder {
+    pub server: Ipv4Addr,
+    pub prefix: Ipv4Net,
+    pub config: DhcpConfig,
+    pub leases: Vec<Lease>,
+}
+
+impl Responder {
+    /// The address to offer the client with `mac`: the one it already holds,
+    /// the one it asked for if that is free, or the first free one.
+    fn pick(
+        &self,
+        mac: &str,
+        requested: Option<Ipv4Addr>,
+        now: u64,
+    ) -> Option<Ipv4Addr> {
+        let held = self
+            .leases
+            .iter()
+            .find(|l| l.mac == mac && self.config.range.contains(&l.addr));
+        if let Some(l) = held {
+            return Some(l.addr);
+        }
+        if let Some(a) = requested {
+            if self.available(a, mac, now) {
+                return Some(a);
+            }
+        }
+        let (first, last) = (
+            u32::from(*self.config.range.start()),
+            u32::from(*self.config.range.end()),
+        );
+        (first..=last)
+            .map(Ipv4Addr::from)
+            .find(|a| self.available(*a, mac, now))
+    }
+
+    /// Whether `addr` may be leased to the client with `mac`.
+    fn available(&self, addr: Ipv4Addr, mac: &str, now: u64) -> bool {
+        self.config.range.contains(&addr)
+            && !self
+                .leases
+                .iter()
+                .any(|l| l.addr == addr && l.mac != mac && l.expires > now)
+    }
+
+    fn reply(&self, req: &Packet, kind: u8, yiaddr: Ipv4Addr) -> Packet {
+        let mut options = vec![
+            (OPT_MESSAGE_TYPE, vec![kind]),
+            (OPT_SERVER_ID, self.server.octets().to_vec()),
+        ];
+        let mut file = String::new();
+        let mut siaddr = Ipv4Addr::UNSPECIFIED;
+        if kind != DHCPNAK {
+            if !yiaddr.is_unspecified() {
+                let secs = LEASE_TIME.as_secs() as u32;
+                options.push((OPT_LEASE_TIME, secs.to_be_bytes().to_vec()));
+            }
+            options.push((
+                OPT_SUBNET_MASK,
+                self.prefix.netmask().octets().to_vec(),
+            ));
+            if let Some(r) = self.config.router {
+                options.push((OPT_ROUTER, r.octets().to_vec()));
+            }
+            if self.config.tftp_root.is_some() {
+                siaddr = self.server;
+                options.push((
+                    OPT_TFTP_SERVER,
+                    self.server.to_string().into_bytes(),
+                ));
+            }
+            if let Some(ref f) = self.config.boot_file {
+                file = f.clone();
+                options.push((OPT_BOOTFILE, f.clone().into_bytes()));
+            }
+        }
+        Packet {
+            op: BOOTREPLY,
+            htype: req.htype,
+            hlen: req.hlen,
+            xid: req.xid,
+            flags: req.flags,
+            ciaddr: Ipv4Addr::UNSPECIFIED,
+            yiaddr,
+            siaddr,
+            giaddr: req.giaddr,
+            chaddr: req.chaddr,
+            file,
+            options,
+        }
+    }
+
+    /// The reply to `req`, if it warrants one. Leases are taken and given
+    /// back as the exchange goes.
+    pub(crate) fn handle(&mut self, req: &Packet, now: u64) -> Option<Packet> {
+        if req.op != BOOTREQUEST || req.hlen == 0 {
+            return None;
+        }
+        let mac = req.mac();
+        let for_us = req
+            .option_addr(OPT_SERVER_ID)
+            .map_or(true, |s| s == self.server);
+        match req.message_type()? {
+            DHCPDISCOVER => {
+                let requested = req.option_addr(OPT_REQUESTED_ADDR);
+                let addr = self.pick(&mac, requested, now)?;
+                Some(self.reply(req, DHCPOFFER, addr))
+            }
+            DHCPREQUEST => {
+                // the client took another server's offer
+                if !for_us {
+                    return None;
+                }
+                let addr =
+                    req.option_addr(OPT_REQUESTED_ADDR).unwrap_or(req.ciaddr);
+                if !self.available(addr, &mac, now) {
+                    return Some(self.reply(
+                        req,
+                        DHCPNAK,
+                        Ipv4Addr::UNSPECIFIED,
+                    ));
+                }
+                let hostname = req
+                    .option(OPT_HOSTNAME)
+                    .map(|h| String::from_utf8_lossy(h).into_owned());
+                self.leases.retain(|l| l.mac != mac && l.addr != addr);
+                self.leases.push(Lease {
+                    mac,
+                    addr,
+                    hostname,
+                    expires: now + LEASE_TIME.as_secs(),
+                });
+                Some(self.reply(req, DHCPACK, addr))
+            }
+            DHCPRELEASE | DHCPDECLINE if for_us => {
+                self.leases.retain(|l| l.mac != mac);
+                None
+            }
+            DHCPINFORM => Some(self.reply(req, DHCPACK, Ipv4Addr::UNSPECIFIED)),
+            _ => None,
+        }
+    }
+}
+
+/// Where to send `reply`. Clients without an address yet, and anyone being
+/// told no, are answered by broadcast on the bound interface.
+pub(crate) fn reply_addr(req: &Packet, reply: &Packet) -> SocketAddrV4 {
+    let nak = reply.message_type() == Some(DHCPNAK);
+    if !req.ciaddr.is_unspecified() && !nak {
+        return SocketAddrV4::new(req.ciaddr, CLIENT_PORT);
+    }
+    SocketAddrV4::new(Ipv4Addr::BROADCAST, CLIENT_PORT)
+}
+
+/// The file under `root` a TFTP client asked for. Names that would leave
+/// the root are refused.
+pub(crate) fn tftp_path(root: &Utf8Path, name: &str) -> Option<Utf8PathBuf> {
+    let rel = Utf8Path::new(name.trim_start_matches('/'));
+    let safe = rel
+        .components()
+        .all(|c| matches!(c, camino::Utf8Component::Normal(_)));
+    if !safe || rel.as_str().is_empty() {
+        return None;
+    }
+    Some(root.join(rel))
+}
+
+/// A UDP socket on `port` that only sends and receives on the interface
+/// `ifname`. The interface is bound before the port, so nothing arriving on
+/// the host's other networks is ever seen.
+fn bound_socket(ifname: &str, port: u16) -> Result<UdpSocket, Error> {
+    let name = std::ffi::CString::new(ifname)?;
+    let index = unsafe { libc::if_nametoindex(name.as_ptr()) };
+    if index == 0 {
+        return Err(Error::Dhcp(format!("no interface {}", ifname)));
+    }
+    let fd = unsafe { libc::socket(libc::AF_INET, libc::SOCK_DGRAM, 0) };
+    if fd < 0 {
+        return Err(io::Error::last_os_error().into());
+    }
+    // the socket owns the descriptor from here, closing it on any error
+    let sock = unsafe { std::net::UdpSocket::from_raw_fd(fd) };
+    let rc = unsafe {
+        libc::setsockopt(
+            sock.as_raw_fd(),
+            libc::IPPROTO_IP,
+            IP_BOUND_IF,
+            &index as *const libc::c_uint as *const libc::c_void,
+            std::mem::size_of::<libc::c_uint>() as libc::socklen_t,
+        )
+    };
+    if rc < 0 {
+        return Err(io::Error::last_os_error().into());
+    }
+    let mut sa: libc::sockaddr_in = unsafe { std::mem::zeroed() };
+    sa.sin_family = libc::AF_INET as libc::sa_family_t;
+    sa.sin_port = port.to_be();
+    sa.sin_addr.s_addr = u32::from(Ipv4Addr::UNSPECIFIED).to_be();
+    let rc = unsafe {
+        libc::bind(
+            sock.as_raw_fd(),
+            &sa as *const libc::sockaddr_in as *const libc::sockaddr,
+            std::mem::size_of::<libc::sockaddr_in>() as libc::socklen_t,
+        )
+    };
+    if rc < 0 {
+        return Err(io::Error::last_os_error().into());
+    }
+    sock.set_broadcast(true)?;
+    sock.set_nonblocking(true)?;
+    Ok(UdpSocket::from_std(sock)?)
+}
+
+/// Answer DHCP on `ifname` until the socket fails.
+async fn serve_dhcp(
+    ifname: String,
+    mut responder: Responder,
+    falcon_dir: Utf8PathBuf,
+    log: Logger,
+) -> Result<(), Error> {
+    let sock = bound_socket(&ifname, SERVER_PORT)?;
+    info!(
+        log,
+        "dhcp: answering on {} from {}-{}",
+        ifname,
+        responder.config.range.start(),
+        responder.config.range.end()
+    );
+    let mut buf = [0u8; 1500];
+    loop {
+        let (n, _) = sock.recv_from(&mut buf).await?;
+        let req = match Packet::parse(&buf[..n]) {
+            Some(p) => p,
+            None => continue,
+        };
+        let before = responder.leases.clone();
+        if let Some(reply) = responder.handle(&req, now()) {
+            let to = reply_addr(&req, &reply);
+            sock.send_to(&reply.encode(), SocketAddr::V4(to)).await?;
+        }
+        if responder.leases != before {
+            for l in responder.leases.iter().filter(|l| !before.contains(l)) {
+                info!(log, "dhcp: leased {} to {}", l.addr, l.mac);
+            }
+            save_leases(&falcon_dir, &responder.leases)?;
+        }
+    }
+}
+
+fn tftp_error(code: u16, msg: &str) -> Vec<u8> {
+    let mut p = Vec::new();
+    p.extend_from_slice(&TFTP_ERROR.to_be_bytes());
+    p.extend_from_slice(&code.to_be_bytes());
+    p.extend_from_slice(msg.as_bytes());
+    p.push(0);
+    p
+}
+
+/// Send a file to a TFTP client in lock step, from a socket of its own.
+async fn tftp_send(
+    ifname: &str,
+    root: &Utf8Path,
+    name: &str,
+    peer: SocketAddr,
+) -> Result<(), Error> {
+    let sock = bound_socket(ifname, 0)?;
+    let data = match tftp_path(root, name) {
+        Some(path) => tokio::fs::read(path).await.ok(),
+        None => None,
+    };
+    let data = match data {
+        Some(d) => d,
+        None => {
+            sock.send_to(&tftp_error(1, "file not found"), peer).await?;
+            return Ok(());
+        }
+    };
+    // a file that fills its last block is ended by an empty one
+    let blocks = data.len() / TFTP_BLOCK + 1;
+    let mut ack = [0u8; 4];
+    for i in 0..blocks {
+        let block = ((i + 1) & 0xffff) as u16;
+        let chunk =
+            &data[i * TFTP_BLOCK..((i + 1) * TFTP_BLOCK).min(data.len())];
+        let mut p = Vec::with_capacity(4 + chunk.len());
+        p.extend_from_slice(&TFTP_DATA.to_be_bytes());
+        p.extend_from_slice(&block.to_be_bytes());
+        p.extend_from_slice(chunk);
+        let mut acked = false;
+        for _ in 0..TFTP_RETRIES {
+            sock.send_to(&p, peer).await?;
+            if let Ok(r) = timeout(TFTP_TIMEOUT, sock.recv_from(&mut ack)).await
+            {
+                let (n, from) = r?;
+                if from == peer
+                    && n == 4
+                    && ack[..2] == TFTP_ACK.to_be_bytes()
+                    && ack[2..4] == block.to_be_bytes()
+                {
+                    acked = true;
+                    break;
+                }
+            }
+        }
+        if !acked {
+            return Err(Error::Dhcp(format!("tftp: {} timed out", name)));
+        }
+    }
+    Ok(())
+}
+
+/// Serve `root` read only over TFTP on `ifname`.
+async fn serve_tftp(
+    ifname: String,
+    root: Utf8PathBuf,
+    log: Logger,
+) -> Result<(), Error> {
+    let sock = bound_socket(&ifname, TFTP_PORT)?;
+    info!(log, "tftp: serving {} on {}", root, ifname);
+    let mut buf = [0u8; 516];
+    loop {
+        let (n, peer) = sock.recv_from(&mut buf).await?;
+        if n < 4 || buf[..2] != TFTP_RRQ.to_be_bytes() {
+            continue;
+        }
+        let name = buf[2..n].split(|b| *b == 0).next().unwrap_or_default();
+        let name = String::from_utf8_lossy(name).into_owned();
+        let (ifname, root, log) = (ifname.clone(), root.clone(), log.clone());
+        tokio::spawn(async move {
+            match tftp_send(&ifname, &root, &name, peer).await {
+                Ok(()) => info!(log, "tftp: sent {} to {}", name, peer),
+                Err(e) => warn!(log, "tftp: {} to {}: {}", name, peer, e),
+            }
+        });
+    }
+}
+
+impl Runner {
+    /// Answer DHCP on the management network, handing out addresses from
+    /// `config.range`, e.g. for PXE booting installers. Needs an IPv4
+    /// management network.
+    ///
+    /// This is the only segment DHCP can be served on. Links between nodes
+    /// are simnet pairs the host has no interface on, so there is no per
+    /// link variant; nodes that need DHCP on a link of their own can run a
+    /// server in a guest with [`Role::DhcpServer`](crate::role::Role).
+    pub fn mgmt_dhcp(&mut self, config: DhcpConfig) -> Result<(), Error> {
+        config.check(&self.deployment)?;
+        self.deployment.dhcp = Some(config);
+        Ok(())
+    }
+
+    /// Start the responder, as a process of its own when the command line
+    /// is driving. Any responder of an earlier run went away with the
+    /// management network it was bound to.
+    pub(crate) fn dhcp_start(&self) -> Result<(), Error> {
+        let config = match self.deployment.dhcp {
+            Some(ref c) => c,
+            None => return Ok(()),
+        };
+        config.check(&self.deployment)?;
+
+        if !self.detach_dhcp {
+            let ifname = self.deployment.mgmt_host_vnic_name();
+            return self.plan.step(
+                Step::call("dhcp.serve", &[&ifname]),
+                || {
+                    let serve = self.serve_dhcp()?;
+                    let log = self.log.clone();
+                    let handle = tokio::spawn(async move {
+                        if let Err(e) = serve.await {
+                            warn!(log, "dhcp: {}", e);
+                        }
+                    });
+                    if let Ok(mut s) = self.services.lock() {
+                        s.push(handle);
+                    }
+                    Ok(())
+                },
+            );
+        }
+
+        self.plan.create_dir_all(&self.falcon_dir)?;
+        let exe = std::env::current_exe()?;
+        let exe = exe.to_string_lossy();
+        let args = ["dhcp", "serve", "--falcon-dir", self.falcon_dir.as_str()];
+        let pid_file = self.falcon_dir.join(PID_FILE);
+        let pid = self.plan.step(Step::command(&exe, &args), || {
+            let out = fs::File::create(self.falcon_dir.join("dhcp.out"))?;
+            let err = fs::File::create(self.falcon_dir.join("dhcp.err"))?;
+            let mut child = Command::new(&*exe)
+                .args(args)
+                .stdout(out)
+                .stderr(err)
+                .spawn()?;
+            // the responder is up once it holds the lock on its pid file
+            for _ in 0..100 {
+                if lock::holder(&pid_file)? == Some(child.id() as i32) {
+                    return Ok(Some(child.id()));
+                }
+                if let Some(status) = child.try_wait()? {
+                    return Err(Error::Dhcp(format!(
+                        "responder exited with {}, see {}/dhcp.err",
+                        status, self.falcon_dir
+                    )));
+                }
+                std::thread::sleep(Duration::from_millis(50));
+            }
+            let _ = child.kill();
+            Err(Error::Dhcp(format!(
+                "responder did not start, see {}/dhcp.err",
+                self.falcon_dir
+            )))
+        })?;
+        if let Some(pid) = pid {
+            info!(self.log, "dhcp: responder running with pid {}", pid);
+        }
+        Ok(())
+    }
+
+    /// Run the responder as the process `dhcp_start` detaches, holding the
+    /// lock on the pid file for as long as it runs.
+    pub(crate) async fn serve_dhcp_detached(&self) -> Result<(), Error> {
+        let path = self.falcon_dir.join(PID_FILE);
+        let mut lock = match FileLock::try_acquire(&path)? {
+            Some(l) => l,
+            None => {
+                return Err(Error::Dhcp(format!(
+                    "a responder is already running for {}",
+                    self.falcon_dir
+                )))
+            }
+        };
+        lock.file().set_len(0)?;
+        writeln!(lock.file(), "{}", std::process::id())?;
+        let result = self.serve_dhcp()?.await;
+        drop(lock);
+        result
+    }
+
+    /// Stop the responder, wherever it runs. A detached responder is found
+    /// by the lock it holds on its pid file, a pid file nobody holds is
+    /// left over from a responder that is gone.
+    pub(crate) fn dhcp_stop(&self) {
+        if let Ok(mut s) = self.services.lock() {
+            for handle in s.drain(..) {
+                handle.abort();
+            }
+        }
+        let path = self.falcon_dir.join(PID_FILE);
+        if !path.exists() {
+            return;
+        }
+        match lock::holder(&path) {
+            Ok(Some(pid)) => {
+                let step = Step::call("kill", &["-TERM", &pid.to_string()]);
+                let _ = self.plan.step(step, || {
+                    unsafe { libc::kill(pid, libc::SIGTERM) };
+                    Ok(())
+                });
+            }
+            Ok(None) => {}
+            Err(e) => warn!(self.log, "dhcp: cannot find the responder: {}", e),
+        }
+        let _ = self.plan.remove(&path);
+    }
+
+    /// The responder for this deployment, ready to run. Resolves once the
+    /// sockets fail.
+    pub(crate) fn serve_dhcp(
+        &self,
+    ) -> Result<impl std::future::Future<Output = Result<(), Error>>, Error>
+    {
+        let d = &self.deployment;
+        let config = d
+            .dhcp
+            .clone()
+            .ok_or_else(|| Error::Dhcp("no dhcp configured".into()))?;
+        let prefix = config.check(d)?;
+        let server =
+            match d.mgmt.host_addrs()?.into_iter().find_map(|a| {
+                match a.addr() {
+                    std::net::IpAddr::V4(a) => Some(a),
+                    _ => None,
+                }
+            }) {
+                Some(a) => a,
+                None => return Err(Error::Dhcp("no host address".into())),
+            };
+        let ifname = d.mgmt_host_vnic_name();
+        let responder = Responder {
+            server,
+            prefix,
+            leases: leases(&self.falcon_dir)?,
+            config: config.clone(),
+        };
+        let falcon_dir = self.falcon_dir.clone();
+        let log = self.log.clone();
+        Ok(async move {
+            if let Some(root) = config.tftp_root {
+                let (ifname, log) = (ifname.clone(), log.clone());
+                tokio::spawn(async move {
+                    if let Err(e) = serve_tftp(ifname, root, log.clone()).await
+                    {
+                        warn!(log, "tftp: {}", e);
+                    }
+                });
+            }
+            serve_dhcp(ifname, responder, falcon_dir, log).await
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::test::Scratch;
+    use anyhow::Result;
+
+    /// Test that a dhcp range must sit clear of the fixed management addresses,
+    /// and that the responder offers, acknowledges and refuses addresses the
+    /// way a PXE client expects, with leases kept across restarts.
+    #[test]
+    fn mgmt_dhcp() -> Result<()> {
+        use crate::dhcp::{self, DhcpConfig, Packet, Responder};
+        use std::net::Ipv4Addr;
+
+        let a = |s: &str| -> Ipv4Addr { s.parse().unwrap() };
+
+        let mut r = crate::Runner::new("dhcp");
+        r.persistent = true;
+        let range = a("10.100.0.100")..=a("10.100.0.110");
+        assert!(r.mgmt_dhcp(DhcpConfig::new(range.clone())).is_err());
+
+        r.node("violin", "helios-2.3", 1, 1024);
+        r.node("piano", "helios-2.3", 1, 1024);
+        r.mgmt_network_v4("10.100.0.0/24")?;
+        // piano has the third address
+        assert!(r
+            .mgmt_dhcp(DhcpConfig::new(a("10.100.0.3")..=a("10.100.0.9")))
+            .is_err());
+        assert!(r
+            .mgmt_dhcp(DhcpConfig::new(a("10.100.0.200")..=a("10.100.1.9")))
+            .is_err());
+        r.mgmt_dhcp(DhcpConfig {
+            range: range.clone(),
+            router: Some(a("10.100.0.1")),
+            boot_file: Some("pxeboot.bin".into()),
+            tftp_root: Some("/tmp/tftp".into()),
+        })?;
+        assert!(r.deployment.dhcp.is_some());
+
+        assert_eq!(
+            dhcp::tftp_path("/tmp/tftp".into(), "/pxeboot.bin"),
+            Some("/tmp/tftp/pxeboot.bin".into())
+        );
+        assert_eq!(dhcp::tftp_path("/tmp/tftp".into(), "../etc/shadow"), None);
+
+        let mut chaddr = [0u8; 16];
+        chaddr[..6].copy_from_slice(&[2, 8, 0x20, 0xab, 0xcd, 0xef]);
+        // options 53 (message type) and 50 (requested address)
+        let request = move |kind: u8, options: Vec<(u8, Vec<u8>)>| Packet {
+            op: 1,
+            htype: 1,
+            hlen: 6,
+            xid: 0x1234,
+            flags: 0x8000,
+            ciaddr: Ipv4Addr::UNSPECIFIED,
+            yiaddr: Ipv4Addr::UNSPECIFIED,
+            siaddr: Ipv4Addr::UNSPECIFIED,
+            giaddr: Ipv4Addr::UNSPECIFIED,
+            chaddr,
+            file: String::new(),
+            options: [vec![(53, vec![kind])], options].concat(),
+        };
+
+        let discover = request(1, Vec::new());
+        let wire = discover.encode();
+        assert_eq!(wire.len(), 300);
+        assert_eq!(Packet::parse(&wire).as_ref(), Some(&discover));
+        assert_eq!(discover.mac(), "2:8:20:ab:cd:ef");
+
+        let mut responder = Responder {
+            server: a("10.100.0.1"),
+            prefix: "10.100.0.0/24".parse()?,
+            config: r.deployment.dhcp.clone().unwrap(),
+            leases: Vec::new(),
+        };
+        let now = 1717251135;
+
+        let offer = responder.handle(&discover, now).unwrap();
+        assert_eq!(offer.message_type(), Some(2));
+        assert_eq!(offer.yiaddr, a("10.100.0.100"));
+        assert_eq!(offer.siaddr, a("10.100.0.1"));
+        assert_eq!(offer.file, "pxeboot.bin");
+        assert_eq!(offer.option(66), Some(&b"10.100.0.1"[..]));
+        assert_eq!(offer.option(67), Some(&b"pxeboot.bin"[..]));
+        assert_eq!(offer.option(1), Some(&[255, 255, 255, 0][..]));
+        let to = dhcp::reply_addr(&discover, &offer);
+        assert_eq!(to.ip(), &Ipv4Addr::BROADCAST);
+        assert!(responder.leases.is_empty());
+
+        // a request for another server's offer is not ours to answer
+        let other = request(
+            3,
+            vec![(54, vec![10, 100, 0, 254]), (50, vec![10, 100, 0, 100])],
+        );
+        assert!(responder.handle(&other, now).is_none());
+
+        let req = request(
+            3,
+            vec![
+                (54, vec![10, 100, 0, 1]),
+                (50, vec![10, 100, 0, 100]),
+                (12, b"violin".to_vec()),
+            ],
+        );
+        let ack = responder.handle(&req, now).unwrap();
+        assert_eq!(ack.message_type(), Some(5));
+        assert_eq!(ack.yiaddr, a("10.100.0.100"));
+        assert_eq!(responder.leases.len(), 1);
+        assert_eq!(responder.leases[0].hostname.as_deref(), Some("violin"));
+
+        // another client asking for the leased address is refused
+        chaddr[5] = 0xee;
+        let req = Packet {
+            chaddr,
+            ..request(3, vec![(50, vec![10, 100, 0, 100])])
+        };
+        let nak = responder.handle(&req, now).unwrap();
+        assert_eq!(nak.message_type(), Some(6));
+        let discover = Packet {
+            chaddr,
+            ..request(1, Vec::new())
+        };
+        let offer = responder.handle(&discover, now).unwrap();
+        assert_eq!(offer.yiaddr, a("10.100.0.101"));
+
+        let scratch = Scratch::new("dhcp")?;
+        let dir = &scratch.dir;
+        assert!(dhcp::leases(dir)?.is_empty());
+        dhcp::save_leases(dir, &responder.leases)?;
+        assert_eq!(dhcp::leases(dir)?, responder.leases);
+
+        Ok(())
+    }
+
+    /// Test that stopping the responder leaves alone the process named in a
+    /// pid file no responder holds, here the test itself.
+    #[test]
+    fn dhcp_stop_stale() -> Result<()> {
+        use crate::ops::fake;
+        use std::sync::{Arc, Mutex};
+
+        let scratch = Scratch::new("dhcp_stop")?;
+        let mut r = scratch.runner("dhcp");
+        std::fs::create_dir_all(&r.falcon_dir)?;
+        let pid_file = r.falcon_dir.join("dhcp.pid");
+        std::fs::write(&pid_file, format!("{}\n", std::process::id()))?;
+
+        let calls = Arc::new(Mutex::new(Vec::<String>::new()));
+        let log = calls.clone();
+        let host = fake::backend(move |bin, args| {
+            log.lock()
+                .unwrap()
+                .push(format!("{} {}", bin, args.join(" ")));
+            fake::ok("")
+        });
+        r.set_backend(host);
+        r.dhcp_stop();
+
+        let calls = calls.lock().unwrap();
+        assert!(calls.is_empty(), "{:?}", calls);
+        assert!(!pid_file.exists());
+        Ok(())
+    }
+}
@@ -0,0 +1,334 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+// Copyright 2022 Oxide Computer Company
+
+//! The environment a node's propolis-server runs with. Falcon sets a few
+//! variables of its own, and more can be added per node, e.g. log filters
+//! while debugging propolis. Values given with a `secret:` prefix are applied
+//! without the prefix and redacted wherever the environment is shown. They
+//! are not saved with the topology, but to a secrets file in the falcon
+//! directory only its owner can read, which hyperstart resolves them from.
+
+use crate::error::Error;
+use crate::{Deployment, Node, NodeRef, Runner};
+use camino::Utf8Path;
+use std::collections::BTreeMap;
+use std::fmt;
+use std::fs;
+use std::io::Write;
+use std::os::unix::fs::{OpenOptionsExt, PermissionsExt};
+
+/// Marks a value that is applied but never shown.
+pub const SECRET_PREFIX: &str = "secret:";
+
+pub(crate) const REDACTED: &str = "<redacted>";
+
+/// The file in the falcon directory holding the secret values of the nodes'
+/// environments.
+pub const SECRETS_FILE: &str = "secrets.ron";
+
+/// Variables falcon sets on every propolis-server. They tie the process back
+/// to its deployment and node.
+pub const FALCON_DEPLOYMENT: &str = "FALCON_DEPLOYMENT";
+pub const FALCON_NODE: &str = "FALCON_NODE";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EnvSource {
+    Falcon,
+    User,
+}
+
+impl fmt::Display for EnvSource {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Falcon => write!(f, "falcon"),
+            Self::User => write!(f, "user"),
+        }
+    }
+}
+
+/// A variable in the effective environment.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct EnvVar {
+    pub key: String,
+    /// The value applied, without any `secret:` prefix.
+    pub value: String,
+    pub secret: bool,
+    pub source: EnvSource,
+}
+
+impl EnvVar {
+    /// The value as it may be shown.
+    pub fn display_value(&self) -> &str {
+        if self.secret {
+            REDACTED
+        } else {
+            &self.value
+        }
+    }
+}
+
+/// The environment of a node's propolis-server.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Environment {
+    pub vars: Vec<EnvVar>,
+    /// Keys given for the node that falcon sets itself, and so were ignored.
+    pub overridden: Vec<String>,
+}
+
+impl Environment {
+    /// The effective environment of `node` in `deployment`. Falcon's own
+    /// variables win over any the node was given with the same key. Fails
+    /// when a secret value is not known, as for a topology read back without
+    /// its secrets file.
+    pub fn for_node(deployment: &str, node: &Node) -> Result<Self, Error> {
+        let mut vars = vec![
+            EnvVar {
+                key: FALCON_DEPLOYMENT.into(),
+                value: deployment.into(),
+                secret: false,
+                source: EnvSource::Falcon,
+            },
+            EnvVar {
+                key: FALCON_NODE.into(),
+                value: node.name.clone(),
+                secret: false,
+                source: EnvSource::Falcon,
+            },
+        ];
+        let mut overridden = Vec::new();
+        for (key, value) in node.propolis_env.iter() {
+            if vars.iter().any(|v| v.key == *key) {
+                overridden.push(key.clone());
+                continue;
+            }
+            let (value, secret) = match value.strip_prefix(SECRET_PREFIX) {
+                // the value is kept with the node's secrets
+                Some("") => match node.secrets.get(key) {
+                    Some(v) => (v.clone(), true),
+                    None => {
+                        return Err(Error::PropolisEnv(format!(
+                            "the secret value of {} on {} is not known, \
+                            launch the topology again to provide it",
+                            key, node.name
+                        )))
+                    }
+                },
+                // saved in full by an older falcon
+                Some(v) => (v.to_string(), true),
+                None => (value.clone(), false),
+            };
+            vars.push(EnvVar {
+                key: key.clone(),
+                value,
+                secret,
+                source: EnvSource::User,
+            });
+        }
+        Ok(Environment { vars, overridden })
+    }
+
+    /// The variables to apply to the process.
+    pub fn pairs(&self) -> impl Iterator<Item = (&str, &str)> {
+        self.vars.iter().map(|v| (v.key.as_str(), v.value.as_str()))
+    }
+
+    /// Record the environment with secrets redacted in
+    /// `<falcon_dir>/<node>.env`, next to the node's instance spec.
+    pub fn record(
+        &self,
+        falcon_dir: &Utf8Path,
+        node: &str,
+    ) -> Result<(), Error> {
+        fs::write(falcon_dir.join(format!("{}.env", node)), self.to_string())?;
+        Ok(())
+    }
+}
+
+/// One `KEY=value` line per variable, secrets redacted.
+impl fmt::Display for Environment {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for v in self.vars.iter() {
+            writeln!(f, "{}={}", v.key, v.display_value())?;
+        }
+        Ok(())
+    }
+}
+
+/// Save the secret environment values of the nodes of `d` to the secrets
+/// file in `falcon_dir`, readable only by its owner. The file is removed
+/// when there are none.
+pub(crate) fn save_secrets(
+    falcon_dir: &Utf8Path,
+    d: &Deployment,
+) -> Result<(), Error> {
+    let path = falcon_dir.join(SECRETS_FILE);
+    let secrets: BTreeMap<&str, &BTreeMap<String, String>> = d
+        .nodes
+        .iter()
+        .filter(|n| !n.secrets.is_empty())
+        .map(|n| (n.name.as_str(), &n.secrets))
+        .collect();
+    if secrets.is_empty() {
+        return match fs::remove_file(&path) {
+            Err(e) if e.kind() != std::io::ErrorKind::NotFound => Err(e.into()),
+            _ => Ok(()),
+        };
+    }
+    let mut f = fs::OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .mode(0o600)
+        .open(&path)?;
+    // the mode only applies to a new file
+    f.set_permissions(fs::Permissions::from_mode(0o600))?;
+    f.write_all(ron::ser::to_string(&secrets)?.as_bytes())?;
+    Ok(())
+}
+
+/// Fill in the secret environment values of the nodes of `d` from the
+/// secrets file in `falcon_dir`, if there is one.
+pub(crate) fn load_secrets(
+    falcon_dir: &Utf8Path,
+    d: &mut Deployment,
+) -> Result<(), Error> {
+    let s = match fs::read_to_string(falcon_dir.join(SECRETS_FILE)) {
+        Ok(s) => s,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(()),
+        Err(e) => return Err(e.into()),
+    };
+    let mut secrets: BTreeMap<String, BTreeMap<String, String>> =
+        ron::de::from_str(&s)?;
+    for n in d.nodes.iter_mut() {
+        if let Some(s) = secrets.remove(&n.name) {
+            n.secrets = s;
+        }
+    }
+    Ok(())
+}
+
+impl Runner {
+    /// Set an environment variable on the node's propolis-server. A value
+    /// prefixed with `secret:` is applied without the prefix and redacted
+    /// wherever the environment is shown. Keys must be non-empty and cannot
+    /// contain `=`.
+    pub fn propolis_env(
+        &mut self,
+        n: NodeRef,
+        key: impl AsRef<str>,
+        value: impl AsRef<str>,
+    ) -> Result<(), Error> {
+        let key = key.as_ref();
+        if key.is_empty() || key.contains('=') || key.contains('\0') {
+            return Err(Error::PropolisEnv(format!(
+                "{:?} is not a valid variable name",
+                key
+            )));
+        }
+        let node = &mut self.deployment.nodes[n.index];
+        let value = value.as_ref();
+        match value.strip_prefix(SECRET_PREFIX) {
+            Some(secret) => {
+                node.secrets.insert(key.into(), secret.into());
+                node.propolis_env.insert(key.into(), SECRET_PREFIX.into());
+            }
+            None => {
+                node.secrets.remove(key);
+                node.propolis_env.insert(key.into(), value.into());
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::test::Scratch;
+    use anyhow::Result;
+
+    /// Test that a node's propolis-server environment combines falcon's own
+    /// variables with the node's, keeps falcon's on a conflict, and applies
+    /// secret values while redacting them from anything shown or recorded.
+    #[test]
+    fn propolis_environment() -> Result<()> {
+        use crate::env::{
+            save_secrets, EnvSource, Environment, FALCON_NODE, SECRETS_FILE,
+        };
+        use ron::ser::{to_string_pretty, PrettyConfig};
+        use std::os::unix::fs::PermissionsExt;
+
+        let mut r = crate::Runner::new("envs");
+        r.persistent = true;
+        let violin = r.node("violin", "helios-2.3", 1, 1024);
+        let piano = r.node("piano", "helios-2.3", 1, 1024);
+        r.propolis_env(violin, "RUST_LOG", "debug")?;
+        r.propolis_env(violin, FALCON_NODE, "cello")?;
+        r.propolis_env(violin, "API_TOKEN", "secret:hunter2")?;
+        for bad in ["", "A=B", "NUL\0"] {
+            assert!(r.propolis_env(violin, bad, "x").is_err(), "{:?}", bad);
+        }
+
+        let env = Environment::for_node("envs", &r.deployment.nodes[0])?;
+        let pairs: Vec<(&str, &str)> = env.pairs().collect();
+        assert_eq!(
+            pairs,
+            [
+                ("FALCON_DEPLOYMENT", "envs"),
+                ("FALCON_NODE", "violin"),
+                ("API_TOKEN", "hunter2"),
+                ("RUST_LOG", "debug"),
+            ]
+        );
+        assert_eq!(env.overridden, ["FALCON_NODE"]);
+        assert_eq!(env.vars[2].source, EnvSource::User);
+        assert_eq!(env.vars[2].display_value(), "<redacted>");
+        assert_eq!(
+            env.to_string(),
+            "FALCON_DEPLOYMENT=envs\n\
+            FALCON_NODE=violin\n\
+            API_TOKEN=<redacted>\n\
+            RUST_LOG=debug\n"
+        );
+
+        // other nodes only get falcon's variables
+        let env =
+            Environment::for_node("envs", &r.deployment.nodes[piano.index])?;
+        assert_eq!(env.vars.len(), 2);
+        assert!(env.overridden.is_empty());
+
+        // the additions persist with the topology so hyperstart applies them
+        // too, secrets only by reference
+        let pretty = PrettyConfig::new();
+        let ron = to_string_pretty(&r.deployment, pretty)?;
+        assert!(!ron.contains("hunter2"));
+        let d = crate::Deployment::from_ron(&ron)?;
+        assert_eq!(d.nodes[0].propolis_env, r.deployment.nodes[0].propolis_env);
+        assert!(Environment::for_node("envs", &d.nodes[0]).is_err());
+
+        let scratch = Scratch::new("env")?;
+        let dir = &scratch.dir;
+        std::fs::write(dir.join("topology.ron"), &ron)?;
+        save_secrets(dir, &r.deployment)?;
+        let mode = std::fs::metadata(dir.join(SECRETS_FILE))?
+            .permissions()
+            .mode();
+        assert_eq!(mode & 0o777, 0o600);
+        let d = crate::Deployment::load(dir)?;
+        let env = Environment::for_node("envs", &d.nodes[0])?;
+        assert_eq!(env.vars[2].value, "hunter2");
+
+        env.record(dir, "violin")?;
+        let recorded = std::fs::read_to_string(dir.join("violin.env"))?;
+        assert!(recorded.contains("API_TOKEN=<redacted>\n"));
+        assert!(!recorded.contains("hunter2"));
+
+        // without secrets the file goes away
+        save_secrets(dir, &crate::Deployment::new("envs"))?;
+        assert!(!dir.join(SECRETS_FILE).exists());
+
+        Ok(())
+    }
+}
@@ -4,7 +4,10 @@
 
 // Copyright 2022 Oxide Computer Company
 
-use std::{ffi, io, str};
+use crate::health::Verdict;
+use crate::report::DestroyReport;
+use camino::Utf8PathBuf;
+use std::{ffi, fmt, io, str};
 use thiserror::Error;
 
 /// Error conditions that can be emitted by Falcon
@@ -32,7 +35,9 @@ pub enum Error {
     #[error("cli: {0}")]
     Cli(String),
     Ron(#[from] ron::Error),
+    Json(#[from] serde_json::Error),
     TomL(#[from] toml::ser::Error),
+    TomlDe(#[from] toml::de::Error),
     AddrParse(#[from] std::net::AddrParseError),
     Propolis(#[from] propolis_client::Error),
     PropolisTypes(
@@ -46,4 +51,83 @@ pub enum Error {
     #[error("no ports available")]
     NoPorts,
     Zfs(String),
+    #[error("unsupported guest: {0}")]
+    UnsupportedGuest(String),
+    #[error("svc: {0}")]
+    Svc(String),
+    #[error("service {fmri} is in maintenance\n{detail}")]
+    SmfMaintenance {
+        fmri: String,
+        detail: String,
+    },
+    #[error("config: {0}")]
+    Config(String),
+    #[error("{problem}\nfix: {fix}")]
+    Environment {
+        problem: String,
+        fix: String,
+    },
+    #[error("role: {0}")]
+    Role(String),
+    #[error("propolis environment: {0}")]
+    PropolisEnv(String),
+    #[error("management network: {0}")]
+    Mgmt(String),
+    #[error("link: {0}")]
+    Link(String),
+    #[error("dhcp: {0}")]
+    Dhcp(String),
+    #[error("image name {0}")]
+    ImageName(String),
+    #[error("no such image: {0}")]
+    NoSuchImage(String),
+    #[error("image {0} exists but has no @base snapshot")]
+    NoBaseSnapshot(String),
+    #[error(
+        "{what} is not supported by propolis {built_against}, needs {feature}"
+    )]
+    Unsupported {
+        what: String,
+        /// The propolis API feature the operation needs.
+        feature: PropolisFeature,
+        /// The propolis revision falcon's client is built from.
+        built_against: String,
+        /// The first propolis revision with the feature, `None` while no
+        /// revision falcon knows of has it.
+        since: Option<String>,
+    },
+    #[error("external peer: {0}")]
+    ExternalPeer(String),
+    #[error("destroy incomplete\n{0}")]
+    Destroy(DestroyReport),
+    #[error(
+        "the last destroy in {dir} failed, destroy again or launch with \
+        --force\n{why}"
+    )]
+    DestroyFailed {
+        dir: Utf8PathBuf,
+        why: String,
+    },
+    #[error("{error}\nconsole output: {log}")]
+    Captured {
+        error: Box<Error>,
+        log: Utf8PathBuf,
+    },
+    #[error("topology is {0}")]
+    Unhealthy(Verdict),
+}
+
+/// Parts of the propolis-server API that some falcon operations need.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PropolisFeature {
+    /// Endpoints that attach and detach devices of a running instance.
+    DeviceHotplug,
+}
+
+impl fmt::Display for PropolisFeature {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::DeviceHotplug => write!(f, "device hot-plug endpoints"),
+        }
+    }
 }
@@ -0,0 +1,319 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+// Copyright 2022 Oxide Computer Company
+
+//! A per-user log of timed operations. The log outlives individual
+//! topologies so slowdowns across many launch/destroy cycles, such as pool
+//! fragmentation making clones slower, show up in `falcon timings`.
+//!
+//! Each line of the log is a JSON encoded [`Event`].
+
+use crate::error::Error;
+use camino::{Utf8Path, Utf8PathBuf};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fs::OpenOptions;
+use std::io::{BufRead, Write};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// A single timed operation.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Event {
+    /// Seconds since the unix epoch at which the operation finished.
+    pub time: u64,
+    pub deployment: String,
+    /// The kind of operation, e.g. `launch`, `destroy` or `exec`.
+    pub op: String,
+    /// The node operated on, `None` for topology wide operations.
+    #[serde(default)]
+    pub node: Option<String>,
+    pub duration_ms: u64,
+    pub ok: bool,
+    /// Further detail, e.g. the core file a crashed instance left.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub detail: Option<String>,
+}
+
+/// Aggregated durations of one operation on one node, or of a topology wide
+/// operation when `node` is `None`.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct Timing {
+    pub op: String,
+    pub node: Option<String>,
+    pub count: usize,
+    pub min_ms: u64,
+    pub median_ms: u64,
+    pub p95_ms: u64,
+}
+
+/// The location of the events log, `FALCON_EVENTS_LOG` if set, otherwise
+/// `falcon/events.log` under the user's state directory.
+pub fn log_path() -> Option<Utf8PathBuf> {
+    if let Ok(s) = std::env::var("FALCON_EVENTS_LOG") {
+        if !s.is_empty() {
+            return Some(s.into());
+        }
+    }
+    Some(state_dir()?.join("events.log"))
+}
+
+/// Falcon's directory under the user's state directory, `XDG_STATE_HOME` if
+/// set, otherwise `~/.local/state`.
+pub(crate) fn state_dir() -> Option<Utf8PathBuf> {
+    let base = match std::env::var("XDG_STATE_HOME") {
+        Ok(s) if !s.is_empty() => Utf8PathBuf::from(s),
+        _ => match std::env::var("HOME") {
+            Ok(s) if !s.is_empty() => {
+                Utf8PathBuf::from(s).join(".local").join("state")
+            }
+            _ => return None,
+        },
+    };
+    Some(base.join("falcon"))
+}
+
+/// Append an event to the events log `log`, if any. Recording is best
+/// effort, a log that cannot be written never fails the operation being timed.
+pub(crate) fn record(
+    log: Option<&Utf8Path>,
+    deployment: &str,
+    op: &str,
+    node: Option<&str>,
+    duration: Duration,
+    ok: bool,
+) {
+    let path = match log {
+        Some(p) => p,
+        None => return,
+    };
+    let event = Event {
+        time: now(),
+        deployment: deployment.into(),
+        op: op.into(),
+        node: node.map(Into::into),
+        duration_ms: duration.as_millis() as u64,
+        ok,
+        detail: None,
+    };
+    let _ = append(path, &event);
+}
+
+/// Record that a node's propolis instance crashed, leaving `core`.
+pub(crate) fn record_crash(
+    log: Option<&Utf8Path>,
+    deployment: &str,
+    node: &str,
+    core: &Utf8Path,
+) {
+    record_failure(log, deployment, "crash", node, core);
+}
+
+/// Record that a node's guest panicked, with its panic output in `saved`.
+pub(crate) fn record_panic(
+    log: Option<&Utf8Path>,
+    deployment: &str,
+    node: &str,
+    saved: &Utf8Path,
+) {
+    record_failure(log, deployment, "panic", node, saved);
+}
+
+/// Record that a console trigger fired on `node` for `line`, and how its
+/// action went.
+pub(crate) fn record_trigger(
+    log: Option<&Utf8Path>,
+    deployment: &str,
+    node: &str,
+    duration: Duration,
+    ok: bool,
+    line: &str,
+) {
+    let path = match log {
+        Some(p) => p,
+        None => return,
+    };
+    let event = Event {
+        time: now(),
+        deployment: deployment.into(),
+        op: "react".into(),
+        node: Some(node.into()),
+        duration_ms: duration.as_millis() as u64,
+        ok,
+        detail: Some(line.into()),
+    };
+    let _ = append(path, &event);
+}
+
+fn record_failure(
+    log: Option<&Utf8Path>,
+    deployment: &str,
+    op: &str,
+    node: &str,
+    detail: &Utf8Path,
+) {
+    let path = match log {
+        Some(p) => p,
+        None => return,
+    };
+    let event = Event {
+        time: now(),
+        deployment: deployment.into(),
+        op: op.into(),
+        node: Some(node.into()),
+        duration_ms: 0,
+        ok: false,
+        detail: Some(detail.to_string()),
+    };
+    let _ = append(path, &event);
+}
+
+/// The events log lines about `deployment` since the destroy before its
+/// latest one, oldest first. That is its current life when the latest event
+/// is the destroy ending it.
+pub(crate) fn lifetime(log: impl BufRead, deployment: &str) -> Vec<String> {
+    let mut lines = Vec::new();
+    for line in log.lines() {
+        let line = match line {
+            Ok(l) => l,
+            Err(_) => break,
+        };
+        let e: Event = match serde_json::from_str(&line) {
+            Ok(e) => e,
+            Err(_) => continue,
+        };
+        if e.deployment != deployment {
+            continue;
+        }
+        lines.push((e.op == "destroy" && e.node.is_none() && e.ok, line));
+    }
+    // everything after the last successful destroy that is not the final
+    // entry
+    let last = lines.len().saturating_sub(1);
+    let start = lines[..last]
+        .iter()
+        .rposition(|(destroyed, _)| *destroyed)
+        .map_or(0, |i| i + 1);
+    lines.drain(start..).map(|(_, l)| l).collect()
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+fn append(path: &Utf8Path, event: &Event) -> Result<(), Error> {
+    if let Some(dir) = path.parent() {
+        std::fs::create_dir_all(dir)?;
+    }
+    // one write per line so concurrent writers do not interleave
+    let line = format!("{}\n", serde_json::to_string(event)?);
+    OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)?
+        .write_all(line.as_bytes())?;
+    Ok(())
+}
+
+/// Aggregate successful events read line by line from `log`, optionally
+/// limited to a deployment and an operation. Lines that do not parse are
+/// skipped. Results are sorted by operation, with the topology wide row for
+/// an operation ahead of its per node rows.
+pub fn aggregate(
+    log: impl BufRead,
+    deployment: Option<&str>,
+    op: Option<&str>,
+) -> Result<Vec<Timing>, Error> {
+    let mut samples: BTreeMap<(String, Option<String>), Vec<u64>> =
+        BTreeMap::new();
+    for line in log.lines() {
+        let e: Event = match serde_json::from_str(&line?) {
+            Ok(e) => e,
+            Err(_) => continue,
+        };
+        if !e.ok
+            || deployment.map_or(false, |d| d != e.deployment)
+            || op.map_or(false, |o| o != e.op)
+        {
+            continue;
+        }
+        samples
+            .entry((e.op, e.node))
+            .or_default()
+            .push(e.duration_ms);
+    }
+
+    Ok(samples
+        .into_iter()
+        .map(|((op, node), mut d)| {
+            d.sort_unstable();
+            Timing {
+                op,
+                node,
+                count: d.len(),
+                min_ms: d[0],
+                median_ms: percentile(&d, 50),
+                p95_ms: percentile(&d, 95),
+            }
+        })
+        .collect())
+}
+
+/// Nearest rank percentile of sorted, non-empty samples.
+fn percentile(sorted: &[u64], p: usize) -> u64 {
+    let rank = (p * sorted.len() + 99) / 100;
+    sorted[rank.max(1) - 1]
+}
+
+#[cfg(test)]
+mod test {
+    use anyhow::Result;
+
+    #[test]
+    fn event_timings() -> Result<()> {
+        use crate::events::aggregate;
+
+        let log = r#"{"time":1,"deployment":"duo","op":"launch","node":null,"duration_ms":9000,"ok":true}
+{"time":1,"deployment":"duo","op":"launch","node":"violin","duration_ms":4000,"ok":true}
+{"time":1,"deployment":"duo","op":"launch","node":"piano","duration_ms":6000,"ok":true}
+{"time":2,"deployment":"duo","op":"exec","node":"violin","duration_ms":100,"ok":true}
+this line is not an event
+{"time":3,"deployment":"duo","op":"exec","node":"violin","duration_ms":300,"ok":true}
+{"time":4,"deployment":"duo","op":"exec","node":"violin","duration_ms":200,"ok":true}
+{"time":5,"deployment":"duo","op":"exec","node":"violin","duration_ms":90000,"ok":false}
+{"time":6,"deployment":"trio","op":"exec","node":"violin","duration_ms":5,"ok":true}
+{"time":7,"deployment":"duo","op":"destroy","duration_ms":2000,"ok":true}
+"#;
+
+        let t = aggregate(log.as_bytes(), Some("duo"), None)?;
+        let rows: Vec<(&str, Option<&str>, usize)> = t
+            .iter()
+            .map(|t| (t.op.as_str(), t.node.as_deref(), t.count))
+            .collect();
+        assert_eq!(
+            rows,
+            vec![
+                ("destroy", None, 1),
+                ("exec", Some("violin"), 3),
+                ("launch", None, 1),
+                ("launch", Some("piano"), 1),
+                ("launch", Some("violin"), 1),
+            ]
+        );
+        // the failed exec is not counted, the trio exec is another deployment
+        let exec = &t[1];
+        assert_eq!((exec.min_ms, exec.median_ms, exec.p95_ms), (100, 200, 300));
+
+        let t = aggregate(log.as_bytes(), None, Some("exec"))?;
+        assert_eq!(t.len(), 1);
+        assert_eq!((t[0].count, t[0].min_ms), (4, 5));
+
+        assert!(aggregate("".as_bytes(), None, None)?.is_empty());
+
+        Ok(())
+    }
+}
@@ -0,0 +1,517 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+// Copyright 2022 Oxide Computer Company
+
+//! A single answer to whether a topology is okay, for cron jobs and
+//! dashboards. Hypervisor state comes from the same code as `falcon status`,
+//! panics from the same code as `falcon crash list`, link states from the
+//! same code as `falcon link` and leases from the same code as `falcon dhcp
+//! leases`, so health cannot disagree with the detailed commands.
+//!
+//! Propolis servers and host tools are asked concurrently, each within a
+//! short timeout. A check that cannot get an answer is UNKNOWN rather than
+//! failed.
+
+use crate::adopt::fmt_mac;
+use crate::cores::{self, HypervisorState};
+use crate::crash::{self, CrashFile};
+use crate::dhcp::{self, Lease};
+use crate::error::Error;
+use crate::mgmt::mgmt_mac_bytes;
+use crate::{ops, Deployment, Link, Node, Runner};
+use camino::Utf8Path;
+use futures::future::join_all;
+use propolis_client::types::InstanceState;
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::fmt;
+use std::fs;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::time::{SystemTime, UNIX_EPOCH};
+use tokio::time::{timeout, Duration};
+
+/// How healthy something is. Ordered from best to worst, so the verdict of
+/// several checks is the worst of them.
+#[derive(
+    Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Default, Serialize,
+)]
+#[serde(rename_all = "UPPERCASE")]
+pub enum Verdict {
+    #[default]
+    Ok,
+    /// The check could not get an answer, e.g. a host tool failed.
+    Unknown,
+    /// Working, but something needs attention.
+    Degraded,
+    Failed,
+}
+
+impl fmt::Display for Verdict {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Ok => write!(f, "OK"),
+            Self::Unknown => write!(f, "UNKNOWN"),
+            Self::Degraded => write!(f, "DEGRADED"),
+            Self::Failed => write!(f, "FAILED"),
+        }
+    }
+}
+
+/// The outcome of one check of a node or link.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct Check {
+    pub name: &'static str,
+    pub verdict: Verdict,
+    pub detail: String,
+}
+
+impl Check {
+    fn new(
+        name: &'static str,
+        verdict: Verdict,
+        detail: impl Into<String>,
+    ) -> Self {
+        Check {
+            name,
+            verdict,
+            detail: detail.into(),
+        }
+    }
+}
+
+/// The checks of a node or link.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct Health {
+    pub name: String,
+    pub verdict: Verdict,
+    pub checks: Vec<Check>,
+}
+
+impl Health {
+    fn new(name: impl Into<String>, checks: Vec<Check>) -> Self {
+        Health {
+            name: name.into(),
+            verdict: worst(checks.iter().map(|c| c.verdict)),
+            checks,
+        }
+    }
+
+    /// The check with the given name, if it was run.
+    pub fn check(&self, name: &str) -> Option<&Check> {
+        self.checks.iter().find(|c| c.name == name)
+    }
+}
+
+/// The health of a whole topology.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct HealthReport {
+    pub verdict: Verdict,
+    pub nodes: Vec<Health>,
+    pub links: Vec<Health>,
+}
+
+/// Limits applied by the health checks.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct HealthOptions {
+    /// How long each propolis server and host tool has to answer.
+    pub timeout: Duration,
+    /// A pool at least this full, in percent, degrades its nodes.
+    pub disk_threshold: u8,
+    /// Panics older than this are no longer held against a node.
+    pub panic_window: Duration,
+}
+
+impl Default for HealthOptions {
+    fn default() -> Self {
+        HealthOptions {
+            timeout: Duration::from_secs(5),
+            disk_threshold: 90,
+            panic_window: Duration::from_secs(24 * 60 * 60),
+        }
+    }
+}
+
+fn worst(verdicts: impl Iterator<Item = Verdict>) -> Verdict {
+    verdicts.max().unwrap_or_default()
+}
+
+/// A node whose hypervisor is not running has failed, whatever the reason.
+pub(crate) fn hypervisor_check(state: &HypervisorState) -> Check {
+    match state {
+        HypervisorState::Running(pid) => {
+            Check::new("hypervisor", Verdict::Ok, format!("running {}", pid))
+        }
+        HypervisorState::Crashed(core) => Check::new(
+            "hypervisor",
+            Verdict::Failed,
+            format!("crashed, core {}", core.path),
+        ),
+        s => Check::new("hypervisor", Verdict::Failed, s.to_string()),
+    }
+}
+
+/// Panics saved at or after `since`, in seconds since the unix epoch,
+/// degrade a node.
+pub(crate) fn panic_check(files: &[CrashFile], since: u64) -> Check {
+    let recent: Vec<&CrashFile> = files
+        .iter()
+        .filter(|f| f.time >= since && crash::is_panic_log(&f.path))
+        .collect();
+    match recent.last() {
+        None => Check::new("panics", Verdict::Ok, "none"),
+        Some(latest) => Check::new(
+            "panics",
+            Verdict::Degraded,
+            format!("{} recent, latest {}", recent.len(), latest.path),
+        ),
+    }
+}
+
+/// A pool at or above `threshold` percent full degrades the nodes on it.
+pub(crate) fn disk_check(
+    pool: &str,
+    capacities: Result<&BTreeMap<String, u8>, &Error>,
+    threshold: u8,
+) -> Check {
+    let capacities = match capacities {
+        Ok(c) => c,
+        Err(e) => return Check::new("disk", Verdict::Unknown, e.to_string()),
+    };
+    match capacities.get(pool).copied() {
+        None => {
+            Check::new("disk", Verdict::Unknown, format!("{} not listed", pool))
+        }
+        Some(c) if c >= threshold => Check::new(
+            "disk",
+            Verdict::Degraded,
+            format!("{} {}% full", pool, c),
+        ),
+        Some(c) => Check::new("disk", Verdict::Ok, format!("{} {}%", pool, c)),
+    }
+}
+
+/// A link is up when every vnic behind it is. A missing vnic fails it.
+pub(crate) fn link_check(
+    vnics: &[String],
+    states: Result<&BTreeMap<String, String>, &Error>,
+) -> Check {
+    let states = match states {
+        Ok(s) => s,
+        Err(e) => return Check::new("state", Verdict::Unknown, e.to_string()),
+    };
+    let mut verdict = Verdict::Ok;
+    let mut problems = Vec::new();
+    for v in vnics {
+        match states.get(v).map(String::as_str) {
+            Some("up") => {}
+            Some(state) => {
+                verdict = verdict.max(Verdict::Degraded);
+                problems.push(format!("{} {}", v, state));
+            }
+            None => {
+                verdict = Verdict::Failed;
+                problems.push(format!("{} missing", v));
+            }
+        }
+    }
+    if problems.is_empty() {
+        return Check::new("state", Verdict::Ok, "up");
+    }
+    Check::new("state", verdict, problems.join(", "))
+}
+
+/// The lease held by a node's management interface, if it holds one, must
+/// not have run out.
+pub(crate) fn lease_check(
+    mac: &str,
+    leases: Result<&[Lease], &Error>,
+    now: u64,
+) -> Check {
+    let leases = match leases {
+        Ok(l) => l,
+        Err(e) => return Check::new("lease", Verdict::Unknown, e.to_string()),
+    };
+    match leases.iter().find(|l| l.mac == mac) {
+        None => Check::new("lease", Verdict::Ok, "none"),
+        Some(l) => match l.remaining(now) {
+            Some(s) => Check::new(
+                "lease",
+                Verdict::Ok,
+                format!("{} for {}m", l.addr, (s + 59) / 60),
+            ),
+            None => Check::new(
+                "lease",
+                Verdict::Degraded,
+                format!("{} expired", l.addr),
+            ),
+        },
+    }
+}
+
+/// What the host says about everything a topology sits on, collected once
+/// for all of its nodes and links.
+struct HostState {
+    capacities: Result<BTreeMap<String, u8>, Error>,
+    links: Result<BTreeMap<String, String>, Error>,
+    /// Only read when the topology serves DHCP.
+    leases: Option<Result<Vec<Lease>, Error>>,
+    now: u64,
+}
+
+/// Ask the named node's propolis server for the state of its instance.
+async fn propolis_check(
+    falcon_dir: &Utf8Path,
+    node: &str,
+    t: Duration,
+) -> Check {
+    let port = fs::read_to_string(falcon_dir.join(format!("{}.port", node)))
+        .ok()
+        .and_then(|p| p.trim_end().parse::<u16>().ok());
+    let port = match port {
+        Some(p) => p,
+        None => return Check::new("propolis", Verdict::Failed, "port unknown"),
+    };
+    let addr = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)), port);
+    let client = propolis_client::Client::new(&format!("http://{}", addr));
+    match timeout(t, client.instance_get().send()).await {
+        Err(_) => Check::new(
+            "propolis",
+            Verdict::Failed,
+            format!("no answer within {:?}", t),
+        ),
+        Ok(Err(e)) => Check::new(
+            "propolis",
+            Verdict::Failed,
+            format!("unresponsive: {}", e),
+        ),
+        Ok(Ok(resp)) => {
+            let state = resp.into_inner().instance.state;
+            let detail = format!("{:?}", state).to_lowercase();
+            match state {
+                InstanceState::Running => {
+                    Check::new("propolis", Verdict::Ok, detail)
+                }
+                InstanceState::Failed | InstanceState::Destroyed => {
+                    Check::new("propolis", Verdict::Failed, detail)
+                }
+                _ => Check::new("propolis", Verdict::Degraded, detail),
+            }
+        }
+    }
+}
+
+fn link_label(d: &Deployment, l: &Link) -> String {
+    match l.name {
+        Some(ref name) => name.clone(),
+        None => {
+            let [a, b] = d.link_nodes(l);
+            format!("{}-{}", a.name, b.name)
+        }
+    }
+}
+
+impl Runner {
+    /// Check every node and link of the topology.
+    pub async fn health(
+        &self,
+        opts: &HealthOptions,
+    ) -> Result<HealthReport, Error> {
+        let d = &self.deployment;
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or(0);
+
+        let mut states = Vec::new();
+        for n in d.iter_nodes() {
+            states.push(cores::hypervisor_state(&self.falcon_dir, &n.name)?);
+        }
+
+        // only running hypervisors are asked, the rest already failed
+        let probes = join_all(d.iter_nodes().zip(states.iter()).map(
+            |(n, s)| async move {
+                match s {
+                    HypervisorState::Running(_) => Some(
+                        propolis_check(&self.falcon_dir, &n.name, opts.timeout)
+                            .await,
+                    ),
+                    _ => None,
+                }
+            },
+        ));
+        let (probes, capacities, links) = tokio::join!(
+            probes,
+            ops::pool_capacities(self.backend(), opts.timeout),
+            ops::link_states(self.backend(), opts.timeout),
+        );
+        let host = HostState {
+            capacities,
+            links,
+            leases: d.dhcp.as_ref().map(|_| dhcp::leases(&self.falcon_dir)),
+            now,
+        };
+
+        let mut nodes = Vec::new();
+        for (((i, n), state), probe) in d
+            .iter_nodes()
+            .enumerate()
+            .zip(states.iter())
+            .zip(probes.into_iter())
+        {
+            nodes.push(self.node_health(i, n, state, probe, &host, opts)?);
+        }
+
+        let links: Vec<Health> = d
+            .iter_links()
+            .map(|l| {
+                let vnics: Vec<String> =
+                    l.endpoints.iter().map(|e| d.vnic_link_name(e)).collect();
+                Health::new(
+                    link_label(d, l),
+                    vec![link_check(&vnics, host.links.as_ref())],
+                )
+            })
+            .collect();
+
+        Ok(HealthReport {
+            verdict: worst(nodes.iter().chain(links.iter()).map(|h| h.verdict)),
+            nodes,
+            links,
+        })
+    }
+
+    fn node_health(
+        &self,
+        index: usize,
+        n: &Node,
+        state: &HypervisorState,
+        probe: Option<Check>,
+        host: &HostState,
+        opts: &HealthOptions,
+    ) -> Result<Health, Error> {
+        let since = host.now.saturating_sub(opts.panic_window.as_secs());
+        let mut checks = vec![hypervisor_check(state)];
+        checks.extend(probe);
+        let crashes = crash::node_crashes(&self.falcon_dir, &n.name)?;
+        checks.push(panic_check(&crashes, since));
+        checks.push(disk_check(
+            ops::pool_of(&n.topo_dataset),
+            host.capacities.as_ref(),
+            opts.disk_threshold,
+        ));
+        if let Some(ref leases) = host.leases {
+            let mac = fmt_mac(&mgmt_mac_bytes(index));
+            checks.push(lease_check(
+                &mac,
+                leases.as_ref().map(Vec::as_slice),
+                host.now,
+            ));
+        }
+        Ok(Health::new(n.name.clone(), checks))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::test::Scratch;
+    use anyhow::Result;
+
+    /// Test that health rolls the checks of every node and link up into a
+    /// single verdict, with the worst check deciding, that nodes whose
+    /// hypervisor is not running are not probed, and that checks whose host
+    /// tool fails are unknown rather than failed.
+    #[tokio::test]
+    async fn topology_health() -> Result<()> {
+        use crate::dhcp::{save_leases, DhcpConfig, Lease};
+        use crate::health::{HealthOptions, Verdict};
+        use crate::ops::fake;
+
+        let scratch = Scratch::new("health")?;
+        let mut r = scratch.runner("health");
+        let violin = r.node("violin", "helios-2.3", 1, 1024);
+        let piano = r.node("piano", "helios-2.3", 1, 1024);
+        let l = r.link(violin, piano);
+        r.name_link(l, "backbone")?;
+        r.deployment.dhcp = Some(DhcpConfig::new(
+            "10.0.0.100".parse()?..="10.0.0.199".parse()?,
+        ));
+
+        let d = &r.deployment;
+        let up = d.vnic_link_name(&d.links[0].endpoints[0]);
+        let states = format!("{}:up\n", up);
+        let host = fake::backend(move |bin, _| match bin {
+            "/usr/sbin/zpool" => fake::ok("rpool\t93\n"),
+            _ => fake::ok(states.clone()),
+        });
+        r.set_backend(host);
+
+        // violin is running but has no port recorded, piano was never launched
+        let panics = r.falcon_dir.join("crash").join("violin");
+        std::fs::create_dir_all(&panics)?;
+        std::fs::write(
+            r.falcon_dir.join("violin.pid"),
+            std::process::id().to_string(),
+        )?;
+        std::fs::write(panics.join("panic-1.txt"), "panic[cpu0]")?;
+        save_leases(
+            &r.falcon_dir,
+            &[Lease {
+                mac: "2:fa:1c:0:0:0".into(),
+                addr: "10.0.0.100".parse()?,
+                hostname: Some("violin".into()),
+                expires: 1,
+            }],
+        )?;
+
+        let report = r.health(&HealthOptions::default()).await?;
+
+        let v = &report.nodes[0];
+        assert_eq!(v.check("hypervisor").map(|c| c.verdict), Some(Verdict::Ok));
+        assert_eq!(
+            v.check("propolis").map(|c| c.detail.as_str()),
+            Some("port unknown")
+        );
+        assert_eq!(
+            v.check("panics").map(|c| c.verdict),
+            Some(Verdict::Degraded)
+        );
+        assert_eq!(
+            v.check("disk").map(|c| c.detail.as_str()),
+            Some("rpool 93% full")
+        );
+        assert_eq!(
+            v.check("lease").map(|c| c.detail.as_str()),
+            Some("10.0.0.100 expired")
+        );
+        assert_eq!(v.verdict, Verdict::Failed);
+
+        let p = &report.nodes[1];
+        assert!(p.check("propolis").is_none());
+        assert_eq!(p.check("lease").map(|c| c.detail.as_str()), Some("none"));
+        assert_eq!(
+            p.check("hypervisor").map(|c| c.detail.as_str()),
+            Some("stopped")
+        );
+
+        let link = &report.links[0];
+        assert_eq!(link.name, "backbone");
+        assert_eq!(link.verdict, Verdict::Failed);
+        assert!(link.checks[0].detail.ends_with("_vnic0 missing"));
+
+        assert_eq!(report.verdict, Verdict::Failed);
+        let json = serde_json::to_string(&report)?;
+        assert!(json.starts_with("{\"verdict\":\"FAILED\""));
+
+        // host tools that fail leave their checks unknown
+        r.set_backend(fake::backend(|_, _| fake::fail("permission denied")));
+        let report = r.health(&HealthOptions::default()).await?;
+        let p = &report.nodes[1];
+        assert_eq!(p.check("disk").map(|c| c.verdict), Some(Verdict::Unknown));
+        assert_eq!(report.links[0].verdict, Verdict::Unknown);
+        assert_eq!(report.links[0].checks[0].detail, "exec: permission denied");
+
+        Ok(())
+    }
+}
@@ -0,0 +1,151 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+// Copyright 2022 Oxide Computer Company
+
+//! Adding and removing NICs on running nodes. Hot-plug needs propolis-server
+//! endpoints that attach and detach devices of a running instance. The
+//! propolis API falcon is built against has none, so requests are checked
+//! and then refused with `Error::Unsupported`, before anything on the host
+//! or in the topology is changed. The error names the missing
+//! [`PropolisFeature`] so callers can tell hot-plug apart from other
+//! unsupported operations.
+
+use crate::cores::{self, HypervisorState};
+use crate::error::{Error, PropolisFeature};
+use crate::{LinkRef, NodeRef, Runner};
+
+/// The propolis revision falcon's client is built from.
+pub const PROPOLIS_REV: &str = "d6fc6d4";
+
+impl Runner {
+    /// Add a link between the referenced running node and `peer`, plugging
+    /// a new NIC into each.
+    pub async fn hotplug_link(
+        &mut self,
+        node: NodeRef,
+        peer: NodeRef,
+    ) -> Result<LinkRef, Error> {
+        if node.index == peer.index {
+            return Err(Error::Link("cannot link a node to itself".into()));
+        }
+        self.hotplug_check(node)?;
+        self.hotplug_check(peer)?;
+        Err(self.hotplug_unsupported("adding a NIC"))
+    }
+
+    /// Unplug the referenced node's NIC on `link` and remove the link.
+    pub async fn hotunplug(
+        &mut self,
+        node: NodeRef,
+        link: LinkRef,
+    ) -> Result<(), Error> {
+        let l = match self.deployment.links.iter().find(|l| l.id == link.id) {
+            Some(l) => l,
+            None => return Err(Error::NotFound(format!("link {}", link.id))),
+        };
+        if !l.endpoints.iter().any(|e| e.node.index == node.index) {
+            return Err(Error::Link(format!(
+                "{} is not on link {}",
+                self.get_node(node).name,
+                l.id
+            )));
+        }
+        for e in l.endpoints.iter() {
+            self.hotplug_check(e.node)?;
+        }
+        Err(self.hotplug_unsupported("removing a NIC"))
+    }
+
+    /// Hot-plug only applies to nodes with a running hypervisor.
+    fn hotplug_check(&self, node: NodeRef) -> Result<(), Error> {
+        let name = &self.get_node(node).name;
+        match cores::hypervisor_state(&self.falcon_dir, name)? {
+            HypervisorState::Running(_) => Ok(()),
+            s => Err(Error::Link(format!(
+                "{} is {}, hot-plug needs a running node",
+                name, s
+            ))),
+        }
+    }
+
+    fn hotplug_unsupported(&self, what: &str) -> Error {
+        Error::Unsupported {
+            what: format!("{} on a running node", what),
+            feature: PropolisFeature::DeviceHotplug,
+            built_against: PROPOLIS_REV.into(),
+            since: None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::test::Scratch;
+    use anyhow::{anyhow, Result};
+
+    /// Test that NIC hot-plug checks its arguments and node states, then
+    /// reports that the propolis falcon is built against cannot hot-plug
+    /// devices.
+    #[tokio::test]
+    async fn nic_hotplug() -> Result<()> {
+        use crate::error::{Error, PropolisFeature};
+        use crate::hotplug::PROPOLIS_REV;
+
+        let scratch = Scratch::new("hotplug")?;
+        let mut r = scratch.runner("hotplug");
+        let violin = r.node("violin", "helios-2.3", 1, 1024);
+        let piano = r.node("piano", "helios-2.3", 1, 1024);
+        let l = r.link(violin, piano);
+        std::fs::create_dir_all(&r.falcon_dir)?;
+
+        match r.hotplug_link(violin, violin).await {
+            Err(Error::Link(msg)) => assert!(msg.contains("itself"), "{}", msg),
+            other => return Err(anyhow!("self link: {:?}", other.map(|_| ()))),
+        }
+        match r.hotplug_link(violin, piano).await {
+            Err(Error::Link(msg)) => {
+                assert!(msg.starts_with("violin is stopped"), "{}", msg)
+            }
+            other => return Err(anyhow!("stopped: {:?}", other.map(|_| ()))),
+        }
+
+        // this process stands in for both hypervisors
+        for n in ["violin", "piano"] {
+            std::fs::write(
+                r.falcon_dir.join(format!("{}.pid", n)),
+                format!("{}\n", std::process::id()),
+            )?;
+        }
+        match r.hotplug_link(violin, piano).await {
+            Err(
+                e @ Error::Unsupported {
+                    feature: PropolisFeature::DeviceHotplug,
+                    since: None,
+                    ..
+                },
+            ) => assert_eq!(
+                e.to_string(),
+                format!(
+                    "adding a NIC on a running node is not supported by \
+                    propolis {}, needs device hot-plug endpoints",
+                    PROPOLIS_REV
+                )
+            ),
+            other => return Err(anyhow!("add: {:?}", other.map(|_| ()))),
+        }
+        match r.hotunplug(piano, l).await {
+            Err(Error::Unsupported { what, .. }) => {
+                assert!(what.starts_with("removing a NIC"), "{}", what)
+            }
+            other => return Err(anyhow!("remove: {:?}", other)),
+        }
+
+        // nothing was touched on the way to refusing
+        assert_eq!(r.deployment.links.len(), 1);
+        assert_eq!(r.deployment.nodes[0].radix, 1);
+
+        Ok(())
+    }
+}
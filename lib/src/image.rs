@@ -0,0 +1,972 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+// Copyright 2022 Oxide Computer Company
+
+//! Moving images between hosts as zfs send streams of their `@base`
+//! snapshot. Exported streams can be zstd compressed on the way out. Imports
+//! recognize zstd and gzip streams by their leading bytes and decompress them
+//! on the way in. Images can also be branched into new images on the same
+//! host without launching anything.
+//!
+//! Images are named by `ImageName`, which is checked when it is parsed, and
+//! every path that needs an image on the host looks it up through `resolve`.
+
+use crate::error::Error;
+use crate::snapshot::{PROP_AUTO, PROP_CREATED, PROP_NODE, PROP_PURPOSE};
+use crate::{ops, Backend, Plan, Step, ZFS_BIN};
+use camino::Utf8Path;
+use flate2::read::MultiGzDecoder;
+use serde::{Deserialize, Serialize};
+use std::cell::Cell;
+use std::collections::BTreeMap;
+use std::convert::{TryFrom, TryInto};
+use std::fmt;
+use std::fs;
+use std::io::{self, Cursor, Read, Write};
+use std::process::{Command, Stdio};
+use std::rc::Rc;
+use std::str::FromStr;
+
+const ZSTD_MAGIC: [u8; 4] = [0x28, 0xb5, 0x2f, 0xfd];
+const GZIP_MAGIC: [u8; 2] = [0x1f, 0x8b];
+/// The magic number of a send stream's BEGIN record.
+const DRR_MAGIC: u64 = 0x2f5bacbac;
+/// The length of a send stream's BEGIN record up to the end of its name.
+const DRR_BEGIN_LEN: usize = 312;
+/// Progress is reported each time this many logical bytes have passed.
+const PROGRESS_INTERVAL: u64 = 64 << 20;
+
+/// The longest image name accepted.
+pub const IMAGE_NAME_MAX: usize = 64;
+
+/// The falcon metadata an image carries, inherited by its `@base` snapshot.
+const METADATA: [&str; 4] = [PROP_AUTO, PROP_CREATED, PROP_NODE, PROP_PURPOSE];
+
+/// The name of an image, e.g. `helios-2.3`. Names start with a letter or
+/// digit, continue with letters, digits, `.`, `-` or `_`, and are at most
+/// `IMAGE_NAME_MAX` long, so they are always a single dataset component.
+#[derive(
+    Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize,
+)]
+#[serde(try_from = "String", into = "String")]
+pub struct ImageName(String);
+
+impl ImageName {
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+}
+
+impl FromStr for ImageName {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Error> {
+        let bad = |why: &str| Err(Error::ImageName(format!("'{}' {}", s, why)));
+        if s.is_empty() {
+            return bad("is empty");
+        }
+        if s.len() > IMAGE_NAME_MAX {
+            return bad(&format!("is longer than {}", IMAGE_NAME_MAX));
+        }
+        if !s.starts_with(|c: char| c.is_ascii_alphanumeric()) {
+            return bad("must start with a letter or digit");
+        }
+        let valid = |c: char| c.is_ascii_alphanumeric() || "._-".contains(c);
+        if let Some(c) = s.chars().find(|c| !valid(*c)) {
+            return bad(&format!("contains '{}'", c));
+        }
+        Ok(ImageName(s.into()))
+    }
+}
+
+impl TryFrom<String> for ImageName {
+    type Error = Error;
+
+    fn try_from(s: String) -> Result<Self, Error> {
+        s.parse()
+    }
+}
+
+impl From<ImageName> for String {
+    fn from(n: ImageName) -> Self {
+        n.0
+    }
+}
+
+impl AsRef<str> for ImageName {
+    fn as_ref(&self) -> &str {
+        &self.0
+    }
+}
+
+impl fmt::Display for ImageName {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+/// An image found on the host.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct ImageRef {
+    pub name: ImageName,
+    /// The image dataset, `<image_dataset>/img/<name>`.
+    pub dataset: String,
+    /// The snapshot nodes are cloned from, `<dataset>@base`.
+    pub snapshot: String,
+    /// The zfs guid of `snapshot`.
+    pub guid: String,
+    /// Falcon metadata recorded on the image, e.g. `falcon:purpose`.
+    pub metadata: BTreeMap<String, String>,
+}
+
+/// Find the named image under `image_dataset`.
+pub fn resolve(
+    host: &dyn Backend,
+    image_dataset: &str,
+    name: &ImageName,
+) -> Result<ImageRef, Error> {
+    Ok(resolve_all(host, &[(image_dataset, name)])?.remove(0))
+}
+
+/// Find each `(image_dataset, name)` image, in order, with a single lookup
+/// for all of them. The first image that cannot be used is the error.
+pub fn resolve_all(
+    host: &dyn Backend,
+    images: &[(&str, &ImageName)],
+) -> Result<Vec<ImageRef>, Error> {
+    let snapshots: Vec<String> = images
+        .iter()
+        .map(|(ds, name)| format!("{}/img/{}@base", ds, name))
+        .collect();
+    let names: Vec<&str> = snapshots.iter().map(String::as_str).collect();
+    let mut props = vec!["guid"];
+    props.extend_from_slice(&METADATA);
+    let found = ops::image_props(host, &names, &props)?;
+
+    let mut result = Vec::new();
+    for ((ds, name), snapshot) in images.iter().zip(snapshots.into_iter()) {
+        let dataset = format!("{}/img/{}", ds, name);
+        let mut metadata = match found.get(&snapshot) {
+            Some(m) => m.clone(),
+            None if ops::dataset_exists(host, &dataset)? => {
+                return Err(Error::NoBaseSnapshot(dataset))
+            }
+            None => return Err(Error::NoSuchImage(dataset)),
+        };
+        let guid = metadata.remove("guid").unwrap_or_default();
+        result.push(ImageRef {
+            name: (*name).clone(),
+            dataset,
+            snapshot,
+            guid,
+            metadata,
+        });
+    }
+    Ok(result)
+}
+
+/// How an exported stream is compressed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Compress {
+    Zstd { level: i32 },
+}
+
+impl FromStr for Compress {
+    type Err = Error;
+
+    /// Parse `zstd` or `zstd:<level>`.
+    fn from_str(s: &str) -> Result<Self, Error> {
+        let (alg, level) = match s.split_once(':') {
+            Some((alg, level)) => (alg, Some(level)),
+            None => (s, None),
+        };
+        match (alg, level) {
+            ("zstd", None) => Ok(Compress::Zstd {
+                level: zstd::DEFAULT_COMPRESSION_LEVEL,
+            }),
+            ("zstd", Some(l)) => match l.parse() {
+                Ok(level)
+                    if zstd::compression_level_range().contains(&level) =>
+                {
+                    Ok(Compress::Zstd { level })
+                }
+                _ => Err(Error::Cli(format!(
+                    "zstd level {} is not in {:?}",
+                    l,
+                    zstd::compression_level_range()
+                ))),
+            },
+            _ => Err(Error::Cli(format!(
+                "unsupported compression {}, expected zstd[:level]",
+                s
+            ))),
+        }
+    }
+}
+
+/// The encoding of a stream being imported.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StreamFormat {
+    Raw,
+    Zstd,
+    Gzip,
+}
+
+impl fmt::Display for StreamFormat {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Raw => write!(f, "uncompressed"),
+            Self::Zstd => write!(f, "zstd"),
+            Self::Gzip => write!(f, "gzip"),
+        }
+    }
+}
+
+/// Recognize a stream's encoding from its first bytes.
+pub fn sniff(head: &[u8]) -> StreamFormat {
+    if head.starts_with(&ZSTD_MAGIC) {
+        StreamFormat::Zstd
+    } else if head.starts_with(&GZIP_MAGIC) {
+        StreamFormat::Gzip
+    } else {
+        StreamFormat::Raw
+    }
+}
+
+/// Bytes moved so far. `logical` counts the send stream itself, `compressed`
+/// what was written to or read from the stream file.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Progress {
+    pub logical: u64,
+    pub compressed: u64,
+}
+
+/// Running byte counts shared with the readers and writers of a stream.
+#[derive(Default)]
+pub(crate) struct Counts {
+    logical: Rc<Cell<u64>>,
+    compressed: Rc<Cell<u64>>,
+}
+
+impl Counts {
+    pub(crate) fn get(&self) -> Progress {
+        Progress {
+            logical: self.logical.get(),
+            compressed: self.compressed.get(),
+        }
+    }
+}
+
+/// A reader or writer counting the bytes passing through it.
+struct Counted<T> {
+    inner: T,
+    count: Rc<Cell<u64>>,
+}
+
+impl<T> Counted<T> {
+    fn new(inner: T, count: &Rc<Cell<u64>>) -> Self {
+        Counted {
+            inner,
+            count: count.clone(),
+        }
+    }
+}
+
+impl<R: Read> Read for Counted<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        self.count.set(self.count.get() + n as u64);
+        Ok(n)
+    }
+}
+
+impl<W: Write> Write for Counted<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let n = self.inner.write(buf)?;
+        self.count.set(self.count.get() + n as u64);
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+/// Copy `src` to `dst`, reporting progress every `PROGRESS_INTERVAL` logical
+/// bytes.
+fn copy(
+    src: &mut impl Read,
+    dst: &mut impl Write,
+    counts: &Counts,
+    progress: &mut dyn FnMut(Progress),
+) -> io::Result<()> {
+    let mut buf = vec![0u8; 1 << 20];
+    let mut next = PROGRESS_INTERVAL;
+    loop {
+        let n = match src.read(&mut buf) {
+            Ok(0) => return Ok(()),
+            Ok(n) => n,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e),
+        };
+        dst.write_all(&buf[..n])?;
+        if counts.logical.get() >= next {
+            progress(counts.get());
+            next += PROGRESS_INTERVAL;
+        }
+    }
+}
+
+/// Copy a send stream from `src` to `dst`, compressing it if asked to.
+pub(crate) fn encode_stream(
+    src: impl Read,
+    dst: impl Write,
+    compress: Option<Compress>,
+    progress: &mut dyn FnMut(Progress),
+) -> Result<Progress, Error> {
+    let counts = Counts::default();
+    let mut src = Counted::new(src, &counts.logical);
+    let mut dst = Counted::new(dst, &counts.compressed);
+    match compress {
+        None => {
+            copy(&mut src, &mut dst, &counts, progress)?;
+            dst.flush()?;
+        }
+        Some(Compress::Zstd { level }) => {
+            let mut enc = zstd::Encoder::new(dst, level)?;
+            copy(&mut src, &mut enc, &counts, progress)?;
+            enc.finish()?.flush()?;
+        }
+    }
+    Ok(counts.get())
+}
+
+/// Wrap `src` in whatever decoder its leading bytes call for. Reads of the
+/// result yield the plain send stream.
+pub(crate) fn decoder<'a>(
+    src: impl Read + 'a,
+    counts: &Counts,
+) -> Result<(StreamFormat, Box<dyn Read + 'a>), Error> {
+    let mut src = Counted::new(src, &counts.compressed);
+    let mut head = Vec::new();
+    (&mut src)
+        .take(ZSTD_MAGIC.len() as u64)
+        .read_to_end(&mut head)?;
+    let format = sniff(&head);
+    let src = Cursor::new(head).chain(src);
+    let plain: Box<dyn Read + 'a> = match format {
+        StreamFormat::Raw => Box::new(src),
+        StreamFormat::Zstd => Box::new(zstd::Decoder::new(src)?),
+        // pigz and concatenated gzip files carry several members
+        StreamFormat::Gzip => Box::new(MultiGzDecoder::new(src)),
+    };
+    Ok((format, Box::new(Counted::new(plain, &counts.logical))))
+}
+
+/// The identity of a send stream, from its BEGIN record.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct StreamBegin {
+    /// The guid of the snapshot the stream carries.
+    pub guid: u64,
+    /// The name of the snapshot on the sending side.
+    pub name: String,
+}
+
+/// Parse the BEGIN record at the head of a plain send stream. Streams are
+/// written in the sender's byte order, which the record's magic tells.
+pub(crate) fn parse_begin(head: &[u8]) -> Option<StreamBegin> {
+    if head.len() < DRR_BEGIN_LEN || head[..4] != [0; 4] {
+        return None;
+    }
+    let le =
+        |off: usize| u64::from_le_bytes(head[off..off + 8].try_into().unwrap());
+    let be =
+        |off: usize| u64::from_be_bytes(head[off..off + 8].try_into().unwrap());
+    let guid = if le(8) == DRR_MAGIC {
+        le(40)
+    } else if be(8) == DRR_MAGIC {
+        be(40)
+    } else {
+        return None;
+    };
+    let name = &head[56..DRR_BEGIN_LEN];
+    let end = name.iter().position(|b| *b == 0).unwrap_or(name.len());
+    Some(StreamBegin {
+        guid,
+        name: String::from_utf8_lossy(&name[..end]).into(),
+    })
+}
+
+/// The image whose `@base` snapshot has `guid`, given a `zfs list` of
+/// snapshot names and guids.
+pub(crate) fn find_guid(listing: &str, guid: u64) -> Option<String> {
+    let guid = guid.to_string();
+    ops::parse_list_property(listing)
+        .into_iter()
+        .filter(|(_, g)| *g == guid)
+        .find_map(|(snap, _)| snap.strip_suffix("@base").map(Into::into))
+}
+
+/// Write a send stream of `<image_dataset>/img/<image>@base` to `output`. In
+/// a dry run nothing is sent and no progress is reported.
+pub fn export(
+    plan: &Plan,
+    image_dataset: &str,
+    image: &ImageName,
+    output: &Utf8Path,
+    compress: Option<Compress>,
+    progress: &mut dyn FnMut(Progress),
+) -> Result<Progress, Error> {
+    let snapshot = resolve(plan.backend(), image_dataset, image)?.snapshot;
+    let step =
+        Step::command(ZFS_BIN, &["send", &snapshot, ">", output.as_str()]);
+    plan.step(step, || {
+        let mut send = Command::new(ZFS_BIN)
+            .args(["send", snapshot.as_str()])
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn()?;
+        let stream = match send.stdout.take() {
+            Some(s) => s,
+            None => {
+                return Err(Error::Zfs("zfs send produced no stream".into()))
+            }
+        };
+
+        let result = encode_stream(
+            stream,
+            fs::File::create(output)?,
+            compress,
+            progress,
+        );
+        let sent = send.wait_with_output()?;
+        if !sent.status.success() {
+            let _ = fs::remove_file(output);
+            return Err(Error::Zfs(String::from_utf8(sent.stderr)?));
+        }
+        if result.is_err() {
+            let _ = fs::remove_file(output);
+        }
+        result
+    })
+}
+
+/// What came of an import.
+#[derive(Debug)]
+pub enum ImportOutcome {
+    Received {
+        format: StreamFormat,
+        progress: Progress,
+    },
+    /// The receive was skipped, `existing` already has the stream's guid.
+    Duplicate { existing: String, guid: u64 },
+}
+
+/// Receive the send stream in `input` as `<image_dataset>/img/<image>`. With
+/// `dedup_check` the receive is skipped when an image with the same `@base`
+/// snapshot already exists. In a dry run the stream is only read as far as
+/// its BEGIN record.
+pub fn import(
+    plan: &Plan,
+    image_dataset: &str,
+    image: &ImageName,
+    input: &Utf8Path,
+    dedup_check: bool,
+    progress: &mut dyn FnMut(Progress),
+) -> Result<ImportOutcome, Error> {
+    let counts = Counts::default();
+    let (format, mut stream) = decoder(fs::File::open(input)?, &counts)?;
+    let mut head = Vec::new();
+    (&mut stream)
+        .take(DRR_BEGIN_LEN as u64)
+        .read_to_end(&mut head)?;
+
+    if dedup_check {
+        let begin = parse_begin(&head).ok_or_else(|| {
+            Error::Zfs(format!("{} is not a zfs send stream", input))
+        })?;
+        let img = format!("{}/img", image_dataset);
+        let listing = ops::zfs(
+            plan.backend(),
+            &[
+                "list",
+                "-Hp",
+                "-r",
+                "-d",
+                "2",
+                "-t",
+                "snapshot",
+                "-o",
+                "name,guid",
+                &img,
+            ],
+        )?;
+        if let Some(existing) = find_guid(&listing, begin.guid) {
+            return Ok(ImportOutcome::Duplicate {
+                existing,
+                guid: begin.guid,
+            });
+        }
+    }
+
+    let dest = format!("{}/img/{}", image_dataset, image);
+    let step = Step::command(ZFS_BIN, &["receive", &dest, "<", input.as_str()]);
+    plan.step(step, || {
+        let mut recv = Command::new(ZFS_BIN)
+            .args(["receive", dest.as_str()])
+            .stdin(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn()?;
+        let mut stdin = match recv.stdin.take() {
+            Some(s) => s,
+            None => {
+                return Err(Error::Zfs("zfs receive took no stream".into()))
+            }
+        };
+        let result = copy(
+            &mut Cursor::new(head).chain(stream),
+            &mut stdin,
+            &counts,
+            progress,
+        );
+        drop(stdin);
+        let received = recv.wait_with_output()?;
+        if !received.status.success() {
+            return Err(Error::Zfs(String::from_utf8(received.stderr)?));
+        }
+        Ok(result?)
+    })?;
+
+    Ok(ImportOutcome::Received {
+        format,
+        progress: counts.get(),
+    })
+}
+
+/// Branch image `src` into a new image `dst`, both under
+/// `<image_dataset>/img`. `src@base` is taken if the image has none yet. An
+/// existing `dst` is only replaced with `force`. Falcon metadata recorded on
+/// `src` is carried over, except that `dst` is never subject to pruning.
+pub fn clone(
+    plan: &Plan,
+    image_dataset: &str,
+    src: &ImageName,
+    dst: &ImageName,
+    force: bool,
+) -> Result<(), Error> {
+    if src == dst {
+        return Err(Error::Zfs(format!("cannot clone {} onto itself", src)));
+    }
+    let img = format!("{}/img", image_dataset);
+    let source = format!("{}/{}", img, src);
+    let dest = format!("{}/{}", img, dst);
+    let base = format!("{}@base", source);
+
+    let has_base = match resolve(plan.backend(), image_dataset, src) {
+        Ok(_) => true,
+        Err(Error::NoBaseSnapshot(_)) => false,
+        Err(e) => return Err(e),
+    };
+    let replace = ops::dataset_exists(plan.backend(), &dest)?;
+    if replace && !force {
+        return Err(Error::Zfs(format!(
+            "image {} already exists, use --force to replace it",
+            dst
+        )));
+    }
+    let props = image_props(plan.backend(), &source)?;
+
+    if !has_base {
+        plan.zfs(&["snapshot", &base])?;
+    }
+
+    // An image can still be a clone of the node it was snapshotted from.
+    // Promote it before branching, so neither image depends on topology
+    // state. The new image itself is never promoted, that would take
+    // src@base away from src.
+    let origin = ops::zfs(
+        plan.backend(),
+        &["get", "-Hp", "-o", "value", "origin", &source],
+    )?;
+    let origin = origin.trim();
+    if origin != "-" && !origin.starts_with(&format!("{}/", img)) {
+        plan.zfs(&["promote", &source])?;
+    }
+
+    // Only take the old image away once everything the clone needs from
+    // src is in place.
+    if replace {
+        plan.zfs(&["destroy", "-r", &dest])?;
+    }
+    plan.zfs(&["clone", &base, &dest])?;
+    plan.zfs(&["snapshot", &format!("{}@base", dest)])?;
+
+    if !props.is_empty() {
+        let mut args = vec!["set"];
+        args.extend(props.iter().map(String::as_str));
+        args.push(&dest);
+        plan.zfs(&args)?;
+    }
+    Ok(())
+}
+
+/// The falcon user properties set on `dataset` as `property=value`, leaving
+/// out whether it is subject to pruning.
+fn image_props(
+    host: &dyn Backend,
+    dataset: &str,
+) -> Result<Vec<String>, Error> {
+    let out = ops::zfs(
+        host,
+        &[
+            "get",
+            "-Hp",
+            "-s",
+            "local",
+            "-o",
+            "property,value",
+            "all",
+            dataset,
+        ],
+    )?;
+    Ok(ops::parse_list_property(&out)
+        .into_iter()
+        .filter(|(p, _)| p.starts_with("falcon:") && p != PROP_AUTO)
+        .map(|(p, v)| format!("{}={}", p, v))
+        .collect())
+}
+
+#[cfg(test)]
+mod test {
+    use anyhow::{anyhow, Result};
+
+    /// Test that image streams survive export and import in each format, that
+    /// byte counts reflect both sides of the compression and that a stream's
+    /// identity is read from its BEGIN record.
+    #[test]
+    fn image_stream_round_trip() -> Result<()> {
+        use crate::image::{
+            decoder, encode_stream, find_guid, parse_begin, sniff, Compress,
+            Counts, StreamFormat,
+        };
+        use flate2::write::GzEncoder;
+        use std::io::{Read, Write};
+
+        // a synthetic send stream, BEGIN record first
+        let mut stream = vec![0u8; 8];
+        stream.extend_from_slice(&0x2f5bacbacu64.to_le_bytes());
+        stream.extend_from_slice(&[0u8; 24]);
+        stream.extend_from_slice(&1234567890123u64.to_le_bytes());
+        stream.extend_from_slice(&[0u8; 8]);
+        let mut name = b"rpool/falcon/img/helios@base".to_vec();
+        name.resize(256, 0);
+        stream.extend_from_slice(&name);
+        stream.extend((0..64 * 1024).map(|i| (i % 7) as u8));
+
+        let begin = parse_begin(&stream).ok_or_else(|| anyhow!("no begin"))?;
+        assert_eq!(begin.guid, 1234567890123);
+        assert_eq!(begin.name, "rpool/falcon/img/helios@base");
+        assert_eq!(parse_begin(&stream[..100]), None);
+
+        // streams from big endian senders
+        let mut be = stream.clone();
+        be[8..16].copy_from_slice(&0x2f5bacbacu64.to_be_bytes());
+        be[40..48].copy_from_slice(&1234567890123u64.to_be_bytes());
+        assert_eq!(parse_begin(&be).map(|b| b.guid), Some(1234567890123));
+
+        let import =
+            |data: &[u8]| -> Result<(StreamFormat, Vec<u8>, u64, u64)> {
+                let counts = Counts::default();
+                let (format, mut r) = decoder(data, &counts)?;
+                let mut out = Vec::new();
+                r.read_to_end(&mut out)?;
+                let p = counts.get();
+                Ok((format, out, p.logical, p.compressed))
+            };
+
+        for compress in [None, Some("zstd".parse()?), Some("zstd:19".parse()?)]
+        {
+            let mut file = Vec::new();
+            let p =
+                encode_stream(&stream[..], &mut file, compress, &mut |_| {})?;
+            assert_eq!(p.logical, stream.len() as u64);
+            assert_eq!(p.compressed, file.len() as u64);
+
+            let (format, out, logical, compressed) = import(&file)?;
+            let expected = match compress {
+                None => StreamFormat::Raw,
+                Some(Compress::Zstd { .. }) => StreamFormat::Zstd,
+            };
+            assert_eq!(format, expected);
+            assert_eq!(out, stream);
+            assert_eq!(logical, stream.len() as u64);
+            assert_eq!(compressed, file.len() as u64);
+            if compress.is_some() {
+                assert!(file.len() < stream.len());
+            }
+        }
+
+        let mut gz = GzEncoder::new(Vec::new(), flate2::Compression::default());
+        gz.write_all(&stream)?;
+        let file = gz.finish()?;
+        assert_eq!(sniff(&file), StreamFormat::Gzip);
+        let (format, out, _, compressed) = import(&file)?;
+        assert_eq!(format, StreamFormat::Gzip);
+        assert_eq!(out, stream);
+        assert_eq!(compressed, file.len() as u64);
+
+        // a multi-member gzip file, as pigz writes, decodes to all members
+        let (first, second) = stream.split_at(stream.len() / 2);
+        let mut file = Vec::new();
+        for part in [first, second] {
+            let mut gz =
+                GzEncoder::new(Vec::new(), flate2::Compression::default());
+            gz.write_all(part)?;
+            file.extend(gz.finish()?);
+        }
+        assert_eq!(import(&file)?.1, stream);
+
+        // tiny and empty inputs are passed through untouched
+        assert_eq!(import(b"ab")?.1, b"ab");
+        assert_eq!(import(b"")?.1, b"");
+
+        assert!("zstd:99".parse::<Compress>().is_err());
+        assert!("xz".parse::<Compress>().is_err());
+
+        let listing = "tank/img/debian@base\t42\n\
+            tank/img/helios@base\t1234567890123\n\
+            tank/img/helios@auto\t1234567890123\n";
+        assert_eq!(
+            find_guid(listing, 1234567890123),
+            Some("tank/img/helios".into())
+        );
+        assert_eq!(find_guid(listing, 7), None);
+
+        Ok(())
+    }
+
+    /// Test the zfs commands behind cloning an image. A source that is still a
+    /// clone of a topology dataset is promoted before it is branched, the new
+    /// image is never promoted, metadata other than the pruning flag carries
+    /// over, and an existing destination is only replaced when forced.
+    #[test]
+    fn image_clone_commands() -> Result<()> {
+        use crate::error::Error;
+        use crate::image;
+        use crate::ops::fake;
+        use crate::Plan;
+        use std::sync::{Arc, Mutex};
+
+        let clone = |existing: &'static [&'static str],
+                     origin: &'static str,
+                     src: &str,
+                     force: bool,
+                     dry_run: bool| {
+            let commands = Arc::new(Mutex::new(Vec::<String>::new()));
+            let log = commands.clone();
+            let host = fake::backend(move |_, args| {
+                log.lock().unwrap().push(args.join(" "));
+                match args {
+                    ["list", "-H", "-o", "name", name] => {
+                        if existing.iter().any(|e| e == name) {
+                            fake::ok(format!("{}\n", name))
+                        } else {
+                            fake::fail("dataset does not exist")
+                        }
+                    }
+                    // image resolution, by guid and metadata
+                    ["list", "-Hp", "-t", "all", "-o", _, names @ ..] => {
+                        fake::ok(
+                            names
+                                .iter()
+                                .filter(|n| existing.contains(*n))
+                                .map(|n| format!("{}\t42\t-\t-\t-\t-\n", n))
+                                .collect::<String>(),
+                        )
+                    }
+                    ["get", "-Hp", "-o", "value", "origin", _] => {
+                        fake::ok(format!("{}\n", origin))
+                    }
+                    ["get", "-Hp", "-s", "local", ..] => fake::ok(
+                        "falcon:auto\ton\n\
+                        falcon:node\tviolin\n\
+                        falcon:purpose\tlogin tweaks\n",
+                    ),
+                    _ => fake::ok(""),
+                }
+            });
+            let src = src.parse().unwrap();
+            let dst = "exp".parse().unwrap();
+            let plan = Plan::new(dry_run).with_backend(host);
+            let result = image::clone(&plan, "tank", &src, &dst, force);
+            // only the commands that change anything, or in a dry run
+            // those the plan would have run
+            let planned: Vec<String> = plan
+                .steps()
+                .iter()
+                .filter(|_| dry_run)
+                .map(|s| s.to_string().replacen("run zfs ", "", 1))
+                .collect();
+            let changes: Vec<String> = commands
+                .lock()
+                .unwrap()
+                .iter()
+                .filter(|c| !c.starts_with("list") && !c.starts_with("get"))
+                .cloned()
+                .chain(planned)
+                .collect();
+            (result, changes)
+        };
+
+        let (result, changes) = clone(
+            &["tank/img/helios", "tank/img/helios@base"],
+            "tank/topo/lab/violin@base",
+            "helios",
+            false,
+            false,
+        );
+        result?;
+        assert_eq!(
+            changes,
+            [
+                "promote tank/img/helios",
+                "clone tank/img/helios@base tank/img/exp",
+                "snapshot tank/img/exp@base",
+                "set falcon:node=violin falcon:purpose=login tweaks tank/img/exp",
+            ]
+        );
+
+        // an image already rooted in the images tree is left as is
+        let (result, changes) = clone(
+            &["tank/img/helios", "tank/img/helios@base"],
+            "tank/img/helios-1.0@base",
+            "helios",
+            false,
+            false,
+        );
+        result?;
+        assert!(!changes.iter().any(|c| c.starts_with("promote")));
+
+        // an existing destination needs force, and then only goes right
+        // before the clone replaces it
+        let existing = &["tank/img/helios", "tank/img/exp"];
+        let (result, changes) = clone(existing, "-", "helios", false, false);
+        assert!(matches!(result, Err(Error::Zfs(e)) if e.contains("--force")));
+        assert!(changes.is_empty());
+
+        let (result, changes) = clone(existing, "-", "helios", true, false);
+        result?;
+        assert_eq!(
+            changes[..3],
+            [
+                "snapshot tank/img/helios@base",
+                "destroy -r tank/img/exp",
+                "clone tank/img/helios@base tank/img/exp",
+            ]
+        );
+
+        let (result, changes) = clone(&[], "-", "helios", false, false);
+        assert!(matches!(result, Err(Error::NoSuchImage(_))));
+        assert!(changes.is_empty());
+        let (result, _) = clone(existing, "-", "exp", true, false);
+        assert!(result.is_err());
+
+        // a dry run plans the same changes without making any
+        let (result, changes) = clone(
+            &["tank/img/helios", "tank/img/helios@base"],
+            "tank/topo/lab/violin@base",
+            "helios",
+            false,
+            true,
+        );
+        result?;
+        assert_eq!(
+            changes,
+            [
+                "promote tank/img/helios",
+                "clone tank/img/helios@base tank/img/exp",
+                "snapshot tank/img/exp@base",
+                "set falcon:node=violin falcon:purpose=login tweaks tank/img/exp",
+            ]
+        );
+
+        Ok(())
+    }
+
+    /// Test that image names are checked when parsed, including when read back
+    /// from a topology, and that resolving an image tells a missing image from
+    /// one without a `@base` snapshot and carries the image's metadata.
+    #[test]
+    fn image_resolution() -> Result<()> {
+        use crate::error::Error;
+        use crate::image::{self, ImageName};
+        use crate::ops::fake;
+
+        for good in ["helios-2.3", "debian-11.0", "exp_1", "7up"] {
+            assert_eq!(good.parse::<ImageName>()?.as_str(), good);
+        }
+        let long = "a".repeat(image::IMAGE_NAME_MAX + 1);
+        let bad = ["", "-helios", ".hidden", "a/b", "a@base", "a b"];
+        for bad in bad.iter().copied().chain([long.as_str()]) {
+            assert!(
+                matches!(bad.parse::<ImageName>(), Err(Error::ImageName(_))),
+                "{:?} accepted",
+                bad
+            );
+        }
+        let name: ImageName = ron::de::from_str("\"helios-2.3\"")?;
+        assert_eq!(ron::ser::to_string(&name)?, "\"helios-2.3\"");
+        assert!(ron::de::from_str::<ImageName>("\"../etc\"").is_err());
+
+        let host = fake::backend(|_, args| match args {
+            ["list", "-Hp", "-t", "all", "-o", fields, names @ ..] => {
+                assert!(fields.starts_with("name,guid,"));
+                fake::ok(
+                    names
+                        .iter()
+                        .filter(|n| n.starts_with("tank/img/helios-2.3@"))
+                        .map(|n| {
+                            format!("{}\t42\t-\t-\tviolin\tlogin tweaks\n", n)
+                        })
+                        .collect::<String>(),
+                )
+            }
+            ["list", "-H", "-o", "name", "tank/img/bare"] => {
+                fake::ok("tank/img/bare\n")
+            }
+            _ => fake::fail("dataset does not exist"),
+        });
+        let helios: ImageName = "helios-2.3".parse()?;
+        let bare: ImageName = "bare".parse()?;
+        let gone: ImageName = "gone".parse()?;
+
+        let images = [("tank", &helios), ("tank", &helios)];
+        let found = image::resolve_all(&*host, &images);
+        let bare_result = image::resolve(&*host, "tank", &bare);
+        let gone_result = image::resolve(&*host, "tank", &gone);
+
+        let found = found?;
+        assert_eq!(found.len(), 2);
+        let i = &found[0];
+        assert_eq!(i.name, helios);
+        assert_eq!(i.dataset, "tank/img/helios-2.3");
+        assert_eq!(i.snapshot, "tank/img/helios-2.3@base");
+        assert_eq!(i.guid, "42");
+        assert_eq!(i.metadata.len(), 2);
+        assert_eq!(i.metadata["falcon:node"], "violin");
+        assert_eq!(i.metadata["falcon:purpose"], "login tweaks");
+
+        let e = bare_result.unwrap_err();
+        assert!(matches!(e, Error::NoBaseSnapshot(_)));
+        assert_eq!(
+            e.to_string(),
+            "image tank/img/bare exists but has no @base snapshot"
+        );
+        let e = gone_result.unwrap_err();
+        assert_eq!(e.to_string(), "no such image: tank/img/gone");
+
+        Ok(())
+    }
+}
@@ -4,22 +4,80 @@
 
 // Copyright 2022 Oxide Computer Company
 
+mod adopt;
+mod boot;
+mod capture;
+mod cores;
+mod env;
+mod events;
+mod hotplug;
+mod lock;
+mod ops;
+mod query;
+mod registry;
 #[cfg(test)]
 mod test;
 mod util;
-
+mod workspace;
+
+pub mod address;
+pub mod audit;
+// SyncResult is returned by Runner::exec_synchronized.
+pub mod barrier;
+// Runner::bundle returns the manifest, and bundles are read and verified
+// without a runner.
+pub mod bundle;
 pub mod cli;
+pub mod config;
+// CrashPolicy and PullReport are taken and returned by the panic watch and
+// crash dump pulls of Runner.
+pub mod crash;
+pub mod dhcp;
 pub mod error;
+pub mod health;
+// ImageName is the type of Node::image.
+pub mod image;
+// MgmtNetwork is the type of Deployment::mgmt.
+pub mod mgmt;
+// OutputCtx is set on a Runner by programs that take its messages.
+pub mod output;
+// ExternalPeer, PeerPort and ExternLink are taken by Runner::extern_link and
+// are the types of Deployment fields.
+pub mod peer;
+pub mod prelude;
+pub mod react;
+// LaunchReport and DestroyReport are returned by Runner::launch and
+// Runner::destroy.
+pub mod report;
+// RetryPolicy::schedule returns a Schedule, RetryPolicy is in the prelude.
+pub mod retry;
+pub mod role;
 pub mod serial;
+pub mod snapshot;
+pub mod svc;
 pub mod unit;
 
+pub use ops::{Backend, Host, Plan, Step};
+
 use camino::{Utf8Path, Utf8PathBuf};
+use config::PortRange;
+use dhcp::DhcpConfig;
+use env::Environment;
 use error::Error;
 use futures::future::join_all;
+use image::ImageName;
+use mgmt::MgmtNetwork;
+use output::OutputCtx;
 use propolis_client::types::InstanceMetadata;
 use propolis_server_config::{BlockDevice, BlockOpts, Device};
+use report::{
+    DestroyReport, LaunchReport, Leftover, NodeDestroyReport, NodeLaunchReport,
+};
+use retry::{RetryOp, RetryPolicy};
+use role::Role;
 use ron::ser::{to_string_pretty, PrettyConfig};
 use serde::{Deserialize, Serialize};
+use serial::Readiness;
 use slog::Drain;
 use slog::{debug, error, info, warn, Logger};
 use std::collections::BTreeMap;
@@ -28,8 +86,12 @@ use std::fs;
 use std::net::{IpAddr, Ipv6Addr, SocketAddr};
 use std::path::PathBuf;
 use std::process::Command;
-use std::str::FromStr;
-use tokio::time::{sleep, Duration};
+use std::str::{self, FromStr};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::mpsc;
+use std::sync::{Arc, Mutex};
+use tokio::task::JoinHandle;
+use tokio::time::{Duration, Instant};
 
 #[macro_export]
 macro_rules! node {
@@ -65,7 +127,16 @@ const ZFS_BIN: &str = "/usr/sbin/zfs";
 const DLADM_BIN: &str = "/usr/sbin/dladm";
 const DD_BIN: &str = "/usr/bin/dd";
 const RM_BIN: &str = "/usr/bin/rm";
+const MV_BIN: &str = "/usr/bin/mv";
 const TRUNCATE_BIN: &str = "/usr/bin/truncate";
+const VMM_DIR: &str = "/dev/vmm";
+
+/// The largest stdin `Runner::exec_with_stdin` will carry over the serial
+/// console.
+pub const SERIAL_STDIN_CAP: usize = 256 * 1024;
+
+/// How many nodes are torn down at once by `Runner::destroy`.
+const DESTROY_CONCURRENCY: usize = 8;
 
 pub struct Runner {
     /// The deployment object that describes the Falcon topology
@@ -80,13 +151,71 @@ pub struct Runner {
 
     pub log: Logger,
 
-    /// The root dataset to use for falcon activities
-    pub dataset: String,
+    /// The parent dataset images are read from. Images live at
+    /// `<image_dataset>/img/<image>`.
+    pub image_dataset: String,
+
+    /// The parent dataset topology clones are created under. Node disks live
+    /// at `<topo_dataset>/topo/<deployment>/<node>`.
+    pub topo_dataset: String,
 
     /// The location of the ".falcon" directory for a given deployment
     ///
     /// This directory is created by falcon and stores configuration.
     pub falcon_dir: Utf8PathBuf,
+
+    /// Ports propolis servers are bound to. When unset any free port is used.
+    pub port_range: Option<PortRange>,
+
+    /// Replaces the built-in retry policy of every kind of operation.
+    retry: Option<RetryPolicy>,
+
+    /// Retry policies for particular kinds of operation.
+    retry_overrides: BTreeMap<RetryOp, RetryPolicy>,
+
+    /// Adopt existing vnics that differ from the plan when every difference
+    /// can be corrected in place.
+    pub adopt_mismatched: bool,
+
+    /// How long to keep capturing a node's console after an operation that
+    /// changes the node, such as a reboot. `None` turns capture off.
+    pub op_capture: Option<Duration>,
+
+    /// Run the DHCP responder as a process of its own rather than a task,
+    /// so it outlives a command line launch.
+    detach_dhcp: bool,
+
+    /// Tasks serving the topology, such as the DHCP responder.
+    services: Mutex<Vec<JoinHandle<()>>>,
+
+    /// Actions taken on console output, served by `react`.
+    console_triggers: Vec<react::ConsoleTrigger>,
+
+    /// The steps taken by mutating operations, or only recorded in a dry
+    /// run.
+    plan: Plan,
+
+    /// Where messages about operations go, only the log unless set.
+    output: OutputCtx,
+
+    /// Where a successful destroy archives the topology's events, cores and
+    /// crash files. `None` uses `workspace::default_archive_dir`.
+    pub archive_dir: Option<Utf8PathBuf>,
+
+    /// Launch even though the last destroy in `falcon_dir` failed.
+    pub force_launch: bool,
+
+    /// The events log operations are timed in, `events::log_path` unless set
+    /// otherwise. `None` records nothing.
+    pub events_log: Option<Utf8PathBuf>,
+
+    /// The registry of running topologies, `registry::path` unless set
+    /// otherwise. `None` registers nothing and finds no other topology.
+    pub registry: Option<Utf8PathBuf>,
+
+    /// Limits on how many nodes boot at once, set with `boot_io_budget` and
+    /// `boot_io_watch`.
+    boot_budget: boot::BootBudget,
 }
 
 /// A Deployment is the top level Falcon object. It contains a set of nodes and
@@ -105,6 +234,31 @@ pub struct Deployment {
 
     /// External links connected to a host data link such as a phy or a vnic.
     pub ext_links: Vec<ExtLink>,
+
+    /// Boot readiness refinements keyed by image name.
+    #[serde(default)]
+    pub readiness: BTreeMap<String, Readiness>,
+
+    /// The management network shared by the host and all nodes.
+    #[serde(default)]
+    pub mgmt: MgmtNetwork,
+
+    /// The DHCP responder on the management network, if any.
+    #[serde(default)]
+    pub dhcp: Option<DhcpConfig>,
+
+    /// Ports other topologies may attach to.
+    #[serde(default)]
+    pub peer_ports: Vec<peer::PeerPort>,
+
+    /// Links to the peer ports of other topologies.
+    #[serde(default)]
+    pub extern_links: Vec<peer::ExternLink>,
+
+    /// The id the next link created is given. It only ever grows, so the
+    /// ids of removed links are not handed out again.
+    #[serde(default)]
+    pub next_link: usize,
 }
 
 impl Default for Deployment {
@@ -114,6 +268,12 @@ impl Default for Deployment {
             nodes: Vec::new(),
             links: Vec::new(),
             ext_links: Vec::new(),
+            readiness: BTreeMap::new(),
+            mgmt: MgmtNetwork::default(),
+            dhcp: None,
+            peer_ports: Vec::new(),
+            extern_links: Vec::new(),
+            next_link: 0,
         }
     }
 }
@@ -132,7 +292,7 @@ pub struct Node {
     /// Name of the node
     pub name: String,
     /// Image node uses
-    pub image: String,
+    pub image: ImageName,
     /// How many links the node has
     pub radix: usize,
     /// Mounted file systems
@@ -143,14 +303,69 @@ pub struct Node {
     pub cores: u8,
     /// how much memory to give the node in mb
     pub memory: u64,
-    /// The root dataset to use for falcon activities
-    pub dataset: String,
+    /// The parent dataset the node's image is read from
+    #[serde(alias = "dataset")]
+    pub image_dataset: String,
+    /// The parent dataset the node's disk is cloned into. Topologies saved
+    /// before images and clones could live apart have only `dataset`, which
+    /// `Deployment::from_ron` uses for both.
+    #[serde(default)]
+    pub topo_dataset: String,
     /// Whether or not to do initial setup on the node
     pub do_setup: bool,
     /// How much space to reserve on the boot disk in GB.
     pub reserved: usize,
     /// How to create the backing of the main disk.
     pub primary_disk_backing: PrimaryDiskBacking,
+    /// The kind of operating system the node's image runs. Topologies saved
+    /// before guest kinds were recorded load as `Other`.
+    #[serde(default)]
+    pub guest: GuestKind,
+    /// Built-in personas applied at launch.
+    #[serde(default)]
+    pub roles: Vec<Role>,
+    /// Environment variables added to the node's propolis-server. Secret
+    /// values are recorded as the bare `secret:` prefix, the values
+    /// themselves are kept in `secrets`.
+    #[serde(default)]
+    pub propolis_env: BTreeMap<String, String>,
+    /// Secret propolis-server environment values by key. They are saved to
+    /// the falcon directory's secrets file rather than with the topology.
+    #[serde(skip)]
+    pub secrets: BTreeMap<String, String>,
+}
+
+/// The operating system family of a node's guest. Guest side conveniences
+/// such as SMF management only apply to some kinds.
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq)]
+pub enum GuestKind {
+    Helios,
+    Linux,
+    Other,
+}
+
+impl Default for GuestKind {
+    /// No guest side conveniences, the safe choice for a guest of unknown
+    /// kind.
+    fn default() -> Self {
+        Self::Other
+    }
+}
+
+impl GuestKind {
+    /// Guess the guest kind from an image name, e.g. `helios-2.3` or
+    /// `debian-11.0`.
+    pub fn from_image(image: &str) -> Self {
+        const LINUX: [&str; 5] =
+            ["debian", "ubuntu", "alpine", "fedora", "linux"];
+        if image.starts_with("helios") {
+            Self::Helios
+        } else if LINUX.iter().any(|x| image.starts_with(x)) {
+            Self::Linux
+        } else {
+            Self::Other
+        }
+    }
 }
 
 #[derive(Debug, Serialize, Deserialize, PartialEq, Eq)]
@@ -188,6 +403,14 @@ pub struct NodeRef {
 #[derive(Serialize, Deserialize)]
 pub struct Link {
     pub endpoints: [Endpoint; 2],
+    /// Identifies the link for the life of the topology. Ids are assigned
+    /// when links are created and never reused, so they survive persisting
+    /// and reloading the topology.
+    #[serde(default)]
+    pub id: usize,
+    /// An optional name to look the link up by.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub name: Option<String>,
 }
 
 #[derive(Serialize, Deserialize)]
@@ -221,6 +444,19 @@ impl EndpointKind {
             Self::SoftNPU(_) => "sn",
         }
     }
+
+    /// The MAC requested for a viona endpoint, as bytes.
+    fn mac(&self) -> Result<Option<Vec<u8>>, Error> {
+        let mac = match self {
+            Self::Viona(Some(mac)) => mac,
+            _ => return Ok(None),
+        };
+        let mut v = Vec::new();
+        for p in mac.split(':') {
+            v.push(u8::from_str_radix(p, 16)?);
+        }
+        Ok(Some(v))
+    }
 }
 
 /// Endpoints are owned by a Link and reference nodes through a references.
@@ -239,10 +475,16 @@ pub struct Endpoint {
 
 /// Opaque handle to a link. Used by clients to perform API functions on
 /// links owned by a Deployment.
-#[derive(Copy, Clone, Serialize, Deserialize)]
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
 pub struct LinkRef {
-    /// The index of the referenced link in `Deployment::links`
-    _index: usize,
+    /// The id of the referenced link, see `Link::id`.
+    id: usize,
+}
+
+impl LinkRef {
+    pub fn id(&self) -> usize {
+        self.id
+    }
 }
 
 impl Runner {
@@ -265,13 +507,31 @@ impl Runner {
         let drain = slog_envlogger::new(drain).fuse();
         let drain = slog_async::Async::new(drain).build().fuse();
 
+        let log = slog::Logger::root(drain, slog::o!());
+
         Runner {
             deployment: Deployment::new(name),
-            log: slog::Logger::root(drain, slog::o!()),
+            log: log.clone(),
             persistent: false,
-            propolis_binary: "propolis-server".into(),
-            dataset: dataset(),
+            propolis_binary: DEFAULT_PROPOLIS_BINARY.into(),
+            image_dataset: image_dataset(),
+            topo_dataset: topo_dataset(),
             falcon_dir: DEFAULT_FALCON_DIR.into(),
+            port_range: None,
+            retry: None,
+            retry_overrides: BTreeMap::new(),
+            adopt_mismatched: false,
+            op_capture: Some(capture::DEFAULT_TAIL),
+            detach_dhcp: false,
+            services: Mutex::new(Vec::new()),
+            console_triggers: Vec::new(),
+            plan: Plan::new(false),
+            output: OutputCtx::logger(log),
+            archive_dir: None,
+            force_launch: false,
+            events_log: events::log_path(),
+            registry: registry::path(),
+            boot_budget: boot::BootBudget::default(),
         }
     }
 
@@ -285,6 +545,12 @@ impl Runner {
         memory: u64,
     ) -> NodeRef {
         namecheck!(name, "node");
+        let image_name: ImageName = match image.parse() {
+            Ok(i) => i,
+            Err(e) => {
+                die!("{}: {}", name, e);
+            }
+        };
 
         let id = uuid::Uuid::new_v4();
 
@@ -293,8 +559,9 @@ impl Runner {
         };
         let n = Node {
             name: String::from(name),
-            image: String::from(image),
-            dataset: self.dataset.clone(),
+            image: image_name,
+            image_dataset: self.image_dataset.clone(),
+            topo_dataset: self.topo_dataset.clone(),
             radix: 0,
             mounts: Vec::new(),
             id,
@@ -303,11 +570,91 @@ impl Runner {
             do_setup: true,
             reserved: 20,
             primary_disk_backing: PrimaryDiskBacking::Zvol,
+            guest: GuestKind::from_image(image),
+            roles: Vec::new(),
+            propolis_env: BTreeMap::new(),
+            secrets: BTreeMap::new(),
         };
         self.deployment.nodes.push(n);
+        self.attach_mgmt();
         r
     }
 
+    /// Set the parent dataset images are read from for this runner and all
+    /// nodes created so far.
+    pub fn set_image_dataset(&mut self, dataset: impl AsRef<str>) {
+        self.image_dataset = dataset.as_ref().into();
+        for n in self.deployment.nodes.iter_mut() {
+            n.image_dataset = self.image_dataset.clone();
+        }
+    }
+
+    /// Set the parent dataset topology clones are created under for this
+    /// runner and all nodes created so far.
+    pub fn set_topo_dataset(&mut self, dataset: impl AsRef<str>) {
+        self.topo_dataset = dataset.as_ref().into();
+        for n in self.deployment.nodes.iter_mut() {
+            n.topo_dataset = self.topo_dataset.clone();
+        }
+    }
+
+    /// Record an operation in the events log. Nothing is recorded for a dry
+    /// run, it took no time worth knowing.
+    pub(crate) fn record_event(
+        &self,
+        op: &str,
+        node: Option<&str>,
+        took: Duration,
+        ok: bool,
+    ) {
+        if !self.plan.is_dry_run() {
+            events::record(
+                self.events_log.as_deref(),
+                &self.deployment.name,
+                op,
+                node,
+                took,
+                ok,
+            );
+        }
+    }
+
+    /// Record the steps mutating operations would take rather than taking
+    /// them. Queries of the host are still made. Starts a new plan.
+    pub fn set_dry_run(&mut self, dry_run: bool) {
+        self.plan = self.plan.restart(Plan::new(dry_run));
+    }
+
+    /// Run host commands with `backend` rather than on the host.
+    pub fn set_backend(&mut self, backend: Arc<dyn Backend>) {
+        self.plan = std::mem::take(&mut self.plan).with_backend(backend);
+    }
+
+    /// The backend host commands are run with.
+    pub fn backend(&self) -> &dyn Backend {
+        self.plan.backend()
+    }
+
+    /// Like `set_dry_run`, but the new plan keeps the steps it takes in a
+    /// real run too, so they can be compared with a dry run's.
+    pub fn record_plan(&mut self, dry_run: bool) {
+        self.plan = self.plan.restart(Plan::recording(dry_run));
+    }
+
+    /// The steps taken, or planned in a dry run, by operations so far.
+    pub fn plan(&self) -> &Plan {
+        &self.plan
+    }
+
+    /// Send messages about operations to `output` rather than only the log.
+    pub fn set_output(&mut self, output: OutputCtx) {
+        self.output = output;
+    }
+
+    pub fn output(&self) -> &OutputCtx {
+        &self.output
+    }
+
     pub fn all_nodes(&self) -> Vec<NodeRef> {
         let mut result = Vec::new();
         for index in 0..self.deployment.nodes.len() {
@@ -320,6 +667,20 @@ impl Runner {
         &self.deployment.nodes[r.index]
     }
 
+    /// Look up a node by name.
+    pub fn find_node(&self, name: &str) -> Option<NodeRef> {
+        self.deployment
+            .nodes
+            .iter()
+            .position(|n| n.name == name)
+            .map(|index| NodeRef { index })
+    }
+
+    /// Override the guest kind inferred from the node's image name.
+    pub fn guest_kind(&mut self, r: NodeRef, kind: GuestKind) {
+        self.deployment.nodes[r.index].guest = kind;
+    }
+
     pub fn do_setup(&mut self, r: NodeRef, value: bool) {
         self.deployment.nodes[r.index].do_setup = value;
     }
@@ -332,27 +693,21 @@ impl Runner {
 
     /// Create a new link within this deployment between the referenced nodes.
     pub fn link(&mut self, a: NodeRef, b: NodeRef) -> LinkRef {
-        let r = LinkRef {
-            _index: self.deployment.links.len(),
-        };
-        let l = Link {
-            endpoints: [
-                Endpoint {
-                    node: a,
-                    index: self.deployment.nodes[a.index].radix,
-                    kind: EndpointKind::Viona(None),
-                },
-                Endpoint {
-                    node: b,
-                    index: self.deployment.nodes[b.index].radix,
-                    kind: EndpointKind::Viona(None),
-                },
-            ],
-        };
-        self.deployment.links.push(l);
+        let endpoints = [
+            Endpoint {
+                node: a,
+                index: self.deployment.nodes[a.index].radix,
+                kind: EndpointKind::Viona(None),
+            },
+            Endpoint {
+                node: b,
+                index: self.deployment.nodes[b.index].radix,
+                kind: EndpointKind::Viona(None),
+            },
+        ];
         self.deployment.nodes[a.index].radix += 1;
         self.deployment.nodes[b.index].radix += 1;
-        r
+        self.push_link(endpoints)
     }
 
     /// Create a sidecar controller link with the provided radix.
@@ -366,25 +721,19 @@ impl Runner {
         radix: usize,
         macs: Option<Vec<String>>,
     ) -> LinkRef {
-        let r = LinkRef {
-            _index: self.deployment.links.len(),
-        };
-        let l = Link {
-            endpoints: [
-                Endpoint {
-                    node: sidecar,
-                    index: self.bump_radix(sidecar),
-                    kind: EndpointKind::Viona(None),
-                },
-                Endpoint {
-                    node: controller,
-                    index: self.bump_radix(controller),
-                    kind: EndpointKind::Sidemux(radix, macs),
-                },
-            ],
-        };
-        self.deployment.links.push(l);
-        r
+        let endpoints = [
+            Endpoint {
+                node: sidecar,
+                index: self.bump_radix(sidecar),
+                kind: EndpointKind::Viona(None),
+            },
+            Endpoint {
+                node: controller,
+                index: self.bump_radix(controller),
+                kind: EndpointKind::Sidemux(radix, macs),
+            },
+        ];
+        self.push_link(endpoints)
     }
 
     pub fn softnpu_link(
@@ -394,27 +743,21 @@ impl Runner {
         node_mac: Option<String>,
         softnpu_mac: Option<String>,
     ) -> LinkRef {
-        let r = LinkRef {
-            _index: self.deployment.links.len(),
-        };
-        let l = Link {
-            endpoints: [
-                Endpoint {
-                    node: softnpu_node,
-                    index: self.deployment.nodes[softnpu_node.index].radix,
-                    kind: EndpointKind::SoftNPU(softnpu_mac),
-                },
-                Endpoint {
-                    node,
-                    index: self.deployment.nodes[node.index].radix,
-                    kind: EndpointKind::Viona(node_mac),
-                },
-            ],
-        };
-        self.deployment.links.push(l);
+        let endpoints = [
+            Endpoint {
+                node: softnpu_node,
+                index: self.deployment.nodes[softnpu_node.index].radix,
+                kind: EndpointKind::SoftNPU(softnpu_mac),
+            },
+            Endpoint {
+                node,
+                index: self.deployment.nodes[node.index].radix,
+                kind: EndpointKind::Viona(node_mac),
+            },
+        ];
         self.deployment.nodes[softnpu_node.index].radix += 1;
         self.deployment.nodes[node.index].radix += 1;
-        r
+        self.push_link(endpoints)
     }
 
     pub fn softnpu_links(
@@ -424,27 +767,77 @@ impl Runner {
         mac1: Option<String>,
         mac2: Option<String>,
     ) -> LinkRef {
-        let r = LinkRef {
-            _index: self.deployment.links.len(),
-        };
-        let l = Link {
-            endpoints: [
-                Endpoint {
-                    node: node1,
-                    index: self.deployment.nodes[node1.index].radix,
-                    kind: EndpointKind::SoftNPU(mac1),
-                },
-                Endpoint {
-                    node: node2,
-                    index: self.deployment.nodes[node2.index].radix,
-                    kind: EndpointKind::SoftNPU(mac2),
-                },
-            ],
-        };
-        self.deployment.links.push(l);
+        let endpoints = [
+            Endpoint {
+                node: node1,
+                index: self.deployment.nodes[node1.index].radix,
+                kind: EndpointKind::SoftNPU(mac1),
+            },
+            Endpoint {
+                node: node2,
+                index: self.deployment.nodes[node2.index].radix,
+                kind: EndpointKind::SoftNPU(mac2),
+            },
+        ];
         self.deployment.nodes[node1.index].radix += 1;
         self.deployment.nodes[node2.index].radix += 1;
-        r
+        self.push_link(endpoints)
+    }
+
+    fn push_link(&mut self, endpoints: [Endpoint; 2]) -> LinkRef {
+        let id = self.deployment.take_link_id();
+        self.deployment.links.push(Link {
+            endpoints,
+            id,
+            name: None,
+        });
+        LinkRef { id }
+    }
+
+    /// Give a link a name it can be looked up by with `find_link`. Names
+    /// must be unique among the links of the topology.
+    pub fn name_link(&mut self, l: LinkRef, name: &str) -> Result<(), Error> {
+        namecheck!(name, "link");
+        if let Some(other) = self.deployment.link_named(name) {
+            if other.id != l.id {
+                return Err(Error::Link(format!(
+                    "link name {} is already used by link {}",
+                    name, other.id
+                )));
+            }
+        }
+        match self.deployment.links.iter_mut().find(|x| x.id == l.id) {
+            Some(link) => {
+                link.name = Some(name.into());
+                Ok(())
+            }
+            None => Err(Error::NotFound(format!("link {}", l.id))),
+        }
+    }
+
+    /// Find a link by the name given to it with `name_link`.
+    pub fn find_link(&self, name: &str) -> Option<LinkRef> {
+        self.deployment
+            .link_named(name)
+            .map(|l| LinkRef { id: l.id })
+    }
+
+    /// The first link declared between the referenced nodes, in either
+    /// direction. Use `Deployment::resolve_link` to pick among parallel
+    /// links.
+    pub fn link_between(&self, a: NodeRef, b: NodeRef) -> Option<LinkRef> {
+        let d = &self.deployment;
+        d.links_between(&d.nodes[a.index].name, &d.nodes[b.index].name)
+            .first()
+            .map(|l| LinkRef { id: l.id })
+    }
+
+    /// Refine when nodes using `image` are considered booted. By default a
+    /// node is booted as soon as its login prompt appears.
+    pub fn readiness(&mut self, image: impl AsRef<str>, readiness: Readiness) {
+        self.deployment
+            .readiness
+            .insert(image.as_ref().into(), readiness);
     }
 
     pub fn reserve(&mut self, n: NodeRef, gb: usize) {
@@ -455,12 +848,24 @@ impl Runner {
         self.deployment.nodes[n.index].primary_disk_backing = backing
     }
 
-    /// Create an external link attached to `host_ifx`.
+    /// Create an external link attached to `host_ifx`. A vnic that already
+    /// exists under the link's name and was not created by falcon is adopted
+    /// and left in place when the network is destroyed. Only external links
+    /// adopt vnics, existing point to point links are replaced.
     pub fn ext_link(&mut self, host_ifx: impl AsRef<str>, n: NodeRef) {
+        self.ext_link_with_kind(host_ifx, n, EndpointKind::Viona(None))
+    }
+
+    pub(crate) fn ext_link_with_kind(
+        &mut self,
+        host_ifx: impl AsRef<str>,
+        n: NodeRef,
+        kind: EndpointKind,
+    ) {
         let endpoint = Endpoint {
             node: n,
             index: self.deployment.nodes[n.index].radix,
-            kind: EndpointKind::Viona(None),
+            kind,
         };
         let host_ifx = host_ifx.as_ref().into();
         self.deployment
@@ -517,12 +922,16 @@ impl Runner {
     /// the propolis VM instances, create the point to point network interfaces,
     /// set up the serial console for each VM and, run any user defined exec
     /// statements.
-    pub async fn launch(&self) -> Result<(), Error> {
+    pub async fn launch(&self) -> Result<LaunchReport, Error> {
+        let _cache = ops::CacheScope::new();
+        let start = Instant::now();
         self.preflight()?;
-        match self.do_launch().await {
-            Ok(()) => Ok(()),
+        let result = self.do_launch().await;
+        self.record_event("launch", None, start.elapsed(), result.is_ok());
+        match result {
+            Ok(report) => Ok(report),
             Err(e) => {
-                error!(self.log, "launch failed: {}", e);
+                self.output.error(format!("launch failed: {}", e));
                 Err(e)
             }
         }
@@ -538,104 +947,417 @@ impl Runner {
             )));
         }
 
+        self.check_destroy_failed()?;
+        self.preflight_datasets()?;
+
         // ensure falcon working dir
-        fs::create_dir_all(&self.falcon_dir)?;
+        self.plan.create_dir_all(&self.falcon_dir)?;
+
+        // the check writes into the falcon directory, which a dry run must
+        // leave as it is
+        if !self.plan.is_dry_run() {
+            if let Err(e) =
+                cores::check_capture(self.backend(), &self.falcon_dir)
+            {
+                self.output.warn(e.to_string());
+            }
+        }
 
         // write falcon config
         let pretty = PrettyConfig::new().separate_tuple_members(true);
         let out = format!("{}\n", to_string_pretty(&self.deployment, pretty)?);
         let mut topo_path = self.falcon_dir.clone();
         topo_path.push("topology.ron");
-        fs::write(&topo_path, out)?;
+        self.plan.write(&topo_path, out)?;
+        let secrets = self.falcon_dir.join(env::SECRETS_FILE);
+        self.plan.step(Step::write(secrets), || {
+            env::save_secrets(&self.falcon_dir, &self.deployment)
+        })?;
+        registry::register(
+            &self.plan,
+            self.registry.as_deref(),
+            &self.deployment.name,
+            &self.falcon_dir,
+        )?;
+
+        // management addresses for the host to resolve nodes with
+        if self.deployment.mgmt.is_enabled() {
+            let mut hosts = self.deployment.mgmt_hosts()?.join("\n");
+            hosts.push('\n');
+            self.plan.write(self.falcon_dir.join("hosts"), hosts)?;
+        }
 
         for n in self.deployment.nodes.iter() {
+            for role in n.roles.iter() {
+                role.check(n, &self.deployment)?;
+            }
             n.preflight(self)?;
         }
 
         Ok(())
     }
 
+    /// Verify the image and topology parent datasets of every node exist and
+    /// report the free space of the pools they live in.
+    fn preflight_datasets(&self) -> Result<(), Error> {
+        let mut parents =
+            vec![self.image_dataset.clone(), self.topo_dataset.clone()];
+        for n in self.deployment.nodes.iter() {
+            parents.push(n.image_dataset.clone());
+            parents.push(n.topo_dataset.clone());
+        }
+        parents.sort();
+        parents.dedup();
+
+        let names: Vec<&str> = parents.iter().map(String::as_str).collect();
+        let avail = ops::list_property(self.backend(), &names, "avail")?;
+
+        let mut pools: BTreeMap<&str, &str> = BTreeMap::new();
+        for p in parents.iter() {
+            let a = match avail.get(p) {
+                Some(a) => a,
+                None => return Err(Error::NotFound(format!("dataset {}", p))),
+            };
+            pools.entry(ops::pool_of(p)).or_insert(a);
+        }
+
+        for (pool, avail) in pools {
+            let avail: u64 = avail.parse()?;
+            self.output.progress(format!(
+                "pool {}: {} free",
+                pool,
+                ops::human_bytes(avail)
+            ));
+        }
+
+        // every node's image must exist before anything is cloned from it
+        let mut images: Vec<(&str, &ImageName)> = self
+            .deployment
+            .nodes
+            .iter()
+            .filter(|n| {
+                matches!(n.primary_disk_backing, PrimaryDiskBacking::Zvol)
+            })
+            .map(|n| (n.image_dataset.as_str(), &n.image))
+            .collect();
+        images.sort();
+        images.dedup();
+        image::resolve_all(self.backend(), &images)?;
+
+        for n in self.deployment.nodes.iter() {
+            if ops::pool_of(&n.image_dataset) != ops::pool_of(&n.topo_dataset) {
+                self.output.warn(format!(
+                    "{}: image pool {} differs from topology pool {}, the \
+                    image will be copied with zfs send/receive rather than \
+                    cloned, this takes a full copy of space and time",
+                    n.name,
+                    ops::pool_of(&n.image_dataset),
+                    ops::pool_of(&n.topo_dataset),
+                ));
+            }
+        }
+
+        Ok(())
+    }
+
     async fn net_launch(&self) -> Result<(), Error> {
-        info!(self.log, "creating links");
+        // find every external peer before anything is created
+        let (peers, locks) = self.resolve_extern_links()?;
+
+        self.plan
+            .remove(self.falcon_dir.join(adopt::ADOPTED_FILE))?;
+
+        self.output.progress("creating links");
         for l in self.deployment.links.iter() {
             l.create(self)?;
         }
 
-        info!(self.log, "creating external links");
+        self.mgmt_create()?;
+        self.dhcp_start()?;
+
+        self.output.progress("creating external links");
         for l in self.deployment.ext_links.iter() {
             l.create(self)?;
         }
 
+        for p in self.deployment.peer_ports.iter() {
+            p.create(self)?;
+        }
+        for (l, peer) in self.deployment.extern_links.iter().zip(peers.iter()) {
+            l.create(self, peer)?;
+        }
+        drop(locks);
+
         Ok(())
     }
 
-    async fn do_launch(&self) -> Result<(), Error> {
+    async fn do_launch(&self) -> Result<LaunchReport, Error> {
         self.net_launch().await?;
 
-        info!(self.log, "creating nodes");
+        self.output.progress("creating nodes");
 
+        let gate = boot::BootGate::new(&self.boot_budget);
         let mut fs = Vec::new();
+        let mut taken = Vec::new();
         for n in self.deployment.nodes.iter() {
-            let port = match portpicker::pick_unused_port() {
-                Some(p) => p,
-                None => return Err(Error::NoPorts),
-            };
-            let vnc_port = match portpicker::pick_unused_port() {
-                Some(p) => p,
-                None => return Err(Error::NoPorts),
-            };
-            fs.push(n.launch(self, port as u32, vnc_port as u32));
+            let port = self.pick_port(&mut taken)?;
+            let vnc_port = self.pick_port(&mut taken)?;
+            let gate = &gate;
+            fs.push(async move {
+                // launch returns as soon as a node without setup is
+                // started, its boot is not waited on so it is not admitted
+                let permit = if n.do_setup {
+                    Some(gate.admit(self).await)
+                } else {
+                    None
+                };
+                let queued = permit.as_ref().map(|p| p.queued);
+                let start = Instant::now();
+                let mut result =
+                    n.launch(self, port as u32, vnc_port as u32).await;
+                drop(permit);
+                self.record_event(
+                    "launch",
+                    Some(&n.name),
+                    start.elapsed(),
+                    result.is_ok(),
+                );
+                if let Ok(ref mut report) = result {
+                    if gate.is_limited() {
+                        report.queued = queued;
+                    }
+                }
+                result
+            });
         }
+        let mut report = LaunchReport::default();
         for x in join_all(fs).await {
-            x?;
+            report.nodes.push(x?);
         }
 
-        Ok(())
+        Ok(report)
+    }
+
+    /// Pick a free port, from `port_range` if one is set. Ports in `taken`
+    /// are skipped and the picked port is added to it.
+    fn pick_port(&self, taken: &mut Vec<u16>) -> Result<u16, Error> {
+        let port = match self.port_range {
+            Some(range) => (range.start..=range.end)
+                .find(|p| !taken.contains(p) && portpicker::is_free(*p)),
+            None => portpicker::pick_unused_port(),
+        };
+        match port {
+            Some(p) => {
+                taken.push(p);
+                Ok(p)
+            }
+            None => Err(Error::NoPorts),
+        }
     }
 
+    /// Tear down the network of the topology, carrying on past failures. If
+    /// anything is left behind `Error::Destroy` carries a report of what and
+    /// how to remove it by hand.
     pub fn net_destroy(&self) -> Result<(), Error> {
-        info!(self.log, "destroying links");
+        let mut leftovers = self.destroy_links();
+        leftovers.extend(self.mgmt_destroy());
+        if leftovers.is_empty() {
+            return Ok(());
+        }
+        Err(Error::Destroy(DestroyReport {
+            leftovers,
+            ..Default::default()
+        }))
+    }
+
+    /// Tear down the links of every kind, carrying on past failures. Returns
+    /// what was left behind.
+    fn destroy_links(&self) -> Vec<Leftover> {
+        let mut leftovers = Vec::new();
+
+        self.output.progress("destroying links");
         for l in self.deployment.links.iter() {
-            l.destroy(self)?;
+            if let Err(e) = l.destroy(self) {
+                for ep in l.endpoints.iter() {
+                    leftovers.extend(self.link_leftovers(ep, true, &e));
+                }
+            }
         }
 
-        info!(self.log, "destroying external links");
+        self.output.progress("destroying external links");
         for l in self.deployment.ext_links.iter() {
-            l.destroy(self)?;
+            if let Err(e) = l.destroy(self) {
+                leftovers.extend(self.link_leftovers(&l.endpoint, false, &e));
+            }
         }
-        Ok(())
+        for p in self.deployment.peer_ports.iter() {
+            if let Err(e) = p.destroy(self) {
+                leftovers.extend(self.link_leftovers(&p.endpoint, true, &e));
+            }
+        }
+        for l in self.deployment.extern_links.iter() {
+            if let Err(e) = l.destroy(self) {
+                leftovers.extend(self.link_leftovers(&l.endpoint, true, &e));
+            }
+        }
+
+        leftovers
     }
 
-    /// Tear down all the nodes, followed by the links and the ZFS pool
-    // TODO in parallel
-    pub fn destroy(&self) -> Result<(), Error> {
-        info!(self.log, "destroying nodes");
-        for n in self.deployment.nodes.iter() {
-            n.destroy(self)?;
+    /// Tear down all the nodes, followed by the links and the ZFS pool.
+    ///
+    /// Nodes are torn down concurrently, at most `DESTROY_CONCURRENCY` at a
+    /// time, with progress logged as each one completes. A failure on one node
+    /// does not stop the others from being destroyed. Network objects are
+    /// removed once all nodes are gone. The falcon directory goes last, with
+    /// the topology's events, cores and crash files archived first. If
+    /// anything is left behind `Error::Destroy` carries a report of what and
+    /// how to remove it by hand. Then, or if anything cannot be archived, the
+    /// falcon directory is kept and marked with `workspace::DESTROY_FAILED`.
+    pub fn destroy(&self) -> Result<DestroyReport, Error> {
+        let start = Instant::now();
+        let result = self.do_destroy();
+        self.record_event("destroy", None, start.elapsed(), result.is_ok());
+        // a workspace that cannot be archived is kept like any other
+        let result = result.and_then(|report| {
+            self.archive_workspace()?;
+            Ok(report)
+        });
+        if let Err(ref e) = result {
+            self.mark_destroy_failed(e);
         }
+        result
+    }
+
+    fn do_destroy(&self) -> Result<DestroyReport, Error> {
+        let mut report = DestroyReport::default();
+
+        let total = self.deployment.nodes.len();
+        self.output.progress(format!("destroying {} nodes", total));
+        let next = AtomicUsize::new(0);
+        let (tx, rx) = mpsc::channel();
+        std::thread::scope(|s| {
+            for _ in 0..DESTROY_CONCURRENCY.min(total) {
+                let tx = tx.clone();
+                let next = &next;
+                s.spawn(move || loop {
+                    let i = next.fetch_add(1, Ordering::SeqCst);
+                    let n = match self.deployment.nodes.get(i) {
+                        Some(n) => n,
+                        None => break,
+                    };
+                    let start = Instant::now();
+                    let leftovers = n.destroy(self);
+                    if tx.send((i, start.elapsed(), leftovers)).is_err() {
+                        break;
+                    }
+                });
+            }
+            drop(tx);
+
+            for (done, (i, took, leftovers)) in rx.iter().enumerate() {
+                let name = &self.deployment.nodes[i].name;
+                if leftovers.is_empty() {
+                    self.output.progress(format!(
+                        "[{}/{}] destroyed {} in {:.1}s",
+                        done + 1,
+                        total,
+                        name,
+                        took.as_secs_f64(),
+                    ));
+                } else {
+                    self.output.warn(format!(
+                        "[{}/{}] {} destroyed with {} object(s) left behind",
+                        done + 1,
+                        total,
+                        name,
+                        leftovers.len(),
+                    ));
+                }
+                self.record_event(
+                    "destroy",
+                    Some(name),
+                    took,
+                    leftovers.is_empty(),
+                );
+                report.nodes.push(NodeDestroyReport {
+                    name: name.clone(),
+                    took,
+                });
+                report.leftovers.extend(leftovers);
+            }
+        });
 
-        self.net_destroy()?;
+        report.leftovers.extend(self.destroy_links());
+        report.leftovers.extend(self.mgmt_destroy());
 
         // Destroy images
-        info!(self.log, "destroying images");
+        self.output.progress("destroying images");
 
         // destroy any zvol backed images
-        let img_dir = format!("{}/topo/{}", self.dataset, self.deployment.name);
-        Command::new(ZFS_BIN)
-            .args(["destroy", "-r", img_dir.as_ref()])
-            .output()?;
+        let mut parents = vec![self.topo_dataset.clone()];
+        for n in self.deployment.nodes.iter() {
+            parents.push(n.topo_dataset.clone());
+        }
+        parents.sort();
+        parents.dedup();
+        for p in parents {
+            let img_dir = format!("{}/topo/{}", p, self.deployment.name);
+            if let Err(e) = zfs_destroy(self, &img_dir) {
+                report.leftovers.push(Leftover {
+                    what: format!("dataset {}", img_dir),
+                    error: e.to_string(),
+                    cleanup: format!("{} destroy -r {}", ZFS_BIN, img_dir),
+                });
+            }
+        }
 
         // destroy any file backed images
         let img_dir = format!("/var/falcon/dsk/{}", self.deployment.name);
-        Command::new(RM_BIN)
-            .args(["-rf", img_dir.as_ref()])
-            .output()?;
+        self.plan.run(RM_BIN, &["-rf", img_dir.as_ref()])?;
+
+        if !report.is_clean() {
+            self.output.warn(format!(
+                "keeping workspace {} for another attempt",
+                self.falcon_dir
+            ));
+            return Err(Error::Destroy(report));
+        }
 
-        // Destroy workspace
-        info!(self.log, "destroying workspace");
-        fs::remove_dir_all(&self.falcon_dir)?;
+        registry::unregister(
+            &self.plan,
+            self.registry.as_deref(),
+            &self.deployment.name,
+            &self.falcon_dir,
+        )?;
 
-        Ok(())
+        Ok(report)
+    }
+
+    /// Describe the link objects behind an endpoint that a failed link destroy
+    /// may have left in place.
+    fn link_leftovers(
+        &self,
+        e: &Endpoint,
+        simnet: bool,
+        err: &Error,
+    ) -> Vec<Leftover> {
+        let d = &self.deployment;
+        let vlink = d.vnic_link_name(e);
+        let mut result = vec![Leftover {
+            what: format!("vnic {}", vlink),
+            error: err.to_string(),
+            cleanup: format!("{} delete-vnic {}", DLADM_BIN, vlink),
+        }];
+        if simnet {
+            let slink = d.simnet_link_name(e);
+            result.push(Leftover {
+                what: format!("simnet {}", slink),
+                error: err.to_string(),
+                cleanup: format!("{} delete-simnet {}", DLADM_BIN, slink),
+            });
+        }
+        result
     }
 
     /// Run a command synchronously in the vm.
@@ -644,7 +1366,119 @@ impl Runner {
         self.do_exec(&name, cmd).await
     }
 
+    /// Run a command synchronously in the vm, feeding it `stdin`. The input
+    /// is carried over the serial console as base64, which needs `base64` in
+    /// the guest and is limited to `SERIAL_STDIN_CAP` bytes. Larger inputs are
+    /// rejected up front rather than spending ages trickling over the console.
+    pub async fn exec_with_stdin(
+        &self,
+        n: NodeRef,
+        cmd: &str,
+        stdin: &[u8],
+    ) -> Result<String, Error> {
+        let name = self.deployment.nodes[n.index].name.clone();
+        self.do_exec_with_stdin(&name, cmd, stdin).await
+    }
+
+    async fn do_exec_with_stdin(
+        &self,
+        name: &str,
+        cmd: &str,
+        stdin: &[u8],
+    ) -> Result<String, Error> {
+        let cmds = serial::stdin_commands(cmd, stdin, &uuid::Uuid::new_v4())?;
+        let mut out = self.do_exec_all(name, cmds).await?;
+        Ok(out.pop().unwrap_or_default())
+    }
+
     async fn do_exec(&self, name: &str, cmd: &str) -> Result<String, Error> {
+        let mut out = self.do_exec_all(name, vec![cmd.to_string()]).await?;
+        Ok(out.pop().unwrap_or_default())
+    }
+
+    /// Run a sequence of commands in a single console session, returning the
+    /// output of each.
+    pub(crate) async fn do_exec_all(
+        &self,
+        name: &str,
+        cmds: Vec<String>,
+    ) -> Result<Vec<String>, Error> {
+        let start = Instant::now();
+        let result = self.exec_session(name, cmds).await;
+        events::record(
+            self.events_log.as_deref(),
+            &self.deployment.name,
+            "exec",
+            Some(name),
+            start.elapsed(),
+            result.is_ok(),
+        );
+        result
+    }
+
+    async fn exec_session(
+        &self,
+        name: &str,
+        cmds: Vec<String>,
+    ) -> Result<Vec<String>, Error> {
+        // commands are not retried, but may be bounded by the exec deadline
+        let timeout_ms = self
+            .retry_policy(RetryOp::Exec)
+            .deadline
+            .map(|d| d.as_millis() as u64);
+        let mut sc = self.serial_commander(name)?;
+        let mut ws = sc.start(true).await?;
+        let mut out = Vec::new();
+        for cmd in cmds {
+            out.push(sc.exec_timeout(&mut ws, cmd, timeout_ms).await?);
+        }
+        sc.logout(&mut ws).await?;
+        Ok(out)
+    }
+
+    /// Run `cmd` on the named node's console, handing its output to `sink` a
+    /// line at a time as it arrives instead of collecting it.
+    pub(crate) async fn do_exec_lines(
+        &self,
+        name: &str,
+        cmd: &str,
+        sink: impl FnMut(&str) -> Result<(), Error>,
+    ) -> Result<(), Error> {
+        let start = Instant::now();
+        let result = self.exec_lines_session(name, cmd, sink).await;
+        events::record(
+            self.events_log.as_deref(),
+            &self.deployment.name,
+            "exec",
+            Some(name),
+            start.elapsed(),
+            result.is_ok(),
+        );
+        result
+    }
+
+    async fn exec_lines_session(
+        &self,
+        name: &str,
+        cmd: &str,
+        sink: impl FnMut(&str) -> Result<(), Error>,
+    ) -> Result<(), Error> {
+        let timeout_ms = self
+            .retry_policy(RetryOp::Exec)
+            .deadline
+            .map(|d| d.as_millis() as u64);
+        let mut sc = self.serial_commander(name)?;
+        let mut ws = sc.start(true).await?;
+        sc.exec_lines(&mut ws, cmd, timeout_ms, sink).await?;
+        sc.logout(&mut ws).await?;
+        Ok(())
+    }
+
+    /// A serial commander for the named node's console.
+    pub(crate) fn serial_commander(
+        &self,
+        name: &str,
+    ) -> Result<serial::SerialCommander, Error> {
         let mut path = self.falcon_dir.clone();
         path.push(format!("{name}.uuid"));
         let id = match fs::read_to_string(&path) {
@@ -681,10 +1515,8 @@ impl Runner {
             name.into(),
             self.log.clone(),
         );
-        let mut ws = sc.start(true).await?;
-        let out = sc.exec(&mut ws, cmd.to_string()).await?;
-        sc.logout(&mut ws).await?;
-        Ok(out)
+        sc.retry = self.retry_policy(RetryOp::ConsoleConnect);
+        Ok(sc)
     }
 }
 
@@ -698,9 +1530,47 @@ impl Deployment {
             nodes: Vec::new(),
             links: Vec::new(),
             ext_links: Vec::new(),
+            readiness: BTreeMap::new(),
+            mgmt: MgmtNetwork::default(),
+            dhcp: None,
+            peer_ports: Vec::new(),
+            extern_links: Vec::new(),
+            next_link: 0,
         }
     }
 
+    /// Read a deployment saved as `topology.ron`, filling in what topologies
+    /// saved by older versions of falcon lack.
+    pub fn from_ron(s: &str) -> Result<Self, Error> {
+        let mut d: Deployment = ron::de::from_str(s)?;
+        for n in d.nodes.iter_mut() {
+            if n.topo_dataset.is_empty() {
+                n.topo_dataset = n.image_dataset.clone();
+            }
+        }
+        // links saved before they had ids all read back as 0, number them
+        // in declaration order as they would have been
+        let mut ids: Vec<usize> = d.links.iter().map(|l| l.id).collect();
+        ids.sort_unstable();
+        ids.dedup();
+        if ids.len() != d.links.len() {
+            for (i, l) in d.links.iter_mut().enumerate() {
+                l.id = i;
+            }
+        }
+        let unused = d.links.iter().map(|l| l.id + 1).max().unwrap_or(0);
+        d.next_link = d.next_link.max(unused);
+        Ok(d)
+    }
+
+    /// Read the deployment saved in `falcon_dir`, along with its secrets.
+    pub fn load(falcon_dir: &Utf8Path) -> Result<Self, Error> {
+        let s = fs::read_to_string(falcon_dir.join("topology.ron"))?;
+        let mut d = Self::from_ron(&s)?;
+        env::load_secrets(falcon_dir, &mut d)?;
+        Ok(d)
+    }
+
     fn simnet_link_name(&self, e: &Endpoint) -> String {
         format!(
             "{}_{}_{}_sim{}",
@@ -724,9 +1594,9 @@ impl Deployment {
 
 impl Drop for Runner {
     fn drop(&mut self) {
-        if !self.persistent {
+        if !self.persistent && !self.plan.is_dry_run() {
             match self.destroy() {
-                Ok(()) => {}
+                Ok(_) => {}
                 Err(e) => error!(self.log, "cleanup failed: {}", e),
             }
         }
@@ -774,16 +1644,8 @@ impl Node {
         let mut softnpu_index = 0;
         let mut sidemux_index = 0;
 
-        let mut endpoints = Vec::new();
-        for l in &d.links {
-            endpoints.extend_from_slice(&l.endpoints);
-        }
-        for l in &d.ext_links {
-            endpoints.push(l.endpoint.clone());
-        }
-
-        let has_softnpu = endpoints
-            .iter()
+        let has_softnpu = d
+            .endpoints()
             .any(|x| matches!(&x.kind, EndpointKind::SoftNPU(_)));
 
         if has_softnpu {
@@ -818,94 +1680,90 @@ impl Node {
             pci_index += 1;
         }
 
-        for e in &endpoints {
-            if d.nodes[e.node.index].name == self.name {
-                match &e.kind {
-                    EndpointKind::Viona(_) => {
-                        //links.push(d.vnic_link_name(e));
-                        let mut opts = BTreeMap::new();
-                        opts.insert(
-                            "vnic".to_string(),
-                            toml::Value::String(d.vnic_link_name(e)),
-                        );
-                        opts.insert(
-                            "pci-path".to_string(),
-                            toml::Value::String(format!("0.{}.0", pci_index)),
-                        );
-                        devices.insert(
-                            format!("net{}", viona_index),
-                            propolis_server_config::Device {
-                                driver: "pci-virtio-viona".to_string(),
-                                options: opts,
-                            },
-                        );
-                        viona_index += 1;
-                        pci_index += 1;
-                    }
-                    EndpointKind::Sidemux(radix, macs) => {
-                        let mut opts = BTreeMap::new();
-                        opts.insert(
-                            "radix".to_string(),
-                            toml::Value::Integer((*radix).try_into()?),
-                        );
-                        opts.insert(
-                            "link-name".to_string(),
-                            toml::Value::String(d.vnic_link_name(e)),
-                        );
-                        opts.insert(
-                            "pci-path".to_string(),
-                            toml::Value::String(format!("0.{}.0", pci_index)),
-                        );
-                        match macs {
-                            Some(macs) => {
-                                opts.insert(
-                                    "macs".to_string(),
-                                    toml::Value::Array(
-                                        macs.iter()
-                                            .map(|x| {
-                                                toml::Value::String(x.clone())
-                                            })
-                                            .collect(),
-                                    ),
-                                );
-                            }
-                            None => {}
+        for e in d.endpoints_of(&self.name) {
+            match &e.kind {
+                EndpointKind::Viona(_) => {
+                    //links.push(d.vnic_link_name(e));
+                    let mut opts = BTreeMap::new();
+                    opts.insert(
+                        "vnic".to_string(),
+                        toml::Value::String(d.vnic_link_name(e)),
+                    );
+                    opts.insert(
+                        "pci-path".to_string(),
+                        toml::Value::String(format!("0.{}.0", pci_index)),
+                    );
+                    devices.insert(
+                        format!("net{}", viona_index),
+                        propolis_server_config::Device {
+                            driver: "pci-virtio-viona".to_string(),
+                            options: opts,
+                        },
+                    );
+                    viona_index += 1;
+                    pci_index += 1;
+                }
+                EndpointKind::Sidemux(radix, macs) => {
+                    let mut opts = BTreeMap::new();
+                    opts.insert(
+                        "radix".to_string(),
+                        toml::Value::Integer((*radix).try_into()?),
+                    );
+                    opts.insert(
+                        "link-name".to_string(),
+                        toml::Value::String(d.vnic_link_name(e)),
+                    );
+                    opts.insert(
+                        "pci-path".to_string(),
+                        toml::Value::String(format!("0.{}.0", pci_index)),
+                    );
+                    match macs {
+                        Some(macs) => {
+                            opts.insert(
+                                "macs".to_string(),
+                                toml::Value::Array(
+                                    macs.iter()
+                                        .map(|x| toml::Value::String(x.clone()))
+                                        .collect(),
+                                ),
+                            );
                         }
-                        devices.insert(
-                            format!("sidemux{}", sidemux_index),
-                            propolis_server_config::Device {
-                                driver: "sidemux".into(),
-                                options: opts,
-                            },
-                        );
-                        sidemux_index += 1;
-                        // +1 on the radix is for the pci port
-                        pci_index += radix + 1;
-                    }
-                    EndpointKind::SoftNPU(mac) => {
-                        let mut opts = BTreeMap::new();
-                        opts.insert(
-                            "vnic".to_string(),
-                            toml::Value::String(d.vnic_link_name(e)),
-                        );
-                        match mac {
-                            Some(ref mac) => {
-                                opts.insert(
-                                    "mac".to_string(),
-                                    toml::Value::String(mac.clone()),
-                                );
-                            }
-                            None => {}
-                        };
-                        devices.insert(
-                            format!("port{}", softnpu_index),
-                            propolis_server_config::Device {
-                                driver: "softnpu-port".to_string(),
-                                options: opts,
-                            },
-                        );
-                        softnpu_index += 1;
+                        None => {}
                     }
+                    devices.insert(
+                        format!("sidemux{}", sidemux_index),
+                        propolis_server_config::Device {
+                            driver: "sidemux".into(),
+                            options: opts,
+                        },
+                    );
+                    sidemux_index += 1;
+                    // +1 on the radix is for the pci port
+                    pci_index += radix + 1;
+                }
+                EndpointKind::SoftNPU(mac) => {
+                    let mut opts = BTreeMap::new();
+                    opts.insert(
+                        "vnic".to_string(),
+                        toml::Value::String(d.vnic_link_name(e)),
+                    );
+                    match mac {
+                        Some(ref mac) => {
+                            opts.insert(
+                                "mac".to_string(),
+                                toml::Value::String(mac.clone()),
+                            );
+                        }
+                        None => {}
+                    };
+                    devices.insert(
+                        format!("port{}", softnpu_index),
+                        propolis_server_config::Device {
+                            driver: "softnpu-port".to_string(),
+                            options: opts,
+                        },
+                    );
+                    softnpu_index += 1;
                 }
             }
         }
@@ -928,7 +1786,7 @@ impl Node {
 
         let mut path = r.falcon_dir.clone();
         path.push(format!("{}.toml", self.name));
-        fs::write(&path, config_toml)?;
+        r.plan.write(&path, config_toml)?;
 
         Ok(())
     }
@@ -936,52 +1794,46 @@ impl Node {
     fn create_zvol_backing(&self, r: &Runner) -> Result<String, Error> {
         //Clone base image
 
-        //TODO incorporate version into img
-        let source = format!("{}/img/{}@base", self.dataset, self.image);
+        let source =
+            image::resolve(r.backend(), &self.image_dataset, &self.image)?
+                .snapshot;
         let dest = format!(
             "{}/topo/{}/{}",
-            self.dataset, r.deployment.name, self.name
+            self.topo_dataset, r.deployment.name, self.name
         );
 
-        let out = Command::new(ZFS_BIN)
-            .args(["clone", "-p", source.as_ref(), dest.as_ref()])
-            .output()?;
-
-        if !out.status.success() {
-            return Err(Error::Zfs(String::from_utf8(out.stderr)?));
+        if ops::pool_of(&self.image_dataset) == ops::pool_of(&self.topo_dataset)
+        {
+            r.plan
+                .zfs(&["clone", "-p", source.as_ref(), dest.as_ref()])?;
+        } else {
+            // Clones cannot span pools, fall back to a full copy.
+            warn!(
+                r.log,
+                "{}: copying {} to {} with zfs send/receive",
+                self.name,
+                source,
+                dest,
+            );
+            let parent =
+                format!("{}/topo/{}", self.topo_dataset, r.deployment.name);
+            r.plan.zfs(&["create", "-p", parent.as_ref()])?;
+            r.plan.zfs_send_receive(&source, &dest)?;
         }
 
         let volsize = format!("volsize={}G", self.reserved);
-
-        let out = Command::new(ZFS_BIN)
-            .args(["set", volsize.as_str(), dest.as_ref()])
-            .output()?;
-
-        if !out.status.success() {
-            return Err(Error::Zfs(String::from_utf8(out.stderr)?));
-        }
-
         let reserved = format!("reservation={}G", self.reserved);
-
-        let out = Command::new(ZFS_BIN)
-            .args(["set", reserved.as_str(), dest.as_ref()])
-            .output()?;
-
-        if !out.status.success() {
-            return Err(Error::Zfs(String::from_utf8(out.stderr)?));
-        }
-
-        let out = Command::new(ZFS_BIN)
-            .args(["set", "sync=disabled", dest.as_ref()])
-            .output()?;
-
-        if !out.status.success() {
-            return Err(Error::Zfs(String::from_utf8(out.stderr)?));
-        }
+        r.plan.zfs(&[
+            "set",
+            volsize.as_str(),
+            reserved.as_str(),
+            "sync=disabled",
+            dest.as_ref(),
+        ])?;
 
         let zvol = format!(
             "/dev/zvol/rdsk/{}/topo/{}/{}",
-            self.dataset, r.deployment.name, self.name,
+            self.topo_dataset, r.deployment.name, self.name,
         );
 
         Ok(zvol)
@@ -991,27 +1843,30 @@ impl Node {
         let size = format!("{}G", self.reserved);
 
         let dir = format!("/var/falcon/dsk/{}", r.deployment.name);
-        if let Err(e) = fs::create_dir_all(&dir) {
+        if let Err(e) = r.plan.create_dir_all(&dir) {
             error!(r.log, "failed to create image directory: {e}");
-            return Err(Error::IO(e));
+            return Err(e);
         }
         let backing = format!("{}/{}", dir, self.name);
-        let source_zvol =
-            format!("/dev/zvol/dsk/{}/img/{}@base", self.dataset, self.image);
+        let source_zvol = format!(
+            "/dev/zvol/dsk/{}",
+            image::resolve(r.backend(), &self.image_dataset, &self.image)?
+                .snapshot
+        );
 
         info!(r.log, "copying backing image for {}", self.name);
         let dd_if = format!("if={source_zvol}");
         let dd_of = format!("of={backing}");
-        let out = Command::new(DD_BIN)
-            .args([dd_if.as_str(), dd_of.as_str(), "bs=1024M"])
-            .output()?;
+        let out = r
+            .plan
+            .run(DD_BIN, &[dd_if.as_str(), dd_of.as_str(), "bs=1024M"])?;
         if !out.status.success() {
             return Err(Error::Exec(String::from_utf8(out.stderr)?));
         }
 
-        let out = Command::new(TRUNCATE_BIN)
-            .args(["-s", size.as_str(), backing.as_str()])
-            .output()?;
+        let out = r
+            .plan
+            .run(TRUNCATE_BIN, &["-s", size.as_str(), backing.as_str()])?;
         if !out.status.success() {
             return Err(Error::Exec(String::from_utf8(out.stderr)?));
         }
@@ -1063,23 +1918,34 @@ impl Node {
         r: &Runner,
         port: u32,
         vnc_port: u32,
-    ) -> Result<(), Error> {
+    ) -> Result<NodeLaunchReport, Error> {
         // launch vm
 
+        let mut report = NodeLaunchReport {
+            name: self.name.clone(),
+            prompt_at: None,
+            quiesced_at: None,
+            queued: None,
+        };
         let id = uuid::Uuid::new_v4();
+        let start = Instant::now();
+        let env = Environment::for_node(&r.deployment.name, self)?;
         launch_vm(
             &r.log,
+            &r.plan,
             &r.propolis_binary,
             port,
             vnc_port,
             &id,
             self,
             &r.falcon_dir,
+            &r.retry_policy(RetryOp::PropolisEnsure),
+            &env,
         )
         .await?;
 
         if !self.do_setup {
-            return Ok(());
+            return Ok(report);
         }
 
         // initial vm configuration
@@ -1093,97 +1959,109 @@ impl Node {
             self.name.clone(),
             r.log.clone(),
         );
-        let mut ws = sc.start(false).await?;
-
-        // setup mounts
-        // TODO this will only work as expected for one mount.
-        for mount in &self.mounts {
-            info!(r.log, "{}: mounting {}", self.name, mount.destination);
-            let cmd = if mount.mechanism == GuestMountMechanism::Mount {
-                format!(
-                    "mkdir -p {dst}; mount -t 9p -o ro,msize=65536 {dst} {dst}",
-                    dst = mount.destination
-                )
-            } else {
-                format!(
-                    "mkdir -p {dst}; cd {dst}; p9kp pull",
-                    dst = mount.destination
-                )
+        sc.retry = r.retry_policy(RetryOp::ConsoleConnect);
+        sc.readiness = r.deployment.readiness.get(self.image.as_str()).cloned();
+        let login = Step::call("console.login", &[&self.name]);
+        let mut ws = r
+            .plan
+            .step_async(login, async { sc.start(false).await.map(Some) })
+            .await?;
+        report.prompt_at = sc.prompt_at.map(|t| t - start);
+        report.quiesced_at = sc.quiesced_at.map(|t| t - start);
+
+        // mounts, hostname and hosts entries
+        info!(r.log, "{}: applying guest configuration", self.name);
+        let mut cmds = self.setup_commands(&r.deployment)?;
+        if !self.roles.is_empty() {
+            info!(r.log, "{}: applying roles", self.name);
+            cmds.extend(self.role_commands());
+        }
+        for cmd in cmds {
+            let step = Step::call("console.exec", &[&self.name, &cmd]);
+            let exec = async {
+                if let Some(ws) = ws.as_mut() {
+                    sc.exec(ws, cmd).await?;
+                }
+                Ok::<_, Error>(())
             };
-            sc.exec(&mut ws, cmd).await?;
-            sc.exec(&mut ws, "cd".into()).await?;
-        }
-
-        // set hostname
-        let cmd = format!("hostname {}", self.name);
-        sc.exec(&mut ws, cmd).await?;
-        let cmd = format!("echo '{name}' > /etc/nodename", name = self.name,);
-        sc.exec(&mut ws, cmd).await?;
-        let cmd = format!(
-            "echo '::1 {name}.local {name}' >> /etc/hosts",
-            name = self.name,
-        );
-        sc.exec(&mut ws, cmd).await?;
-        let cmd = format!(
-            "echo '127.0.0.1 {name}.local {name}' >> /etc/hosts",
-            name = self.name,
-        );
-        sc.exec(&mut ws, cmd).await?;
+            r.plan.step_async(step, exec).await?;
+        }
 
         // log out after finishing setup
-        sc.logout(&mut ws).await?;
+        let logout = Step::call("console.logout", &[&self.name]);
+        let exec = async {
+            match ws.as_mut() {
+                Some(ws) => sc.logout(ws).await,
+                None => Ok(()),
+            }
+        };
+        r.plan.step_async(logout, exec).await?;
 
-        Ok(())
+        Ok(report)
     }
 
-    fn destroy(&self, r: &Runner) -> Result<(), Error> {
+    /// Stop the node's propolis instance and remove its disk. Anything that
+    /// could not be removed is returned rather than stopping the teardown.
+    fn destroy(&self, r: &Runner) -> Vec<Leftover> {
+        let mut leftovers = Vec::new();
+
         // get propolis pid
         let mut path = r.falcon_dir.clone();
         path.push(format!("{}.pid", self.name));
-        let pid = match fs::read_to_string(&path) {
-            Ok(pid) => match pid.parse::<i32>() {
-                Ok(pid) => pid,
+        match fs::read_to_string(&path) {
+            Ok(pid) => match pid.trim_end().parse::<i32>() {
+                // kill propolis instance
+                Ok(pid) => {
+                    let step = Step::call("kill", &["-9", &pid.to_string()]);
+                    let _ = r.plan.step(step, || {
+                        unsafe { libc::kill(pid, libc::SIGKILL) };
+                        Ok(())
+                    });
+                }
                 Err(e) => {
-                    warn!(r.log, "parse propolis pid for {}: {}", self.name, e);
-                    return Ok(());
+                    warn!(r.log, "parse propolis pid for {}: {}", self.name, e)
                 }
             },
             Err(e) => {
-                warn!(r.log, "get propolis pid for {}: {}", self.name, e);
-                return Ok(());
+                warn!(r.log, "get propolis pid for {}: {}", self.name, e)
             }
         };
         path.pop();
 
-        // kill propolis instance
-        unsafe {
-            libc::kill(pid, libc::SIGKILL);
-        }
-
         // get instance uuid
         path.push(format!("{}.uuid", self.name));
-        let uuid = match fs::read_to_string(&path) {
-            Ok(u) => u,
+        match fs::read_to_string(&path) {
+            Ok(uuid) => {
+                if let Err(e) = destroy_bhyve_vm(r, uuid.trim_end()) {
+                    leftovers.push(Leftover {
+                        what: format!("bhyve vm {} ({})", uuid, self.name),
+                        error: e.to_string(),
+                        cleanup: format!("bhyvectl --destroy --vm={}", uuid),
+                    });
+                }
+            }
             Err(e) => {
-                warn!(r.log, "get propolis uuid for {}: {}", self.name, e);
-                return Ok(());
+                warn!(r.log, "get propolis uuid for {}: {}", self.name, e)
             }
-        };
+        }
 
-        // destroy bhyve vm
-        let vm_arg = format!("--vm={}", uuid);
-        match Command::new("bhyvectl")
-            .args(["--destroy", vm_arg.as_ref()])
-            .output()
-        {
-            Ok(_) => {}
-            Err(e) => {
-                warn!(r.log, "delete bhyve vm for {}: {}", self.name, e);
-                return Ok(());
+        // destroy the node's disk now rather than waiting for the whole
+        // topology, this is where most of the time in a destroy goes
+        if let PrimaryDiskBacking::Zvol = self.primary_disk_backing {
+            let ds = format!(
+                "{}/topo/{}/{}",
+                self.topo_dataset, r.deployment.name, self.name
+            );
+            if let Err(e) = zfs_destroy(r, &ds) {
+                leftovers.push(Leftover {
+                    what: format!("zvol {}", ds),
+                    error: e.to_string(),
+                    cleanup: format!("{} destroy -r {}", ZFS_BIN, ds),
+                });
             }
         }
 
-        Ok(())
+        leftovers
     }
 }
 
@@ -1193,117 +2071,181 @@ impl Link {
 
         // create interfaces
         for e in self.endpoints.iter() {
-            let slink = d.simnet_link_name(e);
-            let vlink = d.vnic_link_name(e);
-
-            let slink_h = libnet::LinkHandle::Name(slink.clone());
-            let vlink_h = libnet::LinkHandle::Name(vlink.clone());
-
-            // if dangling links exists, remove them
-            debug!(r.log, "destroying link {}", &vlink);
-            libnet_retry(|| {
-                libnet::delete_link(&vlink_h, libnet::LinkFlags::Active)
-            })?;
-            debug!(r.log, "destroying link {}", &slink);
-            libnet_retry(|| {
-                libnet::delete_link(&slink_h, libnet::LinkFlags::Active)
-            })?;
-
-            info!(r.log, "creating simnet link '{}'", &slink);
-            libnet::create_simnet_link(&slink, libnet::LinkFlags::Active)?;
-
-            info!(r.log, "creating vnic link '{}'", &vlink);
-
-            let mac = if let EndpointKind::Viona(Some(mac)) = &e.kind {
-                let parts = mac.split(':');
-                let mut v = Vec::new();
-                for p in parts {
-                    let x = u8::from_str_radix(p, 16)?;
-                    v.push(x);
-                }
-                Some(v)
-            } else {
-                None
-            };
-
-            libnet::create_vnic_link(
-                &vlink,
-                &slink_h,
-                mac,
-                libnet::LinkFlags::Active,
-            )?;
-            let args =
-                vec!["set-linkprop", "-p", "promisc-filtered=off", &vlink];
-            match Command::new(DLADM_BIN).args(args).output() {
-                Err(e) => {
-                    return Err(Error::Exec(format!(
-                        "failed to run {DLADM_BIN}: {e:?}"
-                    )));
-                }
-                Ok(s) => {
-                    if !s.status.success() {
-                        return Err(Error::Exec(format!(
-                            "{DLADM_BIN} failed: {:?}",
-                            s.stderr
-                        )));
-                    }
-                }
-            }
-
+            create_endpoint_link(r, e)?;
             debug!(r.log, "link pair created");
         }
 
         // make point to point connection beteween interfaces
         let slink0 = d.simnet_link_name(&self.endpoints[0]);
         let slink1 = d.simnet_link_name(&self.endpoints[1]);
+        let step =
+            Step::call("libnet.connect_simnet_peers", &[&slink0, &slink1]);
         let slink0_h = libnet::LinkHandle::Name(slink0);
         let slink1_h = libnet::LinkHandle::Name(slink1);
-        libnet::connect_simnet_peers(&slink0_h, &slink1_h)?;
+        r.plan.step(step, || {
+            libnet::connect_simnet_peers(&slink0_h, &slink1_h)
+                .map(drop)
+                .map_err(Error::from)
+        })?;
 
         Ok(())
     }
 
     fn destroy(&self, r: &Runner) -> Result<(), Error> {
-        let d = &r.deployment;
-
         for e in self.endpoints.iter() {
-            let slink = d.simnet_link_name(e);
-            let vlink = d.vnic_link_name(e);
-            let slink_h = libnet::LinkHandle::Name(slink.clone());
-            let vlink_h = libnet::LinkHandle::Name(vlink.clone());
-
-            info!(r.log, "destroying link {}", &vlink);
-            libnet_retry(|| {
-                libnet::delete_link(&vlink_h, libnet::LinkFlags::Active)
-            })?;
-            info!(r.log, "destroying link {}", &slink);
-            libnet_retry(|| {
-                libnet::delete_link(&slink_h, libnet::LinkFlags::Active)
-            })?;
+            destroy_endpoint_link(r, e)?;
         }
 
         Ok(())
     }
 }
 
+/// Create the simnet behind `e` and the vnic over it, replacing any left by
+/// an earlier run.
+fn create_endpoint_link(r: &Runner, e: &Endpoint) -> Result<(), Error> {
+    let d = &r.deployment;
+    let slink = d.simnet_link_name(e);
+    let vlink = d.vnic_link_name(e);
+
+    let slink_h = libnet::LinkHandle::Name(slink.clone());
+    let vlink_h = libnet::LinkHandle::Name(vlink.clone());
+
+    // Links left by an earlier run are replaced. A vnic by the same
+    // name over anything but our simnet is not ours to remove.
+    if let Some(actual) = adopt::show_vnic(r.backend(), &vlink)? {
+        if actual.over != slink {
+            return Err(Error::Link(format!(
+                "vnic {} already exists over {} rather than simnet {}",
+                vlink, actual.over, slink
+            )));
+        }
+    }
+    debug!(r.log, "destroying link {}", &vlink);
+    libnet_retry(r, "delete_link", &vlink, || {
+        libnet::delete_link(&vlink_h, libnet::LinkFlags::Active)
+    })?;
+    debug!(r.log, "destroying link {}", &slink);
+    libnet_retry(r, "delete_link", &slink, || {
+        libnet::delete_link(&slink_h, libnet::LinkFlags::Active)
+    })?;
+
+    info!(r.log, "creating simnet link '{}'", &slink);
+    let step = Step::call("libnet.create_simnet_link", &[&slink]);
+    r.plan.step(step, || {
+        libnet::create_simnet_link(&slink, libnet::LinkFlags::Active)
+            .map(drop)
+            .map_err(|e| {
+                ops::libnet_error(&format!("create simnet {}", slink), e)
+            })
+    })?;
+
+    info!(r.log, "creating vnic link '{}'", &vlink);
+
+    let mac = e.kind.mac()?;
+    let step = Step::call("libnet.create_vnic_link", &[&vlink, &slink]);
+    r.plan.step(step, || {
+        libnet::create_vnic_link(
+            &vlink,
+            &slink_h,
+            mac,
+            libnet::LinkFlags::Active,
+        )
+        .map(drop)
+        .map_err(|e| ops::libnet_error(&format!("create vnic {}", vlink), e))
+    })?;
+    let args = vec!["set-linkprop", "-p", "promisc-filtered=off", &vlink];
+    match r.plan.run(DLADM_BIN, &args) {
+        Err(e) => {
+            return Err(Error::Exec(format!(
+                "failed to run {DLADM_BIN}: {e:?}"
+            )));
+        }
+        Ok(s) => {
+            if !s.status.success() {
+                let stderr = String::from_utf8_lossy(&s.stderr);
+                let what = format!("set-linkprop {}", vlink);
+                return Err(ops::net_env_error(&what, &stderr).unwrap_or_else(
+                    || {
+                        Error::Exec(format!(
+                            "{DLADM_BIN} failed: {:?}",
+                            s.stderr
+                        ))
+                    },
+                ));
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Remove the vnic behind `e` and the simnet under it.
+fn destroy_endpoint_link(r: &Runner, e: &Endpoint) -> Result<(), Error> {
+    let d = &r.deployment;
+    let slink = d.simnet_link_name(e);
+    let vlink = d.vnic_link_name(e);
+    let slink_h = libnet::LinkHandle::Name(slink.clone());
+    let vlink_h = libnet::LinkHandle::Name(vlink.clone());
+
+    info!(r.log, "destroying link {}", &vlink);
+    libnet_retry(r, "delete_link", &vlink, || {
+        libnet::delete_link(&vlink_h, libnet::LinkFlags::Active)
+    })?;
+    info!(r.log, "destroying link {}", &slink);
+    libnet_retry(r, "delete_link", &slink, || {
+        libnet::delete_link(&slink_h, libnet::LinkFlags::Active)
+    })?;
+    Ok(())
+}
+
 impl ExtLink {
     fn create(&self, r: &Runner) -> Result<(), Error> {
         let vnic_name = r.deployment.vnic_link_name(&self.endpoint);
-        let vnic = libnet::LinkHandle::Name(vnic_name.clone());
         let host_ifx = libnet::LinkHandle::Name(self.host_ifx.clone());
 
-        // destroy any dangling links
-        debug!(r.log, "destroying external link {}", &vnic_name);
-        libnet_retry(|| libnet::delete_link(&vnic, libnet::LinkFlags::Active))?;
+        // A vnic by this name is either falcon's own, left by an earlier run
+        // and replaced, or was created by hand and is adopted.
+        if let Some(actual) = adopt::show_vnic(r.backend(), &vnic_name)? {
+            if !adopt::created(&r.falcon_dir).contains(&vnic_name) {
+                let planned = adopt::VnicProps {
+                    over: self.host_ifx.clone(),
+                    mac: self.endpoint.kind.mac()?,
+                    vid: 0,
+                    mtu: adopt::link_mtu(r.backend(), &self.host_ifx)?,
+                };
+                return r.adopt_vnic(&vnic_name, &planned, &actual);
+            }
+            info!(r.log, "replacing leftover external link {}", &vnic_name);
+            let vnic = libnet::LinkHandle::Name(vnic_name.clone());
+            libnet_retry(r, "delete_link", &vnic_name, || {
+                libnet::delete_link(&vnic, libnet::LinkFlags::Active)
+            })?;
+        }
 
         // create vnic
         info!(r.log, "creating external link {}", &vnic_name);
-        libnet::create_vnic_link(
-            &vnic_name,
-            &host_ifx,
-            None,
-            libnet::LinkFlags::Active,
-        )?;
+        let mac = self.endpoint.kind.mac()?;
+        let step = Step::call(
+            "libnet.create_vnic_link",
+            &[&vnic_name, &self.host_ifx],
+        );
+        r.plan.step(step, || {
+            libnet::create_vnic_link(
+                &vnic_name,
+                &host_ifx,
+                mac,
+                libnet::LinkFlags::Active,
+            )
+            .map(drop)
+            .map_err(|e| {
+                ops::libnet_error(&format!("create vnic {}", vnic_name), e)
+            })
+        })?;
+
+        let created = r.falcon_dir.join(adopt::CREATED_FILE);
+        r.plan.step(Step::write(created), || {
+            adopt::mark_created(&r.falcon_dir, &vnic_name)
+        })?;
 
         debug!(
             r.log,
@@ -1315,66 +2257,106 @@ impl ExtLink {
 
     fn destroy(&self, r: &Runner) -> Result<(), Error> {
         let vnic_name = r.deployment.vnic_link_name(&self.endpoint);
+        if adopt::adopted(&r.falcon_dir).contains(&vnic_name) {
+            info!(r.log, "leaving adopted external link {}", &vnic_name);
+            return Ok(());
+        }
         let vnic = libnet::LinkHandle::Name(vnic_name.clone());
         info!(r.log, "destroying external link {}", &vnic_name);
-        libnet_retry(|| libnet::delete_link(&vnic, libnet::LinkFlags::Active))?;
+        libnet_retry(r, "delete_link", &vnic_name, || {
+            libnet::delete_link(&vnic, libnet::LinkFlags::Active)
+        })?;
+        let created = r.falcon_dir.join(adopt::CREATED_FILE);
+        r.plan.step(Step::write(created), || {
+            adopt::unmark_created(&r.falcon_dir, &vnic_name)
+        })?;
 
         Ok(())
     }
 }
 
+#[allow(clippy::too_many_arguments)]
 pub(crate) async fn launch_vm(
     log: &Logger,
+    plan: &Plan,
     propolis_binary: &str,
     port: u32,
     vnc_port: u32,
     id: &uuid::Uuid,
     node: &Node,
     falcon_dir: &Utf8Path,
+    retry: &RetryPolicy,
+    env: &Environment,
 ) -> Result<(), Error> {
     // launch propolis-server
 
     let mut path = falcon_dir.to_path_buf();
     path.push(format!("{}.port", node.name));
-    fs::write(&path, port.to_string())?;
+    plan.write(&path, port.to_string())?;
     path.pop();
     path.push(format!("{}.vnc_port", node.name));
-    fs::write(&path, vnc_port.to_string())?;
+    plan.write(&path, vnc_port.to_string())?;
     path.pop();
 
-    path.push(format!("{}.out", node.name));
-    let stdout = fs::File::create(&path)?;
-    path.pop();
-    path.push(format!("{}.err", node.name));
-    let stderr = fs::File::create(&path)?;
-    path.pop();
+    let stdout = falcon_dir.join(format!("{}.out", node.name));
+    let stderr = falcon_dir.join(format!("{}.err", node.name));
     path.push(format!("{}.toml", node.name));
     let config = path.clone();
     let sockaddr = format!("[::]:{}", port);
     let vnc_sockaddr = format!("[::]:{}", vnc_port);
-    let mut cmd = Command::new(propolis_binary);
-    cmd.args([
+    let args = [
         "run",
         config.as_ref(),
         sockaddr.as_ref(),
         vnc_sockaddr.as_ref(),
-    ])
-    .stdout(stdout)
-    .stderr(stderr);
-    let child = cmd.spawn()?;
+    ];
+    for key in env.overridden.iter() {
+        warn!(
+            log,
+            "{}: {} is set by falcon, ignoring the value given for it",
+            node.name,
+            key
+        );
+    }
+    plan.step(
+        Step::write(falcon_dir.join(format!("{}.env", node.name))),
+        || env.record(falcon_dir, &node.name),
+    )?;
+    let cores = cores::cores_dir(falcon_dir, &node.name);
+    let pattern = match plan.step(Step::write(&cores), || {
+        cores::core_pattern(falcon_dir, &node.name).map(Some)
+    }) {
+        Ok(pattern) => pattern,
+        Err(e) => {
+            warn!(log, "{}: propolis cores will not be kept: {}", node.name, e);
+            None
+        }
+    };
+    let pid = plan.step(Step::command(propolis_binary, &args), || {
+        let mut cmd = Command::new(propolis_binary);
+        cmd.args(args)
+            .envs(env.pairs())
+            .stdout(fs::File::create(&stdout)?)
+            .stderr(fs::File::create(&stderr)?);
+        if let Some(ref pattern) = pattern {
+            cores::capture(&mut cmd, pattern)?;
+        }
+        Ok(Some(cmd.spawn()?.id()))
+    })?;
     path.pop();
 
+    // a dry run has no pid, the planned write is all that matters
     path.push(format!("{}.pid", node.name));
-    fs::write(&path, child.id().to_string())?;
+    let contents = pid.map(|p| p.to_string()).unwrap_or_default();
+    plan.write(&path, contents)?;
     path.pop();
 
-    info!(
-        log,
-        "launched instance {} with pid {} on port {}",
-        node.name,
-        child.id(),
-        port,
-    );
+    if let Some(pid) = pid {
+        info!(
+            log,
+            "launched instance {} with pid {} on port {}", node.name, pid, port,
+        );
+    }
 
     let sockaddr = format!("[::1]:{}", port);
 
@@ -1390,7 +2372,7 @@ pub(crate) async fn launch_vm(
     // https://github.com/rust-lang/rust-clippy/issues/9317
     #[allow(clippy::unnecessary_to_owned)]
     path.push(format!("{}.uuid", node.name));
-    fs::write(&path, id.to_string())?;
+    plan.write(&path, id.to_string())?;
     path.pop();
 
     let properties = propolis_client::types::InstanceProperties {
@@ -1415,52 +2397,114 @@ pub(crate) async fn launch_vm(
     };
 
     // we just launched the instance, so wait for it to become ready
-    let mut success = false;
-    for _ in 0..30 {
-        info!(log, "instance ensure: {}", node.name);
-        match client.instance_ensure().body(&req).send().await {
-            Ok(_) => {
-                success = true;
-                break;
-            }
-            Err(e) => {
-                debug!(log, "instance ensure error: {e}, retry in 1 second");
-                sleep(Duration::from_secs(1)).await;
-                continue;
-            }
+    info!(log, "instance ensure: {}", node.name);
+    let what = format!("{}: instance ensure", node.name);
+    let ensure = async {
+        let ensured = retry::retry(retry, log, &what, || {
+            client.instance_ensure().body(&req).send()
+        })
+        .await;
+        if let Err(e) = ensured {
+            // propolis-server reports vmm problems on its stderr, which is
+            // more telling than the failed request
+            let out = fs::read_to_string(&stderr).unwrap_or_default();
+            let what = format!("launch {}", node.name);
+            return Err(ops::vmm_env_error(&what, &out).unwrap_or(e.into()));
         }
-    }
-    if !success {
-        client.instance_ensure().body(&req).send().await?;
-    }
+        Ok::<_, Error>(())
+    };
+    let step = Step::call("propolis.instance_ensure", &[&node.name, &sockaddr]);
+    plan.step_async(step, ensure).await?;
 
     info!(log, "instance run: {}", node.name);
     // run vm instance
-    client
-        .instance_state_put()
-        .body(propolis_client::types::InstanceStateRequested::Run)
-        .send()
-        .await?;
+    let run = async {
+        client
+            .instance_state_put()
+            .body(propolis_client::types::InstanceStateRequested::Run)
+            .send()
+            .await?;
+        Ok::<_, Error>(())
+    };
+    let step = Step::call("propolis.instance_state_put", &[&node.name, "run"]);
+    plan.step_async(step, run).await?;
 
     Ok(())
 }
 
+/// The propolis-server binary a runner uses unless told otherwise.
+pub(crate) const DEFAULT_PROPOLIS_BINARY: &str = "propolis-server";
+
+/// The parent dataset used when neither the program nor the environment
+/// names one.
+pub(crate) const DEFAULT_DATASET: &str = "rpool/falcon";
+
 pub(crate) fn dataset() -> String {
     match std::env::var("FALCON_DATASET") {
         Ok(s) if !s.is_empty() => s,
-        _ => "rpool/falcon".to_string(),
+        _ => DEFAULT_DATASET.to_string(),
+    }
+}
+
+/// The parent dataset for images, `FALCON_IMAGE_DATASET` falling back to
+/// `FALCON_DATASET`.
+pub(crate) fn image_dataset() -> String {
+    match std::env::var("FALCON_IMAGE_DATASET") {
+        Ok(s) if !s.is_empty() => s,
+        _ => dataset(),
+    }
+}
+
+/// The parent dataset for topology clones, `FALCON_TOPO_DATASET` falling back
+/// to `FALCON_DATASET`.
+pub(crate) fn topo_dataset() -> String {
+    match std::env::var("FALCON_TOPO_DATASET") {
+        Ok(s) if !s.is_empty() => s,
+        _ => dataset(),
     }
 }
 
-fn libnet_retry<F>(f: F) -> Result<(), Error>
+/// Destroy `dataset` and its descendants if it exists. Freshly killed
+/// instances can hold their zvols open for a moment, so busy datasets are
+/// retried.
+fn zfs_destroy(r: &Runner, dataset: &str) -> Result<(), Error> {
+    let what = format!("destroy {}", dataset);
+    let step = Step::command(ZFS_BIN, &["destroy", "-r", dataset]);
+    r.plan.step(step, || {
+        retry::retry_blocking(
+            &r.retry_policy(RetryOp::ZfsDestroy),
+            &r.log,
+            &what,
+            || ops::zfs_destroy_recursive(r.backend(), dataset),
+        )
+    })
+}
+
+/// Destroy the bhyve vm with the given name. A vm that no longer exists is
+/// not an error.
+fn destroy_bhyve_vm(r: &Runner, name: &str) -> Result<(), Error> {
+    let vm_arg = format!("--vm={}", name);
+    let out = r.plan.run("bhyvectl", &["--destroy", vm_arg.as_ref()])?;
+    if !out.status.success() && Utf8Path::new(VMM_DIR).join(name).exists() {
+        return Err(Error::Exec(String::from_utf8(out.stderr)?));
+    }
+    Ok(())
+}
+
+/// Run the libnet operation `call` on `link`, retrying while the link is
+/// busy.
+fn libnet_retry<F>(
+    r: &Runner,
+    call: &str,
+    link: &str,
+    f: F,
+) -> Result<(), Error>
 where
     F: Fn() -> Result<(), libnet::Error>,
 {
-    for _ in 0..30 {
-        if f().is_ok() {
-            return Ok(());
-        }
-        std::thread::sleep(Duration::from_secs(1));
-    }
-    Ok(f()?)
+    let policy = r.retry_policy(RetryOp::LinkDelete);
+    let step = Step::call(&format!("libnet.{}", call), &[link]);
+    r.plan.step(step, || {
+        Ok(retry::retry_blocking(&policy, &r.log, "libnet", f)?)
+    })
 }
@@ -0,0 +1,126 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+// Copyright 2022 Oxide Computer Company
+
+//! Advisory locks on files shared between falcon processes. These are fcntl
+//! locks: they go away with the process holding them, and another process
+//! can ask which process holds one. A process closing any descriptor of a
+//! locked file drops its lock, so a lock is best taken on a file that is
+//! only opened to be locked.
+
+use crate::error::Error;
+use camino::Utf8Path;
+use std::fs;
+use std::io;
+use std::os::unix::io::AsRawFd;
+
+/// An exclusive lock on a file, released when dropped.
+pub(crate) struct FileLock {
+    file: fs::File,
+}
+
+impl FileLock {
+    /// Wait for an exclusive lock on `path`, creating the file if needed.
+    pub(crate) fn acquire(path: &Utf8Path) -> Result<FileLock, Error> {
+        let file = open(path)?;
+        let fl = whole_file(libc::F_WRLCK);
+        loop {
+            if unsafe { libc::fcntl(file.as_raw_fd(), libc::F_SETLKW, &fl) }
+                == 0
+            {
+                return Ok(FileLock { file });
+            }
+            let e = io::Error::last_os_error();
+            if e.kind() != io::ErrorKind::Interrupted {
+                return Err(e.into());
+            }
+        }
+    }
+
+    /// Take an exclusive lock on `path`, creating the file if needed, or
+    /// return `None` if another process holds one.
+    pub(crate) fn try_acquire(
+        path: &Utf8Path,
+    ) -> Result<Option<FileLock>, Error> {
+        let file = open(path)?;
+        let fl = whole_file(libc::F_WRLCK);
+        if unsafe { libc::fcntl(file.as_raw_fd(), libc::F_SETLK, &fl) } == 0 {
+            return Ok(Some(FileLock { file }));
+        }
+        let e = io::Error::last_os_error();
+        match e.raw_os_error() {
+            Some(libc::EAGAIN) | Some(libc::EACCES) => Ok(None),
+            _ => Err(e.into()),
+        }
+    }
+
+    /// The locked file.
+    pub(crate) fn file(&mut self) -> &mut fs::File {
+        &mut self.file
+    }
+}
+
+/// The pid of the process holding a lock on `path`, `None` when no process
+/// does. A process does not see its own locks.
+pub(crate) fn holder(path: &Utf8Path) -> Result<Option<libc::pid_t>, Error> {
+    let file = match fs::File::open(path) {
+        Ok(f) => f,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e.into()),
+    };
+    let mut fl = whole_file(libc::F_WRLCK);
+    if unsafe { libc::fcntl(file.as_raw_fd(), libc::F_GETLK, &mut fl) } != 0 {
+        return Err(io::Error::last_os_error().into());
+    }
+    if fl.l_type == libc::F_UNLCK as libc::c_short {
+        return Ok(None);
+    }
+    Ok(Some(fl.l_pid))
+}
+
+fn open(path: &Utf8Path) -> Result<fs::File, Error> {
+    Ok(fs::OpenOptions::new()
+        .read(true)
+        .write(true)
+        .create(true)
+        .open(path)?)
+}
+
+fn whole_file(kind: libc::c_int) -> libc::flock {
+    let mut fl: libc::flock = unsafe { std::mem::zeroed() };
+    fl.l_type = kind as libc::c_short;
+    fl.l_whence = libc::SEEK_SET as libc::c_short;
+    fl
+}
+
+#[cfg(test)]
+mod test {
+    use crate::test::Scratch;
+    use anyhow::Result;
+
+    /// Test that a lock can be taken again once dropped, and that files
+    /// nobody else locks, or that do not exist, have no holder.
+    #[test]
+    fn file_locks() -> Result<()> {
+        use crate::lock::{holder, FileLock};
+        use std::io::Write;
+
+        let scratch = Scratch::new("lock")?;
+        let path = scratch.dir.join("dhcp.pid");
+        assert_eq!(holder(&path)?, None);
+
+        let mut lock = FileLock::acquire(&path)?;
+        writeln!(lock.file(), "{}", std::process::id())?;
+        // fcntl locks are per process, ours never conflict with each other
+        assert_eq!(holder(&path)?, None);
+        drop(lock);
+
+        let lock = FileLock::try_acquire(&path)?;
+        assert!(lock.is_some());
+        let pid = std::process::id().to_string();
+        assert_eq!(std::fs::read_to_string(&path)?.trim_end(), pid);
+        Ok(())
+    }
+}
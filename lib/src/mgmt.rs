@@ -0,0 +1,576 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+// Copyright 2022 Oxide Computer Company
+
+//! The management network, an etherstub shared by the host and every node of
+//! a deployment. It is addressed from an IPv4 prefix, an IPv6 prefix or both.
+//! The host takes the first address of each prefix and nodes follow in
+//! declaration order, so management addresses only depend on the topology
+//! description.
+
+use crate::error::Error;
+use crate::report::Leftover;
+use crate::{
+    ops, Backend, Deployment, EndpointKind, GuestKind, NodeRef, Plan, Runner,
+    Step, DLADM_BIN,
+};
+use ipnet::{IpNet, Ipv4Net, Ipv6Net};
+use serde::{Deserialize, Serialize};
+use slog::{debug, info};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+
+const IPADM_BIN: &str = "/usr/sbin/ipadm";
+
+/// The address prefixes of a deployment's management network. The network
+/// is only created when at least one prefix is set.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct MgmtNetwork {
+    pub v4: Option<Ipv4Net>,
+    pub v6: Option<Ipv6Net>,
+}
+
+impl MgmtNetwork {
+    pub fn is_enabled(&self) -> bool {
+        self.v4.is_some() || self.v6.is_some()
+    }
+
+    /// The configured prefixes, IPv6 first.
+    pub fn prefixes(&self) -> Vec<IpNet> {
+        let mut result = Vec::new();
+        if let Some(p) = self.v6 {
+            result.push(IpNet::V6(p));
+        }
+        if let Some(p) = self.v4 {
+            result.push(IpNet::V4(p));
+        }
+        result
+    }
+
+    /// The host's management addresses, IPv6 first.
+    pub fn host_addrs(&self) -> Result<Vec<IpNet>, Error> {
+        self.prefixes()
+            .into_iter()
+            .map(|p| nth_addr(p, 1))
+            .collect()
+    }
+
+    /// The management addresses of the node at `index` in declaration order,
+    /// IPv6 first.
+    pub fn node_addrs(&self, index: usize) -> Result<Vec<IpNet>, Error> {
+        self.prefixes()
+            .into_iter()
+            .map(|p| nth_addr(p, index as u128 + 2))
+            .collect()
+    }
+}
+
+/// The `n`th address of `prefix`, keeping the prefix length. The network
+/// address and, for IPv4, the broadcast address are never handed out.
+pub fn nth_addr(prefix: IpNet, n: u128) -> Result<IpNet, Error> {
+    let bits = u32::from(prefix.max_prefix_len() - prefix.prefix_len());
+    let size = 1u128.checked_shl(bits).unwrap_or(u128::MAX);
+    let addr = match prefix {
+        IpNet::V4(p) if n > 0 && n < size - 1 => {
+            IpAddr::V4(Ipv4Addr::from(u32::from(p.network()) + n as u32))
+        }
+        IpNet::V6(p) if n > 0 && n < size => {
+            IpAddr::V6(Ipv6Addr::from(u128::from(p.network()) + n))
+        }
+        _ => {
+            return Err(Error::Mgmt(format!(
+                "prefix {} has no room for address {}",
+                prefix, n
+            )))
+        }
+    };
+    IpNet::new(addr, prefix.prefix_len())
+        .map_err(|e| Error::Mgmt(e.to_string()))
+}
+
+/// An address as written in the host part of a URL, an `scp` target or an
+/// `ssh -L` forward. IPv6 literals are bracketed.
+pub fn host_literal(addr: IpAddr) -> String {
+    match addr {
+        IpAddr::V4(a) => a.to_string(),
+        IpAddr::V6(a) => format!("[{}]", a),
+    }
+}
+
+/// An address and port as written in a URL, e.g. `[fd0c:feed::2]:22`.
+pub fn host_port(addr: IpAddr, port: u16) -> String {
+    SocketAddr::new(addr, port).to_string()
+}
+
+/// A deterministic, locally administered MAC for a node's management
+/// interface, so guests can find the interface without guessing its name.
+pub(crate) fn mgmt_mac(index: usize) -> String {
+    let m = mgmt_mac_bytes(index);
+    format!(
+        "{:02x}:{:02x}:{:02x}:{:02x}:{:02x}:{:02x}",
+        m[0], m[1], m[2], m[3], m[4], m[5]
+    )
+}
+
+/// The bytes of [`mgmt_mac`].
+pub(crate) fn mgmt_mac_bytes(index: usize) -> [u8; 6] {
+    [
+        0x02,
+        0xfa,
+        0x1c,
+        (index >> 16) as u8,
+        (index >> 8) as u8,
+        index as u8,
+    ]
+}
+
+impl Deployment {
+    /// The name of the etherstub backing the management network.
+    pub(crate) fn mgmt_stub_name(&self) -> String {
+        format!("{}_mgmt_stub0", self.name)
+    }
+
+    /// The name of the host's vnic on the management network.
+    pub(crate) fn mgmt_host_vnic_name(&self) -> String {
+        format!("{}_mgmt_host0", self.name)
+    }
+
+    /// The management addresses of the named node, IPv6 first. Empty if
+    /// there is no management network or no such node.
+    pub fn mgmt_addrs(&self, name: &str) -> Result<Vec<IpNet>, Error> {
+        match self.nodes.iter().position(|n| n.name == name) {
+            Some(i) => self.mgmt.node_addrs(i),
+            None => Ok(Vec::new()),
+        }
+    }
+
+    /// The index of the named node's management interface among its viona
+    /// devices, which is also its `vioifN` instance on helios.
+    pub(crate) fn mgmt_viona_index(&self, name: &str) -> Option<usize> {
+        let stub = self.mgmt_stub_name();
+        let l = self.ext_links.iter().find(|l| {
+            l.host_ifx == stub && self.nodes[l.endpoint.node.index].name == name
+        })?;
+        Some(
+            self.endpoints_of(name)
+                .take_while(|e| !std::ptr::eq(*e, &l.endpoint))
+                .filter(|e| matches!(e.kind, EndpointKind::Viona(_)))
+                .count(),
+        )
+    }
+
+    /// `/etc/hosts` lines for every node on the management network. IPv6
+    /// addresses come first so names resolve to them ahead of IPv4 ones.
+    pub fn mgmt_hosts(&self) -> Result<Vec<String>, Error> {
+        let mut v6 = Vec::new();
+        let mut v4 = Vec::new();
+        for (i, n) in self.nodes.iter().enumerate() {
+            for a in self.mgmt.node_addrs(i)? {
+                let line = format!("{} {}", a.addr(), n.name);
+                match a {
+                    IpNet::V6(_) => v6.push(line),
+                    IpNet::V4(_) => v4.push(line),
+                }
+            }
+        }
+        v6.extend(v4);
+        Ok(v6)
+    }
+
+    /// Commands that configure the named node's management addresses in the
+    /// guest, paired with a probe for each address and its expected output.
+    pub(crate) fn mgmt_guest_config(
+        &self,
+        name: &str,
+        guest: GuestKind,
+    ) -> Result<Vec<(IpNet, String, String)>, Error> {
+        let (index, vi) = match (
+            self.nodes.iter().position(|n| n.name == name),
+            self.mgmt_viona_index(name),
+        ) {
+            (Some(i), Some(vi)) => (i, vi),
+            _ => return Ok(Vec::new()),
+        };
+        let mut result = Vec::new();
+        for a in self.mgmt.node_addrs(index)? {
+            let (apply, probe) = match guest {
+                GuestKind::Helios => {
+                    let ifx = format!("vioif{}", vi);
+                    let (obj, apply) = match a {
+                        IpNet::V6(_) => (
+                            format!("{}/mgmt6", ifx),
+                            format!(
+                                "ipadm create-addr -t -T addrconf {ifx}/ll; \
+                                ipadm create-addr -t -T static -a {a} \
+                                {ifx}/mgmt6",
+                                ifx = ifx,
+                                a = a,
+                            ),
+                        ),
+                        IpNet::V4(_) => (
+                            format!("{}/mgmt4", ifx),
+                            format!(
+                                "ipadm create-addr -t -T static -a {} \
+                                {}/mgmt4",
+                                a, ifx
+                            ),
+                        ),
+                    };
+                    let probe = format!(
+                        "ipadm show-addr -p -o addr {} 2>/dev/null | \
+                        grep -qxF '{}' && echo present || echo missing",
+                        obj, a
+                    );
+                    (apply, probe)
+                }
+                GuestKind::Linux => {
+                    let ifx = format!(
+                        "$(ip -o link | grep -i '{}' | cut -d: -f2 | \
+                        tr -d ' ')",
+                        mgmt_mac(index)
+                    );
+                    (
+                        format!(
+                            "ip link set {ifx} up; ip addr add {a} dev {ifx}",
+                            ifx = ifx,
+                            a = a
+                        ),
+                        format!(
+                            "ip -o addr show dev {} | grep -qF ' {} ' && \
+                            echo present || echo missing",
+                            ifx, a
+                        ),
+                    )
+                }
+                GuestKind::Other => return Ok(Vec::new()),
+            };
+            result.push((a, apply, probe));
+        }
+        Ok(result)
+    }
+}
+
+impl Runner {
+    /// Put the deployment on an IPv4 management network, e.g.
+    /// `r.mgmt_network_v4("10.100.0.0/24")`. Composes with
+    /// `mgmt_network_v6` for a dual stack network.
+    pub fn mgmt_network_v4(&mut self, prefix: &str) -> Result<(), Error> {
+        let p: Ipv4Net = prefix.parse().map_err(|e| {
+            Error::Mgmt(format!("bad IPv4 prefix '{}': {}", prefix, e))
+        })?;
+        self.deployment.mgmt.v4 = Some(p.trunc());
+        self.attach_mgmt();
+        Ok(())
+    }
+
+    /// Put the deployment on an IPv6 management network, e.g.
+    /// `r.mgmt_network_v6("fd0c:feed::/64")`. Composes with
+    /// `mgmt_network_v4` for a dual stack network.
+    pub fn mgmt_network_v6(&mut self, prefix: &str) -> Result<(), Error> {
+        let p: Ipv6Net = prefix.parse().map_err(|e| {
+            Error::Mgmt(format!("bad IPv6 prefix '{}': {}", prefix, e))
+        })?;
+        self.deployment.mgmt.v6 = Some(p.trunc());
+        self.attach_mgmt();
+        Ok(())
+    }
+
+    /// Give every node without one a management interface.
+    pub(crate) fn attach_mgmt(&mut self) {
+        if !self.deployment.mgmt.is_enabled() {
+            return;
+        }
+        let stub = self.deployment.mgmt_stub_name();
+        for node in self.all_nodes() {
+            let name = self.get_node(node).name.clone();
+            if self.deployment.mgmt_viona_index(&name).is_none() {
+                let mac = mgmt_mac(node.index);
+                self.ext_link_with_kind(
+                    &stub,
+                    node,
+                    EndpointKind::Viona(Some(mac)),
+                );
+            }
+        }
+    }
+
+    /// The preferred management address of a node, IPv6 when there is one.
+    pub fn mgmt_addr(&self, n: NodeRef) -> Result<IpAddr, Error> {
+        let name = &self.get_node(n).name;
+        match self.deployment.mgmt_addrs(name)?.first() {
+            Some(a) => Ok(a.addr()),
+            None => Err(Error::Mgmt(format!(
+                "{} is not on a management network",
+                name
+            ))),
+        }
+    }
+
+    /// Arguments to `ssh` for logging into a node over the management
+    /// network.
+    pub fn ssh_args(&self, n: NodeRef) -> Result<Vec<String>, Error> {
+        // user@host takes a bare IPv6 literal
+        Ok(vec![
+            "-o".into(),
+            "StrictHostKeyChecking=no".into(),
+            "-o".into(),
+            "UserKnownHostsFile=/dev/null".into(),
+            format!("root@{}", self.mgmt_addr(n)?),
+        ])
+    }
+
+    /// An `scp` source or destination for `path` on a node.
+    pub fn scp_target(&self, n: NodeRef, path: &str) -> Result<String, Error> {
+        Ok(format!(
+            "root@{}:{}",
+            host_literal(self.mgmt_addr(n)?),
+            path
+        ))
+    }
+
+    /// An `ssh -L` forward of `local_port` on the host to `port` on a node.
+    pub fn port_forward(
+        &self,
+        n: NodeRef,
+        local_port: u16,
+        port: u16,
+    ) -> Result<String, Error> {
+        Ok(format!(
+            "{}:{}",
+            local_port,
+            host_port(self.mgmt_addr(n)?, port)
+        ))
+    }
+
+    /// Create the management etherstub and the host's vnic on it, and give
+    /// the host its management addresses.
+    pub(crate) fn mgmt_create(&self) -> Result<(), Error> {
+        let d = &self.deployment;
+        if !d.mgmt.is_enabled() {
+            return Ok(());
+        }
+        let stub = d.mgmt_stub_name();
+        let vnic = d.mgmt_host_vnic_name();
+
+        // clear out anything left by an earlier run
+        for l in self.mgmt_destroy() {
+            debug!(self.log, "{} not removed: {}", l.what, l.error);
+        }
+
+        info!(self.log, "creating management network {}", stub);
+        net_cmd(&self.plan, DLADM_BIN, &["create-etherstub", "-t", &stub])?;
+        net_cmd(
+            &self.plan,
+            DLADM_BIN,
+            &["create-vnic", "-t", "-l", &stub, &vnic],
+        )?;
+        for a in d.mgmt.host_addrs()? {
+            let obj = match a {
+                IpNet::V6(_) => {
+                    // static IPv6 addresses need a link local address first
+                    let ll = format!("{}/ll", vnic);
+                    net_cmd(
+                        &self.plan,
+                        IPADM_BIN,
+                        &["create-addr", "-t", "-T", "addrconf", &ll],
+                    )?;
+                    format!("{}/mgmt6", vnic)
+                }
+                IpNet::V4(_) => format!("{}/mgmt4", vnic),
+            };
+            let a = a.to_string();
+            net_cmd(
+                &self.plan,
+                IPADM_BIN,
+                &["create-addr", "-t", "-T", "static", "-a", &a, &obj],
+            )?;
+        }
+        Ok(())
+    }
+
+    /// Remove the host's management vnic and the etherstub. Node vnics on
+    /// the etherstub must already be gone.
+    pub(crate) fn mgmt_destroy(&self) -> Vec<Leftover> {
+        let d = &self.deployment;
+        let mut leftovers = Vec::new();
+        if !d.mgmt.is_enabled() {
+            return leftovers;
+        }
+        let stub = d.mgmt_stub_name();
+        let vnic = d.mgmt_host_vnic_name();
+
+        // the dhcp responder holds sockets bound to the vnic
+        self.dhcp_stop();
+
+        if link_exists(self.backend(), &vnic) {
+            let _ = self.plan.run(IPADM_BIN, &["delete-if", &vnic]);
+            if let Err(e) =
+                net_cmd(&self.plan, DLADM_BIN, &["delete-vnic", "-t", &vnic])
+            {
+                leftovers.push(Leftover {
+                    what: format!("vnic {}", vnic),
+                    error: e.to_string(),
+                    cleanup: format!("{} delete-vnic {}", DLADM_BIN, vnic),
+                });
+            }
+        }
+        if link_exists(self.backend(), &stub) {
+            if let Err(e) = net_cmd(
+                &self.plan,
+                DLADM_BIN,
+                &["delete-etherstub", "-t", &stub],
+            ) {
+                leftovers.push(Leftover {
+                    what: format!("etherstub {}", stub),
+                    error: e.to_string(),
+                    cleanup: format!("{} delete-etherstub {}", DLADM_BIN, stub),
+                });
+            }
+        }
+        leftovers
+    }
+}
+
+pub(crate) fn link_exists(host: &dyn Backend, name: &str) -> bool {
+    host.run(DLADM_BIN, &["show-link", "-p", "-o", "link", name])
+        .map(|out| out.status.success())
+        .unwrap_or(false)
+}
+
+/// Run a network administration command as a step of `plan`, translating
+/// privilege failures.
+pub(crate) fn net_cmd(
+    plan: &Plan,
+    bin: &str,
+    args: &[&str],
+) -> Result<(), Error> {
+    plan.step(Step::command(bin, args), || {
+        let out = plan.backend().run(bin, args)?;
+        if !out.status.success() {
+            let stderr = String::from_utf8_lossy(&out.stderr);
+            let what = format!("{} {}", bin, args.join(" "));
+            return Err(ops::net_env_error(&what, &stderr).unwrap_or_else(
+                || Error::Exec(format!("{} failed: {}", what, stderr.trim())),
+            ));
+        }
+        Ok(())
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use crate::test::Scratch;
+    use anyhow::{anyhow, Result};
+
+    #[test]
+    fn mgmt_network_addresses() -> Result<()> {
+        use crate::mgmt::{host_literal, host_port, nth_addr};
+        use std::net::IpAddr;
+
+        let mut r = crate::Runner::new("mgmt");
+        r.persistent = true;
+        let violin = r.node("violin", "helios-2.3", 1, 1024);
+        r.mgmt_network_v6("fd0c:feed::/64")?;
+        let piano = r.node("piano", "debian-11.0", 1, 1024);
+        r.link(violin, piano);
+
+        // the host is ::1, nodes follow in declaration order whether they were
+        // declared before or after the network
+        let d = &r.deployment;
+        assert_eq!(d.mgmt.host_addrs()?[0].to_string(), "fd0c:feed::1/64");
+        assert_eq!(d.mgmt_addrs("violin")?[0].to_string(), "fd0c:feed::2/64");
+        assert_eq!(d.mgmt_addrs("piano")?[0].to_string(), "fd0c:feed::3/64");
+        // links come ahead of external links on a node, whatever order they
+        // were declared in
+        assert_eq!(d.mgmt_viona_index("violin"), Some(1));
+        assert_eq!(d.mgmt_viona_index("piano"), Some(1));
+        assert_eq!(
+            d.mgmt_hosts()?,
+            ["fd0c:feed::2 violin", "fd0c:feed::3 piano"]
+        );
+
+        // literals are bracketed wherever a port or path follows
+        assert_eq!(r.mgmt_addr(violin)?.to_string(), "fd0c:feed::2");
+        assert_eq!(r.ssh_args(violin)?.last().unwrap(), "root@fd0c:feed::2");
+        assert_eq!(
+            r.scp_target(violin, "/tmp/x")?,
+            "root@[fd0c:feed::2]:/tmp/x"
+        );
+        assert_eq!(r.port_forward(violin, 8080, 80)?, "8080:[fd0c:feed::2]:80");
+        let v6: IpAddr = "fd0c:feed::2".parse()?;
+        let v4: IpAddr = "10.0.0.2".parse()?;
+        assert_eq!(host_literal(v6), "[fd0c:feed::2]");
+        assert_eq!(host_literal(v4), "10.0.0.2");
+        assert_eq!(host_port(v6, 22), "[fd0c:feed::2]:22");
+        assert_eq!(host_port(v4, 22), "10.0.0.2:22");
+
+        // guest configuration takes bare literals with a prefix length
+        let setup = r.get_node(violin).setup_commands(&r.deployment)?;
+        assert!(setup.contains(
+            &"ipadm create-addr -t -T addrconf vioif1/ll; ipadm create-addr -t \
+            -T static -a fd0c:feed::2/64 vioif1/mgmt6"
+                .to_string()
+        ));
+        assert!(
+            setup.contains(&"echo 'fd0c:feed::3 piano' >> /etc/hosts".into())
+        );
+
+        // dual stack puts IPv6 first
+        r.mgmt_network_v4("10.100.0.0/24")?;
+        let d = &r.deployment;
+        let addrs: Vec<String> = d
+            .mgmt_addrs("piano")?
+            .iter()
+            .map(ToString::to_string)
+            .collect();
+        assert_eq!(addrs, ["fd0c:feed::3/64", "10.100.0.3/24"]);
+        assert_eq!(d.mgmt_hosts()?.len(), 4);
+        assert_eq!(d.ext_links.len(), 2);
+        assert_eq!(r.port_forward(violin, 8080, 80)?, "8080:[fd0c:feed::2]:80");
+
+        // host bits are dropped, small prefixes run out of room
+        let p = "10.1.2.3/30".parse()?;
+        assert_eq!(nth_addr(p, 1)?.to_string(), "10.1.2.1/30");
+        assert_eq!(nth_addr(p, 2)?.to_string(), "10.1.2.2/30");
+        assert!(nth_addr(p, 3).is_err());
+        assert!(nth_addr(p, 0).is_err());
+        assert!(nth_addr("fd00::/127".parse()?, 2).is_err());
+        assert!(r.mgmt_network_v6("fd00::/129").is_err());
+        assert!(r.mgmt_network_v4("fd00::/64").is_err());
+
+        Ok(())
+    }
+
+    /// Test that tearing down a management network that cannot be removed
+    /// reports every object left behind, not just the last.
+    #[test]
+    fn mgmt_destroy_leftovers() -> Result<()> {
+        use crate::error::Error;
+        use crate::ops::fake;
+        use crate::output::OutputCtx;
+
+        let scratch = Scratch::new("mgmt")?;
+        let mut r = scratch.runner("gone");
+        r.set_backend(fake::backend(|_, args| match args.first() {
+            Some(&"show-link") => fake::ok(""),
+            Some(&"delete-vnic") | Some(&"delete-etherstub") => {
+                fake::fail("link busy")
+            }
+            _ => fake::ok(""),
+        }));
+        r.set_output(OutputCtx::silent());
+        r.mgmt_network_v4("10.100.0.0/24")?;
+        let result = r.net_destroy();
+
+        let report = match result {
+            Err(Error::Destroy(report)) => report,
+            other => return Err(anyhow!("expected leftovers: {:?}", other)),
+        };
+        let what: Vec<&str> =
+            report.leftovers.iter().map(|l| l.what.as_str()).collect();
+        assert_eq!(what, ["vnic gone_mgmt_host0", "etherstub gone_mgmt_stub0"]);
+
+        Ok(())
+    }
+}
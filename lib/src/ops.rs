@@ -0,0 +1,1089 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+// Copyright 2022 Oxide Computer Company
+
+//! Wrappers around the external host tools falcon drives.
+
+use crate::error::Error;
+use crate::{DLADM_BIN, ZFS_BIN};
+use camino::{Utf8Path, Utf8PathBuf};
+use futures::future::BoxFuture;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fmt;
+use std::fs;
+use std::future::Future;
+use std::os::unix::process::ExitStatusExt;
+use std::process::{Command, ExitStatus, Output, Stdio};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+/// Lookups that cannot change while a launch is in progress, keyed by the
+/// queried object. Only populated while a `CacheScope` is alive.
+static CACHE: Mutex<Cache> = Mutex::new(Cache {
+    scopes: 0,
+    entries: BTreeMap::new(),
+});
+
+struct Cache {
+    /// The number of live `CacheScope`s.
+    scopes: usize,
+    entries: BTreeMap<String, String>,
+}
+
+const ZPOOL_BIN: &str = "/usr/sbin/zpool";
+
+/// Runs the external commands falcon drives. Every host tool invocation
+/// goes through a backend, the runner's plan holds the one used for its
+/// operations.
+pub trait Backend: Send + Sync {
+    /// Run `bin` to completion and capture its output.
+    fn run(&self, bin: &str, args: &[&str]) -> Result<Output, Error>;
+
+    /// Like `run`, but the command is killed if it has not finished within
+    /// `t`. Backends that cannot hang need not override it.
+    fn run_within<'a>(
+        &'a self,
+        bin: &'a str,
+        args: &'a [&'a str],
+        _t: Duration,
+    ) -> BoxFuture<'a, Result<Output, Error>> {
+        Box::pin(async move { self.run(bin, args) })
+    }
+
+    /// Make the call `step` stands for in place of the plan, or return
+    /// `None` to have the plan make it.
+    fn call(&self, _step: &Step) -> Option<Result<(), Error>> {
+        None
+    }
+}
+
+impl fmt::Debug for dyn Backend {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("Backend")
+    }
+}
+
+/// The backend that runs commands on the host.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Host;
+
+impl Backend for Host {
+    fn run(&self, bin: &str, args: &[&str]) -> Result<Output, Error> {
+        Ok(Command::new(bin).args(args).output()?)
+    }
+
+    fn run_within<'a>(
+        &'a self,
+        bin: &'a str,
+        args: &'a [&'a str],
+        t: Duration,
+    ) -> BoxFuture<'a, Result<Output, Error>> {
+        Box::pin(async move {
+            let out = tokio::process::Command::new(bin)
+                .args(args)
+                .kill_on_drop(true)
+                .output();
+            match tokio::time::timeout(t, out).await {
+                Ok(out) => Ok(out?),
+                Err(_) => Err(Error::Exec(format!(
+                    "{} {} did not finish within {:?}",
+                    bin,
+                    args.join(" "),
+                    t
+                ))),
+            }
+        })
+    }
+}
+
+/// One externally visible action of a mutating operation.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "kind", rename_all = "lowercase")]
+pub enum Step {
+    /// Run a host command.
+    Command { bin: String, args: Vec<String> },
+    /// Call into libnet, propolis or a guest console.
+    Call { api: String, args: Vec<String> },
+    /// Create or replace a file or directory.
+    Write { path: Utf8PathBuf },
+    /// Remove a file or directory.
+    Remove { path: Utf8PathBuf },
+}
+
+impl Step {
+    pub(crate) fn command(bin: &str, args: &[&str]) -> Self {
+        Step::Command {
+            bin: bin.into(),
+            args: args.iter().map(|a| a.to_string()).collect(),
+        }
+    }
+
+    pub(crate) fn call(api: &str, args: &[&str]) -> Self {
+        Step::Call {
+            api: api.into(),
+            args: args.iter().map(|a| a.to_string()).collect(),
+        }
+    }
+
+    pub(crate) fn write(path: impl AsRef<Utf8Path>) -> Self {
+        Step::Write {
+            path: path.as_ref().into(),
+        }
+    }
+
+    pub(crate) fn remove(path: impl AsRef<Utf8Path>) -> Self {
+        Step::Remove {
+            path: path.as_ref().into(),
+        }
+    }
+}
+
+impl fmt::Display for Step {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Step::Command { bin, args } => {
+                write!(f, "run {}", bin)?;
+                for a in args {
+                    write!(f, " {}", a)?;
+                }
+                Ok(())
+            }
+            Step::Call { api, args } => {
+                write!(f, "call {}({})", api, args.join(", "))
+            }
+            Step::Write { path } => write!(f, "write {}", path),
+            Step::Remove { path } => write!(f, "remove {}", path),
+        }
+    }
+}
+
+/// The ordered steps taken by the mutating operations of a runner. Every
+/// step goes through the plan, which takes it, or in a dry run only records
+/// it. Queries are still run in a dry run so later steps see the host as
+/// it is. Steps are only kept when the plan records them, which a dry run
+/// always does, so a long lived runner does not hold every step it took.
+#[derive(Debug)]
+pub struct Plan {
+    dry_run: bool,
+    record: bool,
+    steps: Mutex<Vec<Step>>,
+    /// The number of steps begun so far.
+    taken: AtomicUsize,
+    /// The first step to fail and its index.
+    failed: Mutex<Option<(usize, Step)>>,
+    backend: Arc<dyn Backend>,
+}
+
+impl Default for Plan {
+    fn default() -> Self {
+        Plan {
+            dry_run: false,
+            record: false,
+            steps: Mutex::default(),
+            taken: AtomicUsize::new(0),
+            failed: Mutex::default(),
+            backend: Arc::new(Host),
+        }
+    }
+}
+
+impl Plan {
+    pub fn new(dry_run: bool) -> Self {
+        Plan {
+            dry_run,
+            record: dry_run,
+            ..Default::default()
+        }
+    }
+
+    /// A plan that keeps the steps it takes even when it is not a dry run.
+    pub fn recording(dry_run: bool) -> Self {
+        Plan {
+            dry_run,
+            record: true,
+            ..Default::default()
+        }
+    }
+
+    /// Run the plan's commands with `backend` rather than on the host.
+    pub fn with_backend(self, backend: Arc<dyn Backend>) -> Self {
+        Plan { backend, ..self }
+    }
+
+    /// The backend commands are run with.
+    pub fn backend(&self) -> &dyn Backend {
+        &*self.backend
+    }
+
+    /// Start `next` in place of this plan, running its commands with the
+    /// same backend.
+    pub(crate) fn restart(&self, next: Plan) -> Self {
+        next.with_backend(self.backend.clone())
+    }
+
+    pub fn is_dry_run(&self) -> bool {
+        self.dry_run
+    }
+
+    /// The steps taken, or planned in a dry run, so far. Empty unless the
+    /// plan records them.
+    pub fn steps(&self) -> Vec<Step> {
+        self.steps.lock().map(|s| s.clone()).unwrap_or_default()
+    }
+
+    /// The step that failed and its number, counting from one.
+    pub fn failed(&self) -> Option<(usize, Step)> {
+        let failed = self.failed.lock().ok()?;
+        failed.as_ref().map(|(i, step)| (i + 1, step.clone()))
+    }
+
+    /// Count, and when recording keep, `step`, returning its index unless
+    /// this is a dry run.
+    fn begin(&self, step: &Step) -> Option<usize> {
+        let i = self.taken.fetch_add(1, Ordering::SeqCst);
+        if self.record {
+            if let Ok(mut steps) = self.steps.lock() {
+                steps.push(step.clone());
+            }
+        }
+        if self.dry_run {
+            return None;
+        }
+        Some(i)
+    }
+
+    fn end<T>(
+        &self,
+        i: usize,
+        step: &Step,
+        result: Result<T, Error>,
+    ) -> Result<T, Error> {
+        if result.is_err() {
+            self.fail(i, step);
+        }
+        result
+    }
+
+    /// Note the first step to fail.
+    fn fail(&self, i: usize, step: &Step) {
+        if let Ok(mut failed) = self.failed.lock() {
+            failed.get_or_insert_with(|| (i, step.clone()));
+        }
+    }
+
+    /// Take `step` by calling `f`. In a dry run `f` is not called and the
+    /// default value is returned. A step whose value later steps depend on,
+    /// such as a pid, should return it as an `Option` so a dry run yields
+    /// `None` rather than a made up value.
+    pub(crate) fn step<T: Default>(
+        &self,
+        step: Step,
+        f: impl FnOnce() -> Result<T, Error>,
+    ) -> Result<T, Error> {
+        let i = match self.begin(&step) {
+            Some(i) => i,
+            None => return Ok(T::default()),
+        };
+        if let Some(result) = self.backend.call(&step) {
+            return self.end(i, &step, result.map(|_| T::default()));
+        }
+        self.end(i, &step, f())
+    }
+
+    /// Take `step` by awaiting `f`. In a dry run `f` is dropped without
+    /// being polled and the default value is returned.
+    pub(crate) async fn step_async<T: Default>(
+        &self,
+        step: Step,
+        f: impl Future<Output = Result<T, Error>>,
+    ) -> Result<T, Error> {
+        let i = match self.begin(&step) {
+            Some(i) => i,
+            None => return Ok(T::default()),
+        };
+        if let Some(result) = self.backend.call(&step) {
+            return self.end(i, &step, result.map(|_| T::default()));
+        }
+        self.end(i, &step, f.await)
+    }
+
+    /// Run a mutating host command whose exit status the caller interprets,
+    /// see `Backend::run`. In a dry run the command is taken to succeed with no
+    /// output.
+    pub(crate) fn run(
+        &self,
+        bin: &str,
+        args: &[&str],
+    ) -> Result<Output, Error> {
+        let step = Step::command(bin, args);
+        let i = match self.begin(&step) {
+            Some(i) => i,
+            None => {
+                return Ok(Output {
+                    status: ExitStatus::from_raw(0),
+                    stdout: Vec::new(),
+                    stderr: Vec::new(),
+                })
+            }
+        };
+        self.end(i, &step, self.backend.run(bin, args))
+    }
+
+    /// Run a mutating `zfs` command, see `zfs`.
+    pub(crate) fn zfs(&self, args: &[&str]) -> Result<String, Error> {
+        self.step(Step::command(ZFS_BIN, args), || zfs(&*self.backend, args))
+    }
+
+    /// Copy `snapshot` into a new dataset `dest`, see `zfs_send_receive`.
+    pub(crate) fn zfs_send_receive(
+        &self,
+        snapshot: &str,
+        dest: &str,
+    ) -> Result<(), Error> {
+        let step = Step::command(
+            ZFS_BIN,
+            &["send", snapshot, "|", ZFS_BIN, "receive", dest],
+        );
+        self.step(step, || zfs_send_receive(snapshot, dest))
+    }
+
+    /// Write `contents` to `path`.
+    pub(crate) fn write(
+        &self,
+        path: impl AsRef<Utf8Path>,
+        contents: impl AsRef<[u8]>,
+    ) -> Result<(), Error> {
+        let path = path.as_ref();
+        self.step(Step::write(path), || Ok(fs::write(path, contents)?))
+    }
+
+    /// Create the directory `path` and its parents.
+    pub(crate) fn create_dir_all(
+        &self,
+        path: impl AsRef<Utf8Path>,
+    ) -> Result<(), Error> {
+        let path = path.as_ref();
+        if path.is_dir() {
+            return Ok(());
+        }
+        self.step(Step::write(path), || Ok(fs::create_dir_all(path)?))
+    }
+
+    /// Remove the file or directory tree at `path` if there is one.
+    pub(crate) fn remove(
+        &self,
+        path: impl AsRef<Utf8Path>,
+    ) -> Result<(), Error> {
+        let path = path.as_ref();
+        if !path.exists() {
+            return Ok(());
+        }
+        self.step(Step::remove(path), || {
+            if path.is_dir() {
+                fs::remove_dir_all(path)?;
+            } else {
+                fs::remove_file(path)?;
+            }
+            Ok(())
+        })
+    }
+}
+
+/// The steps of a plan, one per line, numbered from one.
+impl fmt::Display for Plan {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let failed = self.failed().map(|(n, _)| n);
+        for (i, s) in self.steps().iter().enumerate() {
+            let mark = if failed == Some(i + 1) {
+                " (failed)"
+            } else {
+                ""
+            };
+            writeln!(f, "{:>3}. {}{}", i + 1, s, mark)?;
+        }
+        Ok(())
+    }
+}
+
+/// Caches immutable lookups such as image guids until the last live scope
+/// is dropped. Launch holds one for its duration. Scopes nest, an operation
+/// holding one may call another that holds its own.
+pub(crate) struct CacheScope(());
+
+impl CacheScope {
+    pub(crate) fn new() -> Self {
+        if let Ok(mut c) = CACHE.lock() {
+            c.scopes += 1;
+        }
+        CacheScope(())
+    }
+}
+
+impl Drop for CacheScope {
+    fn drop(&mut self) {
+        if let Ok(mut c) = CACHE.lock() {
+            c.scopes -= 1;
+            if c.scopes == 0 {
+                c.entries.clear();
+            }
+        }
+    }
+}
+
+fn cached(key: &str) -> Option<String> {
+    let c = CACHE.lock().ok()?;
+    if c.scopes == 0 {
+        return None;
+    }
+    c.entries.get(key).cloned()
+}
+
+fn cache(key: &str, value: &str) {
+    if let Ok(mut c) = CACHE.lock() {
+        if c.scopes > 0 {
+            c.entries.insert(key.into(), value.into());
+        }
+    }
+}
+
+/// Run `zfs` with the provided arguments, returning stdout on success.
+pub(crate) fn zfs(host: &dyn Backend, args: &[&str]) -> Result<String, Error> {
+    let out = host.run(ZFS_BIN, args)?;
+    if !out.status.success() {
+        return Err(Error::Zfs(String::from_utf8(out.stderr)?));
+    }
+    Ok(String::from_utf8(out.stdout)?)
+}
+
+/// Return true if the named dataset (or snapshot) exists.
+pub(crate) fn dataset_exists(
+    host: &dyn Backend,
+    name: &str,
+) -> Result<bool, Error> {
+    let out = host.run(ZFS_BIN, &["list", "-H", "-o", "name", name])?;
+    Ok(out.status.success())
+}
+
+/// Get the `property` of each of `names` with a single `zfs list`. Names
+/// that do not exist are absent from the result rather than an error.
+pub(crate) fn list_property(
+    host: &dyn Backend,
+    names: &[&str],
+    property: &str,
+) -> Result<BTreeMap<String, String>, Error> {
+    if names.is_empty() {
+        return Ok(BTreeMap::new());
+    }
+    let fields = format!("name,{}", property);
+    let mut args = vec!["list", "-Hp", "-t", "all", "-o", fields.as_str()];
+    args.extend_from_slice(names);
+    // zfs list prints what it found even when some names are missing
+    let out = host.run(ZFS_BIN, &args)?;
+    Ok(parse_list_property(&String::from_utf8(out.stdout)?))
+}
+
+/// Parse tab separated `name<TAB>value` lines from `zfs list -H`.
+pub(crate) fn parse_list_property(out: &str) -> BTreeMap<String, String> {
+    out.lines()
+        .filter_map(|l| l.split_once('\t'))
+        .map(|(name, value)| (name.to_string(), value.trim().to_string()))
+        .collect()
+}
+
+/// The `props` of the given image snapshots with a single `zfs list`,
+/// served from the launch cache where possible. Missing snapshots are absent
+/// from the result, as are properties a snapshot does not have.
+pub(crate) fn image_props(
+    host: &dyn Backend,
+    snapshots: &[&str],
+    props: &[&str],
+) -> Result<BTreeMap<String, BTreeMap<String, String>>, Error> {
+    let fields = props.join(",");
+    let key = |s: &str| format!("props:{}:{}", fields, s);
+    let mut rows = Vec::new();
+    let mut lookup = Vec::new();
+    for s in snapshots {
+        match cached(&key(s)) {
+            Some(row) => rows.push(row),
+            None => lookup.push(*s),
+        }
+    }
+    if !lookup.is_empty() {
+        let columns = format!("name,{}", fields);
+        let mut args = vec!["list", "-Hp", "-t", "all", "-o", columns.as_str()];
+        args.extend_from_slice(&lookup);
+        // zfs list prints what it found even when some names are missing
+        let out = host.run(ZFS_BIN, &args)?;
+        for row in String::from_utf8(out.stdout)?.lines() {
+            if let Some((name, _)) = row.split_once('\t') {
+                cache(&key(name), row);
+                rows.push(row.to_string());
+            }
+        }
+    }
+
+    let mut result = BTreeMap::new();
+    for row in rows {
+        let mut fields = row.split('\t');
+        let name = match fields.next() {
+            Some(name) => name.to_string(),
+            None => continue,
+        };
+        // unset user properties are shown as a dash
+        let values = props
+            .iter()
+            .zip(fields.map(str::trim))
+            .filter(|(_, v)| *v != "-")
+            .map(|(p, v)| (p.to_string(), v.to_string()))
+            .collect();
+        result.insert(name, values);
+    }
+    Ok(result)
+}
+
+/// Destroy `dataset` and its descendants, a dataset that does not exist is
+/// not an error.
+pub(crate) fn zfs_destroy_recursive(
+    host: &dyn Backend,
+    dataset: &str,
+) -> Result<(), Error> {
+    let out = host.run(ZFS_BIN, &["destroy", "-r", dataset])?;
+    if out.status.success() {
+        return Ok(());
+    }
+    let stderr = String::from_utf8(out.stderr)?;
+    if stderr.contains("does not exist") {
+        return Ok(());
+    }
+    Err(Error::Zfs(stderr))
+}
+
+/// The pool a dataset lives in, i.e. the first component of its name.
+pub(crate) fn pool_of(dataset: &str) -> &str {
+    match dataset.split_once('/') {
+        Some((pool, _)) => pool,
+        None => dataset,
+    }
+}
+
+/// Parse `name<TAB>value` lines, as printed by `zpool list -Hp` and, with
+/// colons, by `dladm -p`.
+pub(crate) fn parse_pairs(out: &str, sep: char) -> BTreeMap<String, String> {
+    out.lines()
+        .filter_map(|l| l.split_once(sep))
+        .map(|(k, v)| (k.trim().to_string(), v.trim().to_string()))
+        .collect()
+}
+
+/// How full each pool on the host is, in percent.
+pub(crate) async fn pool_capacities(
+    host: &dyn Backend,
+    t: Duration,
+) -> Result<BTreeMap<String, u8>, Error> {
+    let args = ["list", "-Hp", "-o", "name,capacity"];
+    let out = host.run_within(ZPOOL_BIN, &args, t).await?;
+    if !out.status.success() {
+        return Err(Error::Zfs(String::from_utf8(out.stderr)?));
+    }
+    Ok(parse_pairs(&String::from_utf8(out.stdout)?, '\t')
+        .into_iter()
+        .filter_map(|(k, v)| Some((k, v.trim_end_matches('%').parse().ok()?)))
+        .collect())
+}
+
+/// The state of every data link on the host, e.g. `up` or `down`.
+pub(crate) async fn link_states(
+    host: &dyn Backend,
+    t: Duration,
+) -> Result<BTreeMap<String, String>, Error> {
+    let args = ["show-link", "-p", "-o", "link,state"];
+    let out = host.run_within(DLADM_BIN, &args, t).await?;
+    if !out.status.success() {
+        return Err(Error::Exec(String::from_utf8(out.stderr)?));
+    }
+    Ok(parse_pairs(&String::from_utf8(out.stdout)?, ':'))
+}
+
+/// Copy `snapshot` into a new dataset `dest` with `zfs send | zfs receive`.
+/// This works across pools, unlike a clone, at the cost of a full copy.
+pub(crate) fn zfs_send_receive(
+    snapshot: &str,
+    dest: &str,
+) -> Result<(), Error> {
+    let mut send = Command::new(ZFS_BIN)
+        .args(["send", snapshot])
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()?;
+
+    let stream = match send.stdout.take() {
+        Some(s) => s,
+        None => return Err(Error::Zfs("zfs send produced no stream".into())),
+    };
+
+    let recv = Command::new(ZFS_BIN)
+        .args(["receive", dest])
+        .stdin(stream)
+        .output()?;
+
+    let sent = send.wait_with_output()?;
+    if !sent.status.success() {
+        return Err(Error::Zfs(String::from_utf8(sent.stderr)?));
+    }
+    if !recv.status.success() {
+        return Err(Error::Zfs(String::from_utf8(recv.stderr)?));
+    }
+
+    Ok(())
+}
+
+/// Render a byte count in the same style as `zfs list`.
+pub(crate) fn human_bytes(bytes: u64) -> String {
+    const UNITS: [&str; 6] = ["B", "K", "M", "G", "T", "P"];
+    let mut value = bytes as f64;
+    let mut unit = 0;
+    while value >= 1024.0 && unit < UNITS.len() - 1 {
+        value /= 1024.0;
+        unit += 1;
+    }
+    if unit == 0 {
+        format!("{}{}", bytes, UNITS[0])
+    } else {
+        format!("{:.1}{}", value, UNITS[unit])
+    }
+}
+
+/// Privilege failures as reported by dladm and libnet. illumos renders EPERM
+/// as "Not owner".
+const PRIVILEGE_PATTERNS: [&str; 2] = ["insufficient privileges", "Not owner"];
+
+/// Opening `/dev/vmmctl` refused, with EACCES or EPERM.
+const VMMCTL_DENIED_PATTERNS: [&str; 2] =
+    ["Permission denied (os error 13)", "Not owner (os error 1)"];
+
+/// The vmm driver is missing or not attached.
+const VMM_MISSING_PATTERNS: [&str; 2] = [
+    "/dev/vmmctl: No such file or directory",
+    "vmm driver not loaded",
+];
+
+/// The vmm memory reservoir cannot back the instance.
+const RESERVOIR_PATTERNS: [&str; 2] =
+    ["reservoir", "Cannot allocate memory (os error 12)"];
+
+/// Translate the stderr (or error text) of a failed network object creation
+/// into an error naming the privilege involved and how to get it. Returns
+/// `None` if the failure is not a known environment problem.
+pub(crate) fn net_env_error(what: &str, stderr: &str) -> Option<Error> {
+    if PRIVILEGE_PATTERNS.iter().any(|p| stderr.contains(p)) {
+        return Some(Error::Environment {
+            problem: format!(
+                "{}: insufficient privileges, creating simnets and vnics \
+                requires the sys_dl_config privilege",
+                what,
+            ),
+            fix: "run falcon with pfexec, or grant the profile with \
+                `usermod -P 'Network Link Management' $USER`"
+                .into(),
+        });
+    }
+    None
+}
+
+/// Map a libnet failure creating `what` to an environment error if it is
+/// one, otherwise pass it through.
+pub(crate) fn libnet_error(what: &str, e: libnet::Error) -> Error {
+    net_env_error(what, &e.to_string()).unwrap_or(Error::Libnet(e))
+}
+
+/// Translate the stderr of a propolis-server that failed to create its vm
+/// into an error naming the driver involved and how to fix it. Returns `None`
+/// if the failure is not a known environment problem.
+pub(crate) fn vmm_env_error(what: &str, stderr: &str) -> Option<Error> {
+    if VMM_MISSING_PATTERNS.iter().any(|p| stderr.contains(p)) {
+        return Some(Error::Environment {
+            problem: format!("{}: the vmm driver is not available", what),
+            fix: "install bhyve, which adds the vmm driver, with `pfexec pkg \
+                install system/bhyve`, then check the driver is loaded with \
+                `modinfo | grep vmm`"
+                .into(),
+        });
+    }
+    let denied = stderr.lines().any(|l| {
+        l.contains("/dev/vmmctl")
+            && VMMCTL_DENIED_PATTERNS.iter().any(|p| l.contains(p))
+    });
+    if denied {
+        return Some(Error::Environment {
+            problem: format!(
+                "{}: insufficient privileges to open /dev/vmmctl, creating \
+                vms requires the sys_config privilege",
+                what,
+            ),
+            fix: "run falcon with pfexec, or grant the profile with \
+                `usermod -P 'Primary Administrator' $USER`"
+                .into(),
+        });
+    }
+    if RESERVOIR_PATTERNS.iter().any(|p| stderr.contains(p)) {
+        return Some(Error::Environment {
+            problem: format!(
+                "{}: the vmm memory reservoir is too small for the instance",
+                what,
+            ),
+            fix: "grow the reservoir with `pfexec rsrvrctl -s <MiB>`, \
+                `rsrvrctl -q` shows its current size"
+                .into(),
+        });
+    }
+    None
+}
+
+/// A stand in for the host tools, so tests can drive the ops layer without
+/// zfs or dladm.
+#[cfg(test)]
+pub(crate) mod fake {
+    use super::{Backend, Step};
+    use crate::error::Error;
+    use std::os::unix::process::ExitStatusExt;
+    use std::process::{ExitStatus, Output};
+    use std::sync::Arc;
+
+    type Handler = Box<dyn Fn(&str, &[&str]) -> Output + Send + Sync>;
+
+    /// Answers commands with a handler rather than running them.
+    pub(crate) struct Fake(Handler);
+
+    /// A backend answering commands with `f`.
+    pub(crate) fn backend(
+        f: impl Fn(&str, &[&str]) -> Output + Send + Sync + 'static,
+    ) -> Arc<dyn Backend> {
+        Arc::new(Fake(Box::new(f)))
+    }
+
+    impl Backend for Fake {
+        fn run(&self, bin: &str, args: &[&str]) -> Result<Output, Error> {
+            Ok((self.0)(bin, args))
+        }
+
+        /// Answer a plan's call step, passing the api as the binary. Other
+        /// steps are taken as they would be without a fake.
+        fn call(&self, step: &Step) -> Option<Result<(), Error>> {
+            let (api, args) = match step {
+                Step::Call { api, args } => (api, args),
+                _ => return None,
+            };
+            let args: Vec<&str> = args.iter().map(String::as_str).collect();
+            let out = (self.0)(api, &args);
+            if out.status.success() {
+                return Some(Ok(()));
+            }
+            Some(Err(Error::Exec(
+                String::from_utf8_lossy(&out.stderr).into(),
+            )))
+        }
+    }
+
+    /// A successful command with the given stdout.
+    pub(crate) fn ok(stdout: impl Into<String>) -> Output {
+        Output {
+            status: ExitStatus::from_raw(0),
+            stdout: stdout.into().into_bytes(),
+            stderr: Vec::new(),
+        }
+    }
+
+    /// A failed command with the given stderr.
+    pub(crate) fn fail(stderr: impl Into<String>) -> Output {
+        Output {
+            status: ExitStatus::from_raw(1 << 8),
+            stdout: Vec::new(),
+            stderr: stderr.into().into_bytes(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::test::Scratch;
+    use anyhow::Result;
+
+    /// Test that lookups stay cached until the outermost cache scope ends,
+    /// not the first one to end.
+    #[test]
+    fn nested_cache_scopes() {
+        use crate::ops::{cache, cached, CacheScope};
+
+        let key = "nested_cache_scopes";
+        let outer = CacheScope::new();
+        {
+            let _inner = CacheScope::new();
+            cache(key, "42");
+        }
+        assert_eq!(cached(key).as_deref(), Some("42"));
+        drop(outer);
+    }
+
+    /// Test the pool a dataset lives in, and that byte counts render the way
+    /// `zfs list` renders them.
+    #[test]
+    fn pools_and_sizes() {
+        use crate::ops::{human_bytes, pool_of};
+
+        assert_eq!(pool_of("rpool/falcon/img"), "rpool");
+        assert_eq!(pool_of("tank"), "tank");
+        assert_eq!(human_bytes(0), "0B");
+        assert_eq!(human_bytes(1023), "1023B");
+        assert_eq!(human_bytes(1536), "1.5K");
+        assert_eq!(human_bytes(5 << 30), "5.0G");
+        assert_eq!(human_bytes(u64::MAX), "16384.0P");
+    }
+
+    /// Environment error translations are keyed on stderr captured from real
+    /// failures, if these stop matching the messages have changed underneath
+    /// us.
+    #[test]
+    fn env_error_translation() {
+        use crate::error::Error;
+        use crate::ops::{net_env_error, vmm_env_error};
+
+        let privileged = [
+            "dladm: simnet creation failed: insufficient privileges\n",
+            "dladm: vnic creation over duo_violin_vn_sim0 failed: \
+             insufficient privileges\n",
+            "ioctl DLDIOC_CREATE_SIMNET: Not owner (os error 1)",
+        ];
+        for stderr in privileged {
+            match net_env_error("create simnet duo_violin_vn_sim0", stderr) {
+                Some(Error::Environment { problem, fix }) => {
+                    assert!(problem.contains("sys_dl_config"), "{}", problem);
+                    assert!(fix.contains("pfexec"), "{}", fix);
+                }
+                other => panic!("{:?} not translated: {:?}", stderr, other),
+            }
+        }
+        assert!(net_env_error(
+            "create vnic duo_violin_vn_vnic0",
+            "dladm: vnic creation failed: object already exists\n",
+        )
+        .is_none());
+
+        let missing = "Error: failed to open /dev/vmmctl: \
+                       /dev/vmmctl: No such file or directory (os error 2)\n";
+        match vmm_env_error("launch violin", missing) {
+            Some(Error::Environment { problem, fix }) => {
+                assert!(problem.contains("vmm driver"), "{}", problem);
+                assert!(fix.contains("pkg install system/bhyve"), "{}", fix);
+            }
+            other => panic!("vmm driver not translated: {:?}", other),
+        }
+
+        let denied = "Error: failed to open /dev/vmmctl: \
+                      Permission denied (os error 13)\n";
+        match vmm_env_error("launch violin", denied) {
+            Some(Error::Environment { problem, .. }) => {
+                assert!(problem.contains("/dev/vmmctl"), "{}", problem);
+            }
+            other => panic!("vmmctl privileges not translated: {:?}", other),
+        }
+
+        let reservoir = "Error: failed to create instance: \
+                         Cannot allocate memory (os error 12)\n";
+        match vmm_env_error("launch violin", reservoir) {
+            Some(Error::Environment { fix, .. }) => {
+                assert!(fix.contains("rsrvrctl"), "{}", fix);
+            }
+            other => panic!("reservoir not translated: {:?}", other),
+        }
+
+        assert!(
+            vmm_env_error("launch violin", "listening on [::]:12400").is_none()
+        );
+        // other files being unreadable is not a vmm problem
+        let elsewhere = "opened /dev/vmmctl\n\
+                         Error: reading /var/falcon/violin.toml: \
+                         Permission denied (os error 13)\n";
+        assert!(vmm_env_error("launch violin", elsewhere).is_none());
+        assert!(net_env_error(
+            "create vnic duo_violin_vn_vnic0",
+            "cannot open /etc/dladm/datalink.conf: Permission denied\n",
+        )
+        .is_none());
+    }
+
+    /// Launch time zfs work should take a fixed number of commands per node,
+    /// plus a fixed number of batched lookups for the whole topology.
+    #[test]
+    fn launch_zfs_command_budget() -> Result<()> {
+        use crate::ops::fake;
+        use std::sync::{Arc, Mutex};
+
+        const NODES: usize = 16;
+
+        let commands = Arc::new(Mutex::new(Vec::<String>::new()));
+        let log = commands.clone();
+        let host = fake::backend(move |bin, args| {
+            log.lock()
+                .unwrap()
+                .push(format!("{} {}", bin, args.join(" ")));
+            // answer batched `zfs list -o name,<prop> <names>..` lookups
+            match args {
+                ["list", "-Hp", "-t", "all", "-o", fields, names @ ..] => {
+                    let value = if fields.ends_with("guid") {
+                        "42"
+                    } else {
+                        "1024"
+                    };
+                    fake::ok(
+                        names
+                            .iter()
+                            .map(|n| format!("{}\t{}\n", n, value))
+                            .collect::<String>(),
+                    )
+                }
+                _ => fake::ok(""),
+            }
+        });
+
+        let mut r = crate::Runner::new("budget");
+        r.persistent = true;
+        r.set_backend(host);
+        for i in 0..NODES {
+            r.node(&format!("n{}", i), "helios-2.3", 1, 1024);
+        }
+
+        let _cache = crate::ops::CacheScope::new();
+        r.preflight_datasets()?;
+        for n in r.deployment.nodes.iter() {
+            n.create_zvol_backing(&r)?;
+        }
+        // cached image lookups do not go back to zfs
+        crate::image::resolve(
+            r.backend(),
+            &r.image_dataset,
+            &"helios-2.3".parse()?,
+        )?;
+
+        let commands = commands.lock().unwrap();
+        let budget = 2 + 2 * NODES;
+        assert!(
+            commands.len() <= budget,
+            "{} commands for {} nodes exceeds budget of {}:\n{}",
+            commands.len(),
+            NODES,
+            budget,
+            commands.join("\n"),
+        );
+
+        Ok(())
+    }
+
+    /// Test that a dry run plans the same steps a real run takes, on the fake
+    /// backend, and that the dry run only queries the host.
+    #[tokio::test]
+    async fn dry_run_plans() -> Result<()> {
+        use crate::ops::fake;
+        use crate::snapshot::{prune, Retention};
+        use crate::{Plan, Step};
+        use std::sync::{Arc, Mutex};
+
+        let commands = Arc::new(Mutex::new(Vec::<String>::new()));
+        let log = commands.clone();
+        let host = fake::backend(move |bin, args| {
+            log.lock()
+                .unwrap()
+                .push(format!("{} {}", bin, args.join(" ")));
+            match args.first() {
+                // nothing is left from an earlier run
+                Some(&"show-link") => fake::fail("link not found"),
+                Some(&"list") if args.contains(&"snapshot") => fake::ok(
+                    "tank/img/violin-a@base\t-\n\
+                    tank/img/violin-b@base\ttank/topo/duo/violin\n",
+                ),
+                Some(&"list") => fake::ok(
+                    "tank/img/violin-a\ton\tviolin\t100\t-\n\
+                    tank/img/violin-b\ton\tviolin\t200\t-\n\
+                    tank/img/violin-c\ton\tviolin\t300\t-\n",
+                ),
+                _ => fake::ok(""),
+            }
+        });
+
+        let scratch = Scratch::new("plan")?;
+        let topology = |dry_run| -> Result<crate::Runner> {
+            let mut r = scratch.runner("plans");
+            r.set_backend(host.clone());
+            let violin = r.node("violin", "helios-2.3", 1, 1024);
+            let piano = r.node("piano", "helios-2.3", 1, 1024);
+            r.link(violin, piano);
+            r.ext_link("igb0", violin);
+            r.mgmt_network_v4("10.100.0.0/24")?;
+            r.record_plan(dry_run);
+            Ok(r)
+        };
+
+        let planned = topology(true)?;
+        planned.net_launch().await?;
+        planned.net_destroy()?;
+        // a dry run only asks whether links exist
+        assert!(commands
+            .lock()
+            .unwrap()
+            .iter()
+            .all(|c| c.contains("show-link")));
+
+        let real = topology(false)?;
+        real.net_launch().await?;
+        real.net_destroy()?;
+        assert_eq!(planned.plan().steps(), real.plan().steps());
+        assert!(real.plan().failed().is_none());
+
+        let steps = planned.plan().steps();
+        assert!(steps.contains(&Step::call(
+            "libnet.create_vnic_link",
+            &["plans_violin_vn_vnic1", "igb0"]
+        )));
+        assert!(steps.contains(&Step::command(
+            crate::DLADM_BIN,
+            &["create-etherstub", "-t", "plans_mgmt_stub0"]
+        )));
+        assert_eq!(
+            steps[0].to_string(),
+            "call libnet.delete_link(plans_violin_vn_vnic0)"
+        );
+
+        // both runs leave the same images, only the real one removed any
+        let keep1 = Retention {
+            keep: 1,
+            keep_days: None,
+        };
+        let dry = Plan::new(true).with_backend(host.clone());
+        let wet = Plan::recording(false).with_backend(host);
+        commands.lock().unwrap().clear();
+        let planned = prune(&dry, "tank", &keep1, 400)?;
+        assert!(commands
+            .lock()
+            .unwrap()
+            .iter()
+            .all(|c| c.contains(" list ")));
+        let pruned = prune(&wet, "tank", &keep1, 400)?;
+        assert_eq!(planned.removed, pruned.removed);
+        assert_eq!(planned.skipped, pruned.skipped);
+        assert_eq!(dry.steps(), wet.steps());
+        assert_eq!(
+            dry.to_string(),
+            "  1. run /usr/sbin/zfs destroy -r tank/img/violin-a\n"
+        );
+        let json = serde_json::to_string(&dry.steps())?;
+        assert_eq!(
+            json,
+            r#"[{"kind":"command","bin":"/usr/sbin/zfs","args":["destroy","-r","tank/img/violin-a"]}]"#
+        );
+
+        Ok(())
+    }
+}
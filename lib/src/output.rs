@@ -0,0 +1,280 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+// Copyright 2022 Oxide Computer Company
+
+//! Messages for the person running falcon. Operations report progress,
+//! outcomes and warnings through the [`OutputCtx`] of their runner rather
+//! than printing, so the command line, library users and tests each decide
+//! where messages go and how much of them to show. A runner's own context
+//! only logs them, the command line shows them on the terminal.
+//!
+//! On a terminal, progress and outcomes go to stdout, warnings and errors to
+//! stderr. In JSON mode every message is a JSON encoded [`Message`] line on
+//! stderr, leaving stdout to the command's result.
+
+use colored::Colorize;
+use serde::Serialize;
+use slog::{debug, error, info, warn, Logger};
+use std::io::Write;
+use std::sync::mpsc::{channel, Receiver, Sender};
+use std::sync::Mutex;
+
+/// How important a message is.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Level {
+    /// Detail only shown when asked for.
+    Debug,
+    /// The step an operation is at.
+    Progress,
+    /// The outcome of an operation.
+    Info,
+    Warning,
+    Error,
+}
+
+/// How much to show.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Verbosity {
+    /// Warnings and errors only.
+    Quiet,
+    /// Everything but debug messages.
+    Normal,
+    Verbose,
+}
+
+impl Verbosity {
+    fn shows(self, level: Level) -> bool {
+        match self {
+            Self::Quiet => level >= Level::Warning,
+            Self::Normal => level >= Level::Progress,
+            Self::Verbose => true,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct Message {
+    pub level: Level,
+    pub text: String,
+}
+
+/// Where messages go.
+enum Sink {
+    Terminal,
+    Silent,
+    Channel(Mutex<Sender<Message>>),
+}
+
+/// Where messages go and how they are shown.
+pub struct OutputCtx {
+    sink: Sink,
+    /// Every message is also logged here, whatever the verbosity.
+    log: Option<Logger>,
+    pub verbosity: Verbosity,
+    /// Color terminal output. Escape codes in message text are removed when
+    /// this is off and in JSON mode.
+    pub color: bool,
+    /// Write messages as JSON lines.
+    pub json: bool,
+}
+
+impl Default for OutputCtx {
+    fn default() -> Self {
+        Self::silent()
+    }
+}
+
+impl OutputCtx {
+    /// Show messages on the terminal.
+    pub fn terminal() -> Self {
+        OutputCtx {
+            sink: Sink::Terminal,
+            log: None,
+            verbosity: Verbosity::Normal,
+            color: colored::control::SHOULD_COLORIZE.should_colorize(),
+            json: false,
+        }
+    }
+
+    /// Drop every message.
+    pub fn silent() -> Self {
+        OutputCtx {
+            sink: Sink::Silent,
+            log: None,
+            verbosity: Verbosity::Quiet,
+            color: false,
+            json: false,
+        }
+    }
+
+    /// Send messages to the returned receiver, at every verbosity and
+    /// without color.
+    pub fn channel() -> (Self, Receiver<Message>) {
+        let (tx, rx) = channel();
+        let ctx = OutputCtx {
+            sink: Sink::Channel(Mutex::new(tx)),
+            log: None,
+            verbosity: Verbosity::Verbose,
+            color: false,
+            json: false,
+        };
+        (ctx, rx)
+    }
+
+    /// Log messages to `log` and show them nowhere else.
+    pub fn logger(log: Logger) -> Self {
+        OutputCtx {
+            log: Some(log),
+            ..Self::silent()
+        }
+    }
+
+    pub fn debug(&self, text: impl Into<String>) {
+        self.emit(Level::Debug, text)
+    }
+
+    pub fn progress(&self, text: impl Into<String>) {
+        self.emit(Level::Progress, text)
+    }
+
+    pub fn info(&self, text: impl Into<String>) {
+        self.emit(Level::Info, text)
+    }
+
+    pub fn warn(&self, text: impl Into<String>) {
+        self.emit(Level::Warning, text)
+    }
+
+    pub fn error(&self, text: impl Into<String>) {
+        self.emit(Level::Error, text)
+    }
+
+    pub fn emit(&self, level: Level, text: impl Into<String>) {
+        let mut text = text.into();
+        if let Some(ref log) = self.log {
+            let plain = strip_escapes(&text);
+            match level {
+                Level::Debug => debug!(log, "{}", plain),
+                Level::Progress | Level::Info => info!(log, "{}", plain),
+                Level::Warning => warn!(log, "{}", plain),
+                Level::Error => error!(log, "{}", plain),
+            }
+        }
+        if !self.verbosity.shows(level) {
+            return;
+        }
+        if self.json || !self.color {
+            text = strip_escapes(&text);
+        }
+        let msg = Message { level, text };
+        match self.sink {
+            Sink::Silent => {}
+            Sink::Channel(ref tx) => {
+                if let Ok(tx) = tx.lock() {
+                    // a receiver that went away wants no more messages
+                    let _ = tx.send(msg);
+                }
+            }
+            Sink::Terminal if self.json => {
+                if let Ok(line) = serde_json::to_string(&msg) {
+                    eprintln!("{}", line);
+                }
+            }
+            Sink::Terminal => self.show(&msg),
+        }
+    }
+
+    fn show(&self, msg: &Message) {
+        let prefix = match msg.level {
+            Level::Warning => "warning:",
+            Level::Error => "error:",
+            _ => {
+                let mut out = std::io::stdout();
+                let _ = writeln!(out, "{}", msg.text);
+                return;
+            }
+        };
+        let prefix = match (self.color, msg.level) {
+            (false, _) => prefix.normal(),
+            (true, Level::Warning) => prefix.yellow(),
+            (true, _) => prefix.red(),
+        };
+        eprintln!("{} {}", prefix, msg.text);
+    }
+}
+
+/// `s` without terminal escape sequences.
+pub(crate) fn strip_escapes(s: &str) -> String {
+    let mut out = String::with_capacity(s.len());
+    let mut chars = s.chars();
+    while let Some(c) = chars.next() {
+        if c != '\x1b' {
+            out.push(c);
+            continue;
+        }
+        // a control sequence runs to its first byte in @ through ~
+        if chars.next() == Some('[') {
+            for c in chars.by_ref() {
+                if ('@'..='~').contains(&c) {
+                    break;
+                }
+            }
+        }
+    }
+    out
+}
+
+#[cfg(test)]
+mod test {
+    use anyhow::Result;
+
+    /// Test that operations report through the runner's output context, which
+    /// filters by verbosity and keeps escape codes out of plain messages.
+    #[tokio::test]
+    async fn output_messages() -> Result<()> {
+        use crate::ops::fake;
+        use crate::output::{Level, Message, OutputCtx, Verbosity};
+        use colored::Colorize;
+
+        let mut r = crate::Runner::new("quiet");
+        r.persistent = true;
+        r.set_backend(fake::backend(|_, args| match args.first() {
+            Some(&"show-link") => fake::fail("link not found"),
+            _ => fake::ok(""),
+        }));
+        let violin = r.node("violin", "helios-2.3", 1, 1024);
+        let piano = r.node("piano", "helios-2.3", 1, 1024);
+        r.link(violin, piano);
+        r.set_dry_run(true);
+        let (out, rx) = OutputCtx::channel();
+        r.set_output(out);
+        r.net_launch().await?;
+        let progress: Vec<String> = rx.try_iter().map(|m| m.text).collect();
+        assert_eq!(progress, vec!["creating links", "creating external links"]);
+
+        let (mut out, rx) = OutputCtx::channel();
+        out.verbosity = Verbosity::Quiet;
+        out.progress("creating links");
+        out.info(format!("{} tank/img/violin-a", "removed".green()));
+        out.warn(format!("{} left behind", "vnic".yellow()));
+        assert_eq!(
+            rx.try_iter().collect::<Vec<_>>(),
+            vec![Message {
+                level: Level::Warning,
+                text: "vnic left behind".into(),
+            }]
+        );
+        assert_eq!(
+            serde_json::to_string(&Message {
+                level: Level::Progress,
+                text: "creating nodes".into(),
+            })?,
+            r#"{"level":"progress","text":"creating nodes"}"#
+        );
+
+        Ok(())
+    }
+}
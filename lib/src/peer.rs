@@ -0,0 +1,358 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+// Copyright 2022 Oxide Computer Company
+
+//! Links between topologies. A long lived topology declares peer ports on
+//! its nodes, and a short lived one attaches its nodes to them with
+//! external peers, e.g. a test topology of a few hosts wired into a core
+//! of switches that stays up across test runs.
+//!
+//! Each side owns only its half of a link, a simnet and vnic per node. The
+//! halves are joined when the attaching topology's network is created, by
+//! looking up the other topology in the registry, and come apart when either
+//! side is destroyed.
+
+use crate::error::Error;
+use crate::lock::FileLock;
+use crate::mgmt::link_exists;
+use crate::{
+    create_endpoint_link, destroy_endpoint_link, registry, Backend, Endpoint,
+    EndpointKind, NodeRef, Runner, Step, DLADM_BIN,
+};
+use crate::{die, namecheck};
+use serde::{Deserialize, Serialize};
+use slog::info;
+use std::collections::BTreeSet;
+use std::fmt;
+
+/// Held in the falcon directory of a topology while another picks one of its
+/// peer ports and connects to it, so two topologies launching at once cannot
+/// both take the same free port.
+const PEERS_LOCK: &str = "peers.lock";
+
+/// A port of a node in another topology.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ExternalPeer {
+    /// The name of the other topology.
+    pub topology: String,
+    /// The node within the other topology.
+    pub node: String,
+    /// The peer port of the node to attach to, any free one if `None`.
+    pub link_name: Option<String>,
+}
+
+impl fmt::Display for ExternalPeer {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}:{}", self.topology, self.node)?;
+        if let Some(ref name) = self.link_name {
+            write!(f, "/{}", name)?;
+        }
+        Ok(())
+    }
+}
+
+/// A half link on a node that another topology may attach to.
+#[derive(Serialize, Deserialize)]
+pub struct PeerPort {
+    pub endpoint: Endpoint,
+    pub name: String,
+}
+
+/// A link from a node to a peer port of another topology.
+#[derive(Serialize, Deserialize)]
+pub struct ExternLink {
+    pub endpoint: Endpoint,
+    pub peer: ExternalPeer,
+}
+
+impl Runner {
+    /// Offer a port on `n` named `name` for other topologies to attach to
+    /// with `extern_link`.
+    pub fn peer_port(&mut self, n: NodeRef, name: &str) {
+        namecheck!(name, "peer port");
+        let endpoint = self.next_endpoint(n);
+        self.deployment.peer_ports.push(PeerPort {
+            endpoint,
+            name: name.into(),
+        });
+    }
+
+    /// Link `n` to a peer port of a node in another running topology. The
+    /// peer is resolved when the network is created.
+    pub fn extern_link(&mut self, n: NodeRef, peer: ExternalPeer) {
+        let endpoint = self.next_endpoint(n);
+        self.deployment
+            .extern_links
+            .push(ExternLink { endpoint, peer });
+    }
+
+    fn next_endpoint(&mut self, n: NodeRef) -> Endpoint {
+        let node = &mut self.deployment.nodes[n.index];
+        let endpoint = Endpoint {
+            node: n,
+            index: node.radix,
+            kind: EndpointKind::Viona(None),
+        };
+        node.radix += 1;
+        endpoint
+    }
+
+    /// The simnet each external link attaches to, in the order of
+    /// `extern_links`, and locks on the peer topologies' ports to hold until
+    /// the links are connected. Fails if a peer's topology or node does not
+    /// exist or the node has no free port.
+    pub(crate) fn resolve_extern_links(
+        &self,
+    ) -> Result<(Vec<String>, Vec<FileLock>), Error> {
+        // taken in name order, so topologies sharing peers cannot deadlock,
+        // and not at all in a dry run, which connects nothing
+        let mut locks = Vec::new();
+        if !self.plan.is_dry_run() {
+            let names: BTreeSet<&str> = self
+                .deployment
+                .extern_links
+                .iter()
+                .map(|l| l.peer.topology.as_str())
+                .collect();
+            for name in names {
+                let (dir, _) =
+                    registry::lookup(self.registry.as_deref(), name)?;
+                locks.push(FileLock::acquire(&dir.join(PEERS_LOCK))?);
+            }
+        }
+
+        let mut taken: Vec<String> = Vec::new();
+        let mut result = Vec::new();
+        for l in self.deployment.extern_links.iter() {
+            let slink = l.resolve(self, &taken)?;
+            taken.push(slink.clone());
+            result.push(slink);
+        }
+        Ok((result, locks))
+    }
+}
+
+impl PeerPort {
+    pub(crate) fn create(&self, r: &Runner) -> Result<(), Error> {
+        info!(r.log, "creating peer port {}", self.name);
+        create_endpoint_link(r, &self.endpoint)
+    }
+
+    pub(crate) fn destroy(&self, r: &Runner) -> Result<(), Error> {
+        destroy_endpoint_link(r, &self.endpoint)
+    }
+}
+
+impl ExternLink {
+    /// Find a free peer port for this link, skipping the simnets in
+    /// `taken`.
+    fn resolve(&self, r: &Runner, taken: &[String]) -> Result<String, Error> {
+        let p = &self.peer;
+        if p.topology == r.deployment.name {
+            return Err(Error::ExternalPeer(format!(
+                "{} is this topology, use a link",
+                p.topology
+            )));
+        }
+        let (_, d) = registry::lookup(r.registry.as_deref(), &p.topology)?;
+        let node = match d.nodes.iter().position(|n| n.name == p.node) {
+            Some(i) => i,
+            None => {
+                return Err(Error::ExternalPeer(format!(
+                    "topology {} has no node {}",
+                    p.topology, p.node
+                )))
+            }
+        };
+        let ports: Vec<&PeerPort> = d
+            .peer_ports
+            .iter()
+            .filter(|pp| pp.endpoint.node.index == node)
+            .filter(|pp| p.link_name.as_ref().map_or(true, |n| *n == pp.name))
+            .collect();
+        if ports.is_empty() {
+            return Err(Error::ExternalPeer(match p.link_name {
+                Some(ref name) => format!("{} has no peer port {}", p, name),
+                None => format!("{} has no peer ports", p),
+            }));
+        }
+        let ours = r.deployment.simnet_link_name(&self.endpoint);
+        let mut created = false;
+        for pp in ports {
+            let slink = d.simnet_link_name(&pp.endpoint);
+            if !link_exists(r.backend(), &slink) {
+                continue;
+            }
+            created = true;
+            if taken.contains(&slink) {
+                continue;
+            }
+            match simnet_peer(r.backend(), &slink)? {
+                None => return Ok(slink),
+                // attached by an earlier run of this topology
+                Some(other) if other == ours => return Ok(slink),
+                Some(_) => continue,
+            }
+        }
+        if !created {
+            return Err(Error::ExternalPeer(format!(
+                "the peer ports of {} do not exist, is {} launched?",
+                p, p.topology
+            )));
+        }
+        Err(Error::ExternalPeer(format!("{} has no free port", p)))
+    }
+
+    /// Create the local half of the link and connect it to `peer`.
+    pub(crate) fn create(&self, r: &Runner, peer: &str) -> Result<(), Error> {
+        info!(r.log, "creating external link to {}", self.peer);
+        create_endpoint_link(r, &self.endpoint)?;
+        let slink = r.deployment.simnet_link_name(&self.endpoint);
+        let step = Step::call("libnet.connect_simnet_peers", &[&slink, peer]);
+        let slink_h = libnet::LinkHandle::Name(slink);
+        let peer_h = libnet::LinkHandle::Name(peer.into());
+        r.plan.step(step, || {
+            libnet::connect_simnet_peers(&slink_h, &peer_h)
+                .map(drop)
+                .map_err(Error::from)
+        })
+    }
+
+    /// Remove the local half of the link, leaving the peer port in place.
+    pub(crate) fn destroy(&self, r: &Runner) -> Result<(), Error> {
+        destroy_endpoint_link(r, &self.endpoint)
+    }
+}
+
+/// The simnet `name` is connected to, if any.
+fn simnet_peer(
+    host: &dyn Backend,
+    name: &str,
+) -> Result<Option<String>, Error> {
+    let out =
+        host.run(DLADM_BIN, &["show-simnet", "-p", "-o", "otherlink", name])?;
+    if !out.status.success() {
+        return Err(Error::Link(format!(
+            "{} is not a simnet: {}",
+            name,
+            String::from_utf8_lossy(&out.stderr).trim()
+        )));
+    }
+    let other = String::from_utf8(out.stdout)?;
+    match other.trim() {
+        "" | "--" => Ok(None),
+        other => Ok(Some(other.into())),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::test::Scratch;
+    use anyhow::Result;
+
+    /// Test that an external link finds a free peer port of a registered
+    /// topology, and that only the local half is created and destroyed.
+    #[tokio::test]
+    async fn linked_topologies() -> Result<()> {
+        use crate::error::Error;
+        use crate::ops::fake;
+        use crate::peer::ExternalPeer;
+        use crate::{registry, Plan};
+        use ron::ser::{to_string_pretty, PrettyConfig};
+
+        let scratch = Scratch::new("peer")?;
+
+        // a core topology offering two ports on rs1, the first already taken
+        let mut core = scratch.runner("core");
+        let rs1 = core.node("rs1", "helios-2.3", 1, 1024);
+        core.peer_port(rs1, "up0");
+        core.peer_port(rs1, "up1");
+        std::fs::create_dir_all(&core.falcon_dir)?;
+        let topo = to_string_pretty(&core.deployment, PrettyConfig::new())?;
+        std::fs::write(core.falcon_dir.join("topology.ron"), topo)?;
+        let wet = Plan::new(false);
+        let registry = core.registry.as_deref();
+        registry::register(&wet, registry, "core", &core.falcon_dir)?;
+        assert_eq!(registry::topologies(registry)?.len(), 1);
+
+        let host = fake::backend(|_, args| match args {
+            ["show-link", .., l] if l.starts_with("core_") => fake::ok(*l),
+            ["show-link", ..] => fake::fail("link not found"),
+            ["show-simnet", .., "core_rs1_vn_sim0"] => {
+                fake::ok("lab_h0_vn_sim0")
+            }
+            _ => fake::ok(""),
+        });
+
+        let edge = |peer: ExternalPeer| {
+            let mut r = scratch.runner("edge");
+            r.set_backend(host.clone());
+            let h0 = r.node("h0", "helios-2.3", 1, 1024);
+            r.extern_link(h0, peer);
+            r.set_dry_run(true);
+            r
+        };
+        let peer = |node: &str, link_name: Option<&str>| ExternalPeer {
+            topology: "core".into(),
+            node: node.into(),
+            link_name: link_name.map(Into::into),
+        };
+
+        let r = edge(peer("rs1", None));
+        r.net_launch().await?;
+        r.net_destroy()?;
+        // only the connection names the core's half, which is left in place
+        let touched: Vec<String> = r
+            .plan()
+            .steps()
+            .iter()
+            .map(|s| s.to_string())
+            .filter(|s| s.contains("core_"))
+            .collect();
+        assert_eq!(
+            touched,
+            vec![
+                "call libnet.connect_simnet_peers(edge_h0_vn_sim0, \
+                core_rs1_vn_sim1)"
+            ]
+        );
+
+        let unresolved = |r: crate::Runner| async move {
+            match r.net_launch().await {
+                Err(Error::ExternalPeer(e)) => e,
+                other => panic!("expected peer error, got {:?}", other.err()),
+            }
+        };
+        assert_eq!(
+            unresolved(edge(peer("rs9", None))).await,
+            "topology core has no node rs9"
+        );
+        assert_eq!(
+            unresolved(edge(peer("rs1", Some("up0")))).await,
+            "core:rs1/up0 has no free port"
+        );
+        let mut gone = peer("rs1", None);
+        gone.topology = "gone".into();
+        assert_eq!(
+            unresolved(edge(gone)).await,
+            "no topology named gone is running"
+        );
+
+        // a core launched elsewhere can neither take the name while this
+        // one runs nor forget it
+        let elsewhere = scratch.dir.join("elsewhere");
+        assert!(matches!(
+            registry::register(&wet, registry, "core", &elsewhere),
+            Err(Error::ExternalPeer(_))
+        ));
+        registry::unregister(&wet, registry, "core", &elsewhere)?;
+        assert!(registry::lookup(registry, "core").is_ok());
+
+        registry::unregister(&wet, registry, "core", &core.falcon_dir)?;
+        assert!(registry::lookup(registry, "core").is_err());
+
+        Ok(())
+    }
+}
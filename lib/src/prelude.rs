@@ -0,0 +1,46 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+// Copyright 2022 Oxide Computer Company
+
+//! The types a topology program needs, in one import. This is the supported
+//! way to use libfalcon, items are added here as the API grows so programs
+//! keep building across versions.
+//!
+//! ```no_run
+//! use libfalcon::prelude::*;
+//! use std::time::Duration;
+//!
+//! #[tokio::main]
+//! async fn main() -> Result<(), Error> {
+//!     let mut d = Runner::new("duo");
+//!
+//!     let violin = d.node("violin", "helios-2.3", 2, gb(2));
+//!     let piano = d.node("piano", "helios-2.3", 2, gb(2));
+//!     let l: LinkRef = d.link(violin, piano);
+//!     d.name_link(l, "backbone")?;
+//!
+//!     d.role(piano, Role::TrafficGen);
+//!     d.guest_kind(piano, GuestKind::Helios);
+//!     d.set_backing(violin, PrimaryDiskBacking::Zvol);
+//!     d.readiness("helios-2.3", Readiness::quiet(Duration::from_secs(2)));
+//!     d.set_retry_for(
+//!         RetryOp::ConsoleConnect,
+//!         RetryPolicy::fixed(60, Duration::from_secs(1)),
+//!     );
+//!
+//!     let _: NodeRef = violin;
+//!     let _: RunMode = run(&mut d).await?;
+//!     Ok(())
+//! }
+//! ```
+
+pub use crate::cli::{run, RunMode};
+pub use crate::error::Error;
+pub use crate::react::{ConsoleTrigger, RateLimit, ReactAction};
+pub use crate::retry::{RetryOp, RetryPolicy};
+pub use crate::role::Role;
+pub use crate::serial::Readiness;
+pub use crate::unit::gb;
+pub use crate::{GuestKind, LinkRef, NodeRef, PrimaryDiskBacking, Runner};
@@ -0,0 +1,334 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+// Copyright 2022 Oxide Computer Company
+
+//! Read only queries over the structure of a deployment. These only look at
+//! the topology description, so they work just as well on a `Deployment`
+//! read back from `topology.ron` as on one being built.
+
+use crate::error::Error;
+use crate::{Deployment, Endpoint, Link, Node};
+
+impl Deployment {
+    /// Iterate over the nodes of the deployment in the order they were
+    /// declared.
+    pub fn iter_nodes(&self) -> impl Iterator<Item = &Node> {
+        self.nodes.iter()
+    }
+
+    /// Iterate over the point to point links of the deployment in the order
+    /// they were declared.
+    pub fn iter_links(&self) -> impl Iterator<Item = &Link> {
+        self.links.iter()
+    }
+
+    /// Look up a node by name.
+    pub fn node_named(&self, name: &str) -> Option<&Node> {
+        self.nodes.iter().find(|n| n.name == name)
+    }
+
+    /// The nodes at either end of a link.
+    pub fn link_nodes(&self, l: &Link) -> [&Node; 2] {
+        [
+            &self.nodes[l.endpoints[0].node.index],
+            &self.nodes[l.endpoints[1].node.index],
+        ]
+    }
+
+    /// The point to point links with at least one end on the named node, in
+    /// declaration order.
+    pub fn links_of(&self, name: &str) -> Vec<&Link> {
+        self.links
+            .iter()
+            .filter(|l| self.link_nodes(l).iter().any(|n| n.name == name))
+            .collect()
+    }
+
+    /// The links joining the named nodes, in either direction, in declaration
+    /// order.
+    pub fn links_between(&self, a: &str, b: &str) -> Vec<&Link> {
+        self.links
+            .iter()
+            .filter(|l| {
+                let [x, y] = self.link_nodes(l);
+                (x.name == a && y.name == b) || (x.name == b && y.name == a)
+            })
+            .collect()
+    }
+
+    /// Look up a link by its id.
+    pub fn link_by_id(&self, id: usize) -> Option<&Link> {
+        self.links.iter().find(|l| l.id == id)
+    }
+
+    /// Look up a link by the name it was given.
+    pub fn link_named(&self, name: &str) -> Option<&Link> {
+        self.links.iter().find(|l| l.name.as_deref() == Some(name))
+    }
+
+    /// Resolve the link between the named nodes. Parallel links must be told
+    /// apart by `index`, their position among the links between the two nodes
+    /// in declaration order.
+    pub fn resolve_link(
+        &self,
+        a: &str,
+        b: &str,
+        index: Option<usize>,
+    ) -> Result<&Link, Error> {
+        for n in [a, b] {
+            if self.node_named(n).is_none() {
+                return Err(Error::NotFound(format!("node {}", n)));
+            }
+        }
+        let links = self.links_between(a, b);
+        match (index, links.len()) {
+            (_, 0) => {
+                Err(Error::Link(format!("{} and {} are not linked", a, b)))
+            }
+            (None, 1) => Ok(links[0]),
+            (None, n) => Err(Error::Link(format!(
+                "{} and {} are joined by {} links (ids {}), \
+                pick one with --index 0..{}",
+                a,
+                b,
+                n,
+                links
+                    .iter()
+                    .map(|l| l.id.to_string())
+                    .collect::<Vec<_>>()
+                    .join(", "),
+                n - 1,
+            ))),
+            (Some(i), n) => links.get(i).copied().ok_or_else(|| {
+                Error::Link(format!(
+                    "{} and {} are joined by {} link(s), index {} is out \
+                    of range",
+                    a, b, n, i
+                ))
+            }),
+        }
+    }
+
+    /// Take the id for a new link.
+    pub(crate) fn take_link_id(&mut self) -> usize {
+        let id = self.next_link;
+        self.next_link += 1;
+        id
+    }
+
+    /// The names of the nodes linked to the named node, in the order their
+    /// links were declared. Each neighbor appears once no matter how many
+    /// links lead to it.
+    pub fn neighbors(&self, name: &str) -> Vec<&str> {
+        let mut result: Vec<&str> = Vec::new();
+        for l in self.links.iter() {
+            let [a, b] = self.link_nodes(l);
+            let peer = if a.name == name {
+                b
+            } else if b.name == name {
+                a
+            } else {
+                continue;
+            };
+            if !result.contains(&peer.name.as_str()) {
+                result.push(&peer.name);
+            }
+        }
+        result
+    }
+
+    /// The number of point to point link ends on the named node. External
+    /// links are not counted, a link from a node to itself counts twice.
+    pub fn degree(&self, name: &str) -> usize {
+        self.links
+            .iter()
+            .flat_map(|l| self.link_nodes(l))
+            .filter(|n| n.name == name)
+            .count()
+    }
+
+    /// Partition the nodes into groups connected through point to point
+    /// links. Components are ordered by their first node and nodes within a
+    /// component by declaration order.
+    pub fn components(&self) -> Vec<Vec<&str>> {
+        // union-find over node indices
+        let mut parent: Vec<usize> = (0..self.nodes.len()).collect();
+        fn root(parent: &mut [usize], mut i: usize) -> usize {
+            while parent[i] != i {
+                parent[i] = parent[parent[i]];
+                i = parent[i];
+            }
+            i
+        }
+        for l in self.links.iter() {
+            let a = root(&mut parent, l.endpoints[0].node.index);
+            let b = root(&mut parent, l.endpoints[1].node.index);
+            parent[a.max(b)] = a.min(b);
+        }
+
+        let mut result: Vec<(usize, Vec<&str>)> = Vec::new();
+        for (i, n) in self.nodes.iter().enumerate() {
+            let r = root(&mut parent, i);
+            match result.iter_mut().find(|(x, _)| *x == r) {
+                Some((_, c)) => c.push(&n.name),
+                None => result.push((r, vec![&n.name])),
+            }
+        }
+        result.into_iter().map(|(_, c)| c).collect()
+    }
+
+    /// True if every node can reach every other node over point to point
+    /// links. An empty deployment is connected.
+    pub fn is_connected(&self) -> bool {
+        self.components().len() <= 1
+    }
+
+    /// All endpoints of the deployment, point to point links first followed
+    /// by external links, peer ports and links to other topologies.
+    pub(crate) fn endpoints(&self) -> impl Iterator<Item = &Endpoint> {
+        self.links
+            .iter()
+            .flat_map(|l| l.endpoints.iter())
+            .chain(self.ext_links.iter().map(|l| &l.endpoint))
+            .chain(self.peer_ports.iter().map(|p| &p.endpoint))
+            .chain(self.extern_links.iter().map(|l| &l.endpoint))
+    }
+
+    /// The endpoints on the named node, in the same order as `endpoints`.
+    pub(crate) fn endpoints_of<'a>(
+        &'a self,
+        name: &'a str,
+    ) -> impl Iterator<Item = &'a Endpoint> {
+        self.endpoints()
+            .filter(move |e| self.nodes[e.node.index].name == name)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use anyhow::Result;
+
+    #[test]
+    fn deployment_queries() {
+        let mut r = crate::Runner::new("queries");
+        r.persistent = true;
+
+        let violin = r.node("violin", "helios-2.3", 1, 1024);
+        let piano = r.node("piano", "helios-2.3", 1, 1024);
+        let cello = r.node("cello", "helios-2.3", 1, 1024);
+        let drum = r.node("drum", "helios-2.3", 1, 1024);
+        r.link(violin, piano);
+        r.link(piano, cello);
+        r.link(violin, piano);
+        r.ext_link("igb0", drum);
+
+        let d = &r.deployment;
+        let names: Vec<&str> =
+            d.iter_nodes().map(|n| n.name.as_str()).collect();
+        assert_eq!(names, ["violin", "piano", "cello", "drum"]);
+        assert_eq!(d.iter_links().count(), 3);
+
+        assert_eq!(d.neighbors("piano"), ["violin", "cello"]);
+        assert_eq!(d.neighbors("violin"), ["piano"]);
+        assert!(d.neighbors("drum").is_empty());
+        assert!(d.neighbors("nope").is_empty());
+
+        assert_eq!(d.links_of("violin").len(), 2);
+        let [a, b] = d.link_nodes(d.links_of("cello")[0]);
+        assert_eq!((a.name.as_str(), b.name.as_str()), ("piano", "cello"));
+
+        assert_eq!(d.degree("piano"), 3);
+        assert_eq!(d.degree("drum"), 0);
+
+        assert_eq!(
+            d.components(),
+            vec![vec!["violin", "piano", "cello"], vec!["drum"]]
+        );
+        assert!(!d.is_connected());
+        assert!(crate::Deployment::new("empty").is_connected());
+
+        assert_eq!(d.endpoints_of("drum").count(), 1);
+        assert!(d.node_named("cello").is_some());
+    }
+
+    /// Test that links keep their ids and names through a round trip of the
+    /// topology and that link resolution tells parallel links apart.
+    #[test]
+    fn link_identity() -> Result<()> {
+        use ron::ser::{to_string_pretty, PrettyConfig};
+
+        let mut r = crate::Runner::new("links");
+        r.persistent = true;
+
+        let violin = r.node("violin", "helios-2.3", 1, 1024);
+        let piano = r.node("piano", "helios-2.3", 1, 1024);
+        let cello = r.node("cello", "helios-2.3", 1, 1024);
+        let first = r.link(violin, piano);
+        let vc = r.link(piano, cello);
+        let second = r.link(piano, violin);
+        r.name_link(vc, "backbone")?;
+        assert!(matches!(
+            r.name_link(first, "backbone"),
+            Err(crate::error::Error::Link(_))
+        ));
+        r.name_link(vc, "backbone")?;
+
+        assert_eq!(r.link_between(violin, piano), Some(first));
+        assert_eq!(r.link_between(piano, violin), Some(first));
+        assert_eq!(r.link_between(violin, cello), None);
+        assert_eq!(r.find_link("backbone"), Some(vc));
+        assert_eq!(r.find_link("nope"), None);
+
+        let d = &r.deployment;
+        assert_eq!(d.links_between("violin", "piano").len(), 2);
+        assert!(matches!(
+            d.resolve_link("violin", "piano", None),
+            Err(crate::error::Error::Link(_))
+        ));
+        assert_eq!(d.resolve_link("piano", "violin", Some(1))?.id, second.id());
+        assert!(d.resolve_link("violin", "piano", Some(2)).is_err());
+        assert!(d.resolve_link("violin", "cello", None).is_err());
+        assert!(matches!(
+            d.resolve_link("violin", "drum", None),
+            Err(crate::error::Error::NotFound(_))
+        ));
+
+        let pretty = PrettyConfig::new().separate_tuple_members(true);
+        let ron = to_string_pretty(d, pretty.clone())?;
+        let back: crate::Deployment = ron::de::from_str(&ron)?;
+        let ids = |d: &crate::Deployment| -> Vec<(usize, Option<String>)> {
+            d.links.iter().map(|l| (l.id, l.name.clone())).collect()
+        };
+        assert_eq!(ids(&back), ids(d));
+        assert_eq!(to_string_pretty(&back, pretty)?, ron);
+        assert_eq!(back.link_named("backbone").map(|l| l.id), Some(vc.id()));
+        assert_eq!(back.resolve_link("cello", "piano", None)?.id, vc.id());
+        let saved = ids(d);
+
+        // removing a link does not shift the ids of the others, and its id
+        // is not reused
+        r.deployment.links.remove(2);
+        let third = r.link(cello, violin);
+        assert_eq!(third.id(), 3);
+        assert_eq!(r.link_between(violin, piano), Some(first));
+        assert!(matches!(
+            r.name_link(second, "gone"),
+            Err(crate::error::Error::NotFound(_))
+        ));
+
+        // links saved before they had ids are numbered on load
+        let mut old = crate::Deployment::from_ron(&ron)?;
+        for l in old.links.iter_mut() {
+            l.id = 0;
+        }
+        old.next_link = 0;
+        let ron = to_string_pretty(&old, PrettyConfig::new())?;
+        let old = crate::Deployment::from_ron(&ron)?;
+        assert_eq!(ids(&old), saved);
+        assert_eq!(old.next_link, 3);
+
+        Ok(())
+    }
+}
@@ -0,0 +1,442 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+// Copyright 2022 Oxide Computer Company
+
+//! Actions triggered by console output, for soak tests that need to react
+//! when a node prints something, e.g. bundle a node as soon as it reports a
+//! degraded pool. A reaction follows the console of every node it applies
+//! to, reconnecting across reboots, and matches each complete line against
+//! its pattern.
+//!
+//! Firings are rate limited per node, so a log storm cannot fork a command
+//! for every line. Each firing is recorded in the events log as a `react`
+//! event carrying the matched line.
+
+use crate::error::Error;
+use crate::{events, Runner};
+use camino::Utf8Path;
+use futures::future::join_all;
+use futures::StreamExt;
+use regex::Regex;
+use slog::{info, warn, Logger};
+use std::collections::{BTreeMap, VecDeque};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use tokio::process::Command;
+use tokio::time::{sleep, Duration, Instant};
+use tokio_tungstenite::tungstenite::Message;
+
+/// How long to wait before reconnecting to a console that went away.
+const RECONNECT: Duration = Duration::from_secs(1);
+
+/// A function called with the node name and the matched line.
+pub type ConsoleCallback = Arc<dyn Fn(&str, &str) + Send + Sync>;
+
+/// What a reaction does when its pattern matches.
+#[derive(Clone)]
+pub enum ReactAction {
+    /// Run a shell command. `{node}` is replaced with the node's name, and
+    /// the command sees `FALCON_NODE` and `FALCON_LINE` in its environment.
+    Run(String),
+    Callback(ConsoleCallback),
+}
+
+/// How often a reaction may fire on a single node.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RateLimit {
+    /// Firings allowed within `per`.
+    pub burst: u32,
+    pub per: Duration,
+}
+
+impl Default for RateLimit {
+    fn default() -> Self {
+        RateLimit {
+            burst: 3,
+            per: Duration::from_secs(60),
+        }
+    }
+}
+
+/// An action taken when a node's console prints a line matching a pattern.
+#[derive(Clone)]
+pub struct ConsoleTrigger {
+    pub pattern: Regex,
+    /// The nodes the trigger applies to, as a glob over node names where
+    /// `*` matches any run of characters and `?` any single one.
+    pub nodes: String,
+    pub action: ReactAction,
+    /// Fire at most once across all nodes.
+    pub once: bool,
+    pub limit: RateLimit,
+}
+
+impl ConsoleTrigger {
+    /// A trigger on every node with the default rate limit.
+    pub fn new(pattern: &str, action: ReactAction) -> Result<Self, Error> {
+        let pattern = Regex::new(pattern)
+            .map_err(|e| Error::Cli(format!("bad pattern: {}", e)))?;
+        Ok(ConsoleTrigger {
+            pattern,
+            nodes: "*".into(),
+            action,
+            once: false,
+            limit: RateLimit::default(),
+        })
+    }
+}
+
+/// Whether `name` matches the glob `pattern`.
+pub(crate) fn glob_match(pattern: &str, name: &str) -> bool {
+    let p: Vec<char> = pattern.chars().collect();
+    let n: Vec<char> = name.chars().collect();
+    let (mut pi, mut ni) = (0, 0);
+    // where to resume after the last star, and what it has consumed
+    let mut star: Option<(usize, usize)> = None;
+    while ni < n.len() {
+        match p.get(pi) {
+            Some('*') => {
+                star = Some((pi, ni));
+                pi += 1;
+            }
+            Some(c) if *c == '?' || *c == n[ni] => {
+                pi += 1;
+                ni += 1;
+            }
+            _ => match star {
+                Some((sp, sn)) => {
+                    pi = sp + 1;
+                    ni = sn + 1;
+                    star = Some((sp, sn + 1));
+                }
+                None => return false,
+            },
+        }
+    }
+    p[pi..].iter().all(|c| *c == '*')
+}
+
+/// The command to run for a firing on `node`.
+pub(crate) fn command_for(template: &str, node: &str) -> String {
+    template.replace("{node}", node)
+}
+
+/// Splits console output into complete lines.
+#[derive(Default)]
+pub(crate) struct LineBuffer {
+    pending: String,
+}
+
+impl LineBuffer {
+    pub(crate) fn feed(&mut self, data: &str) -> Vec<String> {
+        self.pending += data;
+        let mut lines = Vec::new();
+        while let Some(i) = self.pending.find('\n') {
+            let line: String = self.pending.drain(..=i).collect();
+            lines.push(line.trim_end_matches(&['\r', '\n'][..]).into());
+        }
+        lines
+    }
+}
+
+/// Firings of one trigger on one node within the rate limit window.
+#[derive(Default)]
+pub(crate) struct Limiter {
+    fired: VecDeque<Instant>,
+    suppressed: u64,
+}
+
+impl Limiter {
+    /// Whether a firing at `now` is within `limit`, counting it if so.
+    pub(crate) fn allow(&mut self, limit: &RateLimit, now: Instant) -> bool {
+        while let Some(t) = self.fired.front() {
+            if now.duration_since(*t) < limit.per {
+                break;
+            }
+            self.fired.pop_front();
+        }
+        if self.fired.len() as u32 >= limit.burst {
+            self.suppressed += 1;
+            return false;
+        }
+        self.fired.push_back(now);
+        true
+    }
+}
+
+/// A trigger being served.
+struct Armed<'a> {
+    trigger: &'a ConsoleTrigger,
+    spent: AtomicBool,
+    limiters: Mutex<BTreeMap<String, Limiter>>,
+}
+
+impl Armed<'_> {
+    /// Whether the trigger fires on `line` from `node`.
+    fn fires(&self, node: &str, line: &str, log: &Logger) -> bool {
+        let t = self.trigger;
+        if self.spent.load(Ordering::SeqCst) || !t.pattern.is_match(line) {
+            return false;
+        }
+        let mut limiters = match self.limiters.lock() {
+            Ok(l) => l,
+            Err(_) => return false,
+        };
+        let limiter = limiters.entry(node.into()).or_default();
+        if !limiter.allow(&t.limit, Instant::now()) {
+            // say so once per storm rather than once per line
+            if limiter.suppressed == 1 {
+                warn!(
+                    log,
+                    "{}: /{}/ matching faster than {} per {:?}, holding off",
+                    node,
+                    t.pattern,
+                    t.limit.burst,
+                    t.limit.per,
+                );
+            }
+            return false;
+        }
+        limiter.suppressed = 0;
+        // only one node gets to fire a once trigger
+        !t.once || !self.spent.swap(true, Ordering::SeqCst)
+    }
+}
+
+/// Take `action` for `line` from `node`, recording the firing once the
+/// action is done. Commands run in the background.
+fn fire(
+    events_log: Option<&Utf8Path>,
+    deployment: &str,
+    log: &Logger,
+    action: &ReactAction,
+    node: &str,
+    line: &str,
+) {
+    let start = Instant::now();
+    match action {
+        ReactAction::Callback(f) => {
+            f(node, line);
+            events::record_trigger(
+                events_log,
+                deployment,
+                node,
+                start.elapsed(),
+                true,
+                line,
+            );
+        }
+        ReactAction::Run(template) => {
+            let cmd = command_for(template, node);
+            info!(log, "{}: matched '{}', running {}", node, line, cmd);
+            let child = Command::new("/bin/sh")
+                .args(["-c", &cmd])
+                .env("FALCON_NODE", node)
+                .env("FALCON_LINE", line)
+                .spawn();
+            let (deployment, node, line) =
+                (deployment.to_string(), node.to_string(), line.to_string());
+            let events_log = events_log.map(Utf8Path::to_owned);
+            let log = log.clone();
+            tokio::spawn(async move {
+                let ok = match child {
+                    Ok(mut c) => match c.wait().await {
+                        Ok(status) if status.success() => true,
+                        Ok(status) => {
+                            warn!(log, "{}: {} exited {}", node, cmd, status);
+                            false
+                        }
+                        Err(e) => {
+                            warn!(log, "{}: {}: {}", node, cmd, e);
+                            false
+                        }
+                    },
+                    Err(e) => {
+                        warn!(log, "{}: running {}: {}", node, cmd, e);
+                        false
+                    }
+                };
+                events::record_trigger(
+                    events_log.as_deref(),
+                    &deployment,
+                    &node,
+                    start.elapsed(),
+                    ok,
+                    &line,
+                );
+            });
+        }
+    }
+}
+
+impl Runner {
+    /// Call `callback` with the node name and line whenever any node's
+    /// console prints a line matching `pattern`. Triggers are served by
+    /// `react`.
+    pub fn on_console(
+        &mut self,
+        pattern: &str,
+        callback: impl Fn(&str, &str) + Send + Sync + 'static,
+    ) -> Result<(), Error> {
+        let t = ConsoleTrigger::new(
+            pattern,
+            ReactAction::Callback(Arc::new(callback)),
+        )?;
+        self.console_triggers.push(t);
+        Ok(())
+    }
+
+    /// Add a trigger with full control over the nodes it applies to, its
+    /// rate limit and whether it fires once.
+    pub fn on_console_trigger(&mut self, trigger: ConsoleTrigger) {
+        self.console_triggers.push(trigger);
+    }
+
+    /// Follow the consoles of the nodes the registered triggers apply to and
+    /// serve the triggers. Runs until every trigger was a `once` trigger that
+    /// has fired.
+    pub async fn react(&self) -> Result<(), Error> {
+        let armed: Vec<Armed> = self
+            .console_triggers
+            .iter()
+            .map(|trigger| Armed {
+                trigger,
+                spent: AtomicBool::new(false),
+                limiters: Mutex::new(BTreeMap::new()),
+            })
+            .collect();
+        let names: Vec<&str> = self
+            .deployment
+            .iter_nodes()
+            .map(|n| n.name.as_str())
+            .filter(|n| armed.iter().any(|a| glob_match(&a.trigger.nodes, n)))
+            .collect();
+        if names.is_empty() {
+            return Err(Error::NotFound(
+                "nodes for any console trigger".into(),
+            ));
+        }
+        let armed = &armed;
+        join_all(names.into_iter().map(|n| self.react_node(n, armed))).await;
+        Ok(())
+    }
+
+    async fn react_node(&self, name: &str, armed: &[Armed<'_>]) {
+        let armed: Vec<&Armed> = armed
+            .iter()
+            .filter(|a| glob_match(&a.trigger.nodes, name))
+            .collect();
+        let live = || {
+            armed
+                .iter()
+                .any(|a| !a.trigger.once || !a.spent.load(Ordering::SeqCst))
+        };
+        while live() {
+            let ws = match self.serial_commander(name) {
+                Ok(mut sc) => sc.connect().await,
+                Err(e) => Err(e),
+            };
+            let mut ws = match ws {
+                Ok(ws) => ws,
+                Err(e) => {
+                    warn!(self.log, "{}: console: {}", name, e);
+                    sleep(RECONNECT).await;
+                    continue;
+                }
+            };
+            let mut lines = LineBuffer::default();
+            while let Some(Ok(msg)) = ws.next().await {
+                let data = match msg {
+                    Message::Binary(data) => data,
+                    Message::Close(..) => break,
+                    _ => continue,
+                };
+                for line in lines.feed(&String::from_utf8_lossy(&data)) {
+                    for a in armed.iter() {
+                        if a.fires(name, &line, &self.log) {
+                            fire(
+                                self.events_log.as_deref(),
+                                &self.deployment.name,
+                                &self.log,
+                                &a.trigger.action,
+                                name,
+                                &line,
+                            );
+                        }
+                    }
+                }
+                if !live() {
+                    return;
+                }
+            }
+            sleep(RECONNECT).await;
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use anyhow::Result;
+
+    /// Test that console triggers pick nodes by glob, see whole lines however
+    /// the console splits them, substitute the node into commands, and are held
+    /// to their rate limit per node.
+    #[test]
+    fn console_triggers() -> Result<()> {
+        use crate::react::{
+            command_for, glob_match, ConsoleTrigger, Limiter, LineBuffer,
+            RateLimit, ReactAction,
+        };
+        use tokio::time::{Duration, Instant};
+
+        assert!(glob_match("*", "violin"));
+        assert!(glob_match("vio*", "violin"));
+        assert!(glob_match("*lin", "violin"));
+        assert!(glob_match("v?olin", "violin"));
+        assert!(glob_match("*o*n", "violin"));
+        assert!(!glob_match("piano", "violin"));
+        assert!(!glob_match("vio", "violin"));
+        assert!(!glob_match("*x*", "violin"));
+
+        assert_eq!(
+            command_for("falcon bundle {node} /tmp/{node}.tgz", "violin"),
+            "falcon bundle violin /tmp/violin.tgz"
+        );
+
+        let mut lines = LineBuffer::default();
+        assert!(lines.feed("WARNING: pool de").is_empty());
+        assert_eq!(
+            lines.feed("graded\r\nok\r\npartial"),
+            ["WARNING: pool degraded", "ok"]
+        );
+
+        let t = ConsoleTrigger::new(
+            "WARNING: pool (degraded|faulted)",
+            ReactAction::Run("true".into()),
+        )?;
+        assert!(t.pattern.is_match("WARNING: pool degraded"));
+        assert_eq!(t.nodes, "*");
+        assert!(
+            ConsoleTrigger::new("(", ReactAction::Run("true".into())).is_err()
+        );
+
+        let limit = RateLimit {
+            burst: 2,
+            per: Duration::from_secs(10),
+        };
+        let mut l = Limiter::default();
+        let start = Instant::now();
+        assert!(l.allow(&limit, start));
+        assert!(l.allow(&limit, start + Duration::from_secs(1)));
+        // a storm is held off until the window moves past earlier firings
+        for i in 0..100 {
+            assert!(!l.allow(&limit, start + Duration::from_millis(2000 + i)));
+        }
+        assert!(l.allow(&limit, start + Duration::from_secs(10)));
+        assert!(!l.allow(&limit, start + Duration::from_secs(10)));
+
+        Ok(())
+    }
+}
@@ -0,0 +1,167 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+// Copyright 2022 Oxide Computer Company
+
+//! A per-user record of the topologies launched on this host and the falcon
+//! directories they were launched from, so a topology can find another by
+//! name. Topologies are added when they launch and removed when they are
+//! destroyed, under a lock so concurrent launches and destroys do not lose
+//! each other's changes.
+
+use crate::error::Error;
+use crate::lock::FileLock;
+use crate::{events, Deployment, Plan, Step};
+use camino::{Utf8Path, Utf8PathBuf};
+use ron::ser::{to_string_pretty, PrettyConfig};
+use std::collections::BTreeMap;
+use std::convert::TryFrom;
+use std::fs;
+
+/// The location of the registry, `FALCON_REGISTRY` if set, otherwise
+/// `falcon/topologies.ron` under the user's state directory.
+pub fn path() -> Option<Utf8PathBuf> {
+    if let Ok(s) = std::env::var("FALCON_REGISTRY") {
+        if !s.is_empty() {
+            return Some(s.into());
+        }
+    }
+    Some(events::state_dir()?.join("topologies.ron"))
+}
+
+/// The topologies registered in `registry` and their falcon directories.
+pub fn topologies(
+    registry: Option<&Utf8Path>,
+) -> Result<BTreeMap<String, Utf8PathBuf>, Error> {
+    let path = match registry {
+        Some(p) => p,
+        None => return Ok(BTreeMap::new()),
+    };
+    match fs::read_to_string(&path) {
+        Ok(s) => Ok(ron::de::from_str(&s)?),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+            Ok(BTreeMap::new())
+        }
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// The falcon directory and topology of the topology `name` registered in
+/// `registry`.
+pub fn lookup(
+    registry: Option<&Utf8Path>,
+    name: &str,
+) -> Result<(Utf8PathBuf, Deployment), Error> {
+    let dir = match topologies(registry)?.remove(name) {
+        Some(d) => d,
+        None => {
+            return Err(Error::ExternalPeer(format!(
+                "no topology named {} is running",
+                name
+            )))
+        }
+    };
+    match Deployment::load(&dir) {
+        Ok(d) => Ok((dir, d)),
+        Err(e) => Err(Error::ExternalPeer(format!(
+            "cannot read topology {} from {}: {}",
+            name, dir, e
+        ))),
+    }
+}
+
+/// Record in `registry` that `name` was launched from `falcon_dir`. A
+/// topology of the same name launched from another directory is refused
+/// while it is still there.
+pub(crate) fn register(
+    plan: &Plan,
+    registry: Option<&Utf8Path>,
+    name: &str,
+    falcon_dir: &Utf8Path,
+) -> Result<(), Error> {
+    let dir = absolute(falcon_dir)?;
+    let _lock = lock(plan, registry)?;
+    let mut topos = topologies(registry)?;
+    match topos.get(name) {
+        Some(other) if *other == dir => return Ok(()),
+        Some(other) if other.join("topology.ron").exists() => {
+            return Err(Error::ExternalPeer(format!(
+                "a topology named {} is already running from {}, destroy it \
+                or launch this one under another name",
+                name, other
+            )))
+        }
+        // left behind by a topology that is gone
+        _ => {}
+    }
+    topos.insert(name.into(), dir);
+    save(plan, registry, &topos)
+}
+
+/// Forget `name` in `registry`, if it was launched from `falcon_dir`.
+pub(crate) fn unregister(
+    plan: &Plan,
+    registry: Option<&Utf8Path>,
+    name: &str,
+    falcon_dir: &Utf8Path,
+) -> Result<(), Error> {
+    let dir = absolute(falcon_dir)?;
+    let _lock = lock(plan, registry)?;
+    let mut topos = topologies(registry)?;
+    if topos.get(name) != Some(&dir) {
+        return Ok(());
+    }
+    topos.remove(name);
+    save(plan, registry, &topos)
+}
+
+fn absolute(falcon_dir: &Utf8Path) -> Result<Utf8PathBuf, Error> {
+    if falcon_dir.is_absolute() {
+        return Ok(falcon_dir.to_owned());
+    }
+    Ok(Utf8PathBuf::try_from(std::env::current_dir()?)
+        .map_err(|e| Error::PathError(e.to_string()))?
+        .join(falcon_dir))
+}
+
+/// Lock `registry` against other falcon processes for a read, modify and
+/// write. The lock is on a file of its own, as the registry is replaced
+/// rather than written in place. A dry run takes no lock, it would have to
+/// create the lock file.
+fn lock(
+    plan: &Plan,
+    registry: Option<&Utf8Path>,
+) -> Result<Option<FileLock>, Error> {
+    let path = match registry {
+        Some(p) if !plan.is_dry_run() => p,
+        _ => return Ok(None),
+    };
+    if let Some(parent) = path.parent() {
+        plan.create_dir_all(parent)?;
+    }
+    let lock = Utf8PathBuf::from(format!("{}.lock", path));
+    Ok(Some(FileLock::acquire(&lock)?))
+}
+
+/// Write `topos` to `registry` through a temporary file, so a reader never
+/// sees it half written.
+fn save(
+    plan: &Plan,
+    registry: Option<&Utf8Path>,
+    topos: &BTreeMap<String, Utf8PathBuf>,
+) -> Result<(), Error> {
+    let path = match registry {
+        Some(p) => p,
+        None => return Ok(()),
+    };
+    if let Some(parent) = path.parent() {
+        plan.create_dir_all(parent)?;
+    }
+    let out = format!("{}\n", to_string_pretty(topos, PrettyConfig::new())?);
+    let tmp = Utf8PathBuf::from(format!("{}.{}", path, std::process::id()));
+    plan.step(Step::write(path), || {
+        fs::write(&tmp, out)?;
+        Ok(fs::rename(&tmp, path)?)
+    })
+}
@@ -0,0 +1,142 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+// Copyright 2022 Oxide Computer Company
+
+use std::fmt;
+use std::time::Duration;
+
+/// A summary of a topology launch.
+#[derive(Debug, Default)]
+pub struct LaunchReport {
+    pub nodes: Vec<NodeLaunchReport>,
+}
+
+/// Boot timing for a single node. Times are relative to the node's propolis
+/// instance being started.
+#[derive(Debug)]
+pub struct NodeLaunchReport {
+    pub name: String,
+    /// When the login prompt appeared on the console.
+    pub prompt_at: Option<Duration>,
+    /// When the console was considered quiesced. This is the same as
+    /// `prompt_at` unless the node's image has a readiness refinement, and
+    /// `None` when the console did not quiesce within its readiness cap.
+    pub quiesced_at: Option<Duration>,
+    /// How long the node waited for its turn to boot, when the launch has a
+    /// boot budget.
+    pub queued: Option<Duration>,
+}
+
+impl fmt::Display for LaunchReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for n in &self.nodes {
+            write!(f, "{}: ", n.name)?;
+            if let Some(queued) = n.queued {
+                write!(f, "queued for {}, ", secs(Some(queued)))?;
+            }
+            writeln!(
+                f,
+                "prompt at {}, quiesced at {}",
+                secs(n.prompt_at),
+                secs(n.quiesced_at),
+            )?;
+        }
+        Ok(())
+    }
+}
+
+/// A summary of a topology teardown.
+#[derive(Debug, Default)]
+pub struct DestroyReport {
+    pub nodes: Vec<NodeDestroyReport>,
+    /// Objects that could not be destroyed and must be cleaned up by hand.
+    pub leftovers: Vec<Leftover>,
+}
+
+/// Teardown timing for a single node.
+#[derive(Debug)]
+pub struct NodeDestroyReport {
+    pub name: String,
+    pub took: Duration,
+}
+
+/// Something a destroy left behind, along with how to remove it manually.
+#[derive(Debug)]
+pub struct Leftover {
+    /// What was left behind, e.g. `zvol rpool/falcon/topo/duo/violin`.
+    pub what: String,
+    /// Why it could not be destroyed.
+    pub error: String,
+    /// The command that finishes the job.
+    pub cleanup: String,
+}
+
+impl DestroyReport {
+    /// True if everything was destroyed.
+    pub fn is_clean(&self) -> bool {
+        self.leftovers.is_empty()
+    }
+}
+
+impl fmt::Display for DestroyReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for n in &self.nodes {
+            writeln!(f, "{}: destroyed in {}", n.name, secs(Some(n.took)))?;
+        }
+        if self.leftovers.is_empty() {
+            return Ok(());
+        }
+        writeln!(f, "left behind:")?;
+        for l in &self.leftovers {
+            writeln!(f, "  {}: {}", l.what, l.error)?;
+        }
+        writeln!(f, "to finish the job run:")?;
+        for l in &self.leftovers {
+            writeln!(f, "  {}", l.cleanup)?;
+        }
+        Ok(())
+    }
+}
+
+fn secs(d: Option<Duration>) -> String {
+    match d {
+        Some(d) => format!("{:.1}s", d.as_secs_f64()),
+        None => "-".into(),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    #[test]
+    fn destroy_report_lists_cleanup() {
+        use crate::report::{DestroyReport, Leftover, NodeDestroyReport};
+        use std::time::Duration;
+
+        let mut report = DestroyReport {
+            nodes: vec![NodeDestroyReport {
+                name: "violin".into(),
+                took: Duration::from_millis(1500),
+            }],
+            leftovers: Vec::new(),
+        };
+        assert!(report.is_clean());
+        assert_eq!(report.to_string(), "violin: destroyed in 1.5s\n");
+
+        report.leftovers.push(Leftover {
+            what: "zvol rpool/falcon/topo/duo/violin".into(),
+            error: "dataset is busy".into(),
+            cleanup: "/usr/sbin/zfs destroy -r rpool/falcon/topo/duo/violin"
+                .into(),
+        });
+        assert!(!report.is_clean());
+        let s = report.to_string();
+        assert!(
+            s.contains("zvol rpool/falcon/topo/duo/violin: dataset is busy")
+        );
+        assert!(s.contains(
+            "\n  /usr/sbin/zfs destroy -r rpool/falcon/topo/duo/violin\n"
+        ));
+    }
+}
@@ -0,0 +1,414 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+// Copyright 2022 Oxide Computer Company
+
+//! Retrying operations that depend on something outside of falcon's control,
+//! such as a propolis server coming up or a guest service settling. Each kind
+//! of operation has a built-in [`RetryPolicy`], which can be replaced for all
+//! operations at once or for one kind of operation on the `Runner`.
+
+use crate::Runner;
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use slog::{debug, trace, Logger};
+use std::fmt;
+use std::future::Future;
+use tokio::time::{sleep, Duration, Instant};
+
+/// The kinds of operation falcon retries.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum RetryOp {
+    /// Asking a freshly launched propolis server to create its instance.
+    PropolisEnsure,
+    /// Connecting to a node's serial console websocket.
+    ConsoleConnect,
+    /// Running a command on a node. Commands are not retried, the policy's
+    /// deadline bounds how long each one may run.
+    Exec,
+    /// Polling guest state, such as waiting for a service to come online.
+    StatePoll,
+    /// Destroying datasets that freshly killed instances may still hold.
+    ZfsDestroy,
+    /// Deleting data links that may still be busy.
+    LinkDelete,
+}
+
+impl RetryOp {
+    /// The policy used for this kind of operation unless the runner says
+    /// otherwise.
+    pub fn default_policy(&self) -> RetryPolicy {
+        let second = Duration::from_secs(1);
+        match self {
+            Self::PropolisEnsure | Self::ConsoleConnect | Self::LinkDelete => {
+                RetryPolicy::fixed(31, second)
+            }
+            Self::Exec => RetryPolicy::fixed(1, second),
+            Self::StatePoll => RetryPolicy::fixed(1, second).unlimited(),
+            Self::ZfsDestroy => RetryPolicy::fixed(10, second),
+        }
+    }
+}
+
+impl fmt::Display for RetryOp {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::PropolisEnsure => write!(f, "propolis instance ensure"),
+            Self::ConsoleConnect => write!(f, "console connect"),
+            Self::Exec => write!(f, "exec"),
+            Self::StatePoll => write!(f, "state poll"),
+            Self::ZfsDestroy => write!(f, "zfs destroy"),
+            Self::LinkDelete => write!(f, "link delete"),
+        }
+    }
+}
+
+/// How often and how long to try an operation.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct RetryPolicy {
+    /// Give up after this many attempts, `None` for no limit.
+    pub max_attempts: Option<u32>,
+    /// The wait after the first failed attempt.
+    pub base: Duration,
+    /// Each wait is this many times the one before.
+    pub backoff: f64,
+    /// The longest single wait.
+    pub max_delay: Duration,
+    /// Give up once this long has passed since the first attempt.
+    pub deadline: Option<Duration>,
+    /// Vary each wait randomly by up to this fraction of it.
+    pub jitter: f64,
+}
+
+impl RetryPolicy {
+    /// Make up to `attempts` attempts, `delay` apart.
+    pub fn fixed(attempts: u32, delay: Duration) -> Self {
+        RetryPolicy {
+            max_attempts: Some(attempts),
+            base: delay,
+            backoff: 1.0,
+            max_delay: delay,
+            deadline: None,
+            jitter: 0.0,
+        }
+    }
+
+    /// Multiply the wait by `factor` after each attempt, up to `max_delay`.
+    pub fn backoff(mut self, factor: f64, max_delay: Duration) -> Self {
+        self.backoff = factor;
+        self.max_delay = max_delay;
+        self
+    }
+
+    pub fn deadline(mut self, deadline: Duration) -> Self {
+        self.deadline = Some(deadline);
+        self
+    }
+
+    pub fn jitter(mut self, fraction: f64) -> Self {
+        self.jitter = fraction;
+        self
+    }
+
+    /// Remove the limit on attempts.
+    pub fn unlimited(mut self) -> Self {
+        self.max_attempts = None;
+        self
+    }
+
+    /// The wait after failed attempt number `attempt`, counting from one.
+    /// `sample` in `[-1, 1]` picks where in the jitter range the wait lands.
+    pub fn delay(&self, attempt: u32, sample: f64) -> Duration {
+        let exp = attempt.saturating_sub(1).min(i32::MAX as u32) as i32;
+        let d = (self.base.as_secs_f64() * self.backoff.powi(exp))
+            .min(self.max_delay.as_secs_f64());
+        let d = d * (1.0 + self.jitter * sample.clamp(-1.0, 1.0));
+        Duration::from_secs_f64(d.max(0.0))
+    }
+
+    /// Start following the policy with a first attempt at `start`.
+    pub fn schedule(&self, start: Instant) -> Schedule {
+        self.schedule_seeded(start, rand::thread_rng().gen())
+    }
+
+    /// Like `schedule`, with jitter drawn from a generator seeded by `seed`.
+    pub fn schedule_seeded(&self, start: Instant, seed: u64) -> Schedule {
+        Schedule {
+            policy: *self,
+            start,
+            attempts: 0,
+            rng: StdRng::seed_from_u64(seed),
+        }
+    }
+}
+
+impl fmt::Display for RetryPolicy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.max_attempts {
+            Some(n) => write!(f, "up to {} attempts", n)?,
+            None => write!(f, "unlimited attempts")?,
+        }
+        if self.backoff == 1.0 || self.max_delay <= self.base {
+            write!(f, " {:?} apart", self.base)?;
+        } else {
+            write!(
+                f,
+                " from {:?} apart backing off x{} to {:?}",
+                self.base, self.backoff, self.max_delay
+            )?;
+        }
+        if let Some(d) = self.deadline {
+            write!(f, " within {:?}", d)?;
+        }
+        if self.jitter > 0.0 {
+            write!(f, " with {:.0}% jitter", self.jitter * 100.0)?;
+        }
+        Ok(())
+    }
+}
+
+/// The progress of an operation through its retry policy.
+pub struct Schedule {
+    policy: RetryPolicy,
+    start: Instant,
+    attempts: u32,
+    rng: StdRng,
+}
+
+impl Schedule {
+    /// Account for an attempt that failed at `now`. Returns how long to wait
+    /// before the next attempt, or `None` if the policy is spent. A wait never
+    /// runs past the deadline.
+    pub fn next_delay(&mut self, now: Instant) -> Option<Duration> {
+        self.attempts += 1;
+        let p = &self.policy;
+        if p.max_attempts.map_or(false, |m| self.attempts >= m) {
+            return None;
+        }
+        let sample = self.rng.gen_range(-1.0..=1.0);
+        let delay = p.delay(self.attempts, sample);
+        match p.deadline {
+            Some(deadline) => {
+                let left =
+                    (self.start + deadline).checked_duration_since(now)?;
+                if left.is_zero() {
+                    return None;
+                }
+                Some(delay.min(left))
+            }
+            None => Some(delay),
+        }
+    }
+
+    /// The attempts made so far.
+    pub fn attempts(&self) -> u32 {
+        self.attempts
+    }
+}
+
+/// Run `f` until it succeeds or `policy` is spent, returning the last error.
+/// The policy is logged the first time `f` fails, so it shows up in verbose
+/// output when falcon starts waiting on something.
+pub(crate) async fn retry<T, E, F, Fut>(
+    policy: &RetryPolicy,
+    log: &Logger,
+    what: &str,
+    mut f: F,
+) -> Result<T, E>
+where
+    F: FnMut() -> Fut,
+    Fut: Future<Output = Result<T, E>>,
+    E: fmt::Display,
+{
+    let mut schedule = policy.schedule(Instant::now());
+    loop {
+        let e = match f().await {
+            Ok(v) => return Ok(v),
+            Err(e) => e,
+        };
+        let delay = match schedule.next_delay(Instant::now()) {
+            Some(d) => d,
+            None => return Err(e),
+        };
+        log_retry(log, what, &e, &schedule, policy);
+        sleep(delay).await;
+    }
+}
+
+/// `retry` for operations that block.
+pub(crate) fn retry_blocking<T, E, F>(
+    policy: &RetryPolicy,
+    log: &Logger,
+    what: &str,
+    mut f: F,
+) -> Result<T, E>
+where
+    F: FnMut() -> Result<T, E>,
+    E: fmt::Display,
+{
+    let mut schedule = policy.schedule(Instant::now());
+    loop {
+        let e = match f() {
+            Ok(v) => return Ok(v),
+            Err(e) => e,
+        };
+        let delay = match schedule.next_delay(Instant::now()) {
+            Some(d) => d,
+            None => return Err(e),
+        };
+        log_retry(log, what, &e, &schedule, policy);
+        std::thread::sleep(delay);
+    }
+}
+
+/// Log a failed attempt. The first failure also logs the policy in effect.
+pub(crate) fn log_retry(
+    log: &Logger,
+    what: &str,
+    e: &dyn fmt::Display,
+    schedule: &Schedule,
+    policy: &RetryPolicy,
+) {
+    if schedule.attempts() == 1 {
+        debug!(log, "{}: {}, retrying {}", what, e, policy);
+    } else {
+        trace!(log, "{}: attempt {}: {}", what, schedule.attempts(), e);
+    }
+}
+
+impl Runner {
+    /// Use `policy` for every kind of operation that has no policy of its own
+    /// set with `set_retry_for`.
+    pub fn set_retry(&mut self, policy: RetryPolicy) {
+        self.retry = Some(policy);
+    }
+
+    /// Use `policy` for one kind of operation.
+    pub fn set_retry_for(&mut self, op: RetryOp, policy: RetryPolicy) {
+        self.retry_overrides.insert(op, policy);
+    }
+
+    /// The policy in effect for a kind of operation.
+    pub fn retry_policy(&self, op: RetryOp) -> RetryPolicy {
+        self.retry_overrides
+            .get(&op)
+            .copied()
+            .or(self.retry)
+            .unwrap_or_else(|| op.default_policy())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use anyhow::Result;
+
+    /// Test that retry schedules grow, cap and stop as their policy says when
+    /// driven by a hand-rolled clock, that jitter is bounded and reproducible,
+    /// and that runner overrides take precedence over the built-in policies.
+    #[tokio::test(start_paused = true)]
+    async fn retry_policy_schedule() -> Result<()> {
+        use crate::retry::{retry, RetryOp, RetryPolicy};
+        use tokio::time::{Duration, Instant};
+
+        let ms = Duration::from_millis;
+        let t0 = Instant::now();
+
+        // a fixed policy waits the same each time and stops at its attempt
+        // limit
+        let mut s = RetryPolicy::fixed(3, ms(100)).schedule(t0);
+        assert_eq!(s.next_delay(t0), Some(ms(100)));
+        assert_eq!(s.next_delay(t0 + ms(100)), Some(ms(100)));
+        assert_eq!(s.next_delay(t0 + ms(200)), None);
+        assert_eq!(s.attempts(), 3);
+
+        // backoff doubles up to the cap, and the deadline trims the last wait
+        let p = RetryPolicy::fixed(10, ms(100))
+            .backoff(2.0, ms(500))
+            .deadline(ms(1000));
+        let mut s = p.schedule(t0);
+        let mut now = t0;
+        let mut waits = Vec::new();
+        while let Some(d) = s.next_delay(now) {
+            waits.push(d);
+            now += d;
+        }
+        assert_eq!(waits, [ms(100), ms(200), ms(400), ms(300)]);
+        assert_eq!(now - t0, ms(1000));
+        assert_eq!(s.attempts(), 5);
+
+        // jitter stays within its fraction and follows the seed
+        let p = RetryPolicy::fixed(100, ms(1000)).jitter(0.25).unlimited();
+        let draw = |seed| {
+            let mut s = p.schedule_seeded(t0, seed);
+            (0..50)
+                .map(|_| s.next_delay(t0).unwrap())
+                .collect::<Vec<_>>()
+        };
+        let a = draw(7);
+        assert_eq!(a, draw(7));
+        assert_ne!(a, draw(8));
+        assert!(a.iter().all(|d| *d >= ms(750) && *d <= ms(1250)));
+        assert_eq!(p.delay(1, -1.0), ms(750));
+        assert_eq!(p.delay(1, 1.0), ms(1250));
+
+        assert_eq!(
+            RetryPolicy::fixed(31, Duration::from_secs(1)).to_string(),
+            "up to 31 attempts 1s apart",
+        );
+        assert_eq!(
+            RetryPolicy::fixed(5, ms(100))
+                .backoff(2.0, Duration::from_secs(2))
+                .deadline(Duration::from_secs(10))
+                .jitter(0.1)
+                .unlimited()
+                .to_string(),
+            "unlimited attempts from 100ms apart backing off x2 to 2s \
+            within 10s with 10% jitter",
+        );
+
+        // per operation overrides beat the global policy, which beats defaults
+        let mut r = crate::Runner::new("retry");
+        r.persistent = true;
+        assert_eq!(
+            r.retry_policy(RetryOp::ZfsDestroy),
+            RetryOp::ZfsDestroy.default_policy()
+        );
+        let global = RetryPolicy::fixed(2, ms(10));
+        let exec = RetryPolicy::fixed(1, ms(10)).deadline(ms(5000));
+        r.set_retry(global);
+        r.set_retry_for(RetryOp::Exec, exec);
+        assert_eq!(r.retry_policy(RetryOp::ZfsDestroy), global);
+        assert_eq!(r.retry_policy(RetryOp::Exec), exec);
+
+        // the retry loop sleeps on the tokio clock and returns the last error
+        let log = slog::Logger::root(slog::Discard, slog::o!());
+        let p = RetryPolicy::fixed(4, ms(100)).backoff(2.0, ms(1000));
+        let mut calls = 0;
+        let start = Instant::now();
+        let result: Result<(), String> = retry(&p, &log, "test", || {
+            calls += 1;
+            let n = calls;
+            async move { Err(format!("failure {}", n)) }
+        })
+        .await;
+        assert_eq!(result, Err("failure 4".to_string()));
+        assert_eq!(start.elapsed(), ms(700));
+
+        let mut calls = 0;
+        let result: Result<u32, String> = retry(&p, &log, "test", || {
+            calls += 1;
+            let n = calls;
+            async move {
+                match n {
+                    3 => Ok(n),
+                    _ => Err("not yet".to_string()),
+                }
+            }
+        })
+        .await;
+        assert_eq!(result, Ok(3));
+
+        Ok(())
+    }
+}
@@ -0,0 +1,189 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+// Copyright 2022 Oxide Computer Company
+
+//! Built-in guest personas. A role expands to commands run on the node's
+//! console at launch, after falcon's own guest configuration.
+
+use crate::error::Error;
+use crate::{Deployment, GuestKind, Node, NodeRef, Runner};
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::net::Ipv4Addr;
+use std::ops::RangeInclusive;
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Role {
+    /// iperf3 installed with a server listening on its default port.
+    TrafficGen,
+    /// IPv4 forwarding between the node's links.
+    Ipv4Router,
+    /// A DHCP server handing out addresses from `range` on the node's links.
+    /// The node must have an address in the same subnet as the range.
+    DhcpServer { range: RangeInclusive<Ipv4Addr> },
+}
+
+impl fmt::Display for Role {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::TrafficGen => write!(f, "traffic-gen"),
+            Self::Ipv4Router => write!(f, "ipv4-router"),
+            Self::DhcpServer { range } => {
+                write!(f, "dhcp-server({}-{})", range.start(), range.end())
+            }
+        }
+    }
+}
+
+impl Role {
+    /// Check the role can be applied to `n` as part of `d`.
+    pub(crate) fn check(&self, n: &Node, d: &Deployment) -> Result<(), Error> {
+        let unsupported = || {
+            Error::UnsupportedGuest(format!(
+                "{}: role {} is not available on {:?} guests",
+                n.name, self, n.guest
+            ))
+        };
+        // management interfaces are not part of the data plane
+        let stub = d.mgmt_stub_name();
+        let attachments = d.links_of(&n.name).len()
+            + d.ext_links
+                .iter()
+                .filter(|l| l.host_ifx != stub)
+                .filter(|l| d.nodes[l.endpoint.node.index].name == n.name)
+                .count();
+
+        match (self, n.guest) {
+            (_, GuestKind::Other) => return Err(unsupported()),
+            (Self::DhcpServer { .. }, GuestKind::Helios) => {
+                return Err(unsupported())
+            }
+            _ => {}
+        }
+        match self {
+            Self::TrafficGen => Ok(()),
+            Self::Ipv4Router if attachments < 2 => Err(Error::Role(format!(
+                "{}: {} needs at least two links to route between",
+                n.name, self
+            ))),
+            Self::Ipv4Router => Ok(()),
+            Self::DhcpServer { range } if range.is_empty() => Err(Error::Role(
+                format!("{}: {} has an empty range", n.name, self),
+            )),
+            Self::DhcpServer { .. } if attachments == 0 => {
+                Err(Error::Role(format!(
+                    "{}: {} needs a link to serve addresses on",
+                    n.name, self
+                )))
+            }
+            Self::DhcpServer { .. } => Ok(()),
+        }
+    }
+
+    /// The commands that put this role in place on a guest of kind `guest`.
+    /// Only meaningful for roles that passed `check`.
+    pub(crate) fn commands(&self, guest: GuestKind) -> Vec<String> {
+        let apt = "DEBIAN_FRONTEND=noninteractive apt-get install -y -q";
+        match (self, guest) {
+            (Self::TrafficGen, GuestKind::Helios) => {
+                vec!["pkg install -q iperf".into(), "iperf3 -s -D".into()]
+            }
+            (Self::TrafficGen, _) => {
+                vec![format!("{} iperf3", apt), "iperf3 -s -D".into()]
+            }
+            (Self::Ipv4Router, GuestKind::Helios) => {
+                vec!["routeadm -u -e ipv4-forwarding".into()]
+            }
+            (Self::Ipv4Router, _) => {
+                vec!["sysctl -w net.ipv4.ip_forward=1".into()]
+            }
+            (Self::DhcpServer { range }, _) => vec![
+                format!("{} dnsmasq-base", apt),
+                format!(
+                    "dnsmasq --port=0 --dhcp-range={},{},12h",
+                    range.start(),
+                    range.end()
+                ),
+            ],
+        }
+    }
+}
+
+impl Node {
+    /// Commands applying this node's roles, in the order they were assigned.
+    pub(crate) fn role_commands(&self) -> Vec<String> {
+        self.roles
+            .iter()
+            .flat_map(|r| r.commands(self.guest))
+            .collect()
+    }
+}
+
+impl Runner {
+    /// Give a node a built-in role. Roles are applied at launch after the
+    /// node's guest configuration and are checked for compatibility with the
+    /// node during preflight. Assigning the same role twice has no effect.
+    pub fn role(&mut self, n: NodeRef, role: Role) {
+        let roles = &mut self.deployment.nodes[n.index].roles;
+        if !roles.contains(&role) {
+            roles.push(role);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use anyhow::Result;
+
+    #[test]
+    fn role_compatibility() -> Result<()> {
+        use crate::role::Role;
+        use std::net::Ipv4Addr;
+
+        let dhcp = Role::DhcpServer {
+            range: Ipv4Addr::new(10, 0, 0, 100)..=Ipv4Addr::new(10, 0, 0, 200),
+        };
+
+        let mut r = crate::Runner::new("roles");
+        r.persistent = true;
+        let violin = r.node("violin", "helios-2.3", 1, 1024);
+        let cello = r.node("cello", "debian-11.0", 1, 1024);
+        let piano = r.node("piano", "helios-2.3", 1, 1024);
+        let lonely = r.node("lonely", "debian-11.0", 1, 1024);
+        r.link(violin, cello);
+        r.link(cello, piano);
+        r.role(violin, Role::TrafficGen);
+        r.role(violin, Role::TrafficGen);
+        r.role(cello, Role::Ipv4Router);
+        r.role(cello, dhcp.clone());
+        r.role(piano, Role::TrafficGen);
+
+        let d = &r.deployment;
+        let check = |n, role: &Role| role.check(r.get_node(n), d);
+        check(violin, &Role::TrafficGen)?;
+        check(cello, &Role::Ipv4Router)?;
+        check(cello, &dhcp)?;
+        // a router needs two links, dhcp needs one and a linux guest
+        assert!(check(piano, &Role::Ipv4Router).is_err());
+        assert!(check(piano, &dhcp).is_err());
+        assert!(check(lonely, &dhcp).is_err());
+
+        // roles are deduplicated and expand in assignment order per guest kind
+        assert_eq!(r.get_node(violin).roles, [Role::TrafficGen]);
+        assert_eq!(
+            r.get_node(violin).role_commands(),
+            ["pkg install -q iperf", "iperf3 -s -D"]
+        );
+        let cmds = r.get_node(cello).role_commands();
+        assert_eq!(cmds[0], "sysctl -w net.ipv4.ip_forward=1");
+        assert_eq!(
+            cmds.last().unwrap(),
+            "dnsmasq --port=0 --dhcp-range=10.0.0.100,10.0.0.200,12h"
+        );
+        assert_eq!(dhcp.to_string(), "dhcp-server(10.0.0.100-10.0.0.200)");
+
+        Ok(())
+    }
+}
@@ -5,16 +5,18 @@
 // Copyright 2022 Oxide Computer Company
 
 use crate::error::Error;
-use futures::{SinkExt, StreamExt};
+use crate::retry::{self, RetryOp, RetryPolicy};
+use base64::Engine;
+use futures::{SinkExt, Stream, StreamExt};
 use regex::Regex;
+use serde::{Deserialize, Serialize};
 use slog::{debug, trace, warn, Logger};
 use std::net::SocketAddr;
 use tokio::net::TcpStream;
 use tokio::time::timeout;
-use tokio::time::{sleep, Duration};
-use tokio_tungstenite::{
-    connect_async, tungstenite::Message, MaybeTlsStream, WebSocketStream,
-};
+use tokio::time::{Duration, Instant};
+use tokio_tungstenite::tungstenite::{Error as WsError, Message};
+use tokio_tungstenite::{connect_async, MaybeTlsStream, WebSocketStream};
 
 pub enum State {
     Empty,
@@ -24,11 +26,131 @@ pub enum State {
     Executing,
 }
 
+/// What has to happen on the console after the login prompt appears before a
+/// node is considered booted.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum Quiesce {
+    /// The console has produced no output for the given duration.
+    Quiet(Duration),
+    /// A line matching the given regular expression has been printed.
+    Marker(String),
+}
+
+/// A refinement of boot readiness for guests that keep initializing services
+/// well after first presenting a login prompt.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Readiness {
+    pub quiesce: Quiesce,
+    /// The longest to wait for quiescence after the prompt. Once reached the
+    /// node is declared ready anyway.
+    pub cap: Duration,
+}
+
+impl Readiness {
+    pub const DEFAULT_CAP: Duration = Duration::from_secs(300);
+
+    /// Require `quiet` worth of console silence after the login prompt.
+    pub fn quiet(quiet: Duration) -> Self {
+        Self {
+            quiesce: Quiesce::Quiet(quiet),
+            cap: Self::DEFAULT_CAP,
+        }
+    }
+
+    /// Require a line matching `marker` after the login prompt.
+    pub fn marker(marker: impl AsRef<str>) -> Self {
+        Self {
+            quiesce: Quiesce::Marker(marker.as_ref().into()),
+            cap: Self::DEFAULT_CAP,
+        }
+    }
+
+    pub fn cap(mut self, cap: Duration) -> Self {
+        self.cap = cap;
+        self
+    }
+}
+
+/// Wait for `console` of node `name` to satisfy `readiness` after its login
+/// prompt. Returns whether it did, false when the readiness cap elapsed
+/// first.
+pub(crate) async fn quiesce<S>(
+    console: &mut S,
+    name: &str,
+    readiness: &Readiness,
+) -> Result<bool, Error>
+where
+    S: Stream<Item = Result<Message, WsError>> + Unpin,
+{
+    let deadline = Instant::now() + readiness.cap;
+    let (quiet, marker) = match &readiness.quiesce {
+        Quiesce::Quiet(d) => (Some(*d), None),
+        Quiesce::Marker(m) => (
+            None,
+            Some(Regex::new(m).map_err(|e| {
+                Error::Exec(format!("bad readiness marker {}: {}", m, e))
+            })?),
+        ),
+    };
+
+    let mut pending = String::new();
+    loop {
+        let now = Instant::now();
+        if now >= deadline {
+            return Ok(false);
+        }
+        let wait = match quiet {
+            Some(q) => q.min(deadline - now),
+            None => deadline - now,
+        };
+
+        match timeout(wait, console.next()).await {
+            Err(_) => {
+                // Only a full quiet period counts, not one cut short by the
+                // cap.
+                if quiet == Some(wait) {
+                    return Ok(true);
+                }
+            }
+            Ok(Some(Ok(Message::Binary(data)))) => {
+                if let Some(ref marker) = marker {
+                    pending += &String::from_utf8_lossy(data.as_slice());
+                    if marker.is_match(&pending) {
+                        return Ok(true);
+                    }
+                    // Only keep the partial trailing line around for the
+                    // next match.
+                    if let Some(i) = pending.rfind('\n') {
+                        pending.drain(..=i);
+                    }
+                }
+            }
+            Ok(Some(Ok(Message::Close(..)))) | Ok(None) => {
+                return Err(Error::Exec(format!(
+                    "[sc] {}: websocket closed",
+                    name
+                )));
+            }
+            Ok(Some(Ok(_))) => continue,
+            Ok(Some(Err(e))) => return Err(e.into()),
+        }
+    }
+}
+
 pub struct SerialCommander {
     pub addr: SocketAddr,
     pub instance: String,
     pub name: String,
     pub state: State,
+    /// Optional readiness refinement applied after the login prompt.
+    pub readiness: Option<Readiness>,
+    /// When the login prompt was first seen.
+    pub prompt_at: Option<Instant>,
+    /// When the console was considered quiesced, `None` when it did not
+    /// quiesce within the readiness cap.
+    pub quiesced_at: Option<Instant>,
+    /// How to keep trying to connect to the console.
+    pub retry: RetryPolicy,
     eoc_regex: Regex,
     login_prompt_regex: Regex,
     log: Logger,
@@ -37,6 +159,13 @@ pub struct SerialCommander {
 const EOC_DETECTOR: &str = "__FALCON_EXEC_FINISHED__";
 const ENTER: u8 = 0x0d;
 const USERNAME: &[u8] = "root".as_bytes();
+/// How long a console must be quiet after a command is staged.
+const STAGE_QUIET: Duration = Duration::from_millis(250);
+const STAGE_CAP: Duration = Duration::from_secs(10);
+/// The longest line `exec_lines` holds while waiting for its end.
+const MAX_LINE: usize = 64 << 10;
+/// Base64 characters sent per console line when transferring stdin.
+const STDIN_CHUNK: usize = 512;
 
 impl SerialCommander {
     pub fn new(
@@ -53,6 +182,10 @@ impl SerialCommander {
             name,
             log,
             state: State::Empty,
+            readiness: None,
+            prompt_at: None,
+            quiesced_at: None,
+            retry: RetryOp::ConsoleConnect.default_policy(),
             eoc_regex,
             login_prompt_regex,
         }
@@ -66,18 +199,11 @@ impl SerialCommander {
 
         debug!(self.log, "[sc] {}: connecting to {}", self.name, path);
 
-        for _ in 0..30 {
-            match connect_async(path.clone()).await {
-                Ok((ws, _)) => return Ok(ws),
-                Err(e) => {
-                    warn!(self.log, "[sc] {}: {}", self.name, e);
-                    sleep(Duration::from_secs(1)).await;
-                    continue;
-                }
-            }
-        }
-        // one more shot
-        let (ws, _) = connect_async(path).await?;
+        let what = format!("[sc] {}: connect", self.name);
+        let (ws, _) = retry::retry(&self.retry, &self.log, &what, || {
+            connect_async(path.clone())
+        })
+        .await?;
         Ok(ws)
     }
 
@@ -108,6 +234,28 @@ impl SerialCommander {
         }
         self.drain_match(ws, timeout, self.login_prompt_regex.clone())
             .await?;
+        self.prompt_at = Some(Instant::now());
+
+        let readiness = match self.readiness.clone() {
+            Some(r) => r,
+            None => {
+                self.quiesced_at = self.prompt_at;
+                return Ok(());
+            }
+        };
+        debug!(self.log, "[sc] {} waiting for quiescence", self.name);
+        if quiesce(ws, &self.name, &readiness).await? {
+            self.quiesced_at = Some(Instant::now());
+        } else {
+            warn!(
+                self.log,
+                "[sc] {}: console did not quiesce within {:?}, \
+                proceeding anyway",
+                self.name,
+                readiness.cap,
+            );
+        }
+
         Ok(())
     }
 
@@ -207,19 +355,91 @@ impl SerialCommander {
             .drain_match(ws, timeout_ms, self.eoc_regex.clone())
             .await?;
 
-        // Iterate over all returned lines, stripping the first.
-        // This could almost certainly be made more efficient, by perhaps never
-        // adding the first line when parsing the regex.
-        let lines = out.lines().skip(1);
-        let mut stripped = String::new();
-        for line in lines {
-            stripped.push_str(line);
-            stripped.push('\n');
+        Ok(strip_echo(&out))
+    }
+
+    /// Type a command without entering it, waiting for the console to echo
+    /// it back and settle. The command is run by a later `trigger`.
+    pub(crate) async fn stage(
+        &mut self,
+        ws: &mut WebSocketStream<MaybeTlsStream<TcpStream>>,
+        cmd: &str,
+    ) -> Result<(), Error> {
+        debug!(self.log, "[sc] {}: staging command `{}`", self.name, cmd);
+
+        ws.send(Message::binary(Vec::from(cmd.as_bytes()))).await?;
+        self.drain_quiet(ws, STAGE_QUIET, STAGE_CAP).await
+    }
+
+    /// Enter a staged command. Returns once the console echoes the enter key
+    /// back, which is when the command starts, along with the data the echo
+    /// arrived in.
+    pub(crate) async fn trigger(
+        &mut self,
+        ws: &mut WebSocketStream<MaybeTlsStream<TcpStream>>,
+    ) -> Result<(Instant, String), Error> {
+        ws.send(Message::binary(vec![ENTER])).await?;
+        loop {
+            match ws.next().await {
+                Some(Ok(Message::Binary(data))) => {
+                    let at = Instant::now();
+                    let s = String::from_utf8_lossy(data.as_slice());
+                    return Ok((at, s.to_string()));
+                }
+                Some(Ok(Message::Close(..))) | None => {
+                    return Err(Error::Exec(format!(
+                        "[sc] {}: websocket closed",
+                        self.name
+                    )));
+                }
+                Some(Ok(_)) => continue,
+                Some(Err(e)) => return Err(e.into()),
+            }
         }
-        // Remove the last `\n`
-        stripped.pop();
+    }
+
+    /// Wait for a triggered command to finish and return its output. `echoed`
+    /// is the data `trigger` returned.
+    pub(crate) async fn finish(
+        &mut self,
+        ws: &mut WebSocketStream<MaybeTlsStream<TcpStream>>,
+        echoed: String,
+        timeout_ms: Option<u64>,
+    ) -> Result<String, Error> {
+        let out = self
+            .drain_match_from(ws, timeout_ms, self.eoc_regex.clone(), echoed)
+            .await?;
+        Ok(strip_echo(&out))
+    }
 
-        Ok(stripped)
+    /// Drain from the websocket until nothing has arrived for `quiet`, failing
+    /// if that has not happened within `cap`.
+    async fn drain_quiet(
+        &mut self,
+        ws: &mut WebSocketStream<MaybeTlsStream<TcpStream>>,
+        quiet: Duration,
+        cap: Duration,
+    ) -> Result<(), Error> {
+        let deadline = Instant::now() + cap;
+        loop {
+            if Instant::now() >= deadline {
+                return Err(Error::Exec(format!(
+                    "[sc] {}: console did not settle within {:?}",
+                    self.name, cap
+                )));
+            }
+            match timeout(quiet, ws.next()).await {
+                Err(_) => return Ok(()),
+                Ok(Some(Ok(Message::Close(..)))) | Ok(None) => {
+                    return Err(Error::Exec(format!(
+                        "[sc] {}: websocket closed",
+                        self.name
+                    )));
+                }
+                Ok(Some(Ok(_))) => continue,
+                Ok(Some(Err(e))) => return Err(e.into()),
+            }
+        }
     }
 
     // Execute a command with no timeout
@@ -231,7 +451,68 @@ impl SerialCommander {
         self.exec_timeout(ws, command, None).await
     }
 
+    /// Execute a command, handing its output to `sink` a line at a time as it
+    /// arrives rather than collecting it. Only a partial trailing line is
+    /// held, and lines over `MAX_LINE` bytes are refused.
+    pub(crate) async fn exec_lines(
+        &mut self,
+        ws: &mut WebSocketStream<MaybeTlsStream<TcpStream>>,
+        cmd: &str,
+        timeout_ms: Option<u64>,
+        mut sink: impl FnMut(&str) -> Result<(), Error>,
+    ) -> Result<(), Error> {
+        debug!(self.log, "[sc] {}: streaming command `{}`", self.name, cmd);
+
+        let mut v = Vec::from(cmd.as_bytes());
+        v.push(ENTER);
+        ws.send(Message::binary(v)).await?;
+
+        let wait = Duration::from_millis(timeout_ms.unwrap_or(u64::MAX));
+        let mut pending = String::new();
+        let mut echoed = false;
+        loop {
+            let data = match timeout(wait, ws.next()).await {
+                Ok(Some(Ok(Message::Binary(data)))) => data,
+                Ok(Some(Ok(Message::Close(..)))) | Ok(None) => {
+                    return Err(Error::Exec(format!(
+                        "[sc] {}: websocket closed",
+                        self.name
+                    )));
+                }
+                Ok(Some(Ok(_))) => continue,
+                Ok(Some(Err(e))) => return Err(e.into()),
+                Err(_) => {
+                    return Err(Error::Exec(format!(
+                        "[sc] {}: timeout waiting for data",
+                        self.name
+                    )));
+                }
+            };
+            pending += &String::from_utf8_lossy(data.as_slice());
+            while let Some(i) = pending.find('\n') {
+                let line: String = pending.drain(..=i).collect();
+                let line = line.trim_end_matches(&['\r', '\n'][..]);
+                if !echoed {
+                    // the first line is the command echoed back
+                    echoed = true;
+                } else if line.contains(EOC_DETECTOR) {
+                    return Ok(());
+                } else {
+                    sink(line)?;
+                }
+            }
+            if pending.len() > MAX_LINE {
+                return Err(Error::Exec(format!(
+                    "[sc] {}: console line over {} bytes",
+                    self.name, MAX_LINE
+                )));
+            }
+        }
+    }
+
     /// Drain from the websocket until we match the provided regex or timeout.
+    /// The regex must match within a line, lines already searched are not
+    /// searched again as more data arrives.
     ///
     /// Return all read data up to the regex match or an error.
     pub async fn drain_match(
@@ -239,13 +520,31 @@ impl SerialCommander {
         ws: &mut WebSocketStream<MaybeTlsStream<TcpStream>>,
         wait_ms: Option<u64>,
         regex: Regex,
+    ) -> Result<String, Error> {
+        self.drain_match_from(ws, wait_ms, regex, String::new())
+            .await
+    }
+
+    /// Like `drain_match`, with `result` already read from the websocket.
+    async fn drain_match_from(
+        &mut self,
+        ws: &mut WebSocketStream<MaybeTlsStream<TcpStream>>,
+        wait_ms: Option<u64>,
+        regex: Regex,
+        mut result: String,
     ) -> Result<String, Error> {
         trace!(self.log, "[sc] {}: drain by matching regex", self.name);
 
+        if let Some(mat) = regex.find(&result) {
+            result.truncate(mat.start());
+            return Ok(result);
+        }
+        // where the line a later match could start on begins
+        let mut from = result.rfind('\n').map_or(0, |i| i + 1);
+
         // Use the largest possible timeout if we don't want a timeout
         let wait_ms = wait_ms.unwrap_or(u64::MAX);
 
-        let mut result = "".to_string();
         loop {
             match timeout(Duration::from_millis(wait_ms), ws.next()).await {
                 Ok(msg) => match msg {
@@ -259,7 +558,9 @@ impl SerialCommander {
                             s
                         );
                         result += &s;
-                        if let Some(mat) = regex.find(&result) {
+                        let found = regex.find_at(&result, from);
+                        from = result.rfind('\n').map_or(0, |i| i + 1);
+                        if let Some(mat) = found {
                             trace!(
                                 self.log,
                                 "[sc] {}: drained: `{}`",
@@ -323,3 +624,151 @@ impl SerialCommander {
         Ok(result)
     }
 }
+
+/// Build the console commands that stage `stdin` in the guest as base64, one
+/// short line at a time, and then run `cmd` with it decoded on its stdin.
+pub(crate) fn stdin_commands(
+    cmd: &str,
+    stdin: &[u8],
+    id: &uuid::Uuid,
+) -> Result<Vec<String>, Error> {
+    if stdin.len() > crate::SERIAL_STDIN_CAP {
+        return Err(Error::Exec(format!(
+            "stdin is {} bytes, exec over the serial console is limited to {} \
+            bytes",
+            stdin.len(),
+            crate::SERIAL_STDIN_CAP,
+        )));
+    }
+    let staged = format!("/tmp/falcon-stdin-{}.b64", id);
+    let encoded = base64::engine::general_purpose::STANDARD.encode(stdin);
+
+    let mut cmds = vec![format!(": > {}", staged)];
+    for chunk in encoded.as_bytes().chunks(STDIN_CHUNK) {
+        // base64 output is ascii, so chunks split on character boundaries
+        let chunk = std::str::from_utf8(chunk)?;
+        cmds.push(format!("printf '%s' '{}' >> {}", chunk, staged));
+    }
+    cmds.push(format!(
+        "base64 -d < {staged} | ( {cmd} ); rm -f {staged}",
+        staged = staged,
+        cmd = cmd,
+    ));
+    Ok(cmds)
+}
+
+/// Strip the echoed command line from the output of a command.
+fn strip_echo(out: &str) -> String {
+    // Iterate over all returned lines, stripping the first.
+    // This could almost certainly be made more efficient, by perhaps never
+    // adding the first line when parsing the regex.
+    let lines = out.lines().skip(1);
+    let mut stripped = String::new();
+    for line in lines {
+        stripped.push_str(line);
+        stripped.push('\n');
+    }
+    // Remove the last `\n`
+    stripped.pop();
+    stripped
+}
+
+#[cfg(test)]
+mod test {
+    use super::{quiesce, stdin_commands, Readiness};
+    use anyhow::{anyhow, Result};
+    use futures::channel::mpsc::{unbounded, UnboundedSender};
+    use tokio::time::{sleep, Duration, Instant};
+    use tokio_tungstenite::tungstenite::{Error as WsError, Message};
+
+    type Console = UnboundedSender<Result<Message, WsError>>;
+
+    /// Print `text` on `console` every `every` for `times` times, then keep
+    /// the console open and silent.
+    fn chatter(console: Console, text: &'static str, every: u64, times: u32) {
+        tokio::spawn(async move {
+            for _ in 0..times {
+                sleep(Duration::from_secs(every)).await;
+                let _ = console.unbounded_send(Ok(Message::binary(text)));
+            }
+            sleep(Duration::from_secs(3600)).await;
+            drop(console);
+        });
+    }
+
+    /// Test that a console quiesces after a full quiet period or a marker,
+    /// and that one still busy when the cap expires is reported as not
+    /// quiesced rather than quiesced at the cap.
+    #[tokio::test(start_paused = true)]
+    async fn console_quiescence() -> Result<()> {
+        let quiet = Readiness::quiet(Duration::from_secs(2))
+            .cap(Duration::from_secs(10));
+
+        let (tx, mut rx) = unbounded();
+        chatter(tx, "svc.startd: starting\r\n", 1, 3);
+        let start = Instant::now();
+        assert!(quiesce(&mut rx, "violin", &quiet).await?);
+        assert_eq!(start.elapsed().as_secs(), 5);
+
+        let (tx, mut rx) = unbounded();
+        chatter(tx, "svc.startd: starting\r\n", 1, 60);
+        let start = Instant::now();
+        assert!(!quiesce(&mut rx, "violin", &quiet).await?);
+        assert_eq!(start.elapsed().as_secs(), 10);
+
+        // a marker split across messages still matches
+        let marker = Readiness::marker("^ready").cap(Duration::from_secs(10));
+        let (tx, mut rx) = unbounded();
+        for chunk in ["booting\nrea", "dy\n"] {
+            assert!(tx.unbounded_send(Ok(Message::binary(chunk))).is_ok());
+        }
+        assert!(quiesce(&mut rx, "violin", &marker).await?);
+
+        let (tx, mut rx) = unbounded();
+        chatter(tx, "not yet\n", 1, 60);
+        let start = Instant::now();
+        assert!(!quiesce(&mut rx, "violin", &marker).await?);
+        assert_eq!(start.elapsed().as_secs(), 10);
+
+        let (tx, mut rx) = unbounded();
+        drop(tx);
+        assert!(quiesce(&mut rx, "violin", &quiet).await.is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn exec_stdin_commands() -> Result<()> {
+        use base64::Engine;
+
+        let id = uuid::Uuid::nil();
+        let input: Vec<u8> = (0..=255u8).cycle().take(1500).collect();
+        let cmds = stdin_commands("wc -c", &input, &id)?;
+
+        let staged = format!("/tmp/falcon-stdin-{}.b64", id);
+        assert_eq!(cmds[0], format!(": > {}", staged));
+        assert_eq!(
+            cmds.last().unwrap(),
+            &format!("base64 -d < {s} | ( wc -c ); rm -f {s}", s = staged)
+        );
+
+        // the staged chunks reassemble to the input, binary intact
+        let mut encoded = String::new();
+        for c in &cmds[1..cmds.len() - 1] {
+            let chunk = c
+                .strip_prefix("printf '%s' '")
+                .and_then(|c| c.strip_suffix(&format!("' >> {}", staged)))
+                .ok_or_else(|| anyhow!("unexpected chunk command {}", c))?;
+            assert!(chunk.len() <= 512);
+            encoded.push_str(chunk);
+        }
+        let decoded =
+            base64::engine::general_purpose::STANDARD.decode(encoded)?;
+        assert_eq!(decoded, input);
+
+        let big = vec![0u8; crate::SERIAL_STDIN_CAP + 1];
+        assert!(stdin_commands("cat", &big, &id).is_err());
+
+        Ok(())
+    }
+}
@@ -0,0 +1,330 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+// Copyright 2022 Oxide Computer Company
+
+//! Metadata and retention of images snapshotted from nodes. Images are
+//! tagged with zfs user properties when they are created, so retention can
+//! tell automatically named images apart from explicitly named ones, which
+//! are never pruned.
+
+use crate::error::Error;
+use crate::{ops, Backend, Plan};
+use std::cmp::Reverse;
+use std::collections::BTreeMap;
+
+pub const PROP_AUTO: &str = "falcon:auto";
+pub const PROP_NODE: &str = "falcon:node";
+pub const PROP_CREATED: &str = "falcon:created";
+pub const PROP_PURPOSE: &str = "falcon:purpose";
+
+/// An automatically named image.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AutoSnapshot {
+    /// The image dataset, e.g. `rpool/falcon/img/violin-20240601T141237`.
+    pub image: String,
+    /// The node the image was taken from.
+    pub node: String,
+    /// Seconds since the unix epoch at which the image was taken.
+    pub created: u64,
+    pub purpose: Option<String>,
+    /// Whether anything is cloned from the image.
+    pub has_clones: bool,
+}
+
+/// How many automatically named images to keep for each node.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Retention {
+    /// Always keep this many of the most recent images.
+    pub keep: usize,
+    /// Also keep anything younger than this many days.
+    pub keep_days: Option<u32>,
+}
+
+impl Default for Retention {
+    fn default() -> Self {
+        Retention {
+            keep: 5,
+            keep_days: None,
+        }
+    }
+}
+
+/// The outcome of pruning.
+#[derive(Debug, Default)]
+pub struct PruneReport {
+    pub removed: Vec<String>,
+    /// Images that were due for removal but kept, with the reason.
+    pub skipped: Vec<(String, String)>,
+}
+
+/// The name of an automatic image of `vm` taken at `secs` since the epoch,
+/// e.g. `violin-20240601T141237`.
+pub fn auto_name(vm: &str, secs: u64) -> String {
+    format!("{}-{}", vm, utc_stamp(secs))
+}
+
+/// A UTC timestamp of the form `20240601T141237`.
+pub(crate) fn utc_stamp(secs: u64) -> String {
+    // days to civil date, from Howard Hinnant's date algorithms
+    let days = (secs / 86400) as i64 + 719468;
+    let era = days.div_euclid(146097);
+    let doe = days.rem_euclid(146097);
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + i64::from(month <= 2);
+    let rem = secs % 86400;
+    format!(
+        "{:04}{:02}{:02}T{:02}{:02}{:02}",
+        year,
+        month,
+        day,
+        rem / 3600,
+        rem % 3600 / 60,
+        rem % 60
+    )
+}
+
+/// Record where an image came from and why it was taken.
+pub fn tag(
+    plan: &Plan,
+    image: &str,
+    auto: bool,
+    node: &str,
+    created: u64,
+    purpose: Option<&str>,
+) -> Result<(), Error> {
+    let mut props = vec![
+        format!("{}={}", PROP_AUTO, if auto { "on" } else { "off" }),
+        format!("{}={}", PROP_NODE, node),
+        format!("{}={}", PROP_CREATED, created),
+    ];
+    if let Some(p) = purpose {
+        props.push(format!("{}={}", PROP_PURPOSE, p));
+    }
+    let mut args = vec!["set"];
+    args.extend(props.iter().map(String::as_str));
+    args.push(image);
+    plan.zfs(&args)?;
+    Ok(())
+}
+
+/// The automatically named images under `<image_dataset>/img`.
+pub fn list_auto(
+    host: &dyn Backend,
+    image_dataset: &str,
+) -> Result<Vec<AutoSnapshot>, Error> {
+    let img = format!("{}/img", image_dataset);
+    let props = format!(
+        "name,{},{},{},{}",
+        PROP_AUTO, PROP_NODE, PROP_CREATED, PROP_PURPOSE
+    );
+    let images = ops::zfs(
+        host,
+        &[
+            "list",
+            "-Hp",
+            "-r",
+            "-d",
+            "1",
+            "-t",
+            "filesystem,volume",
+            "-o",
+            &props,
+            &img,
+        ],
+    )?;
+    let clones = ops::zfs(
+        host,
+        &[
+            "list",
+            "-Hp",
+            "-r",
+            "-d",
+            "2",
+            "-t",
+            "snapshot",
+            "-o",
+            "name,clones",
+            &img,
+        ],
+    )?;
+    Ok(parse_auto(&images, &clones))
+}
+
+/// Parse `zfs list` output of image properties and snapshot clones into the
+/// automatically named images.
+pub(crate) fn parse_auto(images: &str, clones: &str) -> Vec<AutoSnapshot> {
+    let clones = ops::parse_list_property(clones);
+    let set = |v: &str| match v {
+        "" | "-" => None,
+        v => Some(v.to_string()),
+    };
+    images
+        .lines()
+        .filter_map(|l| {
+            let f: Vec<&str> = l.split('\t').collect();
+            if f.len() != 5 || f[1] != "on" {
+                return None;
+            }
+            let prefix = format!("{}@", f[0]);
+            let has_clones = clones
+                .iter()
+                .any(|(snap, c)| snap.starts_with(&prefix) && set(c).is_some());
+            Some(AutoSnapshot {
+                image: f[0].into(),
+                node: set(f[2])?,
+                created: f[3].parse().ok()?,
+                purpose: set(f[4]),
+                has_clones,
+            })
+        })
+        .collect()
+}
+
+/// The images `policy` does not retain as of `now`. For each node the `keep`
+/// newest are kept, along with anything younger than `keep_days`.
+pub fn select_prune<'a>(
+    snaps: &'a [AutoSnapshot],
+    policy: &Retention,
+    now: u64,
+) -> Vec<&'a AutoSnapshot> {
+    let mut by_node: BTreeMap<&str, Vec<&AutoSnapshot>> = BTreeMap::new();
+    for s in snaps {
+        by_node.entry(&s.node).or_default().push(s);
+    }
+    let mut result = Vec::new();
+    for (_, mut v) in by_node {
+        v.sort_by_key(|s| Reverse(s.created));
+        result.extend(v.into_iter().skip(policy.keep).filter(|s| {
+            match policy.keep_days {
+                Some(d) => {
+                    now.saturating_sub(s.created) >= u64::from(d) * 86400
+                }
+                None => true,
+            }
+        }));
+    }
+    result
+}
+
+/// Remove the automatically named images under `image_dataset` that `policy`
+/// does not retain. Images something is cloned from are skipped.
+pub fn prune(
+    plan: &Plan,
+    image_dataset: &str,
+    policy: &Retention,
+    now: u64,
+) -> Result<PruneReport, Error> {
+    let snaps = list_auto(plan.backend(), image_dataset)?;
+    let mut report = PruneReport::default();
+    for s in select_prune(&snaps, policy, now) {
+        if s.has_clones {
+            report
+                .skipped
+                .push((s.image.clone(), "has dependent clones".into()));
+            continue;
+        }
+        match plan.zfs(&["destroy", "-r", &s.image]) {
+            Ok(_) => report.removed.push(s.image.clone()),
+            // a clone may have appeared since the listing
+            Err(Error::Zfs(e)) if e.contains("dependent clones") => {
+                report.skipped.push((s.image.clone(), e.trim().into()))
+            }
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(report)
+}
+
+#[cfg(test)]
+mod test {
+    use anyhow::Result;
+
+    #[test]
+    fn snapshot_retention() -> Result<()> {
+        use crate::config::UserConfig;
+        use crate::snapshot::{
+            auto_name, parse_auto, select_prune, AutoSnapshot, Retention,
+        };
+
+        assert_eq!(auto_name("violin", 0), "violin-19700101T000000");
+        assert_eq!(auto_name("violin", 1717251157), "violin-20240601T141237");
+        assert_eq!(auto_name("violin", 951827696), "violin-20000229T123456");
+
+        let images = "\
+            tank/img\t-\t-\t-\t-\n\
+            tank/img/helios-2.3\t-\t-\t-\t-\n\
+            tank/img/violin-good\toff\tviolin\t100\tknown good\n\
+            tank/img/violin-a\ton\tviolin\t100\t-\n\
+            tank/img/violin-b\ton\tviolin\t200\tretry\n\
+            tank/img/violin-c\ton\tviolin\t300\t-\n\
+            tank/img/piano-a\ton\tpiano\t150\t-\n\
+            tank/img/broken\ton\t-\tnope\t-\n";
+        let clones = "\
+            tank/img/helios-2.3@base\ttank/topo/duo/violin\n\
+            tank/img/violin-a@base\ttank/topo/duo/violin2\n\
+            tank/img/violin-b@base\t\n\
+            tank/img/violin-c@base\t-\n";
+        let snaps = parse_auto(images, clones);
+
+        // only tagged automatic images are candidates
+        let names: Vec<&str> = snaps.iter().map(|s| s.image.as_str()).collect();
+        assert_eq!(
+            names,
+            [
+                "tank/img/violin-a",
+                "tank/img/violin-b",
+                "tank/img/violin-c",
+                "tank/img/piano-a",
+            ]
+        );
+        assert!(snaps[0].has_clones);
+        assert!(!snaps[1].has_clones);
+        assert_eq!(snaps[1].purpose.as_deref(), Some("retry"));
+
+        let pruned = |policy: Retention, now| -> Vec<String> {
+            select_prune(&snaps, &policy, now)
+                .iter()
+                .map(|s: &&AutoSnapshot| s.image.clone())
+                .collect()
+        };
+        // newest are kept per node
+        let keep1 = Retention {
+            keep: 1,
+            keep_days: None,
+        };
+        assert_eq!(
+            pruned(keep1, 400),
+            ["tank/img/violin-b", "tank/img/violin-a"]
+        );
+        assert!(pruned(Retention::default(), 400).is_empty());
+        // young snapshots survive beyond keep
+        let days = Retention {
+            keep: 0,
+            keep_days: Some(1),
+        };
+        assert_eq!(
+            pruned(days, 86400 + 150),
+            ["tank/img/piano-a", "tank/img/violin-a"]
+        );
+
+        let mut c = UserConfig::default();
+        assert_eq!(c.retention(), Retention::default());
+        c.set("snapshot_keep", "2")?;
+        c.set("snapshot_keep_days", "14")?;
+        assert_eq!(
+            c.retention(),
+            Retention {
+                keep: 2,
+                keep_days: Some(14)
+            }
+        );
+
+        Ok(())
+    }
+}
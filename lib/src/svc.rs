@@ -0,0 +1,316 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+// Copyright 2022 Oxide Computer Company
+
+//! Typed access to SMF services in helios guests over the exec channel.
+
+use crate::error::Error;
+use crate::retry::{self, RetryOp};
+use crate::{GuestKind, NodeRef, Runner};
+use std::fmt;
+use std::str::FromStr;
+use tokio::time::{sleep, Duration, Instant};
+
+/// Marker used to recover the exit status of a command run over the serial
+/// console, which only gives us its output.
+const RC_MARKER: &str = "__FALCON_RC=";
+
+/// The state of an SMF service instance as reported by `svcs`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SmfState {
+    Uninitialized,
+    Offline,
+    Online,
+    Degraded,
+    Maintenance,
+    Disabled,
+    LegacyRun,
+    /// The instance is moving between states, `svcs` marks these with a
+    /// trailing `*`.
+    Transitioning,
+}
+
+impl FromStr for SmfState {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if s.ends_with('*') {
+            return Ok(Self::Transitioning);
+        }
+        match s {
+            "uninitialized" => Ok(Self::Uninitialized),
+            "offline" => Ok(Self::Offline),
+            "online" => Ok(Self::Online),
+            "degraded" => Ok(Self::Degraded),
+            "maintenance" => Ok(Self::Maintenance),
+            "disabled" => Ok(Self::Disabled),
+            "legacy_run" => Ok(Self::LegacyRun),
+            _ => Err(Error::Svc(format!("unknown service state '{}'", s))),
+        }
+    }
+}
+
+impl fmt::Display for SmfState {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let s = match self {
+            Self::Uninitialized => "uninitialized",
+            Self::Offline => "offline",
+            Self::Online => "online",
+            Self::Degraded => "degraded",
+            Self::Maintenance => "maintenance",
+            Self::Disabled => "disabled",
+            Self::LegacyRun => "legacy_run",
+            Self::Transitioning => "transitioning",
+        };
+        write!(f, "{}", s)
+    }
+}
+
+/// A handle for managing the SMF services of a single node. Obtained through
+/// [`Runner::smf`].
+pub struct Smf<'a> {
+    runner: &'a Runner,
+    node: NodeRef,
+}
+
+impl Runner {
+    /// Manage the SMF services of a node. Only helios guests have SMF, calls
+    /// for other guest kinds return `Error::UnsupportedGuest`.
+    pub fn smf(&self, node: NodeRef) -> Smf<'_> {
+        Smf { runner: self, node }
+    }
+}
+
+impl<'a> Smf<'a> {
+    pub async fn enable(&self, fmri: &str) -> Result<(), Error> {
+        self.svcadm("enable", fmri).await
+    }
+
+    pub async fn disable(&self, fmri: &str) -> Result<(), Error> {
+        self.svcadm("disable", fmri).await
+    }
+
+    pub async fn restart(&self, fmri: &str) -> Result<(), Error> {
+        self.svcadm("restart", fmri).await
+    }
+
+    /// Get the current state of a service instance.
+    pub async fn status(&self, fmri: &str) -> Result<SmfState, Error> {
+        self.check_guest()?;
+        let cmd = format!("svcs -H -o state,fmri {} 2>&1", fmri);
+        let (out, rc) = self.exec_rc(&cmd).await?;
+        if rc != 0 {
+            return Err(svc_error(fmri, &out));
+        }
+        let (state, _) = parse_svcs(fmri, &out)?;
+        Ok(state)
+    }
+
+    /// Wait for a service instance to come online, polling as the runner's
+    /// state poll policy says for at most `timeout`. Fails early if the
+    /// instance drops into maintenance.
+    pub async fn wait_online(
+        &self,
+        fmri: &str,
+        timeout: Duration,
+    ) -> Result<(), Error> {
+        let policy = self
+            .runner
+            .retry_policy(RetryOp::StatePoll)
+            .deadline(timeout);
+        let start = Instant::now();
+        let mut schedule = policy.schedule(start);
+        loop {
+            match self.status(fmri).await? {
+                SmfState::Online => return Ok(()),
+                SmfState::Maintenance => {
+                    return Err(self.maintenance(fmri).await);
+                }
+                state => match schedule.next_delay(Instant::now()) {
+                    Some(delay) => {
+                        let what = format!("wait online {}", fmri);
+                        retry::log_retry(
+                            &self.runner.log,
+                            &what,
+                            &state,
+                            &schedule,
+                            &policy,
+                        );
+                        sleep(delay).await;
+                    }
+                    None => {
+                        return Err(Error::Svc(format!(
+                            "{} not online after {}s, currently {}",
+                            fmri,
+                            start.elapsed().as_secs(),
+                            state,
+                        )));
+                    }
+                },
+            }
+        }
+    }
+
+    async fn svcadm(&self, verb: &str, fmri: &str) -> Result<(), Error> {
+        self.check_guest()?;
+        let cmd = format!("svcadm {} {} 2>&1", verb, fmri);
+        let (out, rc) = self.exec_rc(&cmd).await?;
+        if rc != 0 {
+            return Err(svc_error(fmri, &out));
+        }
+        if self.status(fmri).await? == SmfState::Maintenance {
+            return Err(self.maintenance(fmri).await);
+        }
+        Ok(())
+    }
+
+    /// Build a maintenance error carrying the `svcs -xv` explanation.
+    async fn maintenance(&self, fmri: &str) -> Error {
+        let detail =
+            match self.exec_rc(&format!("svcs -xv {} 2>&1", fmri)).await {
+                Ok((out, _)) => out,
+                Err(e) => format!("failed to get svcs -xv output: {}", e),
+            };
+        Error::SmfMaintenance {
+            fmri: fmri.into(),
+            detail,
+        }
+    }
+
+    fn check_guest(&self) -> Result<(), Error> {
+        let node = self.runner.get_node(self.node);
+        if node.guest != GuestKind::Helios {
+            return Err(Error::UnsupportedGuest(format!(
+                "{} is a {:?} guest, smf requires helios",
+                node.name, node.guest,
+            )));
+        }
+        Ok(())
+    }
+
+    async fn exec_rc(&self, cmd: &str) -> Result<(String, i32), Error> {
+        let out = self
+            .runner
+            .exec(self.node, &format!("{}; echo {}$?", cmd, RC_MARKER))
+            .await?;
+        split_rc(&out)
+    }
+}
+
+/// Split the output of a command run with a trailing `echo RC_MARKER$?` into
+/// the command output and its exit status.
+pub(crate) fn split_rc(out: &str) -> Result<(String, i32), Error> {
+    let (body, rc) = match out.rfind(RC_MARKER) {
+        Some(i) => (&out[..i], &out[i + RC_MARKER.len()..]),
+        None => {
+            return Err(Error::Exec(format!(
+                "exit status missing from output: {}",
+                out
+            )))
+        }
+    };
+    let rc = rc.trim().parse()?;
+    Ok((body.trim_end().to_string(), rc))
+}
+
+/// Parse the output of `svcs -H -o state,fmri` for a single instance.
+pub(crate) fn parse_svcs(
+    fmri: &str,
+    out: &str,
+) -> Result<(SmfState, String), Error> {
+    let mut found = Vec::new();
+    for line in out.lines().map(str::trim).filter(|l| !l.is_empty()) {
+        let mut fields = line.split_whitespace();
+        match (fields.next(), fields.next(), fields.next()) {
+            (Some(state), Some(instance), None) => {
+                found.push((state.parse()?, instance.to_string()));
+            }
+            _ => {
+                return Err(Error::Svc(format!(
+                    "unexpected svcs output for {}: {}",
+                    fmri, line
+                )))
+            }
+        }
+    }
+    match found.len() {
+        0 => Err(Error::NotFound(format!("service {}", fmri))),
+        1 => Ok(found.remove(0)),
+        _ => Err(Error::Svc(format!(
+            "{} matches multiple instances: {}",
+            fmri,
+            found
+                .iter()
+                .map(|(_, i)| i.as_str())
+                .collect::<Vec<_>>()
+                .join(", "),
+        ))),
+    }
+}
+
+/// Translate failed svcs/svcadm output into an error.
+fn svc_error(fmri: &str, out: &str) -> Error {
+    if out.contains("doesn't match any instances")
+        || out.contains("doesn't match any services")
+    {
+        Error::NotFound(format!("service {}", fmri))
+    } else {
+        Error::Svc(format!("{}: {}", fmri, out))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use anyhow::Result;
+
+    #[test]
+    fn svcs_parse() -> Result<()> {
+        use crate::error::Error;
+        use crate::svc::{parse_svcs, split_rc, SmfState};
+
+        let (out, rc) = split_rc(
+            "online         svc:/network/ssh:default\n__FALCON_RC=0\n",
+        )?;
+        assert_eq!(rc, 0);
+        let (state, fmri) = parse_svcs("ssh", &out)?;
+        assert_eq!(state, SmfState::Online);
+        assert_eq!(fmri, "svc:/network/ssh:default");
+
+        let (state, _) =
+            parse_svcs("ssh", "offline*       svc:/network/ssh:default")?;
+        assert_eq!(state, SmfState::Transitioning);
+
+        let (state, _) =
+            parse_svcs("ssh", "maintenance    svc:/network/ssh:default")?;
+        assert_eq!(state, SmfState::Maintenance);
+
+        assert!(matches!(parse_svcs("nope", ""), Err(Error::NotFound(_))));
+        assert!(matches!(
+            parse_svcs(
+                "ndp",
+                "online svc:/network/routing/ndp:default\n\
+                 disabled svc:/network/ndp:default",
+            ),
+            Err(Error::Svc(_))
+        ));
+
+        let (out, rc) = split_rc(
+            "svcs: Pattern 'nope' doesn't match any instances\n__FALCON_RC=1",
+        )?;
+        assert_eq!(rc, 1);
+        assert!(out.contains("doesn't match"));
+        assert!(split_rc("no marker here").is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn guest_kind_from_image() {
+        use crate::GuestKind;
+        assert_eq!(GuestKind::from_image("helios-2.3"), GuestKind::Helios);
+        assert_eq!(GuestKind::from_image("debian-11.0"), GuestKind::Linux);
+        assert_eq!(GuestKind::from_image("netbsd-9"), GuestKind::Other);
+    }
+}
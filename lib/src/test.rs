@@ -5,6 +5,47 @@
 // Copyright 2022 Oxide Computer Company
 
 use anyhow::{anyhow, Result};
+use camino::Utf8PathBuf;
+
+/// A directory for the files of one test, removed with everything in it when
+/// dropped.
+pub(crate) struct Scratch {
+    pub(crate) dir: Utf8PathBuf,
+}
+
+impl Scratch {
+    /// Create `/tmp/falcon-<tag>-test-<pid>`, clearing anything an earlier
+    /// run left there.
+    pub(crate) fn new(tag: &str) -> Result<Self> {
+        let dir = Utf8PathBuf::from(format!(
+            "/tmp/falcon-{}-test-{}",
+            tag,
+            std::process::id()
+        ));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir)?;
+        Ok(Scratch { dir })
+    }
+
+    /// A persistent runner whose falcon directory, events log, registry and
+    /// archive are all kept here, so tests running side by side never share
+    /// per-user state.
+    pub(crate) fn runner(&self, name: &str) -> crate::Runner {
+        let mut r = crate::Runner::new(name);
+        r.persistent = true;
+        r.falcon_dir = self.dir.join(name);
+        r.events_log = Some(self.dir.join("events.log"));
+        r.registry = Some(self.dir.join("topologies.ron"));
+        r.archive_dir = Some(self.dir.join("archive"));
+        r
+    }
+}
+
+impl Drop for Scratch {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_dir_all(&self.dir);
+    }
+}
 
 /// Test that when an empty deployment is launched the correct ZFS pools get
 /// created and when a deployment is destroyd the associated zfs pools are
@@ -134,3 +175,200 @@ fn check_link_absent(name: &String) -> Result<()> {
         Err(e) => Err(anyhow!("{}", e)),
     }
 }
+
+/// Test that a node's disk is cloned from an image on the same pool, and
+/// copied with zfs send/receive into a new parent when the image is on
+/// another pool.
+#[test]
+fn cross_pool_backing() -> Result<()> {
+    use crate::ops::fake;
+
+    let mut r = crate::Runner::new("pools");
+    r.persistent = true;
+    r.set_backend(fake::backend(|_, args| match args {
+        ["list", "-Hp", "-t", "all", "-o", _, names @ ..] => fake::ok(
+            names
+                .iter()
+                .map(|n| format!("{}\t42\n", n))
+                .collect::<String>(),
+        ),
+        _ => fake::ok(""),
+    }));
+    r.set_dry_run(true);
+    r.set_image_dataset("rpool/falcon");
+    r.node("violin", "helios-2.3", 1, 1024);
+    r.set_topo_dataset("rpool/falcon");
+    r.deployment.nodes[0].create_zvol_backing(&r)?;
+    r.set_topo_dataset("tank/falcon");
+    r.deployment.nodes[0].create_zvol_backing(&r)?;
+
+    let steps: Vec<String> = r
+        .plan()
+        .steps()
+        .iter()
+        .map(|s| s.to_string())
+        .filter(|s| !s.contains(" set "))
+        .collect();
+    assert_eq!(
+        steps,
+        [
+            "run /usr/sbin/zfs clone -p rpool/falcon/img/helios-2.3@base \
+            rpool/falcon/topo/pools/violin",
+            "run /usr/sbin/zfs create -p tank/falcon/topo/pools",
+            "run /usr/sbin/zfs send rpool/falcon/img/helios-2.3@base | \
+            /usr/sbin/zfs receive tank/falcon/topo/pools/violin",
+        ]
+    );
+
+    Ok(())
+}
+
+/// Test that a topology saved by an older falcon still loads. One from before
+/// images and clones could live in different datasets uses its one dataset
+/// for both, and one from before guest kinds were recorded has no guest side
+/// conveniences.
+#[test]
+fn older_topology() -> Result<()> {
+    use ron::ser::{to_string_pretty, PrettyConfig};
+
+    let mut r = crate::Runner::new("older");
+    r.persistent = true;
+    r.set_image_dataset("tank/falcon");
+    r.node("violin", "helios-2.3", 1, 1024);
+    let current = to_string_pretty(&r.deployment, PrettyConfig::new())?;
+    let older: String = current
+        .lines()
+        .filter(|l| !l.contains("topo_dataset") && !l.contains("guest:"))
+        .map(|l| format!("{}\n", l.replace("image_dataset", "dataset")))
+        .collect();
+    assert!(older.contains("dataset: \"tank/falcon\""));
+
+    let d = crate::Deployment::from_ron(&older)?;
+    assert_eq!(d.nodes[0].image_dataset, "tank/falcon");
+    assert_eq!(d.nodes[0].topo_dataset, "tank/falcon");
+    assert_eq!(d.nodes[0].guest, crate::GuestKind::Other);
+    Ok(())
+}
+
+/// Test that tearing down the network carries on past a link that cannot be
+/// removed, and reports everything that link left behind.
+#[test]
+fn net_destroy_leftovers() -> Result<()> {
+    use crate::error::Error;
+    use crate::ops::fake;
+    use crate::output::OutputCtx;
+    use crate::retry::RetryPolicy;
+    use std::time::Duration;
+
+    let mut r = crate::Runner::new("left");
+    r.persistent = true;
+    r.set_backend(fake::backend(|_, args| match args {
+        ["left_violin_vn_vnic0"] => fake::fail("link busy"),
+        _ => fake::ok(""),
+    }));
+    r.set_retry(RetryPolicy::fixed(1, Duration::ZERO));
+    r.set_output(OutputCtx::silent());
+    r.record_plan(false);
+    let violin = r.node("violin", "helios-2.3", 1, 1024);
+    let piano = r.node("piano", "helios-2.3", 1, 1024);
+    let cello = r.node("cello", "helios-2.3", 1, 1024);
+    r.link(violin, piano);
+    r.link(piano, cello);
+    let result = r.net_destroy();
+
+    let report = match result {
+        Err(Error::Destroy(report)) => report,
+        other => return Err(anyhow!("expected leftovers, got {:?}", other)),
+    };
+    let what: Vec<&str> =
+        report.leftovers.iter().map(|l| l.what.as_str()).collect();
+    assert_eq!(
+        what,
+        [
+            "vnic left_violin_vn_vnic0",
+            "simnet left_violin_vn_sim0",
+            "vnic left_piano_vn_vnic0",
+            "simnet left_piano_vn_sim0",
+        ]
+    );
+    assert!(report.leftovers[0].error.contains("link busy"));
+    // the next link was still torn down
+    assert!(r
+        .plan()
+        .steps()
+        .iter()
+        .any(|s| s.to_string().contains("left_cello_vn_vnic0")));
+
+    Ok(())
+}
+
+/// Test that a dry run of launch, and of destroy, plans exactly the steps the
+/// real operation then takes, and leaves the falcon directory as it was.
+#[tokio::test]
+async fn dry_run_launch_destroy() -> Result<()> {
+    use crate::config::PortRange;
+    use crate::ops::{fake, Plan};
+    use crate::output::OutputCtx;
+
+    let host = fake::backend(|_, args| match args {
+        ["list", "-Hp", "-t", "all", "-o", _, names @ ..] => fake::ok(
+            names
+                .iter()
+                .map(|n| format!("{}\t42\n", n))
+                .collect::<String>(),
+        ),
+        ["show-link", ..] => fake::fail("link not found"),
+        _ => fake::ok(""),
+    });
+
+    let scratch = Scratch::new("rehearsal")?;
+    let topology = |dry_run| -> Result<crate::Runner> {
+        let mut r = scratch.runner("rehearsal");
+        r.set_backend(host.clone());
+        r.set_output(OutputCtx::silent());
+        r.propolis_binary = "/bin/true".into();
+        r.port_range = Some(PortRange {
+            start: 41000,
+            end: 41100,
+        });
+        let violin = r.node("violin", "helios-2.3", 1, 1024);
+        r.do_setup(violin, false);
+        r.mgmt_network_v4("10.100.0.0/24")?;
+        r.record_plan(dry_run);
+        Ok(r)
+    };
+    // archives are named for when the destroy ran
+    let steps = |p: &Plan| -> Vec<String> {
+        p.steps()
+            .iter()
+            .map(|s| {
+                let s = s.to_string();
+                let i = match s.find("/archive/") {
+                    Some(i) => i + "/archive/".len(),
+                    None => return s,
+                };
+                let rest = &s[i..];
+                let end = rest.find(['/', ' ']).unwrap_or(rest.len());
+                format!("{}*{}", &s[..i], &rest[end..])
+            })
+            .collect()
+    };
+
+    let planned = topology(true)?;
+    planned.launch().await?;
+    assert!(!planned.falcon_dir.exists());
+    let real = topology(false)?;
+    real.launch().await?;
+    assert_eq!(steps(planned.plan()), steps(real.plan()));
+    assert!(real.plan().failed().is_none());
+
+    let planned = topology(true)?;
+    planned.destroy()?;
+    assert!(planned.falcon_dir.join("topology.ron").exists());
+    let real = topology(false)?;
+    real.destroy()?;
+    assert_eq!(steps(planned.plan()), steps(real.plan()));
+    assert!(!real.falcon_dir.exists());
+
+    Ok(())
+}
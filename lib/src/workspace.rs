@@ -0,0 +1,231 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+// Copyright 2022 Oxide Computer Company
+
+//! The falcon directory of a topology at the end of its life. A destroy that
+//! removes everything archives the topology's events, propolis cores and
+//! guest crash files, then removes the directory, so nothing stale is left
+//! for the next launch. A destroy that leaves something behind keeps the
+//! directory as it is and marks it with a [`DESTROY_FAILED`] file, which
+//! launch refuses unless forced.
+
+use crate::error::Error;
+use crate::{events, Runner, MV_BIN};
+use camino::{Utf8Path, Utf8PathBuf};
+use std::fs;
+use std::io::BufReader;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Marks a falcon directory whose destroy failed. Holds the failure.
+pub const DESTROY_FAILED: &str = "DESTROY_FAILED";
+
+/// The topology's events in its archive.
+pub const DESTROYED_LOG: &str = "destroyed.log";
+
+/// What is moved to the archive rather than removed.
+const ARCHIVED: [&str; 2] = ["cores", "crash"];
+
+/// Where destroyed topologies are archived by default, `falcon/archive`
+/// under the user's state directory.
+pub fn default_archive_dir() -> Option<Utf8PathBuf> {
+    Some(events::state_dir()?.join("archive"))
+}
+
+/// Why the last destroy of the topology in `falcon_dir` failed, if it did.
+pub fn destroy_failed(falcon_dir: &Utf8Path) -> Option<String> {
+    fs::read_to_string(falcon_dir.join(DESTROY_FAILED)).ok()
+}
+
+impl Runner {
+    /// Refuse to launch over a failed destroy unless `force_launch` is set,
+    /// in which case the marker is cleared.
+    pub(crate) fn check_destroy_failed(&self) -> Result<(), Error> {
+        let why = match destroy_failed(&self.falcon_dir) {
+            Some(why) => why,
+            None => return Ok(()),
+        };
+        if !self.force_launch {
+            return Err(Error::DestroyFailed {
+                dir: self.falcon_dir.clone(),
+                why,
+            });
+        }
+        self.output.warn(format!(
+            "launching over the failed destroy kept in {}",
+            self.falcon_dir
+        ));
+        self.plan.remove(self.falcon_dir.join(DESTROY_FAILED))
+    }
+
+    /// Keep the falcon directory of a failed destroy, marked with why.
+    pub(crate) fn mark_destroy_failed(&self, e: &Error) {
+        let marker = self.falcon_dir.join(DESTROY_FAILED);
+        let result = self
+            .plan
+            .create_dir_all(&self.falcon_dir)
+            .and_then(|_| self.plan.write(&marker, format!("{}\n", e)));
+        match result {
+            Ok(()) => self.output.warn(format!(
+                "keeping {} for forensics, see {}",
+                self.falcon_dir, marker
+            )),
+            Err(e) => self.output.warn(format!("marking {}: {}", marker, e)),
+        }
+    }
+
+    /// Archive the topology's events and any cores and crash files, then
+    /// remove the falcon directory. Returns the archive, `None` when there
+    /// is nowhere to archive to. The directory is kept if anything could not
+    /// be archived.
+    pub(crate) fn archive_workspace(
+        &self,
+    ) -> Result<Option<Utf8PathBuf>, Error> {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or(0);
+        // topologies of one name destroyed within a second of each other
+        // still get an archive each
+        let unique = uuid::Uuid::new_v4().simple().to_string();
+        let name = format!("{}-{}-{}", self.deployment.name, now, &unique[..8]);
+        let archive = self
+            .archive_dir
+            .clone()
+            .or_else(default_archive_dir)
+            .map(|d| d.join(name));
+
+        if let Some(ref archive) = archive {
+            self.plan.create_dir_all(archive)?;
+            let lines = match self.events_log.as_ref().map(fs::File::open) {
+                Some(Ok(f)) => {
+                    events::lifetime(BufReader::new(f), &self.deployment.name)
+                }
+                _ => Vec::new(),
+            };
+            let mut log = lines.join("\n");
+            if !log.is_empty() {
+                log.push('\n');
+            }
+            self.plan.write(archive.join(DESTROYED_LOG), log)?;
+
+            for kept in ARCHIVED {
+                let from = self.falcon_dir.join(kept);
+                let empty = from
+                    .read_dir_utf8()
+                    .map_or(true, |mut d| d.next().is_none());
+                if empty {
+                    continue;
+                }
+                let to = archive.join(kept);
+                let out =
+                    self.plan.run(MV_BIN, &[from.as_str(), to.as_str()])?;
+                if !out.status.success() {
+                    return Err(Error::Exec(format!(
+                        "archiving {}: {}",
+                        from,
+                        String::from_utf8_lossy(&out.stderr).trim()
+                    )));
+                }
+                self.output
+                    .warn(format!("archived {} files in {}", kept, to));
+            }
+        } else if ARCHIVED.iter().any(|k| self.falcon_dir.join(k).exists()) {
+            // nowhere to put them, so they are lost with the directory
+            self.output.warn(format!(
+                "no archive directory, removing {} with its cores and \
+                crash files",
+                self.falcon_dir
+            ));
+        }
+
+        self.output.progress("destroying workspace");
+        self.plan.remove(&self.falcon_dir)?;
+        Ok(archive)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::test::Scratch;
+    use anyhow::{anyhow, Result};
+
+    /// Test that a clean destroy archives the topology's events and crash files
+    /// before removing its falcon directory, and that a failed one keeps the
+    /// directory marked so launch refuses it unless forced.
+    #[test]
+    fn destroy_workspace() -> Result<()> {
+        use crate::error::Error;
+        use crate::ops::fake;
+        use crate::output::OutputCtx;
+        use crate::retry::RetryPolicy;
+        use crate::workspace::{destroy_failed, DESTROYED_LOG};
+        use crate::PrimaryDiskBacking;
+        use std::time::Duration;
+
+        let scratch = Scratch::new("workspace")?;
+        let host = fake::backend(|_, args| match args {
+            ["destroy", "-r", ds] if ds.ends_with("/broken") => {
+                fake::fail("permission denied")
+            }
+            [from, _] if from.ends_with("/stuck/crash") => {
+                fake::fail("mv: cannot rename")
+            }
+            _ => fake::ok(""),
+        });
+        let topology = |name: &str| -> Result<crate::Runner> {
+            let mut r = scratch.runner(name);
+            r.set_backend(host.clone());
+            r.set_retry(RetryPolicy::fixed(1, Duration::ZERO));
+            r.set_output(OutputCtx::silent());
+            r.record_plan(false);
+            let violin = r.node("violin", "helios-2.3", 1, 1024);
+            r.set_backing(violin, PrimaryDiskBacking::File);
+            std::fs::create_dir_all(r.falcon_dir.join("crash"))?;
+            std::fs::write(r.falcon_dir.join("crash/violin.panic"), "panic")?;
+            Ok(r)
+        };
+
+        let tidy = topology("tidy")?;
+        tidy.destroy()?;
+        assert!(!tidy.falcon_dir.exists());
+        let archive = std::fs::read_dir(scratch.dir.join("archive"))?
+            .next()
+            .ok_or_else(|| anyhow!("nothing archived"))??
+            .path();
+        let log = std::fs::read_to_string(archive.join(DESTROYED_LOG))?;
+        assert_eq!(log.lines().count(), 2);
+        assert!(log.lines().all(|l| l.contains(r#""op":"destroy""#)));
+        let moved = tidy
+            .plan()
+            .steps()
+            .iter()
+            .any(|s| s.to_string().starts_with("run /usr/bin/mv"));
+        assert!(moved);
+
+        // a crash file that cannot be archived keeps the directory too
+        let stuck = topology("stuck")?;
+        assert!(matches!(stuck.destroy(), Err(Error::Exec(_))));
+        let why = destroy_failed(&stuck.falcon_dir);
+        assert!(why.map_or(false, |w| w.contains("cannot rename")));
+        assert!(stuck.falcon_dir.join("crash/violin.panic").exists());
+
+        let broken = topology("broken")?;
+        assert!(matches!(broken.destroy(), Err(Error::Destroy(_))));
+        let why = destroy_failed(&broken.falcon_dir);
+        assert!(why.map_or(false, |w| w.contains("permission denied")));
+        assert!(broken.falcon_dir.join("crash/violin.panic").exists());
+        assert!(matches!(
+            broken.check_destroy_failed(),
+            Err(Error::DestroyFailed { .. })
+        ));
+
+        let mut forced = broken;
+        forced.force_launch = true;
+        forced.check_destroy_failed()?;
+        assert!(destroy_failed(&forced.falcon_dir).is_none());
+
+        Ok(())
+    }
+}